@@ -1,35 +1,329 @@
-use crate::{database::Database, error::AggregatorError};
-use chrono::{DateTime, Utc};
-use futures_util::StreamExt;
+use crate::{
+    config::{
+        Config, InsertFailurePolicy, SubscribeMode, DEFAULT_TIMESTAMP_FORMAT,
+        RFC3339_TIMESTAMP_FORMAT,
+    },
+    database::{
+        DatabaseWriter, Store, TransactionRecord, STATUS_FAILED, STATUS_SUCCESS, TRANSFER_TYPE_SOL,
+        TRANSFER_TYPE_TOKEN,
+    },
+    dedup::DedupCache,
+    error::{report, AggregatorError, DatabaseError, SourceError},
+    events,
+    metrics::{Metrics, METRICS},
+    rate_limit::RateLimiter,
+    retention,
+    shutdown::{self, Shutdown},
+};
+use chrono::{DateTime, FixedOffset, NaiveDateTime, Offset, SecondsFormat, Utc};
+use futures_util::{Stream, StreamExt};
 use solana_client::{
-    nonblocking::pubsub_client::PubsubClient, rpc_client::RpcClient, rpc_request::RpcRequest,
+    client_error::{ClientError, ClientErrorKind},
+    nonblocking::{pubsub_client::PubsubClient, rpc_client::RpcClient},
+    rpc_client::GetConfirmedSignaturesForAddress2Config,
+    rpc_config::{
+        RpcBlockConfig, RpcBlockSubscribeConfig, RpcBlockSubscribeFilter, RpcTransactionConfig,
+    },
+    rpc_custom_error::{
+        JSON_RPC_SERVER_ERROR_LONG_TERM_STORAGE_SLOT_SKIPPED, JSON_RPC_SERVER_ERROR_SLOT_SKIPPED,
+    },
+    rpc_request::{RpcError, RpcRequest},
+    rpc_response::{RpcBlockUpdate, SlotInfo},
+};
+use solana_sdk::{
+    bs58,
+    commitment_config::{CommitmentConfig, CommitmentLevel},
+    pubkey::Pubkey,
+    signature::Signature,
+    vote,
 };
-use solana_sdk::pubkey::Pubkey;
 use solana_transaction_status::{
-    EncodedConfirmedBlock, EncodedTransaction, EncodedTransactionWithStatusMeta, UiMessage,
-    UiRawMessage, UiTransactionStatusMeta,
+    option_serializer::OptionSerializer, parse_accounts::ParsedAccount, EncodedConfirmedBlock,
+    EncodedConfirmedTransactionWithStatusMeta, EncodedTransaction,
+    EncodedTransactionWithStatusMeta, TransactionDetails, UiInstruction, UiMessage,
+    UiParsedInstruction, UiTransactionEncoding, UiTransactionStatusMeta, UiTransactionTokenBalance,
 };
 use std::{
+    cell::RefCell,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fmt,
+    future::Future,
+    pin::pin,
     str::FromStr,
-    thread,
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Arc,
+    },
     time::{Duration, UNIX_EPOCH},
 };
-use tokio::runtime::Handle;
-const MAX_ITERATIONS: i32 = 100;
+use tokio::{
+    runtime::Handle,
+    sync::{
+        mpsc::{self, UnboundedSender},
+        Mutex, Semaphore,
+    },
+};
+use tracing::{debug, error, info, info_span, instrument, warn, Instrument};
+
+/// The SPL Token program.
+const TOKEN_PROGRAM_ID: &str = "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA";
+/// The SPL Token-2022 program, whose transfer instructions have the same layout.
+const TOKEN_2022_PROGRAM_ID: &str = "TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb";
+/// The first byte of the data of a token program `transfer` instruction.
+const TRANSFER_INSTRUCTION: u8 = 3;
+/// The first byte of the data of a token program `transferChecked` instruction.
+const TRANSFER_CHECKED_INSTRUCTION: u8 = 12;
+/// The number of transaction records logged for each block in a dry run.
+const DRY_RUN_SAMPLE_RECORDS: usize = 3;
+
+/// How the timestamps of the transactions are formatted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimestampFormat {
+    /// The `strftime` format string, or `None` for RFC 3339.
+    pub format: Option<String>,
+    /// The offset from UTC the timestamps are given in.
+    pub offset: FixedOffset,
+}
+
+impl Default for TimestampFormat {
+    /// The `%Y-%m-%d %H:%M:%S` format in UTC.
+    fn default() -> TimestampFormat {
+        TimestampFormat {
+            format: Some(DEFAULT_TIMESTAMP_FORMAT.to_string()),
+            offset: Utc.fix(),
+        }
+    }
+}
+
+impl TimestampFormat {
+    /// Creates the `TimestampFormat` of the `timestamp_format` and `timestamp_offset` settings.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration.
+    pub fn from_config(config: &Config) -> TimestampFormat {
+        TimestampFormat {
+            format: Some(config.timestamp_format.clone())
+                .filter(|format| format != RFC3339_TIMESTAMP_FORMAT),
+            offset: config.timestamp_offset,
+        }
+    }
+
+    /// Converts a Unix timestamp to a formatted string.
+    ///
+    /// # Arguments
+    ///
+    /// * `timestamp` - The Unix timestamp to convert.
+    ///
+    /// # Returns
+    ///
+    /// A string representing the formatted timestamp.
+    pub fn format(&self, timestamp: i64) -> String {
+        let d = UNIX_EPOCH + Duration::from_secs(timestamp as u64);
+        let datetime = DateTime::<Utc>::from(d).with_timezone(&self.offset);
+        match &self.format {
+            Some(format) => datetime.format(format).to_string(),
+            None => datetime.to_rfc3339_opts(SecondsFormat::Secs, true),
+        }
+    }
+
+    /// Formats a stored timestamp, which is in the default format in UTC.
+    ///
+    /// # Arguments
+    ///
+    /// * `stored` - The stored timestamp.
+    ///
+    /// # Returns
+    ///
+    /// The formatted timestamp, or the stored one unchanged if it isn't in the default format,
+    /// like the timestamps stored in another format by older versions.
+    pub fn reformat(&self, stored: &str) -> String {
+        if *self == TimestampFormat::default() {
+            return stored.to_string();
+        }
+        match NaiveDateTime::parse_from_str(stored, DEFAULT_TIMESTAMP_FORMAT) {
+            Ok(datetime) => self.format(datetime.and_utc().timestamp()),
+            Err(_) => stored.to_string(),
+        }
+    }
+}
+
+/// Options controlling how the transactions of a block are processed.
+#[derive(Debug, Default, Clone)]
+pub struct BlockOptions {
+    /// The index of the account whose balance change is used as the transaction amount.
+    pub sender_index: usize,
+    /// Skip transactions whose balances can't be read instead of failing the block.
+    pub lenient_balances: bool,
+    /// Store the transactions of blocks without a block time instead of failing the block.
+    pub lenient_block_time: bool,
+    /// Skip transactions that can't be parsed instead of failing the block.
+    pub lenient_transactions: bool,
+    /// Only keep transactions touching one of these accounts, unless it is empty.
+    pub allowlist: Vec<String>,
+    /// Skip transactions touching one of these accounts.
+    pub denylist: Vec<String>,
+    /// Only keep transactions invoking one of these programs, unless it is empty.
+    pub program_ids: Vec<String>,
+    /// Store the log messages of the transactions.
+    pub store_logs: bool,
+    /// Store vote transactions instead of skipping them.
+    pub include_votes: bool,
+    /// Skip transactions with fewer signatures than this.
+    pub min_signatures: usize,
+    /// The format of the timestamps of the transactions published to the `/stream` subscribers.
+    /// The stored timestamps are always in the default format in UTC.
+    pub timestamp_format: TimestampFormat,
+    /// Whether a failed insertion fails the block or skips the failing transactions.
+    pub insert_failure_policy: InsertFailurePolicy,
+    /// Log a summary of the parsed transactions instead of inserting them.
+    pub dry_run: bool,
+}
+
+impl BlockOptions {
+    /// Creates `BlockOptions` from the configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration.
+    pub fn from_config(config: &Config) -> BlockOptions {
+        let accounts = |list: &Vec<String>| {
+            list.iter()
+                .map(|account| account.trim().to_string())
+                .filter(|account| !account.is_empty())
+                .collect()
+        };
+        BlockOptions {
+            sender_index: config.sender_index,
+            lenient_balances: config.lenient_balances,
+            lenient_block_time: config.lenient_block_time,
+            lenient_transactions: config.lenient_transactions,
+            allowlist: accounts(&config.account_allowlist),
+            denylist: accounts(&config.account_denylist),
+            program_ids: accounts(&config.program_ids),
+            store_logs: config.store_logs,
+            include_votes: config.include_votes,
+            min_signatures: config.min_signatures,
+            timestamp_format: TimestampFormat::from_config(config),
+            insert_failure_policy: config.insert_failure_policy,
+            dry_run: config.dry_run,
+        }
+    }
+
+    /// Checks whether a transaction passes the account allow and deny lists.
+    ///
+    /// # Arguments
+    ///
+    /// * `encoded_transaction` - The encoded transaction with status metadata.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the transaction should be kept. Transactions whose accounts can't be read are kept.
+    pub fn is_tracked(&self, encoded_transaction: &EncodedTransactionWithStatusMeta) -> bool {
+        let (_, account_keys) = match transaction_keys(encoded_transaction) {
+            Some(res) => res,
+            None => return true,
+        };
+        let allowed = self.allowlist.is_empty()
+            || account_keys
+                .iter()
+                .any(|account| self.allowlist.contains(account));
+        let denied = account_keys
+            .iter()
+            .any(|account| self.denylist.contains(account));
+        allowed && !denied
+    }
+
+    /// Checks whether a transaction invokes one of the tracked programs.
+    ///
+    /// Only the programs of the top-level instructions are matched, not the ones they invoke.
+    ///
+    /// # Arguments
+    ///
+    /// * `encoded_transaction` - The encoded transaction with status metadata.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the transaction should be kept, which they all are when `program_ids` is empty.
+    /// Transactions whose accounts can't be read are kept.
+    pub fn invokes_tracked_program(
+        &self,
+        encoded_transaction: &EncodedTransactionWithStatusMeta,
+    ) -> bool {
+        if self.program_ids.is_empty() {
+            return true;
+        }
+        let (_, account_keys) = match transaction_keys(encoded_transaction) {
+            Some(res) => res,
+            None => return true,
+        };
+        program_ids(&encoded_transaction.transaction, &account_keys)
+            .iter()
+            .any(|program_id| self.program_ids.contains(program_id))
+    }
+}
 
-#[derive(serde::Deserialize)]
-struct Env {
-    ws_url: url::Url,
-    rpc_url: url::Url,
+/// Checks whether a transaction invokes the vote program.
+///
+/// # Arguments
+///
+/// * `encoded_transaction` - The encoded transaction with status metadata.
+///
+/// # Returns
+///
+/// `true` if one of the transaction's instructions invokes the vote program.
+pub fn is_vote_transaction(encoded_transaction: &EncodedTransactionWithStatusMeta) -> bool {
+    let vote_program = vote::program::ID.to_string();
+    match &encoded_transaction.transaction {
+        EncodedTransaction::Json(message) => match &message.message {
+            UiMessage::Raw(msg) => msg.instructions.iter().any(|instruction| {
+                msg.account_keys
+                    .get(instruction.program_id_index as usize)
+                    .is_some_and(|program| *program == vote_program)
+            }),
+            _ => false,
+        },
+        transaction => match transaction.decode() {
+            Some(decoded) => decoded.message.instructions().iter().any(|instruction| {
+                decoded
+                    .message
+                    .static_account_keys()
+                    .get(instruction.program_id_index as usize)
+                    .is_some_and(|program| *program == vote::program::ID)
+            }),
+            None => false,
+        },
+    }
 }
 
+/// A transaction being parsed.
+///
+/// The amounts are measured on the account at `BlockOptions::sender_index`, the fee payer by
+/// default, and exclude the fee when it is the fee payer.
 #[derive(Debug)]
 struct Transaction {
     sender: Pubkey,
     receiver: Pubkey,
+    /// The transferred lamports, never negative: the size of `net_change`.
     amount: i64,
-    timestamp: String,
+    /// The signed balance change of the account, negative when lamports left it and positive
+    /// when it received them.
+    net_change: i64,
+    /// The fee paid by the fee payer.
+    fee: i64,
+    /// Whether the transaction failed, in which case only its fee was paid.
+    failed: bool,
+    /// The time of the block, or `None` if it is unknown.
+    timestamp: Option<String>,
     signatures: Vec<String>,
+    slot: u64,
+    /// The height of the block, if the RPC node returned it.
+    block_height: Option<u64>,
+    tx_index: usize,
+    balance_changes: Vec<(Pubkey, i64)>,
+    logs: Option<Vec<String>>,
+    program_id: Option<String>,
+    token_transfer: Option<TokenTransfer>,
 }
 
 impl Transaction {
@@ -39,8 +333,18 @@ impl Transaction {
             sender: Pubkey::default(),
             receiver: Pubkey::default(),
             amount: 0,
-            timestamp: "".to_string(),
+            net_change: 0,
+            fee: 0,
+            failed: false,
+            timestamp: None,
             signatures: vec![],
+            slot: 0,
+            block_height: None,
+            tx_index: 0,
+            balance_changes: vec![],
+            logs: None,
+            program_id: None,
+            token_transfer: None,
         }
     }
 
@@ -49,185 +353,2178 @@ impl Transaction {
     /// # Arguments
     ///
     /// * `encoded_transaction` - The encoded transaction with status metadata.
+    /// * `options` - The block processing options.
     ///
     /// # Errors
     ///
     /// Returns `AggregatorError::MetaDataFetchError` if the metadata is missing.
+    /// Returns `AggregatorError::TransactionDecodeError` if the transaction can't be decoded.
+    /// Returns `AggregatorError::SignatureFetchError` if the transaction has no signatures.
+    /// Returns `AggregatorError::AccountFetchError` if the sender or receiver can't be read.
+    /// Returns `AggregatorError::BalanceFetchError` if the balances of the sender can't be read.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the transaction should be stored, `false` if it has fewer signatures than
+    /// `options.min_signatures`.
     fn handle_transaction(
         &mut self,
         encoded_transaction: &EncodedTransactionWithStatusMeta,
-    ) -> Result<(), AggregatorError> {
+        options: &BlockOptions,
+    ) -> Result<bool, AggregatorError> {
         let meta_data = match encoded_transaction.meta.as_ref() {
             Some(res) => res,
             None => return Err(AggregatorError::MetaDataFetchError),
         };
 
-        if let EncodedTransaction::Json(message) = &encoded_transaction.transaction {
-            let signatures = &message.signatures;
-            self.signatures = signatures.to_vec();
-            if let UiMessage::Raw(msg) = &message.message {
-                self.fetch_sender(meta_data, msg);
-                self.fetch_receiver(meta_data, msg);
-                self.fetch_amount(meta_data, msg);
-            }
+        let (signatures, account_keys) = match transaction_keys(encoded_transaction) {
+            Some(res) => res,
+            None => return Err(AggregatorError::TransactionDecodeError),
+        };
+        if signatures.is_empty() {
+            return Err(AggregatorError::SignatureFetchError);
         }
-        Ok(())
+        if signatures.len() < options.min_signatures {
+            return Ok(false);
+        }
+        self.signatures = signatures;
+        self.program_id = primary_program_id(&encoded_transaction.transaction, &account_keys);
+        self.token_transfer =
+            token_transfer(&encoded_transaction.transaction, meta_data, &account_keys);
+        self.fetch_sender(meta_data, &account_keys)?;
+        self.fetch_receiver(meta_data, &account_keys)?;
+        self.fetch_fee(meta_data);
+        self.failed = meta_data.err.is_some();
+        self.fetch_amount(meta_data, options.sender_index)?;
+        self.fetch_balance_changes(meta_data, &account_keys);
+        if options.store_logs {
+            self.fetch_logs(meta_data);
+        }
+        Ok(true)
     }
 
     /// Fetches the sender's public key from the transaction message.
     ///
+    /// The sender is the account whose balance decreased the most. If no balance decreased,
+    /// the fee payer (the first account) is used.
+    ///
     /// # Arguments
     ///
-    /// * `_meta_data` - The transaction status metadata (unused).
-    /// * `message` - The raw transaction message.
-    fn fetch_sender(&mut self, _meta_data: &UiTransactionStatusMeta, message: &UiRawMessage) {
-        let account_keys = &message.account_keys;
-        let key = Pubkey::from_str(&account_keys[0]);
-        self.sender = key.unwrap();
+    /// * `meta_data` - The transaction status metadata.
+    /// * `account_keys` - The account keys of the transaction message.
+    ///
+    /// # Errors
+    ///
+    /// Returns `AggregatorError::AccountFetchError` if the message has no valid sender account.
+    fn fetch_sender(
+        &mut self,
+        meta_data: &UiTransactionStatusMeta,
+        account_keys: &[String],
+    ) -> Result<(), AggregatorError> {
+        let index = balance_deltas(meta_data, account_keys)
+            .filter(|(_, delta)| *delta < 0)
+            .min_by_key(|(_, delta)| *delta)
+            .map(|(index, _)| index)
+            .unwrap_or(0);
+        self.sender = account_key(account_keys, index)?;
+        Ok(())
     }
 
     /// Fetches the receiver's public key from the transaction message.
     ///
+    /// The receiver is the account other than the sender whose balance increased the most.
+    /// If no balance increased, the second account is used, or the sender itself when the
+    /// message only has one account. `fetch_sender` must be called first.
+    ///
+    /// # Arguments
+    ///
+    /// * `meta_data` - The transaction status metadata.
+    /// * `account_keys` - The account keys of the transaction message.
+    ///
+    /// # Errors
+    ///
+    /// Returns `AggregatorError::AccountFetchError` if an account key is not a valid public key.
+    fn fetch_receiver(
+        &mut self,
+        meta_data: &UiTransactionStatusMeta,
+        account_keys: &[String],
+    ) -> Result<(), AggregatorError> {
+        let sender = self.sender.to_string();
+        let index = balance_deltas(meta_data, account_keys)
+            .filter(|(index, delta)| *delta > 0 && account_keys[*index] != sender)
+            .max_by_key(|(_, delta)| *delta);
+        self.receiver = match index {
+            Some((index, _)) => account_key(account_keys, index)?,
+            None if account_keys.len() > 1 => account_key(account_keys, 1)?,
+            None => self.sender,
+        };
+        Ok(())
+    }
+
+    /// Fetches the transaction fee from the transaction metadata.
+    ///
+    /// # Arguments
+    ///
+    /// * `meta_data` - The transaction status metadata.
+    fn fetch_fee(&mut self, meta_data: &UiTransactionStatusMeta) {
+        self.fee = meta_data.fee as i64;
+    }
+
+    /// Fetches the transaction amount and net change from the transaction metadata.
+    ///
+    /// The net change is the post balance minus the pre balance of the account at
+    /// `sender_index`, so an outgoing transfer is negative and an incoming one positive. The
+    /// amount is its absolute value. The fee is paid by the first account, so it is excluded from
+    /// both when `sender_index` is the fee payer. `fetch_fee` must be called first.
+    ///
     /// # Arguments
     ///
-    /// * `_meta_data` - The transaction status metadata (unused).
-    /// * `message` - The raw transaction message.
-    fn fetch_receiver(&mut self, _meta_data: &UiTransactionStatusMeta, message: &UiRawMessage) {
-        let account_keys = &message.account_keys;
-        let key = Pubkey::from_str(&account_keys[1]);
-        self.receiver = key.unwrap();
+    /// * `meta_data` - The transaction status metadata.
+    /// * `sender_index` - The index of the account whose balance change is the amount.
+    ///
+    /// # Errors
+    ///
+    /// Returns `AggregatorError::BalanceFetchError` if the balance arrays don't contain `sender_index`.
+    fn fetch_amount(
+        &mut self,
+        meta_data: &UiTransactionStatusMeta,
+        sender_index: usize,
+    ) -> Result<(), AggregatorError> {
+        let (pre_balance, post_balance) = match (
+            meta_data.pre_balances.get(sender_index),
+            meta_data.post_balances.get(sender_index),
+        ) {
+            (Some(pre_balance), Some(post_balance)) => (*pre_balance, *post_balance),
+            _ => return Err(AggregatorError::BalanceFetchError),
+        };
+        self.net_change = post_balance as i64 - pre_balance as i64;
+        if sender_index == 0 {
+            self.net_change += self.fee;
+        }
+        self.amount = self.net_change.abs();
+        Ok(())
     }
 
-    /// Fetches the transaction amount from the transaction metadata.
+    /// Fetches the non-zero balance change of every account touched by the transaction.
     ///
     /// # Arguments
     ///
     /// * `meta_data` - The transaction status metadata.
-    /// * `_message` - The raw transaction message (unused).
-    fn fetch_amount(&mut self, meta_data: &UiTransactionStatusMeta, _message: &UiRawMessage) {
-        let amount = meta_data.pre_balances[0] as i64 - meta_data.post_balances[0] as i64;
-        self.amount = amount;
+    /// * `account_keys` - The account keys of the transaction message.
+    fn fetch_balance_changes(
+        &mut self,
+        meta_data: &UiTransactionStatusMeta,
+        account_keys: &[String],
+    ) {
+        self.balance_changes = balance_deltas(meta_data, account_keys)
+            .filter(|(_, delta)| *delta != 0)
+            .filter_map(|(index, delta)| Some((account_key(account_keys, index).ok()?, delta)))
+            .collect();
     }
 
-    /// Inserts the transaction into the database.
+    /// Fetches the log messages from the transaction metadata.
     ///
     /// # Arguments
     ///
-    /// * `database` - The database instance.
-    fn insert_to_database(&self, database: &mut Database) {
-        let _ = database.insert(
-            self.sender,
-            self.receiver,
-            self.amount,
-            &self.timestamp,
-            &self.signatures[0],
-        );
+    /// * `meta_data` - The transaction status metadata.
+    fn fetch_logs(&mut self, meta_data: &UiTransactionStatusMeta) {
+        self.logs = match &meta_data.log_messages {
+            OptionSerializer::Some(logs) => Some(logs.clone()),
+            _ => None,
+        };
+    }
+
+    /// Builds the record of the transaction stored in the `transactions` table.
+    ///
+    /// # Returns
+    ///
+    /// The transaction record, or `None` if the transaction has no signatures.
+    fn record(&self) -> Option<TransactionRecord> {
+        Some(TransactionRecord {
+            sender: self.sender.to_string(),
+            receiver: self.receiver.to_string(),
+            amount: self.amount,
+            net_change: self.net_change,
+            timestamp: self.timestamp.clone(),
+            signature: self.signatures.first()?.clone(),
+            signatures: self.signatures.clone(),
+            slot: self.slot,
+            block_height: self.block_height,
+            tx_index: self.tx_index as u64,
+            fee: self.fee,
+            program_id: self.program_id.clone(),
+            transfer_type: match self.token_transfer {
+                Some(_) => TRANSFER_TYPE_TOKEN,
+                None => TRANSFER_TYPE_SOL,
+            }
+            .to_string(),
+            token_mint: self
+                .token_transfer
+                .as_ref()
+                .map(|transfer| transfer.mint.clone()),
+            token_amount: self
+                .token_transfer
+                .as_ref()
+                .map(|transfer| transfer.amount.to_string()),
+            token_decimals: self
+                .token_transfer
+                .as_ref()
+                .map(|transfer| transfer.decimals),
+            status: if self.failed {
+                STATUS_FAILED
+            } else {
+                STATUS_SUCCESS
+            }
+            .to_string(),
+            balance_changes: self.balance_changes.clone(),
+            logs: self.logs.clone(),
+        })
     }
 }
 
-/// Aggregates data from the Solana blockchain by subscribing to new slots and processing transactions.
+/// Inserts parsed transactions into the database.
+///
+/// The transaction records of a block are inserted in a single batch, along with their
+/// balance changes and log messages. Once the batch is committed, the records are published to
+/// the `/stream` subscribers.
+///
+/// If the batch fails and `options.insert_failure_policy` is `Skip`, the records are inserted
+/// one by one instead, and the ones that fail are skipped with their balance changes and logs.
+///
+/// With `options.dry_run`, nothing is inserted or published, and a summary of the records is
+/// logged instead.
+///
+/// # Arguments
+///
+/// * `transactions` - The parsed transactions.
+/// * `options` - The block processing options.
+/// * `database` - The database instance.
 ///
 /// # Errors
 ///
-/// Returns an `AggregatorError` if there is an error fetching environment variables, connecting to the Pubsub client,
-/// subscribing to slots, or other runtime errors.
-pub async fn aggregate_data() -> Result<(), AggregatorError> {
-    let _ = Database::new();
-    let env = match envy::from_env::<Env>() {
-        Ok(res) => res,
-        Err(_) => return Err(AggregatorError::EnvFetchError),
+/// Returns `AggregatorError::TransactionParseError` if the batch fails and the policy is
+/// `Abort`, in which case no transaction record is inserted.
+#[instrument(skip_all, fields(transactions = transactions.len()))]
+fn insert_transactions(
+    transactions: &[Transaction],
+    options: &BlockOptions,
+    database: &mut (impl Store + ?Sized),
+) -> Result<(), AggregatorError> {
+    let records: Vec<TransactionRecord> = transactions
+        .iter()
+        .filter_map(Transaction::record)
+        .collect();
+    if options.dry_run {
+        log_dry_run(&records);
+        return Ok(());
+    }
+    let inserted = match database.insert_batch(&records) {
+        Ok(_) => vec![true; records.len()],
+        Err(err) => {
+            Metrics::add(&METRICS.insert_errors, 1);
+            if options.insert_failure_policy == InsertFailurePolicy::Abort {
+                return Err(AggregatorError::TransactionParseError(Box::new(err.into())));
+            }
+            warn!(error = %err, "failed to insert the block, inserting its transactions one by one");
+            records
+                .iter()
+                .map(|record| insert_record(record, database))
+                .collect()
+        }
     };
+    let inserted: Vec<&TransactionRecord> = records
+        .iter()
+        .zip(inserted)
+        .filter_map(|(record, inserted)| inserted.then_some(record))
+        .collect();
+    Metrics::add(&METRICS.transactions_inserted, inserted.len() as u64);
+    for record in inserted {
+        let mut body = record.to_json();
+        body["timestamp"] = serde_json::json!(record
+            .timestamp
+            .as_ref()
+            .map(|timestamp| options.timestamp_format.reformat(timestamp)));
+        events::publish(body.to_string());
+    }
+    Ok(())
+}
 
-    let pubsub = match PubsubClient::new(env.ws_url.as_ref()).await {
-        Ok(res) => res,
-        Err(_) => return Err(AggregatorError::PubsubClientError),
-    };
+/// Logs the transaction records a block would insert, with a sample of them.
+///
+/// # Arguments
+///
+/// * `records` - The transaction records.
+fn log_dry_run(records: &[TransactionRecord]) {
+    let sample: Vec<String> = records
+        .iter()
+        .take(DRY_RUN_SAMPLE_RECORDS)
+        .map(|record| record.to_json().to_string())
+        .collect();
+    info!(
+        transactions = records.len(),
+        sample = %format!("[{}]", sample.join(",")),
+        "dry run, not inserting the transactions"
+    );
+}
 
-    let (mut accounts, unsubscriber) = match pubsub.slot_subscribe().await {
-        Ok(res) => res,
-        Err(_) => return Err(AggregatorError::SlotSubscribeError),
-    };
+/// Inserts a single transaction record, logging it if it fails.
+///
+/// # Arguments
+///
+/// * `record` - The transaction record.
+/// * `database` - The database instance.
+///
+/// # Returns
+///
+/// `true` if the record was inserted.
+fn insert_record(record: &TransactionRecord, database: &mut (impl Store + ?Sized)) -> bool {
+    match database.insert_batch(std::slice::from_ref(record)) {
+        Ok(_) => true,
+        Err(err) => {
+            Metrics::add(&METRICS.insert_errors, 1);
+            warn!(signature = %record.signature, error = %err, "skipping a transaction that failed to insert");
+            false
+        }
+    }
+}
 
-    for _ in 0..MAX_ITERATIONS {
-        if let Some(response) = accounts.next().await {
-            println!("{:?}", response);
-            let handle = Handle::current();
-            handle.spawn(async move { get_block(response.root).await });
+/// Returns the signatures and account keys of an encoded transaction.
+///
+/// JSON encoded transactions are read as is, and binary encoded ones are decoded first. The
+/// account indices of a versioned transaction also cover the addresses it loads from lookup
+/// tables, so the static account keys of raw and decoded messages are followed by the writable
+/// and then the readonly loaded addresses of the metadata. Parsed messages already list them.
+///
+/// # Arguments
+///
+/// * `encoded_transaction` - The encoded transaction with status metadata.
+///
+/// # Returns
+///
+/// The signatures and account keys, or `None` if the transaction can't be decoded.
+fn transaction_keys(
+    encoded_transaction: &EncodedTransactionWithStatusMeta,
+) -> Option<(Vec<String>, Vec<String>)> {
+    let transaction = &encoded_transaction.transaction;
+    let with_loaded_addresses = |mut account_keys: Vec<String>| {
+        let loaded = encoded_transaction
+            .meta
+            .as_ref()
+            .map(|meta| &meta.loaded_addresses);
+        if let Some(OptionSerializer::Some(loaded)) = loaded {
+            account_keys.extend(loaded.writable.iter().cloned());
+            account_keys.extend(loaded.readonly.iter().cloned());
+        }
+        account_keys
+    };
+    let parsed_keys = |account_keys: &Vec<ParsedAccount>| {
+        account_keys
+            .iter()
+            .map(|account| account.pubkey.clone())
+            .collect()
+    };
+    match transaction {
+        EncodedTransaction::Json(ui_transaction) => {
+            let account_keys = match &ui_transaction.message {
+                UiMessage::Raw(msg) => with_loaded_addresses(msg.account_keys.clone()),
+                UiMessage::Parsed(msg) => parsed_keys(&msg.account_keys),
+            };
+            Some((ui_transaction.signatures.clone(), account_keys))
+        }
+        EncodedTransaction::Accounts(list) => {
+            Some((list.signatures.clone(), parsed_keys(&list.account_keys)))
+        }
+        _ => {
+            let decoded = transaction.decode()?;
+            Some((
+                decoded.signatures.iter().map(ToString::to_string).collect(),
+                with_loaded_addresses(
+                    decoded
+                        .message
+                        .static_account_keys()
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect(),
+                ),
+            ))
         }
     }
-    unsubscriber().await;
-    Ok(())
 }
 
-/// Retrieves and processes a block from the Solana blockchain.
+/// Returns the program invoked by a transaction.
+///
+/// This is the program of the first instruction, skipping the Compute Budget instructions that
+/// most transactions start with.
 ///
 /// # Arguments
 ///
-/// * `slot` - The slot number to fetch the block for.
+/// * `transaction` - The encoded transaction.
+/// * `account_keys` - The account keys of the transaction, including its loaded addresses.
 ///
-/// # Errors
+/// # Returns
 ///
-/// Returns an `AggregatorError` if there is an error connecting to the database, fetching environment variables,
-/// sending the RPC request, or processing the block.
-pub async fn get_block(slot: u64) -> Result<(), AggregatorError> {
-    let mut database = match Database::new_connection() {
-        Ok(res) => res,
-        Err(_) => return Err(AggregatorError::DatabaseError),
+/// The program id, or `None` if the transaction has no other instruction or its instructions
+/// can't be read.
+pub fn primary_program_id(
+    transaction: &EncodedTransaction,
+    account_keys: &[String],
+) -> Option<String> {
+    let compute_budget = solana_sdk::compute_budget::id().to_string();
+    program_ids(transaction, account_keys)
+        .into_iter()
+        .find(|program_id| *program_id != compute_budget)
+}
+
+/// Represents an SPL token transfer.
+#[derive(Debug, PartialEq)]
+pub struct TokenTransfer {
+    /// The mint of the transferred token.
+    pub mint: String,
+    /// The transferred amount, in the smallest unit of the token.
+    pub amount: u64,
+    /// The number of decimal places of the token.
+    pub decimals: u8,
+}
+
+/// Returns the SPL token transfer made by a transaction.
+///
+/// The transaction must invoke a `transfer` or `transferChecked` instruction of the SPL Token
+/// or Token-2022 program. The transfer is then read from the token balances of the metadata:
+/// the token account whose balance decreased the most is the source, and the decrease is the
+/// amount.
+///
+/// # Arguments
+///
+/// * `transaction` - The encoded transaction.
+/// * `meta_data` - The transaction status metadata.
+/// * `account_keys` - The account keys of the transaction message.
+///
+/// # Returns
+///
+/// The token transfer, or `None` if the transaction doesn't transfer tokens or its token
+/// balances are missing.
+pub fn token_transfer(
+    transaction: &EncodedTransaction,
+    meta_data: &UiTransactionStatusMeta,
+    account_keys: &[String],
+) -> Option<TokenTransfer> {
+    if !has_token_transfer_instruction(transaction, account_keys) {
+        return None;
+    }
+    let (pre_balances, post_balances) = match (
+        &meta_data.pre_token_balances,
+        &meta_data.post_token_balances,
+    ) {
+        (OptionSerializer::Some(pre), OptionSerializer::Some(post)) => (pre, post),
+        _ => return None,
+    };
+    let amount = |balance: &UiTransactionTokenBalance| -> Option<u64> {
+        balance.ui_token_amount.amount.parse().ok()
     };
+    pre_balances
+        .iter()
+        .filter_map(|pre| {
+            let post = post_balances
+                .iter()
+                .find(|post| post.account_index == pre.account_index && post.mint == pre.mint)
+                .map_or(Some(0), amount)?;
+            Some((pre, amount(pre)?.checked_sub(post)?))
+        })
+        .filter(|(_, decrease)| *decrease > 0)
+        .max_by_key(|(_, decrease)| *decrease)
+        .map(|(pre, decrease)| TokenTransfer {
+            mint: pre.mint.clone(),
+            amount: decrease,
+            decimals: pre.ui_token_amount.decimals,
+        })
+}
 
-    let env = match envy::from_env::<Env>() {
-        Ok(res) => res,
-        Err(_) => return Err(AggregatorError::EnvFetchError),
+/// Returns whether a transaction invokes a `transfer` or `transferChecked` instruction of the
+/// SPL Token or Token-2022 program.
+///
+/// # Arguments
+///
+/// * `transaction` - The encoded transaction.
+/// * `account_keys` - The account keys of the transaction message.
+fn has_token_transfer_instruction(
+    transaction: &EncodedTransaction,
+    account_keys: &[String],
+) -> bool {
+    let is_token_program =
+        |program_id: &str| program_id == TOKEN_PROGRAM_ID || program_id == TOKEN_2022_PROGRAM_ID;
+    let is_transfer = |data: &[u8]| {
+        matches!(
+            data.first(),
+            Some(&TRANSFER_INSTRUCTION) | Some(&TRANSFER_CHECKED_INSTRUCTION)
+        )
+    };
+    let is_encoded_transfer = |data: &str| match bs58::decode(data).into_vec() {
+        Ok(data) => is_transfer(&data),
+        Err(_) => false,
     };
-    let rpc = RpcClient::new(env.rpc_url.to_string());
-    let ten_millis = Duration::from_millis(1000);
-    thread::sleep(ten_millis);
+    let indexed = |index: u8| match account_keys.get(index as usize) {
+        Some(program_id) => is_token_program(program_id),
+        None => false,
+    };
+    match transaction {
+        EncodedTransaction::Json(ui_transaction) => match &ui_transaction.message {
+            UiMessage::Raw(msg) => msg.instructions.iter().any(|instruction| {
+                indexed(instruction.program_id_index) && is_encoded_transfer(&instruction.data)
+            }),
+            UiMessage::Parsed(msg) => {
+                msg.instructions
+                    .iter()
+                    .any(|instruction| match instruction {
+                        UiInstruction::Compiled(instruction) => {
+                            indexed(instruction.program_id_index)
+                                && is_encoded_transfer(&instruction.data)
+                        }
+                        UiInstruction::Parsed(UiParsedInstruction::Parsed(instruction)) => {
+                            is_token_program(&instruction.program_id)
+                                && matches!(
+                                    instruction.parsed["type"].as_str(),
+                                    Some("transfer") | Some("transferChecked")
+                                )
+                        }
+                        UiInstruction::Parsed(UiParsedInstruction::PartiallyDecoded(
+                            instruction,
+                        )) => {
+                            is_token_program(&instruction.program_id)
+                                && is_encoded_transfer(&instruction.data)
+                        }
+                    })
+            }
+        },
+        EncodedTransaction::Accounts(_) => false,
+        _ => match transaction.decode() {
+            Some(decoded) => decoded.message.instructions().iter().any(|instruction| {
+                indexed(instruction.program_id_index) && is_transfer(&instruction.data)
+            }),
+            None => false,
+        },
+    }
+}
 
-    let request = RpcRequest::GetBlock;
-    let params = serde_json::json!([slot, {
-    "maxSupportedTransactionVersion":0,
-    }]);
+/// Returns the programs invoked by the instructions of a transaction, in order.
+///
+/// Program indices are resolved against the account keys of `transaction_keys`, so a program
+/// loaded from an address lookup table is found among the loaded addresses that follow the static
+/// account keys. Programs whose index is past all of them are left out.
+///
+/// # Arguments
+///
+/// * `transaction` - The encoded transaction.
+/// * `account_keys` - The account keys of the transaction, including its loaded addresses.
+fn program_ids(transaction: &EncodedTransaction, account_keys: &[String]) -> Vec<String> {
+    let indexed = |index: u8| account_keys.get(index as usize).cloned();
+    match transaction {
+        EncodedTransaction::Json(ui_transaction) => match &ui_transaction.message {
+            UiMessage::Raw(msg) => msg
+                .instructions
+                .iter()
+                .filter_map(|instruction| indexed(instruction.program_id_index))
+                .collect(),
+            UiMessage::Parsed(msg) => msg
+                .instructions
+                .iter()
+                .filter_map(|instruction| match instruction {
+                    UiInstruction::Compiled(instruction) => indexed(instruction.program_id_index),
+                    UiInstruction::Parsed(UiParsedInstruction::Parsed(instruction)) => {
+                        Some(instruction.program_id.clone())
+                    }
+                    UiInstruction::Parsed(UiParsedInstruction::PartiallyDecoded(instruction)) => {
+                        Some(instruction.program_id.clone())
+                    }
+                })
+                .collect(),
+        },
+        EncodedTransaction::Accounts(_) => vec![],
+        _ => match transaction.decode() {
+            Some(decoded) => decoded
+                .message
+                .instructions()
+                .iter()
+                .filter_map(|instruction| indexed(instruction.program_id_index))
+                .collect(),
+            None => vec![],
+        },
+    }
+}
 
-    let block: EncodedConfirmedBlock = match rpc.send(request, params) {
-        Ok(res) => res,
-        Err(_) => return Err(AggregatorError::BlockFetchError),
-    };
-    handle_block(block, &mut database)
+/// Returns the balance change of every account of a message that has balances.
+///
+/// # Arguments
+///
+/// * `meta_data` - The transaction status metadata.
+/// * `account_keys` - The account keys of the transaction message.
+///
+/// # Returns
+///
+/// An iterator of `(account index, post - pre)` pairs.
+fn balance_deltas<'a>(
+    meta_data: &'a UiTransactionStatusMeta,
+    account_keys: &'a [String],
+) -> impl Iterator<Item = (usize, i64)> + 'a {
+    meta_data
+        .pre_balances
+        .iter()
+        .zip(meta_data.post_balances.iter())
+        .take(account_keys.len())
+        .map(|(pre, post)| *post as i64 - *pre as i64)
+        .enumerate()
 }
 
-/// Processes a block of transactions and inserts them into the database.
+/// Parses the account key at the given index of a message.
 ///
 /// # Arguments
 ///
-/// * `block` - The encoded confirmed block containing transactions.
-/// * `database` - The database instance.
+/// * `account_keys` - The account keys of the transaction message.
+/// * `index` - The index of the account.
 ///
 /// # Errors
 ///
-/// Returns an `AggregatorError` if there is an error fetching the block time or parsing a transaction.
-fn handle_block(
-    block: EncodedConfirmedBlock,
-    database: &mut Database,
-) -> Result<(), AggregatorError> {
-    let transactions = &block.transactions;
-    let block_time = match block.block_time {
-        Some(res) => res,
-        None => return Err(AggregatorError::TimeFetchError),
-    };
-    let time_stamp = get_timestamp(block_time);
-    for encoded_transaction in transactions.iter() {
-        let mut transaction = Transaction::new();
-        transaction.timestamp.clone_from(&time_stamp);
-        match transaction.handle_transaction(encoded_transaction) {
-            Ok(_) => transaction.insert_to_database(database),
-            Err(_) => return Err(AggregatorError::TransactionParseError),
+/// Returns `AggregatorError::AccountFetchError` if the index is out of bounds or the key is invalid.
+fn account_key(account_keys: &[String], index: usize) -> Result<Pubkey, AggregatorError> {
+    match account_keys.get(index).map(|key| Pubkey::from_str(key)) {
+        Some(Ok(key)) => Ok(key),
+        _ => Err(AggregatorError::AccountFetchError),
+    }
+}
+
+/// Opens the write connection of the aggregator.
+///
+/// With `DRY_RUN`, the blocks are still fetched and parsed, but their transactions are logged
+/// instead of inserted, and the slot checkpoints are written to an in-memory database, so the
+/// database at `DB_PATH` is never modified.
+///
+/// # Arguments
+///
+/// * `config` - The configuration.
+///
+/// # Errors
+///
+/// Returns `DatabaseError::ConnectError` if the connection to the database fails.
+fn open_writer(config: &Config) -> Result<DatabaseWriter, DatabaseError> {
+    if config.dry_run {
+        info!("dry run, the database won't be modified");
+        return DatabaseWriter::in_memory();
+    }
+    DatabaseWriter::connect(config)
+}
+
+/// Aggregates data from the Solana blockchain by subscribing to new slots and processing transactions.
+///
+/// It handles `MAX_SLOTS` slot notifications, or runs forever when `MAX_SLOTS` is `0` or unset.
+/// The subscription is reconnected with backoff whenever the websocket connection drops.
+///
+/// Roots that were already seen recently are skipped. The number of remembered roots and
+/// how long they are remembered are configured with `DEDUP_CAPACITY` and `DEDUP_MAX_AGE_SECS`.
+///
+/// Roots at or below the last processed slot of a previous run are skipped unless
+/// `REPROCESS_SLOTS` is set. On the first newer root, the slots between the last processed
+/// slot and that root are backfilled, up to `MAX_BACKFILL_SLOTS` slots. When a later root jumps
+/// ahead of the previous one, the skipped slots in between are fetched too, up to `MAX_GAP_SLOTS`
+/// slots. Slots without a block are not retried.
+///
+/// Each slot must be fetched, parsed and inserted within `PIPELINE_TIMEOUT_SECS` seconds.
+/// Slots that miss the deadline are queued and processed again on the next notification,
+/// up to `PIPELINE_MAX_ATTEMPTS` times.
+///
+/// At most `MAX_CONCURRENT_BLOCKS` slots are processed at the same time.
+///
+/// When `ACCOUNT_ALLOWLIST` has at most `ACCOUNT_PUSHDOWN_MAX` accounts, only the transactions of
+/// those accounts are fetched on each notification instead of full blocks.
+///
+/// When `SUBSCRIBE_MODE` is `block`, blocks are subscribed to instead of slots, and each block
+/// notification is processed directly without fetching the block.
+///
+/// When a shutdown is requested, the subscription is ended and unsubscribed. The slots being
+/// processed are then awaited so that their blocks are fully written before returning.
+///
+/// With `DRY_RUN`, the database is left untouched, see `open_writer`.
+///
+/// When `RETENTION_DAYS` is set, the transactions older than that many days are pruned every
+/// `RETENTION_INTERVAL_SECS` seconds alongside the aggregation, see `retention::run`.
+///
+/// # Arguments
+///
+/// * `config` - The configuration.
+/// * `shutdown` - Receives the request to stop aggregating.
+///
+/// # Errors
+///
+/// Returns an `AggregatorError` if there is an error connecting to the database or the Pubsub client,
+/// subscribing to slots, or other runtime errors.
+pub async fn aggregate_data(config: Config, shutdown: Shutdown) -> Result<(), AggregatorError> {
+    let writer = Arc::new(open_writer(&config)?);
+    let last_processed_slot = writer.lock().await.last_processed_slot();
+    let config = Arc::new(config);
+
+    let roots = DedupCache::new(
+        config.dedup_capacity,
+        Duration::from_secs(config.dedup_max_age_secs),
+    );
+    let checkpoint = if config.reprocess_slots {
+        None
+    } else {
+        last_processed_slot
+    };
+    let mut scheduler = SlotScheduler::new(
+        checkpoint,
+        config.max_backfill_slots,
+        config.max_gap_slots,
+        roots,
+    );
+    let options = BlockOptions::from_config(&config);
+    if config.retention_days > 0 {
+        tokio::spawn(retention::run(
+            writer.clone(),
+            config.retention_days,
+            Duration::from_secs(config.retention_interval_secs.max(1)),
+            shutdown.clone(),
+        ));
+    }
+    let pushdown = if !options.allowlist.is_empty()
+        && options.allowlist.len() <= config.account_pushdown_max
+    {
+        let mut accounts = vec![];
+        for account in options.allowlist.iter() {
+            match Pubkey::from_str(account) {
+                Ok(res) => accounts.push(res),
+                Err(err) => return Err(AggregatorError::EnvFetchError(SourceError::new(err))),
+            }
+        }
+        Some(accounts)
+    } else {
+        None
+    };
+    let rpc = Arc::new(rpc_client(&config));
+    let cursors = Arc::new(Mutex::new(HashMap::new()));
+    let permits = Arc::new(Semaphore::new(config.max_concurrent_blocks.max(1)));
+    let deadline = Duration::from_secs(config.pipeline_timeout_secs);
+    let (retry_queue, mut retries) = mpsc::unbounded_channel();
+    let max_slots = (config.max_slots > 0).then_some(config.max_slots);
+    let on_slot = RefCell::new(|response: SlotInfo| {
+        debug!(
+            slot = response.slot,
+            parent = response.parent,
+            root = response.root,
+            "slot notification"
+        );
+        let handle = Handle::current();
+        if let Some(accounts) = &pushdown {
+            let accounts = accounts.clone();
+            let cursors = cursors.clone();
+            let config = config.clone();
+            let rpc = rpc.clone();
+            let writer = writer.clone();
+            handle.spawn(async move {
+                let result = get_account_transactions(config, rpc, writer, accounts, cursors).await;
+                if let Err(err) = &result {
+                    error!(
+                        error = %report(err),
+                        "failed to fetch the tracked transactions"
+                    );
+                }
+                result
+            });
+            return;
+        }
+        let mut slots: Vec<(u64, u32)> = vec![];
+        while let Ok(retry) = retries.try_recv() {
+            slots.push(retry);
+        }
+        slots.extend(
+            scheduler
+                .schedule(response.root)
+                .into_iter()
+                .map(|slot| (slot, 1)),
+        );
+        for (slot, attempts) in slots {
+            let retry_queue = retry_queue.clone();
+            let max_attempts = config.pipeline_max_attempts;
+            let permits = permits.clone();
+            let config = config.clone();
+            let rpc = rpc.clone();
+            let writer = writer.clone();
+            handle.spawn(async move {
+                process_slot(
+                    slot,
+                    run_with_permit(
+                        &permits,
+                        run_with_deadline(
+                            slot,
+                            attempts,
+                            deadline,
+                            max_attempts,
+                            get_block(config, rpc, writer, slot),
+                            &retry_queue,
+                        ),
+                    ),
+                )
+                .await
+            });
+        }
+    });
+    let on_block = |update: RpcBlockUpdate| {
+        let permits = permits.clone();
+        let writer = writer.clone();
+        let options = options.clone();
+        Handle::current().spawn(async move {
+            process_slot(
+                update.slot,
+                run_with_permit(&permits, async move {
+                    let mut database = writer.lock().await;
+                    handle_block_update(update, &options, database.as_mut())
+                }),
+            )
+            .await
+        });
+    };
+    let result = run_subscriptions(
+        max_slots,
+        config.reconnect_max_attempts,
+        Duration::from_millis(config.reconnect_initial_backoff_ms),
+        shutdown.clone(),
+        |remaining| {
+            let on_slot = &on_slot;
+            let on_block = &on_block;
+            let config = &config;
+            let shutdown = shutdown.clone();
+            async move {
+                match config.subscribe_mode {
+                    SubscribeMode::Slot => {
+                        subscribe_slots(&config.ws_url, remaining, shutdown, |response| {
+                            (on_slot.borrow_mut())(response)
+                        })
+                        .await
+                    }
+                    SubscribeMode::Block => {
+                        subscribe_blocks(
+                            &config.ws_url,
+                            config.commitment,
+                            remaining,
+                            shutdown,
+                            on_block,
+                        )
+                        .await
+                    }
+                }
+            }
+        },
+    )
+    .await;
+    let _ = permits
+        .acquire_many(config.max_concurrent_blocks.max(1) as u32)
+        .await;
+    let _ = cursors.lock().await;
+    result
+}
+
+/// Runs the pipeline of a slot and logs its outcome.
+///
+/// Skipped slots aren't failures, so they are logged at the info level and not counted as failed.
+///
+/// # Arguments
+///
+/// * `slot` - The slot being processed, recorded as a field of the logs.
+/// * `pipeline` - The fetch, parse and insert pipeline of the slot.
+///
+/// # Returns
+///
+/// The result of the pipeline.
+#[instrument(skip(pipeline))]
+pub async fn process_slot(
+    slot: u64,
+    pipeline: impl Future<Output = Result<(), AggregatorError>>,
+) -> Result<(), AggregatorError> {
+    let result = pipeline.await;
+    match &result {
+        Ok(_) => info!("processed the slot"),
+        Err(AggregatorError::SlotSkipped) => info!("the slot was skipped"),
+        Err(err) => {
+            Metrics::add(&METRICS.blocks_failed, 1);
+            error!(error = %report(err), "failed to process the slot")
+        }
+    }
+    result
+}
+
+/// Processes a range of past slots, independently of the live subscription.
+///
+/// Blocks are fetched with the same retries as live ones, at most `MAX_CONCURRENT_BLOCKS` at a
+/// time. Slots that fail are logged and don't stop the range. Skipped slots have no block, so
+/// they aren't reported as failed.
+///
+/// # Arguments
+///
+/// * `config` - The configuration.
+/// * `from` - The first slot to process.
+/// * `to` - The last slot to process.
+///
+/// # Returns
+///
+/// The slots that failed, in ascending order.
+pub async fn backfill(config: Config, from: u64, to: u64) -> Vec<u64> {
+    let writer = match open_writer(&config) {
+        Ok(res) => Arc::new(res),
+        Err(err) => {
+            error!(error = %report(&err), "failed to connect to the database");
+            return (from..=to).collect();
+        }
+    };
+    let rpc = Arc::new(rpc_client(&config));
+    let config = Arc::new(config);
+    let failed = backfill_range(from, to, config.max_concurrent_blocks, |slot| {
+        get_block(config.clone(), rpc.clone(), writer.clone(), slot)
+    })
+    .await;
+    info!(from, to, failed = failed.len(), "backfilled the slots");
+    failed
+}
+
+/// Runs the pipeline of every slot in a range.
+///
+/// # Arguments
+///
+/// * `from` - The first slot to process.
+/// * `to` - The last slot to process.
+/// * `max_concurrent` - The maximum number of slots processed at the same time.
+/// * `pipeline` - Builds the fetch, parse and insert pipeline of a slot.
+///
+/// # Returns
+///
+/// The slots whose pipeline failed, in ascending order, leaving out the skipped slots.
+pub async fn backfill_range<F, Fut>(
+    from: u64,
+    to: u64,
+    max_concurrent: usize,
+    pipeline: F,
+) -> Vec<u64>
+where
+    F: Fn(u64) -> Fut,
+    Fut: Future<Output = Result<(), AggregatorError>>,
+{
+    let mut failed: Vec<u64> = futures_util::stream::iter(from..=to)
+        .map(|slot| {
+            let pipeline = pipeline(slot);
+            async move { (slot, process_slot(slot, pipeline).await) }
+        })
+        .buffer_unordered(max_concurrent.max(1))
+        .filter_map(|(slot, result)| async move {
+            match result {
+                Ok(_) | Err(AggregatorError::SlotSkipped) => None,
+                Err(_) => Some(slot),
+            }
+        })
+        .collect()
+        .await;
+    failed.sort_unstable();
+    failed
+}
+
+/// Connects to the websocket endpoint and handles slot notifications until the subscription
+/// ends, the maximum number of notifications is reached or a shutdown is requested.
+///
+/// # Arguments
+///
+/// * `ws_url` - The websocket endpoint.
+/// * `max_notifications` - The maximum number of notifications to handle, or `None` for no limit.
+/// * `shutdown` - Receives the request to end the subscription.
+/// * `handle` - The function handling each slot notification.
+///
+/// # Errors
+///
+/// Returns `AggregatorError::PubsubClientError` if the connection fails.
+/// Returns `AggregatorError::SlotSubscribeError` if the subscription fails.
+///
+/// # Returns
+///
+/// The number of notifications handled.
+async fn subscribe_slots(
+    ws_url: &url::Url,
+    max_notifications: Option<u64>,
+    shutdown: Shutdown,
+    handle: impl FnMut(SlotInfo),
+) -> Result<u64, AggregatorError> {
+    let pubsub = match PubsubClient::new(ws_url.as_ref()).await {
+        Ok(res) => res,
+        Err(err) => return Err(AggregatorError::PubsubClientError(SourceError::new(err))),
+    };
+
+    let (mut accounts, unsubscriber) = match pubsub.slot_subscribe().await {
+        Ok(res) => res,
+        Err(err) => return Err(AggregatorError::SlotSubscribeError(SourceError::new(err))),
+    };
+
+    Ok(run_session(
+        &mut accounts,
+        unsubscriber,
+        max_notifications,
+        shutdown,
+        handle,
+    )
+    .await)
+}
+
+/// Connects to the websocket endpoint and handles block notifications until the subscription
+/// ends, the maximum number of notifications is reached or a shutdown is requested.
+///
+/// # Arguments
+///
+/// * `ws_url` - The websocket endpoint.
+/// * `commitment` - The commitment level the blocks must have reached.
+/// * `max_notifications` - The maximum number of notifications to handle, or `None` for no limit.
+/// * `shutdown` - Receives the request to end the subscription.
+/// * `handle` - The function handling each block notification.
+///
+/// # Errors
+///
+/// Returns `AggregatorError::PubsubClientError` if the connection fails.
+/// Returns `AggregatorError::BlockSubscribeError` if the subscription fails, e.g. because the
+/// endpoint doesn't support block subscriptions.
+///
+/// # Returns
+///
+/// The number of notifications handled.
+async fn subscribe_blocks(
+    ws_url: &url::Url,
+    commitment: CommitmentLevel,
+    max_notifications: Option<u64>,
+    shutdown: Shutdown,
+    mut handle: impl FnMut(RpcBlockUpdate),
+) -> Result<u64, AggregatorError> {
+    let pubsub = match PubsubClient::new(ws_url.as_ref()).await {
+        Ok(res) => res,
+        Err(err) => return Err(AggregatorError::PubsubClientError(SourceError::new(err))),
+    };
+
+    let (mut blocks, unsubscriber) = match pubsub
+        .block_subscribe(
+            RpcBlockSubscribeFilter::All,
+            Some(block_subscribe_config(commitment)),
+        )
+        .await
+    {
+        Ok(res) => res,
+        Err(err) => return Err(AggregatorError::BlockSubscribeError(SourceError::new(err))),
+    };
+
+    Ok(run_session(
+        &mut blocks,
+        unsubscriber,
+        max_notifications,
+        shutdown,
+        |response| handle(response.value),
+    )
+    .await)
+}
+
+/// Builds the block subscription configuration, requesting the same transaction details as
+/// `get_block_params`.
+///
+/// # Arguments
+///
+/// * `commitment` - The commitment level the blocks must have reached.
+pub fn block_subscribe_config(commitment: CommitmentLevel) -> RpcBlockSubscribeConfig {
+    RpcBlockSubscribeConfig {
+        commitment: Some(CommitmentConfig { commitment }),
+        encoding: Some(UiTransactionEncoding::Json),
+        transaction_details: Some(TransactionDetails::Full),
+        show_rewards: Some(false),
+        max_supported_transaction_version: Some(0),
+    }
+}
+
+/// Processes a block delivered by the block subscription.
+///
+/// Once the block is fully written, its slot is recorded as processed. Notifications without a
+/// block are ignored.
+///
+/// # Arguments
+///
+/// * `update` - The block notification.
+/// * `options` - The block processing options.
+/// * `database` - The database instance.
+///
+/// # Errors
+///
+/// Returns `AggregatorError::BlockFetchError` if the notification reports an error, or an
+/// `AggregatorError` if processing the block fails.
+pub fn handle_block_update(
+    update: RpcBlockUpdate,
+    options: &BlockOptions,
+    database: &mut (impl Store + ?Sized),
+) -> Result<(), AggregatorError> {
+    if let Some(err) = update.err {
+        return Err(AggregatorError::BlockFetchError(SourceError::new(err)));
+    }
+    let block = match update.block {
+        Some(res) => res,
+        None => return Ok(()),
+    };
+    info!(slot = update.slot, "received the block");
+    handle_block(block.into(), update.slot, options, database)?;
+    database.set_last_processed_slot(update.slot)?;
+    METRICS.set_last_processed_slot(update.slot);
+    Ok(())
+}
+
+/// Handles the notifications of a subscription until it ends, the maximum number of
+/// notifications is reached or a shutdown is requested, then unsubscribes.
+///
+/// # Arguments
+///
+/// * `notifications` - The stream of notifications.
+/// * `unsubscribe` - Ends the subscription.
+/// * `max_notifications` - The maximum number of notifications to handle, or `None` for no limit.
+/// * `shutdown` - Receives the request to end the subscription.
+/// * `handle` - The function handling each notification.
+///
+/// # Returns
+///
+/// The number of notifications handled.
+pub async fn run_session<T, Fut>(
+    notifications: impl Stream<Item = T> + Unpin,
+    unsubscribe: impl FnOnce() -> Fut,
+    max_notifications: Option<u64>,
+    shutdown: Shutdown,
+    handle: impl FnMut(T),
+) -> u64
+where
+    Fut: Future<Output = ()>,
+{
+    let notifications = pin!(notifications.take_until(shutdown::requested(shutdown)));
+    let handled = handle_notifications(notifications, max_notifications, handle).await;
+    unsubscribe().await;
+    handled
+}
+
+/// Runs subscription sessions until the maximum number of notifications is reached or a shutdown
+/// is requested, reconnecting with exponential backoff whenever a session fails or its subscription ends.
+///
+/// The backoff is reset once a session has handled a notification.
+///
+/// # Arguments
+///
+/// * `max_notifications` - The maximum number of notifications to handle, or `None` to run forever.
+/// * `max_attempts` - The maximum number of consecutive sessions that may end without handling a notification.
+/// * `initial_backoff` - The wait before the first reconnection, doubled after each further one.
+/// * `shutdown` - Receives the request to stop reconnecting.
+/// * `session` - Connects, subscribes and handles up to the given number of notifications,
+///   returning the number handled.
+///
+/// # Errors
+///
+/// Returns `AggregatorError::ReconnectError` if every reconnection attempt failed.
+pub async fn run_subscriptions<Fut>(
+    max_notifications: Option<u64>,
+    max_attempts: u32,
+    initial_backoff: Duration,
+    shutdown: Shutdown,
+    mut session: impl FnMut(Option<u64>) -> Fut,
+) -> Result<(), AggregatorError>
+where
+    Fut: Future<Output = Result<u64, AggregatorError>>,
+{
+    let mut remaining = max_notifications;
+    let mut attempts = 0;
+    let mut backoff = initial_backoff;
+    loop {
+        if shutdown::is_requested(&shutdown) {
+            return Ok(());
+        }
+        match session(remaining).await {
+            Ok(handled) => {
+                if let Some(remaining) = &mut remaining {
+                    *remaining = remaining.saturating_sub(handled);
+                    if *remaining == 0 {
+                        return Ok(());
+                    }
+                }
+                if handled > 0 {
+                    attempts = 0;
+                    backoff = initial_backoff;
+                }
+            }
+            Err(err) => warn!(error = %report(&err), "the slot subscription failed"),
+        }
+        if shutdown::is_requested(&shutdown) {
+            return Ok(());
+        }
+        attempts += 1;
+        if attempts >= max_attempts {
+            return Err(AggregatorError::ReconnectError);
+        }
+        warn!(
+            attempts,
+            backoff_ms = backoff.as_millis() as u64,
+            "reconnecting the slot subscription"
+        );
+        Metrics::add(&METRICS.reconnects, 1);
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = shutdown::requested(shutdown.clone()) => return Ok(()),
+        }
+        backoff *= 2;
+    }
+}
+
+/// Handles notifications from a stream until it ends or the maximum number of notifications is reached.
+///
+/// # Arguments
+///
+/// * `notifications` - The stream of notifications.
+/// * `max_notifications` - The maximum number of notifications to handle, or `None` for no limit.
+/// * `handle` - The function handling each notification.
+///
+/// # Returns
+///
+/// The number of notifications handled.
+pub async fn handle_notifications<T>(
+    mut notifications: impl Stream<Item = T> + Unpin,
+    max_notifications: Option<u64>,
+    mut handle: impl FnMut(T),
+) -> u64 {
+    let mut handled = 0;
+    while !matches!(max_notifications, Some(max) if handled >= max) {
+        match notifications.next().await {
+            Some(notification) => handle(notification),
+            None => break,
+        }
+        handled += 1;
+    }
+    handled
+}
+
+/// Runs a task once a permit of the semaphore is available.
+///
+/// The permit is released when the task completes, fails or is dropped.
+///
+/// # Arguments
+///
+/// * `permits` - The semaphore bounding the number of concurrent tasks.
+/// * `task` - The task to run.
+///
+/// # Returns
+///
+/// The output of the task.
+pub async fn run_with_permit<T>(permits: &Semaphore, task: impl Future<Output = T>) -> T {
+    let _permit = permits.acquire().await;
+    task.await
+}
+
+/// Runs the pipeline of a slot, aborting it if it doesn't complete before the deadline.
+///
+/// # Arguments
+///
+/// * `slot` - The slot being processed.
+/// * `attempts` - The number of times the pipeline has been started for this slot, including this one.
+/// * `deadline` - The time the pipeline may take.
+/// * `max_attempts` - The maximum number of times the pipeline is started for a slot.
+/// * `pipeline` - The fetch, parse and insert pipeline of the slot.
+/// * `retry_queue` - The queue receiving `(slot, attempts)` of slots to process again.
+///
+/// # Errors
+///
+/// Returns `AggregatorError::DeadlineExceeded` if the pipeline was aborted, in which case the slot
+/// is queued for another attempt unless `max_attempts` is reached. Otherwise returns the pipeline's error.
+pub async fn run_with_deadline(
+    slot: u64,
+    attempts: u32,
+    deadline: Duration,
+    max_attempts: u32,
+    pipeline: impl Future<Output = Result<(), AggregatorError>>,
+    retry_queue: &UnboundedSender<(u64, u32)>,
+) -> Result<(), AggregatorError> {
+    match tokio::time::timeout(deadline, pipeline).await {
+        Ok(result) => result,
+        Err(_) => {
+            if attempts < max_attempts {
+                let _ = retry_queue.send((slot, attempts + 1));
+            }
+            Err(AggregatorError::DeadlineExceeded)
+        }
+    }
+}
+
+/// Decides which slots to fetch for each root notified by the slot subscription.
+pub struct SlotScheduler {
+    checkpoint: Option<u64>,
+    backfilled: bool,
+    max_backfill_slots: u64,
+    last_root: Option<u64>,
+    max_gap_slots: u64,
+    roots: DedupCache<u64>,
+}
+
+impl SlotScheduler {
+    /// Creates a new `SlotScheduler`.
+    ///
+    /// # Arguments
+    ///
+    /// * `checkpoint` - The last slot processed by a previous run, if it should be skipped.
+    /// * `max_backfill_slots` - The maximum number of slots to backfill after the checkpoint.
+    /// * `max_gap_slots` - The maximum number of slots to fill between two notified roots.
+    /// * `roots` - The cache of recently fetched slots.
+    pub fn new(
+        checkpoint: Option<u64>,
+        max_backfill_slots: u64,
+        max_gap_slots: u64,
+        roots: DedupCache<u64>,
+    ) -> SlotScheduler {
+        SlotScheduler {
+            checkpoint,
+            backfilled: false,
+            max_backfill_slots,
+            last_root: None,
+            max_gap_slots,
+            roots,
+        }
+    }
+
+    /// Returns the slots to fetch for a notified root.
+    ///
+    /// Roots at or below the checkpoint were already processed and are skipped. The first
+    /// root above the checkpoint also schedules the gap between them, and every later root the
+    /// gap since the previous root. Slots that were recently scheduled are never scheduled again.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - The notified root.
+    ///
+    /// # Returns
+    ///
+    /// The slots to fetch, in ascending order.
+    pub fn schedule(&mut self, root: u64) -> Vec<u64> {
+        let mut slots = vec![];
+        if let Some(checkpoint) = self.checkpoint {
+            if root <= checkpoint {
+                return slots;
+            }
+            if !self.backfilled {
+                self.backfilled = true;
+                slots.extend(backfill_slots(checkpoint, root, self.max_backfill_slots));
+            }
+        }
+        if let Some(last_root) = self.last_root {
+            slots.extend(backfill_slots(last_root, root, self.max_gap_slots));
+        }
+        self.last_root = Some(self.last_root.map_or(root, |last_root| last_root.max(root)));
+        slots.push(root);
+        slots.sort_unstable();
+        slots.dedup();
+        slots.retain(|slot| self.roots.insert(*slot));
+        slots
+    }
+}
+
+/// Computes the slots to backfill between the last processed slot and the current slot.
+///
+/// # Arguments
+///
+/// * `last_processed_slot` - The highest slot written by a previous run.
+/// * `current_slot` - The first slot received from the live subscription.
+/// * `max_backfill_slots` - The maximum number of slots to backfill.
+///
+/// # Returns
+///
+/// The range of slots strictly between `last_processed_slot` and `current_slot`, limited to
+/// the most recent `max_backfill_slots` slots.
+pub fn backfill_slots(
+    last_processed_slot: u64,
+    current_slot: u64,
+    max_backfill_slots: u64,
+) -> std::ops::Range<u64> {
+    let start = (last_processed_slot + 1).max(current_slot.saturating_sub(max_backfill_slots));
+    start..current_slot.max(start)
+}
+
+/// Runs an operation until it succeeds or the maximum number of attempts is reached.
+///
+/// # Arguments
+///
+/// * `max_attempts` - The maximum number of attempts (at least one).
+/// * `operation` - The operation to run.
+///
+/// # Returns
+///
+/// The result of the last attempt and the number of attempts made.
+pub fn retry<T, E>(
+    max_attempts: u32,
+    mut operation: impl FnMut() -> Result<T, E>,
+) -> (Result<T, E>, u32) {
+    let mut attempts = 1;
+    loop {
+        let result = operation();
+        if result.is_ok() || attempts >= max_attempts {
+            return (result, attempts);
+        }
+        attempts += 1;
+    }
+}
+
+/// Runs an operation until it succeeds, fails permanently, or the maximum number of attempts
+/// is reached, waiting between attempts with an exponentially increasing backoff.
+///
+/// # Arguments
+///
+/// * `max_attempts` - The maximum number of attempts (at least one).
+/// * `initial_backoff` - The wait after the first failed attempt, doubled after each further one.
+/// * `is_retryable` - Decides whether an error is transient and the operation should be retried.
+/// * `operation` - The operation to run.
+///
+/// # Returns
+///
+/// The result of the last attempt and the number of attempts made.
+pub async fn retry_with_backoff<T, E, Fut>(
+    max_attempts: u32,
+    initial_backoff: Duration,
+    is_retryable: impl Fn(&E) -> bool,
+    mut operation: impl FnMut() -> Fut,
+) -> (Result<T, E>, u32)
+where
+    E: fmt::Display,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempts = 1;
+    let mut backoff = initial_backoff;
+    loop {
+        let result = operation().await;
+        match &result {
+            Err(err) if attempts < max_attempts && is_retryable(err) => {
+                warn!(attempts, error = %err, "attempt failed, retrying");
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempts += 1;
+            }
+            _ => return (result, attempts),
+        }
+    }
+}
+
+/// Checks whether an RPC error reports that a slot was skipped and has no block.
+///
+/// # Arguments
+///
+/// * `err` - The RPC client error.
+///
+/// # Returns
+///
+/// `true` if the slot was skipped, in which case fetching it again can't succeed.
+pub fn is_slot_skipped(err: &ClientError) -> bool {
+    matches!(
+        err.kind(),
+        ClientErrorKind::RpcError(RpcError::RpcResponseError { code, .. })
+            if *code == JSON_RPC_SERVER_ERROR_SLOT_SKIPPED
+                || *code == JSON_RPC_SERVER_ERROR_LONG_TERM_STORAGE_SLOT_SKIPPED
+    )
+}
+
+/// Records the attempts of an operation on a slot if it needed a retry or failed.
+///
+/// # Arguments
+///
+/// * `database` - The database instance.
+/// * `slot` - The slot the operation was performed for.
+/// * `operation` - The name of the operation.
+/// * `attempts` - The number of attempts made.
+/// * `exhausted` - Whether every attempt failed.
+pub fn record_attempts(
+    database: &mut (impl Store + ?Sized),
+    slot: u64,
+    operation: &str,
+    attempts: u32,
+    exhausted: bool,
+) {
+    if attempts > 1 || exhausted {
+        let _ = database.record_slot_attempts(slot, operation, attempts, exhausted);
+    }
+}
+
+/// Retrieves and processes a block from the Solana blockchain.
+///
+/// If `COMMITMENT_WAIT_SECS` is positive, the block is only fetched once the slot has reached
+/// `COMMITMENT`, or that long has passed, so that it isn't requested before the RPC node can
+/// return it. Each request for the block waits for the shared RPC request budget. Fetching the block
+/// is retried up to `RPC_MAX_ATTEMPTS` times with an exponential backoff starting at
+/// `RPC_INITIAL_BACKOFF_MS`, unless the slot was skipped. Recording the slot as processed is
+/// retried up to `DB_MAX_ATTEMPTS` times. Slots that needed a retry are recorded in the
+/// `failed_slots` table with their attempt count. Once the block is fully written, its slot is
+/// recorded as processed.
+///
+/// # Arguments
+///
+/// * `config` - The configuration.
+/// * `rpc` - The RPC client, shared by every slot.
+/// * `writer` - The write connection, shared by every slot and only taken once the block is fetched.
+/// * `slot` - The slot number to fetch the block for.
+///
+/// # Errors
+///
+/// Returns `AggregatorError::SlotSkipped` if the slot was skipped and has no block, without
+/// retrying or recording it as failed.
+/// Returns an `AggregatorError` if there is an error sending the RPC request or processing the
+/// block.
+#[instrument(skip(config, rpc, writer))]
+pub async fn get_block(
+    config: Arc<Config>,
+    rpc: Arc<LimitedRpcClient>,
+    writer: Arc<DatabaseWriter>,
+    slot: u64,
+) -> Result<(), AggregatorError> {
+    let request = RpcRequest::GetBlock;
+    let params = get_block_params(slot, config.commitment);
+    let rpc = rpc.as_ref();
+    if config.commitment_wait_secs > 0 {
+        wait_for_commitment(
+            rpc,
+            slot,
+            config.commitment,
+            Duration::from_millis(config.commitment_poll_ms.max(1)),
+            Duration::from_secs(config.commitment_wait_secs),
+        )
+        .await;
+    }
+    info!("fetching the block");
+
+    let (block, attempts) = retry_with_backoff(
+        config.rpc_max_attempts,
+        Duration::from_millis(config.rpc_initial_backoff_ms),
+        |err| !is_slot_skipped(err),
+        || {
+            let params = params.clone();
+            async move {
+                rpc.acquire().await;
+                let endpoint = rpc.current();
+                let block = endpoint
+                    .client
+                    .send::<EncodedConfirmedBlock>(request, params)
+                    .await;
+                rpc.report(endpoint, &block);
+                block
+            }
+        },
+    )
+    .instrument(info_span!("fetch_block", slot))
+    .await;
+    let skipped = matches!(&block, Err(err) if is_slot_skipped(err));
+    let mut database = writer.lock().await;
+    record_attempts(
+        database.as_mut(),
+        slot,
+        "get_block",
+        attempts,
+        block.is_err() && !skipped,
+    );
+    Metrics::add(&METRICS.rpc_retries, attempts.saturating_sub(1) as u64);
+    let block = match block {
+        Ok(res) => res,
+        Err(_) if skipped => return Err(AggregatorError::SlotSkipped),
+        Err(err) => return Err(AggregatorError::BlockFetchError(SourceError::new(err))),
+    };
+    Metrics::add(&METRICS.blocks_fetched, 1);
+    info!(
+        attempts,
+        transactions = block.transactions.len(),
+        "fetched the block"
+    );
+    handle_block(
+        block,
+        slot,
+        &BlockOptions::from_config(&config),
+        database.as_mut(),
+    )?;
+
+    let (result, attempts) = retry(config.db_max_attempts, || {
+        database.set_last_processed_slot(slot)
+    });
+    record_attempts(
+        database.as_mut(),
+        slot,
+        "set_last_processed_slot",
+        attempts,
+        result.is_err(),
+    );
+    result?;
+    METRICS.set_last_processed_slot(slot);
+    Ok(())
+}
+
+/// Waits until a slot has reached a commitment level, so its block can be fetched.
+///
+/// The slot the RPC node reports at the commitment level is polled with `getSlot`, each request
+/// drawing from the shared request budget, until it reaches the slot. A slot below it has either
+/// reached the commitment level or been skipped, and the fetch tells them apart. The wait gives
+/// up after `max_wait`, or as soon as `getSlot` fails, leaving the block fetch and its retries to
+/// handle the slot.
+///
+/// # Arguments
+///
+/// * `rpc` - The RPC client, shared by every slot.
+/// * `slot` - The slot of the block about to be fetched.
+/// * `commitment` - The commitment level the block must have reached.
+/// * `poll_interval` - The wait between two `getSlot` requests.
+/// * `max_wait` - The longest time to wait for the slot.
+///
+/// # Returns
+///
+/// `true` if the slot reached the commitment level before the wait gave up.
+pub async fn wait_for_commitment(
+    rpc: &LimitedRpcClient,
+    slot: u64,
+    commitment: CommitmentLevel,
+    poll_interval: Duration,
+    max_wait: Duration,
+) -> bool {
+    let deadline = tokio::time::Instant::now() + max_wait;
+    loop {
+        rpc.acquire().await;
+        let params = serde_json::json!([CommitmentConfig { commitment }]);
+        let endpoint = rpc.current();
+        let committed = endpoint
+            .client
+            .send::<u64>(RpcRequest::GetSlot, params)
+            .await;
+        rpc.report(endpoint, &committed);
+        match committed {
+            Ok(committed) if committed >= slot => return true,
+            Ok(committed) => debug!(slot, committed, "waiting for the slot to be committed"),
+            Err(err) => {
+                warn!(slot, error = %err, "failed to get the committed slot");
+                return false;
+            }
+        }
+        if tokio::time::Instant::now() + poll_interval > deadline {
+            warn!(
+                slot,
+                "the slot wasn't committed in time, fetching the block anyway"
+            );
+            return false;
+        }
+        tokio::time::sleep(poll_interval).await;
+    }
+}
+
+/// An RPC endpoint, with the number of requests that failed on it in a row.
+///
+/// Its client is the nonblocking one, so the block fetches waiting on a response don't hold up
+/// the worker threads of the runtime.
+pub struct RpcEndpoint {
+    pub url: String,
+    pub client: RpcClient,
+    index: usize,
+    failures: AtomicU32,
+}
+
+impl RpcEndpoint {
+    /// Creates a new `RpcEndpoint`.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The RPC client of the endpoint.
+    /// * `index` - The position of the endpoint in the failover order.
+    fn new(client: RpcClient, index: usize) -> RpcEndpoint {
+        RpcEndpoint {
+            url: client.url(),
+            client,
+            index,
+            failures: AtomicU32::new(0),
+        }
+    }
+
+    /// Returns the number of requests that failed on the endpoint since the last one succeeded.
+    pub fn failures(&self) -> u32 {
+        self.failures.load(Ordering::Relaxed)
+    }
+}
+
+/// The RPC client shared by the block fetches, with the limiter of their request rate.
+///
+/// The requests are sent to one endpoint at a time, starting with the first. When a request
+/// fails on an endpoint, the next requests fail over to the next one, wrapping around after the
+/// last, so a retry doesn't go back to the endpoint that just failed.
+pub struct LimitedRpcClient {
+    pub endpoints: Vec<RpcEndpoint>,
+    current: AtomicUsize,
+    pub limiter: Option<RateLimiter>,
+}
+
+impl LimitedRpcClient {
+    /// The bucket of the RPC requests, which all the block fetches draw from.
+    const BUCKET: &'static str = "rpc";
+
+    /// Creates a new `LimitedRpcClient`.
+    ///
+    /// # Arguments
+    ///
+    /// * `clients` - The RPC clients of the endpoints, in the order they are failed over to. There
+    ///   must be at least one.
+    /// * `limiter` - The limiter of the request rate, shared by all the endpoints.
+    pub fn new(clients: Vec<RpcClient>, limiter: Option<RateLimiter>) -> LimitedRpcClient {
+        LimitedRpcClient {
+            endpoints: clients
+                .into_iter()
+                .enumerate()
+                .map(|(index, client)| RpcEndpoint::new(client, index))
+                .collect(),
+            current: AtomicUsize::new(0),
+            limiter,
+        }
+    }
+
+    /// Waits until the request budget allows another request.
+    ///
+    /// This yields to the runtime instead of blocking the worker thread, so the block fetches
+    /// waiting for the budget don't hold up the others.
+    pub async fn acquire(&self) {
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire(LimitedRpcClient::BUCKET).await;
+        }
+    }
+
+    /// Returns the endpoint the requests are currently sent to.
+    pub fn current(&self) -> &RpcEndpoint {
+        &self.endpoints[self.current.load(Ordering::Relaxed)]
+    }
+
+    /// Records the result of a request sent to an endpoint, failing over to the next endpoint if
+    /// the request failed.
+    ///
+    /// A slot reported as skipped is an answer rather than a failure of the endpoint, so it
+    /// doesn't fail over.
+    ///
+    /// # Arguments
+    ///
+    /// * `endpoint` - The endpoint the request was sent to, as returned by `current`.
+    /// * `result` - The result of the request.
+    pub fn report<T>(&self, endpoint: &RpcEndpoint, result: &Result<T, ClientError>) {
+        match result {
+            Err(err) if !is_slot_skipped(err) => {
+                let failures = endpoint.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                let next = (endpoint.index + 1) % self.endpoints.len();
+                // Another request may have failed over already, which this one doesn't undo.
+                if next != endpoint.index
+                    && self
+                        .current
+                        .compare_exchange(
+                            endpoint.index,
+                            next,
+                            Ordering::Relaxed,
+                            Ordering::Relaxed,
+                        )
+                        .is_ok()
+                {
+                    // The URLs are left out of the logs, since they may contain API keys.
+                    warn!(
+                        endpoint = endpoint.index,
+                        failures,
+                        next,
+                        error = %err,
+                        "the RPC endpoint failed, failing over to the next one"
+                    );
+                }
+            }
+            _ => endpoint.failures.store(0, Ordering::Relaxed),
+        }
+    }
+}
+
+impl From<RpcClient> for LimitedRpcClient {
+    fn from(client: RpcClient) -> LimitedRpcClient {
+        LimitedRpcClient::new(vec![client], None)
+    }
+}
+
+/// Creates the RPC client shared by the block fetches.
+///
+/// Requests that get no response within `RPC_TIMEOUT_SECS` seconds fail, so a hung endpoint
+/// can't stall a fetch and the retries can take over. The requests go to `RPC_URL`, failing over
+/// to the endpoints of `RPC_URLS` in turn. The block requests are limited to
+/// `RPC_REQUESTS_PER_SECOND` across all the fetches and endpoints, with bursts of up to
+/// `RPC_BURST` requests, unless `RPC_REQUESTS_PER_SECOND` isn't positive.
+///
+/// # Arguments
+///
+/// * `config` - The configuration.
+pub fn rpc_client(config: &Config) -> LimitedRpcClient {
+    let limiter = (config.rpc_requests_per_second > 0.0).then(|| {
+        let burst = config
+            .rpc_burst
+            .unwrap_or(config.rpc_requests_per_second.ceil() as u32);
+        RateLimiter::new(config.rpc_requests_per_second, burst, false)
+    });
+    let mut urls = vec![&config.rpc_url];
+    for url in &config.rpc_urls {
+        if !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+    let clients = urls
+        .into_iter()
+        .map(|url| {
+            RpcClient::new_with_timeout(
+                url.to_string(),
+                Duration::from_secs(config.rpc_timeout_secs),
+            )
+        })
+        .collect();
+    LimitedRpcClient::new(clients, limiter)
+}
+
+/// Builds the `getBlock` parameters of a slot.
+///
+/// Full JSON encoded transactions are requested, since `handle_block` reads their balances and
+/// account keys.
+///
+/// # Arguments
+///
+/// * `slot` - The slot of the block.
+/// * `commitment` - The commitment level the block must have reached.
+///
+/// # Returns
+///
+/// The JSON parameters of the request.
+pub fn get_block_params(slot: u64, commitment: CommitmentLevel) -> serde_json::Value {
+    serde_json::json!([slot, {
+        "encoding": UiTransactionEncoding::Json,
+        "transactionDetails": TransactionDetails::Full,
+        "commitment": commitment,
+        "maxSupportedTransactionVersion": 0,
+    }])
+}
+
+/// Retrieves and processes the new transactions of tracked accounts.
+///
+/// Polls are skipped while a previous poll is still running.
+///
+/// # Arguments
+///
+/// * `config` - The configuration.
+/// * `rpc` - The RPC client, shared by every poll.
+/// * `writer` - The write connection, only taken once the transactions are fetched.
+/// * `accounts` - The tracked accounts.
+/// * `cursors` - The newest signature fetched for each account.
+///
+/// # Errors
+///
+/// Returns an `AggregatorError` if there is an error connecting to the database,
+/// fetching the transactions or the heights of their blocks, or processing them.
+#[instrument(skip(config, rpc, writer, cursors))]
+pub async fn get_account_transactions(
+    config: Arc<Config>,
+    rpc: Arc<LimitedRpcClient>,
+    writer: Arc<DatabaseWriter>,
+    accounts: Vec<Pubkey>,
+    cursors: Arc<Mutex<HashMap<Pubkey, String>>>,
+) -> Result<(), AggregatorError> {
+    let mut cursors = match cursors.try_lock() {
+        Ok(res) => res,
+        Err(_) => return Ok(()),
+    };
+
+    let rpc = &rpc;
+    let transactions = fetch_account_transactions(
+        &accounts,
+        &mut cursors,
+        |account, until| async move {
+            let config = GetConfirmedSignaturesForAddress2Config {
+                until: until.and_then(|until| Signature::from_str(&until).ok()),
+                ..Default::default()
+            };
+            rpc.acquire().await;
+            let endpoint = rpc.current();
+            let statuses = endpoint
+                .client
+                .get_signatures_for_address_with_config(&account, config)
+                .await;
+            rpc.report(endpoint, &statuses);
+            match statuses {
+                Ok(res) => Ok(res.into_iter().map(|status| status.signature).collect()),
+                Err(err) => Err(AggregatorError::TransactionFetchError(SourceError::new(
+                    err,
+                ))),
+            }
+        },
+        |signature| async move {
+            let signature = match Signature::from_str(&signature) {
+                Ok(res) => res,
+                Err(err) => {
+                    return Err(AggregatorError::TransactionFetchError(SourceError::new(
+                        err,
+                    )))
+                }
+            };
+            let config = RpcTransactionConfig {
+                encoding: Some(UiTransactionEncoding::Json),
+                commitment: None,
+                max_supported_transaction_version: Some(0),
+            };
+            rpc.acquire().await;
+            let endpoint = rpc.current();
+            let transaction = endpoint
+                .client
+                .get_transaction_with_config(&signature, config)
+                .await;
+            rpc.report(endpoint, &transaction);
+            match transaction {
+                Ok(res) => Ok(res),
+                Err(err) => Err(AggregatorError::TransactionFetchError(SourceError::new(
+                    err,
+                ))),
+            }
+        },
+    )
+    .await?;
+    let mut block_heights = HashMap::new();
+    for transaction in &transactions {
+        if let Entry::Vacant(entry) = block_heights.entry(transaction.slot) {
+            if let Some(block_height) = get_block_height(rpc, transaction.slot).await? {
+                entry.insert(block_height);
+            }
+        }
+    }
+    let mut database = writer.lock().await;
+    handle_account_transactions(
+        transactions,
+        &block_heights,
+        &BlockOptions::from_config(&config),
+        database.as_mut(),
+    )
+}
+
+/// Retrieves the block height of a slot, without the transactions of its block.
+///
+/// # Arguments
+///
+/// * `rpc` - The RPC client.
+/// * `slot` - The slot of the block.
+///
+/// # Errors
+///
+/// Returns an `AggregatorError` if the block can't be fetched.
+///
+/// # Returns
+///
+/// The block height, or `None` if the node doesn't report it.
+async fn get_block_height(
+    rpc: &LimitedRpcClient,
+    slot: u64,
+) -> Result<Option<u64>, AggregatorError> {
+    let config = RpcBlockConfig {
+        transaction_details: Some(TransactionDetails::None),
+        rewards: Some(false),
+        max_supported_transaction_version: Some(0),
+        ..Default::default()
+    };
+    rpc.acquire().await;
+    let endpoint = rpc.current();
+    let block = endpoint.client.get_block_with_config(slot, config).await;
+    rpc.report(endpoint, &block);
+    match block {
+        Ok(res) => Ok(res.block_height),
+        Err(err) => Err(AggregatorError::BlockFetchError(SourceError::new(err))),
+    }
+}
+
+/// Fetches the transactions of the given accounts that are newer than their cursors.
+///
+/// Each account's cursor is moved to its newest signature once all of its transactions are fetched.
+/// A transaction touching several accounts is only fetched once.
+///
+/// # Arguments
+///
+/// * `accounts` - The accounts to fetch the transactions of.
+/// * `cursors` - The newest signature fetched for each account.
+/// * `signatures_for_address` - Fetches the signatures of an account newer than the given one, newest first.
+/// * `get_transaction` - Fetches the transaction with the given signature.
+///
+/// # Errors
+///
+/// Returns the first error of `signatures_for_address` or `get_transaction`.
+///
+/// # Returns
+///
+/// The fetched transactions in chronological order.
+pub async fn fetch_account_transactions<SignaturesFut, TransactionFut>(
+    accounts: &[Pubkey],
+    cursors: &mut HashMap<Pubkey, String>,
+    mut signatures_for_address: impl FnMut(Pubkey, Option<String>) -> SignaturesFut,
+    mut get_transaction: impl FnMut(String) -> TransactionFut,
+) -> Result<Vec<EncodedConfirmedTransactionWithStatusMeta>, AggregatorError>
+where
+    SignaturesFut: Future<Output = Result<Vec<String>, AggregatorError>>,
+    TransactionFut:
+        Future<Output = Result<EncodedConfirmedTransactionWithStatusMeta, AggregatorError>>,
+{
+    let mut fetched = HashSet::new();
+    let mut transactions = vec![];
+    for account in accounts {
+        let signatures = signatures_for_address(*account, cursors.get(account).cloned()).await?;
+        for signature in signatures.iter().rev() {
+            if fetched.insert(signature.clone()) {
+                transactions.push(get_transaction(signature.clone()).await?);
+            }
+        }
+        if let Some(newest) = signatures.first() {
+            cursors.insert(*account, newest.clone());
+        }
+    }
+    transactions.sort_by_key(|transaction| transaction.slot);
+    Ok(transactions)
+}
+
+/// Processes transactions fetched one by one and inserts them into the database.
+///
+/// The position of a transaction within its block isn't known here, so transactions of the
+/// same slot are indexed in the order they were fetched.
+///
+/// # Arguments
+///
+/// * `transactions` - The confirmed transactions in chronological order.
+/// * `block_heights` - The block height of each slot, left unset for the slots missing from it.
+/// * `options` - The block processing options.
+/// * `database` - The database instance.
+///
+/// # Errors
+///
+/// Returns an `AggregatorError` if there is an error fetching a block time or parsing a transaction.
+/// Transactions without a block time are stored without a timestamp instead when
+/// `options.lenient_block_time` is set.
+/// Transactions whose balances can't be read are skipped instead when `options.lenient_balances` is set,
+/// and so are all the transactions that can't be parsed when `options.lenient_transactions` is set.
+/// Transactions rejected by the account allow and deny lists are skipped, and so are
+/// transactions invoking none of `options.program_ids` unless it is empty, transactions with fewer
+/// signatures than `options.min_signatures` and vote transactions unless `options.include_votes`
+/// is set, like in blocks.
+pub fn handle_account_transactions(
+    transactions: Vec<EncodedConfirmedTransactionWithStatusMeta>,
+    block_heights: &HashMap<u64, u64>,
+    options: &BlockOptions,
+    database: &mut (impl Store + ?Sized),
+) -> Result<(), AggregatorError> {
+    let mut previous_slot = None;
+    let mut tx_index = 0;
+    let mut parsed = vec![];
+    for confirmed_transaction in transactions {
+        tx_index = match previous_slot {
+            Some(slot) if slot == confirmed_transaction.slot => tx_index + 1,
+            _ => 0,
+        };
+        previous_slot = Some(confirmed_transaction.slot);
+        let encoded_transaction = &confirmed_transaction.transaction;
+        if !options.is_tracked(encoded_transaction)
+            || !options.invokes_tracked_program(encoded_transaction)
+        {
+            continue;
+        }
+        if !options.include_votes && is_vote_transaction(encoded_transaction) {
+            continue;
+        }
+        let block_time = match confirmed_transaction.block_time {
+            Some(res) => Some(res),
+            None if options.lenient_block_time => {
+                warn!(
+                    slot = confirmed_transaction.slot,
+                    "the transaction has no block time, storing it without a timestamp"
+                );
+                None
+            }
+            None => return Err(AggregatorError::TimeFetchError),
+        };
+        let mut transaction = Transaction::new();
+        transaction.timestamp = block_time.map(get_timestamp);
+        transaction.slot = confirmed_transaction.slot;
+        transaction.block_height = block_heights.get(&confirmed_transaction.slot).copied();
+        transaction.tx_index = tx_index;
+        match transaction.handle_transaction(&confirmed_transaction.transaction, options) {
+            Ok(true) => parsed.push(transaction),
+            Ok(false) => continue,
+            Err(AggregatorError::BalanceFetchError) if options.lenient_balances => continue,
+            Err(err) if options.lenient_transactions => {
+                Metrics::add(&METRICS.parse_errors, 1);
+                warn!(tx_index, error = %err, "skipping a transaction that can't be parsed");
+                continue;
+            }
+            Err(err) => return Err(AggregatorError::TransactionParseError(Box::new(err))),
+        };
+    }
+    insert_transactions(&parsed, options, database)
+}
+
+/// Processes a block of transactions and inserts them into the database.
+///
+/// # Arguments
+///
+/// * `block` - The encoded confirmed block containing transactions.
+/// * `slot` - The slot of the block.
+/// * `options` - The block processing options.
+/// * `database` - The database instance.
+///
+/// # Errors
+///
+/// Returns an `AggregatorError` if there is an error fetching the block time or parsing a transaction.
+/// Blocks without a block time are stored without a timestamp instead when
+/// `options.lenient_block_time` is set.
+/// Transactions whose balances can't be read are skipped instead when `options.lenient_balances` is set,
+/// and so are all the transactions that can't be parsed when `options.lenient_transactions` is set.
+/// Returns `AggregatorError::TransactionParseError` if the transactions can't be inserted, unless
+/// `options.insert_failure_policy` is `Skip`, in which case the failing ones are skipped.
+/// Transactions rejected by the account allow and deny lists are skipped, and so are
+/// transactions invoking none of `options.program_ids` unless it is empty, transactions with fewer
+/// signatures than `options.min_signatures` and vote transactions unless `options.include_votes`
+/// is set.
+#[instrument(skip(block, options, database))]
+pub fn handle_block(
+    block: EncodedConfirmedBlock,
+    slot: u64,
+    options: &BlockOptions,
+    database: &mut (impl Store + ?Sized),
+) -> Result<(), AggregatorError> {
+    let transactions = &block.transactions;
+    let block_time = match block.block_time {
+        Some(res) => Some(res),
+        None if options.lenient_block_time => {
+            warn!("the block has no block time, storing its transactions without a timestamp");
+            None
+        }
+        None => return Err(AggregatorError::TimeFetchError),
+    };
+    let time_stamp = block_time.map(get_timestamp);
+    let mut parsed = vec![];
+    for (tx_index, encoded_transaction) in transactions.iter().enumerate() {
+        if !options.is_tracked(encoded_transaction)
+            || !options.invokes_tracked_program(encoded_transaction)
+        {
+            continue;
+        }
+        if !options.include_votes && is_vote_transaction(encoded_transaction) {
+            continue;
+        }
+        let mut transaction = Transaction::new();
+        transaction.timestamp.clone_from(&time_stamp);
+        transaction.slot = slot;
+        transaction.block_height = block.block_height;
+        transaction.tx_index = tx_index;
+        match transaction.handle_transaction(encoded_transaction, options) {
+            Ok(true) => parsed.push(transaction),
+            Ok(false) => continue,
+            Err(AggregatorError::BalanceFetchError) if options.lenient_balances => continue,
+            Err(err) if options.lenient_transactions => {
+                Metrics::add(&METRICS.parse_errors, 1);
+                warn!(tx_index, error = %err, "skipping a transaction that can't be parsed");
+                continue;
+            }
+            Err(err) => return Err(AggregatorError::TransactionParseError(Box::new(err))),
         };
     }
 
+    insert_transactions(&parsed, options, database)?;
+    Metrics::add(&METRICS.blocks_processed, 1);
     Ok(())
 }
 
-/// Converts a Unix timestamp to a formatted string.
+/// Converts a Unix timestamp to a string in the default format, `%Y-%m-%d %H:%M:%S` in UTC.
 ///
 /// # Arguments
 ///
@@ -237,8 +2534,5 @@ fn handle_block(
 ///
 /// A string representing the formatted timestamp.
 pub fn get_timestamp(timestamp: i64) -> String {
-    let d = UNIX_EPOCH + Duration::from_secs(timestamp as u64);
-    let datetime = DateTime::<Utc>::from(d);
-    let timestamp_str = datetime.format("%Y-%m-%d %H:%M:%S").to_string();
-    timestamp_str
+    TimestampFormat::default().format(timestamp)
 }
@@ -0,0 +1,68 @@
+use clap::{error::ErrorKind, value_parser, Arg, Command};
+use std::ffi::OsString;
+
+/// What the application was asked to do on the command line.
+#[derive(Debug, PartialEq)]
+pub enum Mode {
+    /// Runs the live aggregator and the web server. The default without a subcommand.
+    Run,
+    /// Processes the slots from `from` to `to`, inclusive, and exits.
+    Backfill { from: u64, to: u64 },
+}
+
+/// Builds the command line interface.
+fn command() -> Command<'static> {
+    Command::new("solana-aggregator")
+        .about("Aggregates Solana transactions and serves them over a RESTful API")
+        .subcommand(Command::new("run").about("Runs the live aggregator and the web server"))
+        .subcommand(
+            Command::new("backfill")
+                .about("Processes a range of past slots and exits")
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .value_name("SLOT")
+                        .help("The first slot to process")
+                        .required(true)
+                        .value_parser(value_parser!(u64)),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .value_name("SLOT")
+                        .help("The last slot to process")
+                        .required(true)
+                        .value_parser(value_parser!(u64)),
+                ),
+        )
+}
+
+/// Parses the command line arguments.
+///
+/// # Arguments
+///
+/// * `args` - The arguments, starting with the binary name.
+///
+/// # Errors
+///
+/// Returns a `clap::Error` describing the usage if the arguments are invalid, if `--from` is
+/// after `--to`, or if help was requested.
+pub fn parse<I, T>(args: I) -> Result<Mode, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let mut command = command();
+    let matches = command.try_get_matches_from_mut(args)?;
+    let matches = match matches.subcommand() {
+        Some(("backfill", matches)) => matches,
+        _ => return Ok(Mode::Run),
+    };
+    match (matches.get_one::<u64>("from"), matches.get_one::<u64>("to")) {
+        (Some(from), Some(to)) if from <= to => Ok(Mode::Backfill {
+            from: *from,
+            to: *to,
+        }),
+        _ => Err(command.error(ErrorKind::ValueValidation, "--from must not be after --to")),
+    }
+}
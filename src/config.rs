@@ -0,0 +1,406 @@
+use crate::error::{AggregatorError, SourceError};
+use chrono::{
+    format::{Item, StrftimeItems},
+    FixedOffset,
+};
+use serde::{de, Deserialize, Deserializer};
+use solana_sdk::commitment_config::CommitmentLevel;
+use std::{collections::HashMap, env, fs, io, num::NonZeroUsize, path::PathBuf};
+
+const DEFAULT_DEDUP_CAPACITY: usize = 10_000;
+const DEFAULT_DEDUP_MAX_AGE_SECS: u64 = 3_600;
+const DEFAULT_MAX_BACKFILL_SLOTS: u64 = 100;
+const DEFAULT_MAX_GAP_SLOTS: u64 = 100;
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_DB_CONNECT_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_DB_CONNECT_INITIAL_BACKOFF_MS: u64 = 200;
+const DEFAULT_RPC_REQUESTS_PER_SECOND: f64 = 4.0;
+const DEFAULT_RPC_INITIAL_BACKOFF_MS: u64 = 500;
+const DEFAULT_RPC_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_DB_BUSY_TIMEOUT_MS: u64 = 5_000;
+const DEFAULT_DB_POOL_SIZE: u32 = 4;
+const DEFAULT_PIPELINE_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_PIPELINE_MAX_ATTEMPTS: u32 = 2;
+const DEFAULT_RECONNECT_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_RECONNECT_INITIAL_BACKOFF_MS: u64 = 1_000;
+const DEFAULT_ACCOUNT_PUSHDOWN_MAX: usize = 10;
+const DEFAULT_MAX_CONCURRENT_BLOCKS: usize = 4;
+const DEFAULT_RETENTION_INTERVAL_SECS: u64 = 3_600;
+const DEFAULT_COMMITMENT: CommitmentLevel = CommitmentLevel::Finalized;
+const DEFAULT_COMMITMENT_POLL_MS: u64 = 400;
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+const DEFAULT_DB_PATH: &str = "transactions.db";
+const DEFAULT_BIND_ADDR: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 8080;
+/// The format of the stored timestamps, e.g. `2024-07-28 21:11:50`.
+pub const DEFAULT_TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+/// The `timestamp_format` selecting RFC 3339 timestamps, e.g. `2024-07-28T21:11:50Z`.
+pub const RFC3339_TIMESTAMP_FORMAT: &str = "rfc3339";
+
+/// How the aggregator learns about new blocks.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SubscribeMode {
+    /// Subscribes to slots and fetches the block of each root with `getBlock`.
+    #[default]
+    Slot,
+    /// Subscribes to blocks, which the websocket delivers with their transactions.
+    Block,
+}
+
+/// The database the aggregator writes to.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DbBackend {
+    /// The SQLite file at `db_path`.
+    #[default]
+    Sqlite,
+    /// The Postgres database at `database_url`, with the `postgres` feature.
+    Postgres,
+}
+
+/// What the aggregator does when the transactions of a block can't be inserted.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InsertFailurePolicy {
+    /// Fails the block, which is then retried or recorded as failed.
+    #[default]
+    Abort,
+    /// Inserts the transactions of the block one by one, skipping the ones that fail.
+    Skip,
+}
+
+/// The configuration of the aggregator and the web server, loaded once on startup.
+///
+/// Every field can be set in the configuration file, and by the environment variable of the same
+/// name, e.g. `max_slots` by `MAX_SLOTS`, which takes precedence.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    pub ws_url: url::Url,
+    pub rpc_url: url::Url,
+    #[serde(default)]
+    pub rpc_urls: Vec<url::Url>,
+    #[serde(default = "default_db_path")]
+    pub db_path: PathBuf,
+    #[serde(default)]
+    pub db_backend: DbBackend,
+    pub database_url: Option<String>,
+    #[serde(default = "default_db_pool_size")]
+    pub db_pool_size: u32,
+    #[serde(default = "default_bind_addr")]
+    pub bind_addr: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub http_workers: Option<NonZeroUsize>,
+    #[serde(default)]
+    pub max_slots: u64,
+    #[serde(default = "default_dedup_capacity")]
+    pub dedup_capacity: usize,
+    #[serde(default = "default_dedup_max_age_secs")]
+    pub dedup_max_age_secs: u64,
+    #[serde(default = "default_max_backfill_slots")]
+    pub max_backfill_slots: u64,
+    #[serde(default = "default_max_gap_slots")]
+    pub max_gap_slots: u64,
+    #[serde(default = "default_max_attempts")]
+    pub rpc_max_attempts: u32,
+    #[serde(default = "default_rpc_initial_backoff_ms")]
+    pub rpc_initial_backoff_ms: u64,
+    #[serde(default = "default_rpc_timeout_secs")]
+    pub rpc_timeout_secs: u64,
+    #[serde(default = "default_max_attempts")]
+    pub db_max_attempts: u32,
+    #[serde(default = "default_db_busy_timeout_ms")]
+    pub db_busy_timeout_ms: u64,
+    #[serde(default = "default_db_connect_max_attempts")]
+    pub db_connect_max_attempts: u32,
+    #[serde(default = "default_db_connect_initial_backoff_ms")]
+    pub db_connect_initial_backoff_ms: u64,
+    #[serde(default = "default_pipeline_timeout_secs")]
+    pub pipeline_timeout_secs: u64,
+    #[serde(default = "default_pipeline_max_attempts")]
+    pub pipeline_max_attempts: u32,
+    #[serde(default = "default_rpc_requests_per_second")]
+    pub rpc_requests_per_second: f64,
+    pub rpc_burst: Option<u32>,
+    #[serde(default = "default_max_concurrent_blocks")]
+    pub max_concurrent_blocks: usize,
+    #[serde(default = "default_reconnect_max_attempts")]
+    pub reconnect_max_attempts: u32,
+    #[serde(default = "default_reconnect_initial_backoff_ms")]
+    pub reconnect_initial_backoff_ms: u64,
+    #[serde(default)]
+    pub reprocess_slots: bool,
+    #[serde(default)]
+    pub exit_on_aggregator_error: bool,
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub retention_days: u32,
+    #[serde(default = "default_retention_interval_secs")]
+    pub retention_interval_secs: u64,
+    #[serde(default)]
+    pub sender_index: usize,
+    #[serde(default)]
+    pub lenient_balances: bool,
+    #[serde(default)]
+    pub lenient_block_time: bool,
+    #[serde(default)]
+    pub lenient_transactions: bool,
+    #[serde(default)]
+    pub store_logs: bool,
+    #[serde(default)]
+    pub include_votes: bool,
+    #[serde(default)]
+    pub min_signatures: usize,
+    #[serde(default)]
+    pub account_allowlist: Vec<String>,
+    #[serde(default)]
+    pub account_denylist: Vec<String>,
+    #[serde(default)]
+    pub program_ids: Vec<String>,
+    #[serde(default = "default_account_pushdown_max")]
+    pub account_pushdown_max: usize,
+    #[serde(default = "default_commitment")]
+    pub commitment: CommitmentLevel,
+    #[serde(default)]
+    pub commitment_wait_secs: u64,
+    #[serde(default = "default_commitment_poll_ms")]
+    pub commitment_poll_ms: u64,
+    #[serde(default)]
+    pub subscribe_mode: SubscribeMode,
+    #[serde(default)]
+    pub insert_failure_policy: InsertFailurePolicy,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub admin_token: Option<String>,
+    #[serde(default)]
+    pub rate_limit_rps: f64,
+    pub rate_limit_burst: Option<u32>,
+    #[serde(default)]
+    pub trust_forwarded_for: bool,
+    pub otel_endpoint: Option<String>,
+    #[serde(
+        default = "default_timestamp_format",
+        deserialize_with = "timestamp_format"
+    )]
+    pub timestamp_format: String,
+    #[serde(default = "default_timestamp_offset", deserialize_with = "utc_offset")]
+    pub timestamp_offset: FixedOffset,
+}
+
+fn default_db_path() -> PathBuf {
+    PathBuf::from(DEFAULT_DB_PATH)
+}
+
+fn default_db_pool_size() -> u32 {
+    DEFAULT_DB_POOL_SIZE
+}
+
+fn default_bind_addr() -> String {
+    DEFAULT_BIND_ADDR.to_string()
+}
+
+fn default_port() -> u16 {
+    DEFAULT_PORT
+}
+
+fn default_dedup_capacity() -> usize {
+    DEFAULT_DEDUP_CAPACITY
+}
+
+fn default_dedup_max_age_secs() -> u64 {
+    DEFAULT_DEDUP_MAX_AGE_SECS
+}
+
+fn default_max_backfill_slots() -> u64 {
+    DEFAULT_MAX_BACKFILL_SLOTS
+}
+
+fn default_max_gap_slots() -> u64 {
+    DEFAULT_MAX_GAP_SLOTS
+}
+
+fn default_max_attempts() -> u32 {
+    DEFAULT_MAX_ATTEMPTS
+}
+
+fn default_rpc_initial_backoff_ms() -> u64 {
+    DEFAULT_RPC_INITIAL_BACKOFF_MS
+}
+
+fn default_rpc_timeout_secs() -> u64 {
+    DEFAULT_RPC_TIMEOUT_SECS
+}
+
+fn default_db_busy_timeout_ms() -> u64 {
+    DEFAULT_DB_BUSY_TIMEOUT_MS
+}
+
+fn default_db_connect_max_attempts() -> u32 {
+    DEFAULT_DB_CONNECT_MAX_ATTEMPTS
+}
+
+fn default_db_connect_initial_backoff_ms() -> u64 {
+    DEFAULT_DB_CONNECT_INITIAL_BACKOFF_MS
+}
+
+fn default_pipeline_timeout_secs() -> u64 {
+    DEFAULT_PIPELINE_TIMEOUT_SECS
+}
+
+fn default_pipeline_max_attempts() -> u32 {
+    DEFAULT_PIPELINE_MAX_ATTEMPTS
+}
+
+fn default_rpc_requests_per_second() -> f64 {
+    DEFAULT_RPC_REQUESTS_PER_SECOND
+}
+
+fn default_max_concurrent_blocks() -> usize {
+    DEFAULT_MAX_CONCURRENT_BLOCKS
+}
+
+fn default_retention_interval_secs() -> u64 {
+    DEFAULT_RETENTION_INTERVAL_SECS
+}
+
+fn default_reconnect_max_attempts() -> u32 {
+    DEFAULT_RECONNECT_MAX_ATTEMPTS
+}
+
+fn default_reconnect_initial_backoff_ms() -> u64 {
+    DEFAULT_RECONNECT_INITIAL_BACKOFF_MS
+}
+
+fn default_account_pushdown_max() -> usize {
+    DEFAULT_ACCOUNT_PUSHDOWN_MAX
+}
+
+fn default_commitment() -> CommitmentLevel {
+    DEFAULT_COMMITMENT
+}
+
+fn default_commitment_poll_ms() -> u64 {
+    DEFAULT_COMMITMENT_POLL_MS
+}
+
+fn default_timestamp_format() -> String {
+    DEFAULT_TIMESTAMP_FORMAT.to_string()
+}
+
+fn default_timestamp_offset() -> FixedOffset {
+    FixedOffset::east_opt(0).unwrap()
+}
+
+/// Deserializes a timestamp format, either `rfc3339` or a `strftime` format string.
+///
+/// Invalid format strings are rejected here, since formatting a timestamp with one panics.
+fn timestamp_format<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let format = String::deserialize(deserializer)?;
+    if format.eq_ignore_ascii_case(RFC3339_TIMESTAMP_FORMAT) {
+        return Ok(RFC3339_TIMESTAMP_FORMAT.to_string());
+    }
+    if format.is_empty() || StrftimeItems::new(&format).any(|item| item == Item::Error) {
+        return Err(de::Error::custom(format!(
+            "invalid timestamp format {:?}",
+            format
+        )));
+    }
+    Ok(format)
+}
+
+/// Deserializes an offset from UTC such as `+02:00` or `-05:30`.
+fn utc_offset<'de, D>(deserializer: D) -> Result<FixedOffset, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let offset = String::deserialize(deserializer)?;
+    match offset.parse() {
+        Ok(res) => Ok(res),
+        Err(_) => Err(de::Error::custom(format!(
+            "invalid timestamp offset {:?}, expected e.g. +02:00",
+            offset
+        ))),
+    }
+}
+
+impl Config {
+    /// Loads the configuration from the file at `CONFIG_PATH` (default `config.toml`) and the
+    /// environment variables, which override the values of the file.
+    ///
+    /// The default file is optional, while a file set with `CONFIG_PATH` must exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns `AggregatorError::ConfigFileError` if the file can't be read or parsed.
+    /// Returns `AggregatorError::EnvFetchError` if a value is missing or invalid.
+    pub fn load() -> Result<Config, AggregatorError> {
+        let (path, required) = match env::var("CONFIG_PATH") {
+            Ok(path) => (path, true),
+            Err(_) => (DEFAULT_CONFIG_PATH.to_string(), false),
+        };
+        let file = match fs::read_to_string(path) {
+            Ok(res) => Some(res),
+            Err(err) if err.kind() == io::ErrorKind::NotFound && !required => None,
+            Err(err) => return Err(AggregatorError::ConfigFileError(SourceError::new(err))),
+        };
+        Config::from_sources(file.as_deref(), env::vars())
+    }
+
+    /// Builds the configuration from the contents of a configuration file and environment variables.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The TOML contents of the configuration file, if any.
+    /// * `vars` - The environment variables, overriding the values of the file.
+    ///
+    /// # Errors
+    ///
+    /// Returns `AggregatorError::ConfigFileError` if the file can't be parsed.
+    /// Returns `AggregatorError::EnvFetchError` if a value is missing or invalid.
+    pub fn from_sources(
+        file: Option<&str>,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Config, AggregatorError> {
+        let mut values: HashMap<String, String> = HashMap::new();
+        if let Some(file) = file {
+            let table = match toml::from_str::<toml::value::Table>(file) {
+                Ok(res) => res,
+                Err(err) => return Err(AggregatorError::ConfigFileError(SourceError::new(err))),
+            };
+            for (key, value) in table {
+                values.insert(key.to_lowercase(), env_value(value));
+            }
+        }
+        // Both sources are parsed like environment variables, so lists are comma separated.
+        values.extend(
+            vars.into_iter()
+                .map(|(key, value)| (key.to_lowercase(), value)),
+        );
+        match envy::from_iter::<_, Config>(values) {
+            Ok(res) => Ok(res),
+            Err(err) => Err(AggregatorError::EnvFetchError(SourceError::new(err))),
+        }
+    }
+}
+
+/// Formats a value of the configuration file like the environment variable setting it.
+///
+/// # Arguments
+///
+/// * `value` - The TOML value.
+fn env_value(value: toml::Value) -> String {
+    match value {
+        toml::Value::String(value) => value,
+        toml::Value::Array(values) => values
+            .into_iter()
+            .map(env_value)
+            .collect::<Vec<String>>()
+            .join(","),
+        value => value.to_string(),
+    }
+}
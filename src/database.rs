@@ -1,146 +1,1993 @@
-use crate::error::DatabaseError;
+#[cfg(feature = "postgres")]
+use crate::postgres::{PostgresPool, PostgresStore};
+use crate::{
+    aggregator::TimestampFormat,
+    config::{Config, DbBackend},
+    error::DatabaseError,
+    units::lamports_to_sol,
+};
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
 use solana_sdk::pubkey::Pubkey;
+use std::{
+    fs, io, mem,
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+use tracing::{info, warn};
 
-use rusqlite::{Connection, Result};
+use rusqlite::{
+    params, params_from_iter, Connection, OpenFlags, OptionalExtension, Result, Row,
+    StatementStatus,
+};
+use serde::{Deserialize, Serialize};
+
+const POOL_CONNECTION_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long a connection waits for another one to release its lock before its statement fails
+/// with `SQLITE_BUSY`.
+pub const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// The value of `PRAGMA auto_vacuum` for incremental auto-vacuum.
+const SQLITE_INCREMENTAL_VACUUM: i64 = 2;
+/// The version of the schema, stored in the `user_version` of SQLite databases. Version 1 stores
+/// non-negative amounts and their direction in `net_change`, version 2 records whether each
+/// transaction failed in `status`, version 3 records the height of its block in
+/// `block_height`, version 4 stores each signature once, version 5 creates the remaining
+/// indexes, and version 6 clears the epoch timestamps stored for unknown block times.
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
+/// The migrations of SQLite databases, in order. The migration at index `i` brings a database
+/// from version `i` to version `i + 1`.
+///
+/// Databases created by an older version keep their tables, since `CREATE TABLE IF NOT EXISTS`
+/// doesn't alter them, so new columns and indexes on them are added by a new migration rather
+/// than by editing an existing one.
+const MIGRATIONS: [&[MigrationStep]; 6] = [
+    &[
+        // The first release stored neither the position of the transactions nor their fee, which
+        // are unknown for its rows.
+        MigrationStep::AddColumn("transactions", "slot", "bigint NOT NULL DEFAULT 0"),
+        MigrationStep::AddColumn("transactions", "tx_index", "bigint NOT NULL DEFAULT 0"),
+        MigrationStep::AddColumn("transactions", "fee", "bigint NOT NULL DEFAULT 0"),
+        MigrationStep::AddColumn("transactions", "program_id", "text"),
+        MigrationStep::AddColumn("transactions", "transfer_type", "text"),
+        MigrationStep::AddColumn("transactions", "token_mint", "text"),
+        MigrationStep::AddColumn("transactions", "token_amount", "text"),
+        MigrationStep::AddColumn("transactions", "token_decimals", "integer"),
+        MigrationStep::AddColumn("transactions", "signature_count", "integer"),
+        MigrationStep::AddColumn("transactions", "net_change", "bigint"),
+        // Unversioned databases stored the balance decrease of the account as the amount, which
+        // was negative for incoming transfers.
+        MigrationStep::Execute(
+            "UPDATE transactions SET net_change = -amount, amount = ABS(amount) WHERE net_change IS NULL",
+        ),
+    ],
+    &[MigrationStep::AddColumn("transactions", "status", "text")],
+    &[MigrationStep::AddColumn("transactions", "block_height", "bigint")],
+    // Databases created before the signature was unique may hold the same transaction more than
+    // once, which would fail the index, so only the first copy is kept.
+    &[
+        MigrationStep::Execute(
+            "DELETE FROM transactions WHERE rowid NOT IN (SELECT MIN(rowid) FROM transactions GROUP BY signature)",
+        ),
+        MigrationStep::Execute(
+            "CREATE UNIQUE INDEX IF NOT EXISTS transactions_signature ON transactions (signature)",
+        ),
+    ],
+    // The other indexes used to be created on every startup, so databases from before this
+    // version may already have them, but may also hold a balance change more than once.
+    &[
+        MigrationStep::Execute(
+            "DELETE FROM balance_changes WHERE rowid NOT IN (SELECT MIN(rowid) FROM balance_changes GROUP BY signature, account)",
+        ),
+        MigrationStep::Execute(
+            "CREATE UNIQUE INDEX IF NOT EXISTS balance_changes_signature_account ON balance_changes (signature, account)",
+        ),
+        MigrationStep::Execute(
+            "CREATE INDEX IF NOT EXISTS transactions_sender ON transactions (sender)",
+        ),
+        MigrationStep::Execute(
+            "CREATE INDEX IF NOT EXISTS transactions_receiver ON transactions (receiver)",
+        ),
+        MigrationStep::Execute(
+            "CREATE INDEX IF NOT EXISTS transactions_timestamp ON transactions (timestamp)",
+        ),
+        MigrationStep::Execute(
+            "CREATE INDEX IF NOT EXISTS transactions_program_id ON transactions (program_id)",
+        ),
+        MigrationStep::Execute(
+            "CREATE INDEX IF NOT EXISTS transactions_token_mint ON transactions (token_mint)",
+        ),
+    ],
+    &[
+        MigrationStep::Execute(
+            "UPDATE transactions SET timestamp = NULL WHERE timestamp = '1970-01-01 00:00:00'",
+        ),
+        MigrationStep::Execute(
+            "UPDATE balance_changes SET timestamp = NULL WHERE timestamp = '1970-01-01 00:00:00'",
+        ),
+    ],
+];
+
+/// A step of a schema migration.
+enum MigrationStep {
+    /// Adds a column to a table unless it exists: the table, the column and its type, with any
+    /// constraint.
+    AddColumn(&'static str, &'static str, &'static str),
+    /// Executes an idempotent statement.
+    Execute(&'static str),
+}
+
+impl MigrationStep {
+    /// Applies the step.
+    ///
+    /// # Arguments
+    ///
+    /// * `database_client` - The connection to the database.
+    ///
+    /// # Errors
+    ///
+    /// Returns the `rusqlite::Error` of the failed statement.
+    fn apply(&self, database_client: &Connection) -> Result<()> {
+        match self {
+            MigrationStep::AddColumn(table, column, column_type) => {
+                let exists = database_client.query_row(
+                    "SELECT COUNT(*) FROM pragma_table_info($1) WHERE name = $2",
+                    [table, column],
+                    |row| row.get::<usize, i64>(0),
+                )? > 0;
+                if !exists {
+                    database_client.execute(
+                        &format!(
+                            "ALTER TABLE {} ADD COLUMN {} {}",
+                            table, column, column_type
+                        ),
+                        [],
+                    )?;
+                }
+                Ok(())
+            }
+            MigrationStep::Execute(statement) => database_client.execute_batch(statement),
+        }
+    }
+}
+/// How many exported transactions are written at once.
+const EXPORT_BATCH_SIZE: usize = 1000;
+/// The `transfer_type` of transactions moving SOL.
+pub const TRANSFER_TYPE_SOL: &str = "sol";
+/// The `transfer_type` of transactions moving SPL tokens.
+pub const TRANSFER_TYPE_TOKEN: &str = "token";
+/// The `status` of transactions that succeeded.
+pub const STATUS_SUCCESS: &str = "success";
+/// The `status` of transactions that failed, which still paid their fee.
+pub const STATUS_FAILED: &str = "failed";
+
+/// Converts the error of a failed query into a `DatabaseError`.
+///
+/// # Arguments
+///
+/// * `err` - The SQLite error.
+///
+/// # Returns
+///
+/// `DatabaseError::BusyError` if the database stayed locked by another connection for longer
+/// than the busy timeout, which retrying later may solve, or `DatabaseError::QueryError`
+/// otherwise.
+fn query_error(err: rusqlite::Error) -> DatabaseError {
+    match err.sqlite_error_code() {
+        Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked) => {
+            DatabaseError::BusyError
+        }
+        _ => DatabaseError::QueryError,
+    }
+}
+
+/// Creates the directory of a file if it doesn't exist, along with its missing parents.
+///
+/// # Arguments
+///
+/// * `path` - The path of the file.
+///
+/// # Errors
+///
+/// Returns the `io::Error` if a directory can't be created.
+pub fn create_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => fs::create_dir_all(parent),
+        _ => Ok(()),
+    }
+}
+
+/// Connects to the database backend selected by `db_backend`.
+///
+/// `sqlite` (the default) opens the `db_path` file. `postgres` connects to `database_url` and
+/// requires the `postgres` feature.
+///
+/// # Arguments
+///
+/// * `config` - The configuration.
+///
+/// # Errors
+///
+/// Returns `DatabaseError::ConnectError` if the backend is not compiled in, if `database_url`
+/// is missing, or if the connection to the database fails.
+pub fn connect(config: &Config) -> Result<Box<dyn Store + Send>, DatabaseError> {
+    match config.db_backend {
+        DbBackend::Sqlite => Ok(Box::new(Database::with_path(&config.db_path)?)),
+        #[cfg(feature = "postgres")]
+        DbBackend::Postgres => match &config.database_url {
+            Some(url) => Ok(Box::new(PostgresStore::connect(url)?)),
+            None => Err(DatabaseError::ConnectError),
+        },
+        #[cfg(not(feature = "postgres"))]
+        DbBackend::Postgres => Err(DatabaseError::ConnectError),
+    }
+}
+
+/// Creates the pool the web server reads the database backend selected by `db_backend` through.
+///
+/// `sqlite` initializes the `db_path` file, and pools up to `db_pool_size` read-only connections
+/// to it that wait up to `db_busy_timeout_ms` for the aggregator's writes. `postgres` shares a
+/// connection to `database_url` and requires the `postgres` feature. The timestamps are formatted
+/// with `timestamp_format` and `timestamp_offset`.
+///
+/// # Arguments
+///
+/// * `config` - The configuration.
+///
+/// # Errors
+///
+/// Returns the `DatabaseError` of the initialization of the database, or
+/// `DatabaseError::ConnectError` if the backend is not compiled in, if `database_url` is missing,
+/// or if the connection to the database fails.
+pub fn connect_pool(config: &Config) -> Result<Arc<dyn StorePool>, DatabaseError> {
+    let timestamp_format = TimestampFormat::from_config(config);
+    match config.db_backend {
+        DbBackend::Sqlite => {
+            Database::with_path(&config.db_path)?;
+            Ok(Arc::new(
+                DatabasePool::with_busy_timeout(
+                    &config.db_path,
+                    config.db_pool_size,
+                    Duration::from_millis(config.db_busy_timeout_ms),
+                )
+                .with_timestamp_format(timestamp_format),
+            ))
+        }
+        #[cfg(feature = "postgres")]
+        DbBackend::Postgres => match &config.database_url {
+            Some(url) => Ok(Arc::new(
+                PostgresPool::connect(url)?.with_timestamp_format(timestamp_format),
+            )),
+            None => Err(DatabaseError::ConnectError),
+        },
+        #[cfg(not(feature = "postgres"))]
+        DbBackend::Postgres => Err(DatabaseError::ConnectError),
+    }
+}
+
+/// Connects to the database backend like `connect`, retrying while the database can't be
+/// connected to.
+///
+/// This gives a database on a slow or not yet mounted volume, or a database server that is still
+/// starting, time to become reachable on startup. The attempts are bounded by
+/// `db_connect_max_attempts`, and the wait after the first failed one is
+/// `db_connect_initial_backoff_ms`, doubled after each further one.
+///
+/// # Arguments
+///
+/// * `config` - The configuration.
+///
+/// # Errors
+///
+/// Returns `DatabaseError::ConnectError` if every attempt failed to connect.
+/// Returns the error of `connect` if it failed for another reason.
+pub fn connect_with_retries(config: &Config) -> Result<Box<dyn Store + Send>, DatabaseError> {
+    let mut attempts = 1;
+    let mut backoff = Duration::from_millis(config.db_connect_initial_backoff_ms);
+    loop {
+        match connect(config) {
+            Err(DatabaseError::ConnectError) if attempts < config.db_connect_max_attempts => {
+                warn!(attempts, backend = ?config.db_backend, "failed to connect to the database, retrying");
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempts += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// The connection the aggregator writes through.
+///
+/// SQLite allows a single writer at a time, so the slots being processed share one write
+/// connection and take turns, instead of each opening their own and contending for the write
+/// lock. Readers use the separate connections of a `DatabasePool`, which WAL mode lets run
+/// alongside the writer.
+pub struct DatabaseWriter {
+    store: tokio::sync::Mutex<Box<dyn Store + Send>>,
+}
+
+impl DatabaseWriter {
+    /// Opens the write connection with the backend selected by `db_backend`, like `connect`.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::ConnectError` if the connection to the database fails.
+    pub fn connect(config: &Config) -> Result<DatabaseWriter, DatabaseError> {
+        Ok(DatabaseWriter {
+            store: tokio::sync::Mutex::new(connect(config)?),
+        })
+    }
+
+    /// Opens the write connection to a SQLite database file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the database file.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::ConnectError` if the connection to the database fails.
+    pub fn with_path(path: &Path) -> Result<DatabaseWriter, DatabaseError> {
+        Ok(DatabaseWriter {
+            store: tokio::sync::Mutex::new(Box::new(Database::with_path(path)?)),
+        })
+    }
+
+    /// Opens the write connection to a new in-memory SQLite database, which is dropped with it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::ConnectError` if the database can't be created.
+    pub fn in_memory() -> Result<DatabaseWriter, DatabaseError> {
+        Ok(DatabaseWriter {
+            store: tokio::sync::Mutex::new(Box::new(Database::in_memory()?)),
+        })
+    }
+
+    /// Takes the write connection, waiting for the current writer to release it.
+    ///
+    /// # Returns
+    ///
+    /// The connection, released when the guard is dropped.
+    pub async fn lock(&self) -> tokio::sync::MutexGuard<'_, Box<dyn Store + Send>> {
+        self.store.lock().await
+    }
+}
+
+/// Represents a stored transaction.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct TransactionRecord {
+    pub sender: String,
+    pub receiver: String,
+    /// The transferred lamports, never negative.
+    pub amount: i64,
+    /// The signed balance change of the account at `SENDER_INDEX`, excluding the fee: negative
+    /// when lamports left it, positive when it received them.
+    pub net_change: i64,
+    /// The time of the block, or `None` if it was unknown and `LENIENT_BLOCK_TIME` is set.
+    pub timestamp: Option<String>,
+    pub signature: String,
+    pub signatures: Vec<String>,
+    pub slot: u64,
+    /// The height of the block, or `None` if the RPC node didn't return it.
+    pub block_height: Option<u64>,
+    pub tx_index: u64,
+    pub fee: i64,
+    /// The program invoked by the transaction, if it could be read.
+    pub program_id: Option<String>,
+    /// `sol` for SOL transfers or `token` for SPL token transfers.
+    pub transfer_type: String,
+    /// The mint of the transferred token, for token transfers.
+    pub token_mint: Option<String>,
+    /// The transferred amount in the smallest unit of the token, for token transfers.
+    pub token_amount: Option<String>,
+    /// The number of decimal places of the transferred token, for token transfers.
+    pub token_decimals: Option<u8>,
+    /// `success` or `failed`.
+    pub status: String,
+    /// The balance change of each account, inserted along with the record. Left empty when the
+    /// record is read back, see `Store::balance_changes`.
+    #[serde(skip)]
+    pub balance_changes: Vec<(Pubkey, i64)>,
+    /// The log messages, inserted along with the record if set. Left unset when the record is
+    /// read back, see `Store::logs`.
+    #[serde(skip)]
+    pub logs: Option<Vec<String>>,
+}
+
+impl TransactionRecord {
+    /// Converts the record to JSON, adding the `transfer_direction` derived from its net change.
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut body = serde_json::json!(self);
+        body["transfer_direction"] = serde_json::json!(direction(self.net_change));
+        body
+    }
+}
+
+/// Derives the direction of a transfer from the sign of its net change.
+///
+/// # Arguments
+///
+/// * `net_change` - The signed balance change of the account, excluding the fee.
+///
+/// # Returns
+///
+/// `outgoing` if lamports left the account, `incoming` if it received them, or `none` if its
+/// balance didn't change apart from the fee.
+pub fn direction(net_change: i64) -> &'static str {
+    match net_change.signum() {
+        -1 => "outgoing",
+        1 => "incoming",
+        _ => "none",
+    }
+}
+
+/// Represents the transactions sent by a sender.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SenderStats {
+    pub sender: String,
+    pub tx_count: u64,
+    pub total_amount: i64,
+    pub total_amount_sol: String,
+}
+
+/// Represents the transactions between an account and one of its counterparties.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Counterparty {
+    pub counterparty: String,
+    pub tx_count: u64,
+    pub total_amount: i64,
+    pub total_amount_sol: String,
+}
+
+/// Represents the transactions of a time bucket.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct TimeBucket {
+    /// The start of the bucket, in the `%Y-%m-%d %H:%M:%S` format.
+    pub bucket_start: String,
+    pub tx_count: u64,
+    pub total_amount: i64,
+}
+
+/// The length of the time buckets transactions are grouped into.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BucketInterval {
+    Hour,
+    #[default]
+    Day,
+}
+
+impl BucketInterval {
+    /// The SQLite `strftime` format truncating a timestamp to the start of its bucket.
+    fn format(self) -> &'static str {
+        match self {
+            BucketInterval::Hour => "%Y-%m-%d %H:00:00",
+            BucketInterval::Day => "%Y-%m-%d 00:00:00",
+        }
+    }
+
+    /// The SQLite date modifier moving to the start of the next bucket.
+    fn modifier(self) -> &'static str {
+        match self {
+            BucketInterval::Hour => "+1 hour",
+            BucketInterval::Day => "+1 day",
+        }
+    }
+
+    /// The unit of the bucket in Postgres dates and intervals.
+    #[cfg(feature = "postgres")]
+    pub(crate) fn unit(self) -> &'static str {
+        match self {
+            BucketInterval::Hour => "hour",
+            BucketInterval::Day => "day",
+        }
+    }
+}
+
+/// Represents the summary of a stored transaction.
+#[derive(Debug, PartialEq)]
+pub struct TransactionSummary {
+    pub sender: String,
+    pub receiver: String,
+    pub amount: i64,
+    /// The time of the block, or `None` if it is unknown.
+    pub timestamp: Option<String>,
+    pub signature: String,
+}
 
 /// Represents a database connection and provides methods for interacting with it.
+///
+/// The statements of the queries are cached by the connection, so running a query again reuses
+/// its compiled SQL. The timestamps are stored in the default format in UTC, and the ones read
+/// are formatted with `timestamp_format`, the default one unless the connection comes from a
+/// `DatabasePool` with another format.
 pub struct Database {
-    client: Connection,
+    client: Client,
+    timestamp_format: TimestampFormat,
+}
+
+/// A connection owned by a `Database` or borrowed from a `DatabasePool`.
+enum Client {
+    Owned(Connection),
+    Pooled(PooledConnection<SqliteConnectionManager>),
+}
+
+impl Deref for Client {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        match self {
+            Client::Owned(connection) => connection,
+            Client::Pooled(connection) => connection,
+        }
+    }
+}
+
+impl DerefMut for Client {
+    fn deref_mut(&mut self) -> &mut Connection {
+        match self {
+            Client::Owned(connection) => connection,
+            Client::Pooled(connection) => connection,
+        }
+    }
+}
+
+/// A pool of read-only database connections shared by the web server's handlers.
+pub struct DatabasePool {
+    pool: Pool<SqliteConnectionManager>,
+    timestamp_format: TimestampFormat,
+}
+
+impl DatabasePool {
+    /// Creates a pool of read-only connections to the given file, whose statements wait up to
+    /// `DEFAULT_BUSY_TIMEOUT` for a locked database.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the database file.
+    /// * `max_size` - The maximum number of open connections (at least one).
+    pub fn read_only(path: &Path, max_size: u32) -> DatabasePool {
+        DatabasePool::with_busy_timeout(path, max_size, DEFAULT_BUSY_TIMEOUT)
+    }
+
+    /// Creates a pool of read-only connections to the given file.
+    ///
+    /// Connections are opened lazily, and waiting for one gives up after a second so that
+    /// requests fail fast when the database is unavailable. A statement finding the database
+    /// locked by another connection retries until `busy_timeout` has passed, and then fails with
+    /// `DatabaseError::BusyError`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the database file.
+    /// * `max_size` - The maximum number of open connections (at least one).
+    /// * `busy_timeout` - How long a statement waits for a locked database.
+    pub fn with_busy_timeout(path: &Path, max_size: u32, busy_timeout: Duration) -> DatabasePool {
+        let manager = SqliteConnectionManager::file(path)
+            .with_flags(
+                OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | OpenFlags::SQLITE_OPEN_URI
+                    | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )
+            .with_init(move |connection| connection.busy_timeout(busy_timeout));
+        let pool = Pool::builder()
+            .max_size(max_size.max(1))
+            .min_idle(Some(0))
+            .connection_timeout(POOL_CONNECTION_TIMEOUT)
+            .build_unchecked(manager);
+        DatabasePool {
+            pool,
+            timestamp_format: TimestampFormat::default(),
+        }
+    }
+
+    /// Sets the format of the timestamps read through the connections of the pool.
+    ///
+    /// # Arguments
+    ///
+    /// * `timestamp_format` - The format of the timestamps of the responses.
+    pub fn with_timestamp_format(mut self, timestamp_format: TimestampFormat) -> DatabasePool {
+        self.timestamp_format = timestamp_format;
+        self
+    }
+
+    /// Returns the format of the timestamps read through the connections of the pool.
+    pub fn timestamp_format(&self) -> &TimestampFormat {
+        &self.timestamp_format
+    }
+
+    /// Takes a connection from the pool. The connection returns to the pool when dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::ConnectError` if no connection could be opened in time.
+    pub fn get(&self) -> Result<Database, DatabaseError> {
+        match self.pool.get() {
+            Ok(res) => Ok(Database {
+                client: Client::Pooled(res),
+                timestamp_format: self.timestamp_format.clone(),
+            }),
+            Err(_) => Err(DatabaseError::ConnectError),
+        }
+    }
+
+    /// Returns the number of connections currently opened by the pool.
+    pub fn connections(&self) -> u32 {
+        self.pool.state().connections
+    }
+}
+
+/// Lends the connections the web server's handlers read through.
+///
+/// `DatabasePool` implements it on top of SQLite, and `PostgresPool` on top of Postgres with the
+/// `postgres` feature.
+pub trait StorePool: Send + Sync {
+    /// Takes a connection from the pool. The connection returns to the pool when dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::ConnectError` if no connection could be opened in time.
+    fn store(&self) -> Result<Box<dyn Store + Send>, DatabaseError>;
+}
+
+impl StorePool for DatabasePool {
+    fn store(&self) -> Result<Box<dyn Store + Send>, DatabaseError> {
+        Ok(Box::new(self.get()?))
+    }
+}
+
+/// Stores the aggregated transactions and the aggregator's progress.
+///
+/// `Database` implements it on top of SQLite, and `PostgresStore` on top of Postgres with the
+/// `postgres` feature. The aggregator and the web server only depend on this trait, so tests
+/// can run against an in-memory database or a fake. The queries taken by `records`,
+/// `each_record`, `count`, `sender_stats` and `time_buckets` select from `transactions` with
+/// `?` placeholders, in the SQL both backends understand, like the `/transactions` filter query.
+pub trait Store {
+    /// Inserts transaction records, along with their signatures, balance changes and log
+    /// messages, into the database in a single database transaction.
+    ///
+    /// Records whose signature is already stored are skipped, like their balance changes and
+    /// logs that are already stored. If any record fails, none of them are inserted.
+    ///
+    /// # Arguments
+    ///
+    /// * `records` - The transaction records.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::InsertionError` if an insertion fails.
+    fn insert_batch(&mut self, records: &[TransactionRecord]) -> Result<(), DatabaseError>;
+
+    /// Inserts a per-account balance change record into the database.
+    ///
+    /// Inserting a change that is already stored for the same signature and account is a no-op.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The public key of the account whose balance changed.
+    /// * `delta` - The balance change in lamports (`post - pre`).
+    /// * `timestamp` - The transaction timestamp.
+    /// * `signature` - The transaction signature.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::InsertionError` if the insertion fails.
+    fn insert_balance_change(
+        &mut self,
+        account: Pubkey,
+        delta: i64,
+        timestamp: &str,
+        signature: &str,
+    ) -> Result<(), DatabaseError>;
+
+    /// Inserts the log messages of a transaction into the database.
+    ///
+    /// The messages are stored JSON-encoded. Inserting logs that are already stored for the
+    /// same signature is a no-op.
+    ///
+    /// # Arguments
+    ///
+    /// * `signature` - The transaction signature.
+    /// * `logs` - The log messages.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::InsertionError` if the insertion fails.
+    fn insert_logs(&mut self, signature: &str, logs: &[String]) -> Result<(), DatabaseError>;
+
+    /// Inserts all signatures of a transaction into the database.
+    ///
+    /// The signatures are stored JSON-encoded under the first signature, which identifies the
+    /// transaction. Inserting signatures that are already stored for the same transaction is a no-op.
+    ///
+    /// # Arguments
+    ///
+    /// * `signature` - The first transaction signature.
+    /// * `signatures` - All transaction signatures.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::InsertionError` if the insertion fails.
+    fn insert_signatures(
+        &mut self,
+        signature: &str,
+        signatures: &[String],
+    ) -> Result<(), DatabaseError>;
+
+    /// Returns the log messages of a transaction.
+    ///
+    /// # Arguments
+    ///
+    /// * `signature` - The transaction signature.
+    ///
+    /// # Returns
+    ///
+    /// The log messages, or `None` if no logs are stored for the signature.
+    fn logs(&mut self, signature: &str) -> Option<Vec<String>>;
+
+    /// Returns the balance changes of an account in chronological order.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The public key of the account.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::QueryError` if the query fails.
+    ///
+    /// # Returns
+    ///
+    /// A vector of `(timestamp, delta)` pairs. The changes of transactions stored without a
+    /// timestamp are left out.
+    fn balance_changes(&mut self, account: &str) -> Result<Vec<(String, i64)>, DatabaseError>;
+
+    /// Returns the highest slot that has been fully written to the database.
+    ///
+    /// # Returns
+    ///
+    /// The last processed slot, or `None` if no slot has been processed yet.
+    fn last_processed_slot(&mut self) -> Option<u64>;
+
+    /// Records a slot as fully written to the database.
+    ///
+    /// Blocks may finish out of order, so the stored value only ever moves forward.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - The slot that has been processed.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::InsertionError` if the update fails.
+    fn set_last_processed_slot(&mut self, slot: u64) -> Result<(), DatabaseError>;
+
+    /// Records how many attempts an operation on a slot took.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - The slot the operation was performed for.
+    /// * `operation` - The name of the operation.
+    /// * `attempts` - The number of attempts made.
+    /// * `exhausted` - Whether every attempt failed.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::InsertionError` if the insertion fails.
+    fn record_slot_attempts(
+        &mut self,
+        slot: u64,
+        operation: &str,
+        attempts: u32,
+        exhausted: bool,
+    ) -> Result<(), DatabaseError>;
+
+    /// Returns the recorded attempts of an operation on a slot.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - The slot the operation was performed for.
+    /// * `operation` - The name of the operation.
+    ///
+    /// # Returns
+    ///
+    /// The number of attempts and whether they were exhausted, or `None` if nothing was recorded.
+    fn slot_attempts(&mut self, slot: u64, operation: &str) -> Option<(u32, bool)>;
+
+    /// Deletes the oldest transactions stored before a cutoff, along with their balance changes,
+    /// log messages and signatures.
+    ///
+    /// # Arguments
+    ///
+    /// * `cutoff` - The timestamp, in the stored format, before which transactions are deleted.
+    /// * `limit` - The maximum number of transactions deleted.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::PruneError` if the deletion fails, in which case nothing is deleted.
+    ///
+    /// # Returns
+    ///
+    /// The number of deleted transactions.
+    fn prune(&mut self, cutoff: &str, limit: u32) -> Result<usize, DatabaseError>;
+
+    /// Returns the space of the deleted rows to the file system.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::PruneError` if the space can't be reclaimed.
+    fn reclaim_space(&mut self) -> Result<(), DatabaseError>;
+
+    /// Returns the most recent transactions, newest first.
+    ///
+    /// Transactions are ordered by `slot` and then by their position within the block.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The maximum number of transactions to return.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::QueryError` if the query fails.
+    ///
+    /// # Returns
+    ///
+    /// A vector of `TransactionSummary`s.
+    fn latest(&mut self, limit: u32) -> Result<Vec<TransactionSummary>, DatabaseError>;
+
+    /// Returns the transaction with the given signature.
+    ///
+    /// # Arguments
+    ///
+    /// * `signature` - The transaction signature.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::QueryError` if the query fails.
+    ///
+    /// # Returns
+    ///
+    /// The transaction, or `None` if the signature is unknown.
+    fn transaction(&mut self, signature: &str) -> Result<Option<TransactionRecord>, DatabaseError>;
+
+    /// Returns a transaction together with its chronological neighbors.
+    ///
+    /// Transactions are ordered by `slot` and then by their position within the block.
+    ///
+    /// # Arguments
+    ///
+    /// * `signature` - The signature of the transaction at the center of the window.
+    /// * `window` - The number of transactions to return before and after it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::QueryError` if the query fails.
+    ///
+    /// # Returns
+    ///
+    /// The surrounding transactions in chronological order, or `None` if the signature is unknown.
+    fn context(
+        &mut self,
+        signature: &str,
+        window: u32,
+    ) -> Result<Option<Vec<TransactionRecord>>, DatabaseError>;
+
+    /// Checks that the database can be read.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::ConnectError` if the database can't be read.
+    fn ping(&mut self) -> Result<(), DatabaseError>;
+
+    /// Counts the rows selected by a query.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The SQL query whose rows are counted.
+    /// * `params` - The values bound to the query's parameters.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::QueryError` if the query fails.
+    fn count(&mut self, query: &str, params: &[String]) -> Result<u64, DatabaseError>;
+
+    /// Groups the transactions selected by a query by sender.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The SQL query selecting the transactions.
+    /// * `params` - The values bound to the query's parameters.
+    /// * `limit` - The maximum number of senders to return.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::QueryError` if the query fails.
+    ///
+    /// # Returns
+    ///
+    /// The senders with the highest total amount first.
+    fn sender_stats(
+        &mut self,
+        query: &str,
+        params: &[String],
+        limit: u32,
+    ) -> Result<Vec<SenderStats>, DatabaseError>;
+
+    /// Groups the transactions sent or received by an account by the other party.
+    ///
+    /// The counterparty of a transaction sent by the account is its receiver, and the one of a
+    /// transaction received by the account is its sender. A transaction from the account to
+    /// itself counts once, with the account as its counterparty.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The public key of the account.
+    /// * `limit` - The maximum number of counterparties to return.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::QueryError` if the query fails.
+    ///
+    /// # Returns
+    ///
+    /// The counterparties with the most transactions first.
+    fn counterparties(
+        &mut self,
+        account: &str,
+        limit: u32,
+    ) -> Result<Vec<Counterparty>, DatabaseError>;
+
+    /// Groups the transactions selected by a query into time buckets.
+    ///
+    /// The buckets are computed in SQL from the `timestamp` column, and the transactions stored
+    /// without a timestamp are left out.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The SQL query selecting the transactions.
+    /// * `params` - The values bound to the query's parameters.
+    /// * `interval` - The length of the buckets.
+    /// * `fill` - Whether to include the empty buckets between the first and the last one.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::QueryError` if the query fails.
+    ///
+    /// # Returns
+    ///
+    /// The buckets, oldest first.
+    fn time_buckets(
+        &mut self,
+        query: &str,
+        params: &[String],
+        interval: BucketInterval,
+        fill: bool,
+    ) -> Result<Vec<TimeBucket>, DatabaseError>;
+
+    /// Executes a query on the `transactions` table and returns the selected records.
+    ///
+    /// The query must select the `transactions` columns followed by the `signatures` column of
+    /// `transaction_signatures`, like the `/transactions` filter query does.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The SQL query to execute.
+    /// * `params` - The values bound to the query's parameters.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::QueryError` if the query fails.
+    fn records(
+        &mut self,
+        query: &str,
+        params: &[String],
+    ) -> Result<Vec<TransactionRecord>, DatabaseError>;
+
+    /// Executes a query on the `transactions` table and passes the selected records to a
+    /// callback one at a time, without collecting them.
+    ///
+    /// The query must select the same columns as for `records`.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The SQL query to execute.
+    /// * `params` - The values bound to the query's parameters.
+    /// * `callback` - Receives each record, and returns `false` to stop reading.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::QueryError` if the query fails or a row can't be read.
+    fn each_record(
+        &mut self,
+        query: &str,
+        params: &[String],
+        callback: &mut dyn FnMut(TransactionRecord) -> bool,
+    ) -> Result<(), DatabaseError>;
+
+    /// Exports the transactions selected by a query into a new SQLite database file, along with
+    /// their signatures.
+    ///
+    /// The transactions are read with `each_record` and written in batches, so they don't have to
+    /// fit in memory. Their timestamps are exported as they are stored rather than formatted.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the database file to create.
+    /// * `query` - The SQL query selecting the transactions, like the one of `records`.
+    /// * `params` - The values bound to the query's parameters.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::ExportError` if the file can't be created or written, or the error
+    /// of the query.
+    fn export(&mut self, path: &Path, query: &str, params: &[String]) -> Result<(), DatabaseError>;
+}
+
+/// Writes the records read by a `Store` into a new database file, `EXPORT_BATCH_SIZE` at a time.
+///
+/// # Arguments
+///
+/// * `path` - The path of the database file to create.
+/// * `each_record` - Reads the records, passing each one to its callback like `each_record`.
+///
+/// # Errors
+///
+/// Returns `DatabaseError::ExportError` if the file can't be created or written, or the error of
+/// `each_record`.
+pub(crate) fn export_records(
+    path: &Path,
+    each_record: impl FnOnce(&mut dyn FnMut(TransactionRecord) -> bool) -> Result<(), DatabaseError>,
+) -> Result<(), DatabaseError> {
+    let mut export = match Database::with_path(path) {
+        Ok(res) => res,
+        Err(_) => return Err(DatabaseError::ExportError),
+    };
+    // The exported file is downloaded on its own, so it must not depend on a WAL file.
+    if export
+        .client
+        .query_row("PRAGMA journal_mode = DELETE", [], |row| {
+            row.get::<usize, String>(0)
+        })
+        .is_err()
+    {
+        return Err(DatabaseError::ExportError);
+    }
+    let mut batch = Vec::with_capacity(EXPORT_BATCH_SIZE);
+    let mut written = Ok(());
+    each_record(&mut |record| {
+        batch.push(record);
+        if batch.len() == EXPORT_BATCH_SIZE {
+            written = export.insert_batch(&batch);
+            batch.clear();
+        }
+        written.is_ok()
+    })?;
+    match written.and_then(|_| export.insert_batch(&batch)) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(DatabaseError::ExportError),
+    }
 }
 
 impl Database {
-    /// Creates a new `Database` instance with an initialized database connection.
+    /// Creates a new `Database` instance with an initialized database connection
+    /// to the given file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the database file.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::ConnectError` if the connection to the database fails.
+    /// Returns `DatabaseError::InitTableError` if the table creation fails.
+    pub fn with_path(path: &Path) -> Result<Database, DatabaseError> {
+        let client = Database::init_database(path)?;
+        Ok(Database {
+            client: Client::Owned(client),
+            timestamp_format: TimestampFormat::default(),
+        })
+    }
+
+    /// Creates a new `Database` instance backed by a new in-memory database.
+    ///
+    /// The database has the same tables as a file database, and is discarded when dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::ConnectError` if the database can't be opened.
+    /// Returns `DatabaseError::InitTableError` if the table creation fails.
+    pub fn in_memory() -> Result<Database, DatabaseError> {
+        let client = match Connection::open_in_memory() {
+            Ok(res) => res,
+            Err(_) => return Err(DatabaseError::ConnectError),
+        };
+        Database::create_tables(&client)?;
+        Ok(Database {
+            client: Client::Owned(client),
+            timestamp_format: TimestampFormat::default(),
+        })
+    }
+
+    /// Creates a new `Database` instance backed by a named in-memory database, which the
+    /// connections of a `DatabasePool` opened on `Database::shared_memory_path(name)` share.
+    ///
+    /// The database is discarded once its last connection is closed, so this instance must
+    /// outlive the pool.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the database, unique to the process.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::ConnectError` if the database can't be opened.
+    /// Returns `DatabaseError::InitTableError` if the table creation fails.
+    pub fn shared_in_memory(name: &str) -> Result<Database, DatabaseError> {
+        let client = match Connection::open(Database::shared_memory_path(name)) {
+            Ok(res) => res,
+            Err(_) => return Err(DatabaseError::ConnectError),
+        };
+        Database::create_tables(&client)?;
+        Ok(Database {
+            client: Client::Owned(client),
+            timestamp_format: TimestampFormat::default(),
+        })
+    }
+
+    /// Returns the URI of a named in-memory database, see `shared_in_memory`.
     ///
-    /// # Panics
+    /// # Arguments
     ///
-    /// This function will panic if the database initialization fails.
-    pub fn new() -> Database {
-        let client = Database::init_database().unwrap();
-        Database { client }
+    /// * `name` - The name of the database.
+    pub fn shared_memory_path(name: &str) -> PathBuf {
+        PathBuf::from(format!("file:{}?mode=memory&cache=shared", name))
     }
 
-    /// Establishes a new database connection.
+    /// Establishes a new read-only database connection to the given file.
+    ///
+    /// The database is in WAL mode, so reads see the last committed snapshot and are
+    /// not blocked by the aggregator's writes.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the database file.
     ///
     /// # Errors
     ///
     /// Returns `DatabaseError::ConnectError` if the connection to the database fails.
-    pub fn new_connection() -> Result<Database, DatabaseError> {
-        let client = match Connection::open("transactions.db") {
+    pub fn read_only(path: &Path) -> Result<Database, DatabaseError> {
+        let client = match Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        ) {
             Ok(res) => res,
             Err(_) => return Err(DatabaseError::ConnectError),
         };
-        Ok(Database { client })
+        if client.busy_timeout(DEFAULT_BUSY_TIMEOUT).is_err() {
+            return Err(DatabaseError::ConnectError);
+        }
+        Ok(Database {
+            client: Client::Owned(client),
+            timestamp_format: TimestampFormat::default(),
+        })
     }
 
     /// Initializes the database, creating the necessary tables if they do not exist.
     ///
+    /// The directory of the file is created if it doesn't exist. The database is switched to WAL
+    /// mode so that readers don't wait for writers, and the connection waits up to
+    /// `DEFAULT_BUSY_TIMEOUT` for the locks of other connections, like a checkpoint or another
+    /// process writing to the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the database file.
+    ///
     /// # Errors
     ///
-    /// Returns `DatabaseError::ConnectError` if the connection to the database fails.
+    /// Returns `DatabaseError::ConnectError` if the directory can't be created or the connection
+    /// to the database fails.
     /// Returns `DatabaseError::InitTableError` if the table creation fails.
-    pub fn init_database() -> Result<Connection, DatabaseError> {
-        let database_client = Connection::open("transactions.db").unwrap();
+    pub fn init_database(path: &Path) -> Result<Connection, DatabaseError> {
+        if create_parent_dir(path).is_err() {
+            return Err(DatabaseError::ConnectError);
+        }
+        let database_client = match Connection::open(path) {
+            Ok(res) => res,
+            Err(_) => return Err(DatabaseError::ConnectError),
+        };
+        if database_client.busy_timeout(DEFAULT_BUSY_TIMEOUT).is_err() {
+            return Err(DatabaseError::ConnectError);
+        }
+
+        if database_client
+            .query_row("PRAGMA journal_mode = WAL", [], |row| {
+                row.get::<usize, String>(0)
+            })
+            .is_err()
+        {
+            return Err(DatabaseError::ConnectError);
+        }
+
+        Database::create_tables(&database_client)?;
+        Ok(database_client)
+    }
 
-        database_client
-            .execute(
-                "
+    /// Creates the necessary tables if they do not exist, and brings them up to date with
+    /// `migrate`, which also creates their indexes.
+    ///
+    /// `transactions` is indexed on the columns the `/transactions` query parameters filter by.
+    /// Shared by the file and in-memory databases so that their schemas can't drift.
+    ///
+    /// # Arguments
+    ///
+    /// * `database_client` - The connection to the database.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DatabaseError::InitTableError` if a table can't be created.
+    /// Returns `DatabaseError::MigrationError` if a migration fails.
+    fn create_tables(database_client: &Connection) -> Result<(), DatabaseError> {
+        let execute = |statement: &str| match database_client.execute(statement, []) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(DatabaseError::InitTableError),
+        };
+        execute(
+            "
                 CREATE TABLE IF NOT EXISTS transactions (
                     sender              text,
                     receiver            text,
                     amount              bigint,
                     timestamp           char(20),
+                    signature           text,
+                    slot                bigint,
+                    tx_index            bigint,
+                    fee                 bigint,
+                    program_id          text,
+                    transfer_type       text,
+                    token_mint          text,
+                    token_amount        text,
+                    token_decimals      integer,
+                    signature_count     integer,
+                    net_change          bigint,
+                    status              text,
+                    block_height        bigint
+                    )
+            ",
+        )?;
+
+        execute(
+            "
+                CREATE TABLE IF NOT EXISTS balance_changes (
+                    account             text,
+                    delta               bigint,
+                    timestamp           char(20),
                     signature           text
                     )
             ",
-                [],
-            )
-            .unwrap();
-        Ok(database_client)
+        )?;
+
+        execute(
+            "
+                CREATE TABLE IF NOT EXISTS progress (
+                    id                  integer PRIMARY KEY CHECK (id = 0),
+                    slot                bigint
+                    )
+            ",
+        )?;
+
+        execute(
+            "
+                CREATE TABLE IF NOT EXISTS failed_slots (
+                    slot                bigint,
+                    operation           text,
+                    attempts            integer,
+                    exhausted           boolean,
+                    PRIMARY KEY (slot, operation)
+                    )
+            ",
+        )?;
+
+        execute(
+            "
+                CREATE TABLE IF NOT EXISTS transaction_logs (
+                    signature           text PRIMARY KEY,
+                    logs                text
+                    )
+            ",
+        )?;
+
+        execute(
+            "
+                CREATE TABLE IF NOT EXISTS transaction_signatures (
+                    signature           text PRIMARY KEY,
+                    signatures          text
+                    )
+            ",
+        )?;
+
+        Database::migrate(database_client)
     }
 
-    /// Inserts a new transaction record into the database.
+    /// Applies the migrations the database hasn't applied yet.
+    ///
+    /// The version of the schema is the number of applied migrations, stored in the
+    /// `user_version` of the database. Each migration is applied in a transaction along with its
+    /// version bump, so a failed migration leaves the database at the previous version, and its
+    /// steps are idempotent, so it can be applied again.
     ///
     /// # Arguments
     ///
-    /// * `sender` - The sender's public key.
-    /// * `receiver` - The receiver's public key.
-    /// * `amount` - The transaction amount.
-    /// * `timestamp` - The transaction timestamp.
-    /// * `signature` - The transaction signature.
+    /// * `database_client` - The connection to the database.
     ///
     /// # Errors
     ///
-    /// Returns `DatabaseError::InsertionError` if the insertion fails.
-    pub fn insert(
-        &mut self,
-        sender: Pubkey,
-        receiver: Pubkey,
-        amount: i64,
-        timestamp: &String,
-        signature: &String,
-    ) -> Result<(), DatabaseError> {
-        match self.client.execute(
-            "INSERT INTO transactions (sender, receiver, amount, timestamp, signature) VALUES ($1, $2, $3, $4, $5)",
-            [&sender.to_string(), &receiver.to_string(), &amount.to_string(), timestamp, signature],
-        ){
-            Ok(_) => Ok(()),
-            Err(_) => Err(DatabaseError::InsertionError)
+    /// Returns `DatabaseError::MigrationError` if the version can't be read or a migration fails.
+    fn migrate(database_client: &Connection) -> Result<(), DatabaseError> {
+        let version = match database_client
+            .query_row("PRAGMA user_version", [], |row| row.get::<usize, i64>(0))
+        {
+            Ok(res) => res,
+            Err(_) => return Err(DatabaseError::MigrationError),
+        };
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version.max(0) as usize) {
+            let version = index as i64 + 1;
+            let transaction = match database_client.unchecked_transaction() {
+                Ok(res) => res,
+                Err(_) => return Err(DatabaseError::MigrationError),
+            };
+            for step in migration.iter() {
+                if step.apply(&transaction).is_err() {
+                    return Err(DatabaseError::MigrationError);
+                }
+            }
+            if transaction
+                .execute_batch(&format!("PRAGMA user_version = {}", version))
+                .is_err()
+                || transaction.commit().is_err()
+            {
+                return Err(DatabaseError::MigrationError);
+            }
+            info!(version, "migrated the database schema");
         }
+        Ok(())
     }
 
-    /// Executes a query on the database and returns the results.
+    /// Returns how many times the cached statement of a query has run on this connection.
     ///
     /// # Arguments
     ///
-    /// * `query` - The SQL query to execute.
+    /// * `query` - The SQL query.
     ///
     /// # Returns
     ///
-    /// A vector of strings representing the query results.
-    pub fn query(&mut self, query: &str) -> Vec<String> {
-        let mut stmt = self.client.prepare(query).unwrap();
-        let mut rows = stmt.query([]).unwrap();
-        let mut query_response: Vec<String> = vec![];
-        while let Ok(Some(row)) = rows.next() {
-            let mut result = "{".to_string();
-            if let Ok(res) = row.get::<usize, String>(0) {
-                result.push_str("sender:");
-                result.push_str(&res);
-                result.push_str(", ");
+    /// The number of runs, `0` if the query wasn't cached, or `None` if it is invalid.
+    pub fn statement_runs(&self, query: &str) -> Option<i32> {
+        match self.client.prepare_cached(query) {
+            Ok(stmt) => Some(stmt.get_status(StatementStatus::Run)),
+            Err(_) => None,
+        }
+    }
+
+    /// Reads a `transactions` row joined with its signatures into a `TransactionRecord`.
+    ///
+    /// The columns are read by name. Transactions without stored signatures list their first
+    /// signature only.
+    ///
+    /// # Arguments
+    ///
+    /// * `row` - The row to read.
+    fn record_from_row(row: &Row, timestamp_format: &TimestampFormat) -> Result<TransactionRecord> {
+        let signature: String = row.get("signature")?;
+        let signatures = row
+            .get::<&str, Option<String>>("signatures")?
+            .and_then(|signatures| serde_json::from_str(&signatures).ok())
+            .unwrap_or_else(|| vec![signature.clone()]);
+        let amount: i64 = row.get("amount")?;
+        Ok(TransactionRecord {
+            sender: row.get("sender")?,
+            receiver: row.get("receiver")?,
+            amount,
+            net_change: row
+                .get::<&str, Option<i64>>("net_change")?
+                .unwrap_or(-amount),
+            timestamp: row
+                .get::<&str, Option<String>>("timestamp")?
+                .map(|timestamp| timestamp_format.reformat(&timestamp)),
+            signature,
+            signatures,
+            slot: row.get::<&str, i64>("slot")? as u64,
+            block_height: row
+                .get::<&str, Option<i64>>("block_height")?
+                .map(|height| height as u64),
+            tx_index: row.get::<&str, i64>("tx_index")? as u64,
+            fee: row.get("fee")?,
+            program_id: row.get("program_id")?,
+            transfer_type: row
+                .get::<&str, Option<String>>("transfer_type")?
+                .unwrap_or_else(|| TRANSFER_TYPE_SOL.to_string()),
+            token_mint: row.get("token_mint")?,
+            token_amount: row.get("token_amount")?,
+            token_decimals: row.get("token_decimals")?,
+            status: row
+                .get::<&str, Option<String>>("status")?
+                .unwrap_or_else(|| STATUS_SUCCESS.to_string()),
+            balance_changes: vec![],
+            logs: None,
+        })
+    }
+}
+
+impl Store for Database {
+    fn insert_batch(&mut self, records: &[TransactionRecord]) -> Result<(), DatabaseError> {
+        let transaction = match self.client.transaction() {
+            Ok(res) => res,
+            Err(_) => return Err(DatabaseError::InsertionError),
+        };
+        {
+            let mut insert_transaction = match transaction.prepare_cached(
+                "INSERT OR IGNORE INTO transactions (sender, receiver, amount, timestamp, signature, slot, tx_index, fee, program_id, transfer_type, token_mint, token_amount, token_decimals, signature_count, net_change, status, block_height) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17)",
+            ) {
+                Ok(res) => res,
+                Err(_) => return Err(DatabaseError::InsertionError),
+            };
+            let mut insert_signatures = match transaction.prepare_cached(
+                "INSERT OR IGNORE INTO transaction_signatures (signature, signatures) VALUES ($1, $2)",
+            ) {
+                Ok(res) => res,
+                Err(_) => return Err(DatabaseError::InsertionError),
+            };
+            let mut insert_balance_change = match transaction.prepare_cached(
+                "INSERT OR IGNORE INTO balance_changes (account, delta, timestamp, signature) VALUES ($1, $2, $3, $4)",
+            ) {
+                Ok(res) => res,
+                Err(_) => return Err(DatabaseError::InsertionError),
+            };
+            let mut insert_logs = match transaction.prepare_cached(
+                "INSERT OR IGNORE INTO transaction_logs (signature, logs) VALUES ($1, $2)",
+            ) {
+                Ok(res) => res,
+                Err(_) => return Err(DatabaseError::InsertionError),
+            };
+            for record in records {
+                let signatures = match serde_json::to_string(&record.signatures) {
+                    Ok(res) => res,
+                    Err(_) => return Err(DatabaseError::InsertionError),
+                };
+                let logs = match record.logs.as_ref().map(serde_json::to_string).transpose() {
+                    Ok(res) => res,
+                    Err(_) => return Err(DatabaseError::InsertionError),
+                };
+                if insert_transaction
+                    .execute(params![
+                        record.sender,
+                        record.receiver,
+                        record.amount,
+                        record.timestamp,
+                        record.signature,
+                        record.slot,
+                        record.tx_index,
+                        record.fee,
+                        record.program_id,
+                        record.transfer_type,
+                        record.token_mint,
+                        record.token_amount,
+                        record.token_decimals,
+                        record.signatures.len(),
+                        record.net_change,
+                        record.status,
+                        record.block_height.map(|height| height as i64),
+                    ])
+                    .is_err()
+                    || insert_signatures
+                        .execute(params![record.signature, signatures])
+                        .is_err()
+                {
+                    return Err(DatabaseError::InsertionError);
+                }
+                for (account, delta) in &record.balance_changes {
+                    if insert_balance_change
+                        .execute(params![
+                            account.to_string(),
+                            delta,
+                            record.timestamp,
+                            record.signature
+                        ])
+                        .is_err()
+                    {
+                        return Err(DatabaseError::InsertionError);
+                    }
+                }
+                if let Some(logs) = logs {
+                    if insert_logs
+                        .execute(params![record.signature, logs])
+                        .is_err()
+                    {
+                        return Err(DatabaseError::InsertionError);
+                    }
+                }
             }
+        }
+        match transaction.commit() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(DatabaseError::InsertionError),
+        }
+    }
+
+    fn insert_balance_change(
+        &mut self,
+        account: Pubkey,
+        delta: i64,
+        timestamp: &str,
+        signature: &str,
+    ) -> Result<(), DatabaseError> {
+        match self.client.execute(
+            "INSERT OR IGNORE INTO balance_changes (account, delta, timestamp, signature) VALUES ($1, $2, $3, $4)",
+            [&account.to_string(), &delta.to_string(), timestamp, signature],
+        ){
+            Ok(_) => Ok(()),
+            Err(_) => Err(DatabaseError::InsertionError)
+        }
+    }
+
+    fn insert_logs(&mut self, signature: &str, logs: &[String]) -> Result<(), DatabaseError> {
+        let logs = match serde_json::to_string(logs) {
+            Ok(res) => res,
+            Err(_) => return Err(DatabaseError::InsertionError),
+        };
+        match self.client.execute(
+            "INSERT OR IGNORE INTO transaction_logs (signature, logs) VALUES ($1, $2)",
+            [signature, &logs],
+        ) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(DatabaseError::InsertionError),
+        }
+    }
+
+    fn insert_signatures(
+        &mut self,
+        signature: &str,
+        signatures: &[String],
+    ) -> Result<(), DatabaseError> {
+        let signatures = match serde_json::to_string(signatures) {
+            Ok(res) => res,
+            Err(_) => return Err(DatabaseError::InsertionError),
+        };
+        match self.client.execute(
+            "INSERT OR IGNORE INTO transaction_signatures (signature, signatures) VALUES ($1, $2)",
+            [signature, &signatures],
+        ) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(DatabaseError::InsertionError),
+        }
+    }
+
+    fn logs(&mut self, signature: &str) -> Option<Vec<String>> {
+        let logs = self
+            .client
+            .query_row(
+                "SELECT logs FROM transaction_logs WHERE signature = $1",
+                [signature],
+                |row| row.get::<usize, String>(0),
+            )
+            .ok()?;
+        serde_json::from_str(&logs).ok()
+    }
 
-            if let Ok(res) = row.get::<usize, String>(1) {
-                result.push_str("receiver:");
-                result.push_str(&res);
-                result.push_str(", ");
+    fn balance_changes(&mut self, account: &str) -> Result<Vec<(String, i64)>, DatabaseError> {
+        let mut stmt = match self.client.prepare_cached(
+            "SELECT timestamp, delta FROM balance_changes WHERE account = $1 AND timestamp IS NOT NULL ORDER BY timestamp, rowid",
+        ) {
+            Ok(res) => res,
+            Err(err) => return Err(query_error(err)),
+        };
+        let mut rows = match stmt.query([account]) {
+            Ok(res) => res,
+            Err(err) => return Err(query_error(err)),
+        };
+        let mut changes: Vec<(String, i64)> = vec![];
+        while let Ok(Some(row)) = rows.next() {
+            if let (Ok(timestamp), Ok(delta)) =
+                (row.get::<usize, String>(0), row.get::<usize, i64>(1))
+            {
+                changes.push((self.timestamp_format.reformat(&timestamp), delta));
             }
+        }
+        Ok(changes)
+    }
+
+    fn last_processed_slot(&mut self) -> Option<u64> {
+        self.client
+            .query_row("SELECT slot FROM progress WHERE id = 0", [], |row| {
+                row.get::<usize, i64>(0)
+            })
+            .ok()
+            .map(|slot| slot as u64)
+    }
+
+    fn set_last_processed_slot(&mut self, slot: u64) -> Result<(), DatabaseError> {
+        match self.client.execute(
+            "INSERT INTO progress (id, slot) VALUES (0, $1) ON CONFLICT (id) DO UPDATE SET slot = MAX(slot, excluded.slot)",
+            [slot as i64],
+        ) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(DatabaseError::InsertionError),
+        }
+    }
+
+    fn record_slot_attempts(
+        &mut self,
+        slot: u64,
+        operation: &str,
+        attempts: u32,
+        exhausted: bool,
+    ) -> Result<(), DatabaseError> {
+        match self.client.execute(
+            "INSERT OR REPLACE INTO failed_slots (slot, operation, attempts, exhausted) VALUES ($1, $2, $3, $4)",
+            params![slot as i64, operation, attempts, exhausted],
+        ) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(DatabaseError::InsertionError),
+        }
+    }
+
+    fn slot_attempts(&mut self, slot: u64, operation: &str) -> Option<(u32, bool)> {
+        self.client
+            .query_row(
+                "SELECT attempts, exhausted FROM failed_slots WHERE slot = $1 AND operation = $2",
+                params![slot as i64, operation],
+                |row| Ok((row.get::<usize, u32>(0)?, row.get::<usize, bool>(1)?)),
+            )
+            .ok()
+    }
 
-            if let Ok(res) = row.get::<usize, i64>(2) {
-                result.push_str("amount:");
-                result.push_str(&res.to_string());
-                result.push_str(", ");
+    fn prune(&mut self, cutoff: &str, limit: u32) -> Result<usize, DatabaseError> {
+        let transaction = match self.client.transaction() {
+            Ok(res) => res,
+            Err(_) => return Err(DatabaseError::PruneError),
+        };
+        let mut deleted = 0;
+        // The transactions are deleted last, so the others can select the same ones.
+        for table in [
+            "balance_changes",
+            "transaction_logs",
+            "transaction_signatures",
+            "transactions",
+        ] {
+            match transaction.execute(
+                &format!(
+                    "DELETE FROM {} WHERE signature IN (SELECT signature FROM transactions WHERE timestamp < $1 ORDER BY timestamp, signature LIMIT $2)",
+                    table
+                ),
+                params![cutoff, limit],
+            ) {
+                Ok(res) => deleted = res,
+                Err(_) => return Err(DatabaseError::PruneError),
             }
+        }
+        match transaction.commit() {
+            Ok(_) => Ok(deleted),
+            Err(_) => Err(DatabaseError::PruneError),
+        }
+    }
+
+    fn reclaim_space(&mut self) -> Result<(), DatabaseError> {
+        let auto_vacuum = match self
+            .client
+            .query_row("PRAGMA auto_vacuum", [], |row| row.get::<usize, i64>(0))
+        {
+            Ok(res) => res,
+            Err(_) => return Err(DatabaseError::PruneError),
+        };
+        // Switching to incremental auto-vacuum takes a full `VACUUM` once, after which the free
+        // pages are released without rewriting the whole file.
+        let statement = if auto_vacuum == SQLITE_INCREMENTAL_VACUUM {
+            "PRAGMA incremental_vacuum"
+        } else {
+            "PRAGMA auto_vacuum = INCREMENTAL; VACUUM"
+        };
+        match self.client.execute_batch(statement) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(DatabaseError::PruneError),
+        }
+    }
 
-            if let Ok(res) = row.get::<usize, String>(3) {
-                result.push_str("timestamp:");
-                result.push_str(&res);
-                result.push_str(", ");
+    fn latest(&mut self, limit: u32) -> Result<Vec<TransactionSummary>, DatabaseError> {
+        let mut stmt = match self.client.prepare_cached(
+            "SELECT sender, receiver, amount, timestamp, signature FROM transactions ORDER BY slot DESC, tx_index DESC LIMIT $1",
+        ) {
+            Ok(res) => res,
+            Err(err) => return Err(query_error(err)),
+        };
+        let mut rows = match stmt.query([limit]) {
+            Ok(res) => res,
+            Err(err) => return Err(query_error(err)),
+        };
+        let mut transactions: Vec<TransactionSummary> = vec![];
+        while let Ok(Some(row)) = rows.next() {
+            if let (Ok(sender), Ok(receiver), Ok(amount), Ok(timestamp), Ok(signature)) = (
+                row.get::<usize, String>(0),
+                row.get::<usize, String>(1),
+                row.get::<usize, i64>(2),
+                row.get::<usize, Option<String>>(3),
+                row.get::<usize, String>(4),
+            ) {
+                transactions.push(TransactionSummary {
+                    sender,
+                    receiver,
+                    amount,
+                    timestamp: timestamp
+                        .map(|timestamp| self.timestamp_format.reformat(&timestamp)),
+                    signature,
+                });
             }
+        }
+        Ok(transactions)
+    }
+
+    fn transaction(&mut self, signature: &str) -> Result<Option<TransactionRecord>, DatabaseError> {
+        match self
+            .client
+            .query_row(
+                "SELECT sender, receiver, amount, timestamp, signature, slot, tx_index, fee, program_id, transfer_type, token_mint, token_amount, token_decimals, net_change, status, block_height, signatures FROM transactions LEFT JOIN transaction_signatures USING (signature) WHERE signature = $1",
+                [signature],
+                |row| Database::record_from_row(row, &self.timestamp_format),
+            )
+            .optional()
+        {
+            Ok(res) => Ok(res),
+            Err(err) => Err(query_error(err)),
+        }
+    }
+
+    fn context(
+        &mut self,
+        signature: &str,
+        window: u32,
+    ) -> Result<Option<Vec<TransactionRecord>>, DatabaseError> {
+        let (slot, tx_index) = match self
+            .client
+            .query_row(
+                "SELECT slot, tx_index FROM transactions WHERE signature = $1",
+                [signature],
+                |row| Ok((row.get::<usize, i64>(0)?, row.get::<usize, i64>(1)?)),
+            )
+            .optional()
+        {
+            Ok(Some(res)) => res,
+            Ok(None) => return Ok(None),
+            Err(err) => return Err(query_error(err)),
+        };
+
+        let mut stmt = match self.client.prepare_cached(
+            "
+                SELECT context.*, signatures FROM (
+                    SELECT * FROM (
+                        SELECT * FROM transactions WHERE (slot, tx_index) < ($1, $2)
+                        ORDER BY slot DESC, tx_index DESC LIMIT $3
+                    )
+                    UNION ALL
+                    SELECT * FROM transactions WHERE signature = $4
+                    UNION ALL
+                    SELECT * FROM (
+                        SELECT * FROM transactions WHERE (slot, tx_index) > ($1, $2)
+                        ORDER BY slot, tx_index LIMIT $3
+                    )
+                ) AS context
+                LEFT JOIN transaction_signatures USING (signature)
+                ORDER BY slot, tx_index
+            ",
+        ) {
+            Ok(res) => res,
+            Err(err) => return Err(query_error(err)),
+        };
+        let rows = match stmt.query_map(params![slot, tx_index, window, signature], |row| {
+            Database::record_from_row(row, &self.timestamp_format)
+        }) {
+            Ok(res) => res,
+            Err(err) => return Err(query_error(err)),
+        };
+        match rows.collect() {
+            Ok(res) => Ok(Some(res)),
+            Err(err) => Err(query_error(err)),
+        }
+    }
+
+    fn ping(&mut self) -> Result<(), DatabaseError> {
+        match self
+            .client
+            .query_row("SELECT count(*) FROM sqlite_master", [], |row| {
+                row.get::<usize, i64>(0)
+            }) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(DatabaseError::ConnectError),
+        }
+    }
+
+    fn count(&mut self, query: &str, params: &[String]) -> Result<u64, DatabaseError> {
+        let mut stmt = match self
+            .client
+            .prepare_cached(&format!("SELECT COUNT(*) FROM ({})", query))
+        {
+            Ok(res) => res,
+            Err(err) => return Err(query_error(err)),
+        };
+        match stmt.query_row(params_from_iter(params), |row| row.get::<usize, i64>(0)) {
+            Ok(res) => Ok(res as u64),
+            Err(err) => Err(query_error(err)),
+        }
+    }
+
+    fn sender_stats(
+        &mut self,
+        query: &str,
+        params: &[String],
+        limit: u32,
+    ) -> Result<Vec<SenderStats>, DatabaseError> {
+        let mut stmt = match self.client.prepare_cached(&format!(
+            "SELECT sender, COUNT(*) AS tx_count, SUM(amount) AS total_amount FROM ({}) GROUP BY sender ORDER BY total_amount DESC, sender LIMIT {}",
+            query, limit
+        )) {
+            Ok(res) => res,
+            Err(err) => return Err(query_error(err)),
+        };
+        let rows = match stmt.query_map(params_from_iter(params), |row| {
+            let total_amount: i64 = row.get(2)?;
+            Ok(SenderStats {
+                sender: row.get(0)?,
+                tx_count: row.get::<usize, i64>(1)? as u64,
+                total_amount,
+                total_amount_sol: lamports_to_sol(total_amount),
+            })
+        }) {
+            Ok(res) => res,
+            Err(err) => return Err(query_error(err)),
+        };
+        match rows.collect() {
+            Ok(res) => Ok(res),
+            Err(err) => Err(query_error(err)),
+        }
+    }
+
+    fn counterparties(
+        &mut self,
+        account: &str,
+        limit: u32,
+    ) -> Result<Vec<Counterparty>, DatabaseError> {
+        let mut stmt = match self.client.prepare_cached(
+            "SELECT counterparty, COUNT(*) AS tx_count, SUM(amount) AS total_amount FROM (SELECT receiver AS counterparty, amount FROM transactions WHERE sender = $1 UNION ALL SELECT sender AS counterparty, amount FROM transactions WHERE receiver = $1 AND sender != $1) GROUP BY counterparty ORDER BY tx_count DESC, total_amount DESC, counterparty LIMIT $2",
+        ) {
+            Ok(res) => res,
+            Err(err) => return Err(query_error(err)),
+        };
+        let rows = match stmt.query_map(params![account, limit], |row| {
+            let total_amount: i64 = row.get(2)?;
+            Ok(Counterparty {
+                counterparty: row.get(0)?,
+                tx_count: row.get::<usize, i64>(1)? as u64,
+                total_amount,
+                total_amount_sol: lamports_to_sol(total_amount),
+            })
+        }) {
+            Ok(res) => res,
+            Err(err) => return Err(query_error(err)),
+        };
+        match rows.collect() {
+            Ok(res) => Ok(res),
+            Err(err) => Err(query_error(err)),
+        }
+    }
+
+    fn time_buckets(
+        &mut self,
+        query: &str,
+        params: &[String],
+        interval: BucketInterval,
+        fill: bool,
+    ) -> Result<Vec<TimeBucket>, DatabaseError> {
+        let buckets = format!(
+            "buckets AS (SELECT strftime('{}', timestamp) AS bucket_start, COUNT(*) AS tx_count, SUM(amount) AS total_amount FROM ({}) GROUP BY bucket_start HAVING bucket_start IS NOT NULL)",
+            interval.format(),
+            query
+        );
+        let statement = if fill {
+            format!(
+                "WITH RECURSIVE {}, series(bucket_start) AS (SELECT MIN(bucket_start) FROM buckets UNION ALL SELECT datetime(bucket_start, '{}') FROM series WHERE bucket_start < (SELECT MAX(bucket_start) FROM buckets)) SELECT series.bucket_start, COALESCE(tx_count, 0), COALESCE(total_amount, 0) FROM series LEFT JOIN buckets USING (bucket_start) WHERE series.bucket_start IS NOT NULL ORDER BY series.bucket_start",
+                buckets,
+                interval.modifier()
+            )
+        } else {
+            format!(
+                "WITH {} SELECT bucket_start, tx_count, total_amount FROM buckets ORDER BY bucket_start",
+                buckets
+            )
+        };
+        let mut stmt = match self.client.prepare_cached(&statement) {
+            Ok(res) => res,
+            Err(err) => return Err(query_error(err)),
+        };
+        let rows = match stmt.query_map(params_from_iter(params), |row| {
+            Ok(TimeBucket {
+                bucket_start: self
+                    .timestamp_format
+                    .reformat(&row.get::<usize, String>(0)?),
+                tx_count: row.get::<usize, i64>(1)? as u64,
+                total_amount: row.get(2)?,
+            })
+        }) {
+            Ok(res) => res,
+            Err(err) => return Err(query_error(err)),
+        };
+        match rows.collect() {
+            Ok(res) => Ok(res),
+            Err(err) => Err(query_error(err)),
+        }
+    }
+
+    fn records(
+        &mut self,
+        query: &str,
+        params: &[String],
+    ) -> Result<Vec<TransactionRecord>, DatabaseError> {
+        let mut stmt = match self.client.prepare_cached(query) {
+            Ok(res) => res,
+            Err(err) => return Err(query_error(err)),
+        };
+        let rows = match stmt.query_map(params_from_iter(params), |row| {
+            Database::record_from_row(row, &self.timestamp_format)
+        }) {
+            Ok(res) => res,
+            Err(err) => return Err(query_error(err)),
+        };
+        match rows.collect() {
+            Ok(res) => Ok(res),
+            Err(err) => Err(query_error(err)),
+        }
+    }
 
-            if let Ok(res) = row.get::<usize, String>(4) {
-                result.push_str("signature:");
-                result.push_str(&res);
-                result.push_str(", ");
+    fn each_record(
+        &mut self,
+        query: &str,
+        params: &[String],
+        callback: &mut dyn FnMut(TransactionRecord) -> bool,
+    ) -> Result<(), DatabaseError> {
+        let mut stmt = match self.client.prepare_cached(query) {
+            Ok(res) => res,
+            Err(err) => return Err(query_error(err)),
+        };
+        let rows = match stmt.query_map(params_from_iter(params), |row| {
+            Database::record_from_row(row, &self.timestamp_format)
+        }) {
+            Ok(res) => res,
+            Err(err) => return Err(query_error(err)),
+        };
+        for row in rows {
+            let record = match row {
+                Ok(res) => res,
+                Err(err) => return Err(query_error(err)),
+            };
+            if !callback(record) {
+                break;
             }
-            result.push('}');
-            query_response.push(result);
         }
-        query_response
+        Ok(())
     }
-}
 
-impl Default for Database {
-    fn default() -> Self {
-        Self::new()
+    fn export(&mut self, path: &Path, query: &str, params: &[String]) -> Result<(), DatabaseError> {
+        let timestamp_format = mem::take(&mut self.timestamp_format);
+        let result = export_records(path, |callback| self.each_record(query, params, callback));
+        self.timestamp_format = timestamp_format;
+        result
     }
 }
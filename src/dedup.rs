@@ -0,0 +1,102 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+/// A bounded set of recently seen keys used to skip duplicate work.
+///
+/// Entries are evicted once they are older than `max_age`, and the oldest entry is
+/// evicted whenever inserting would exceed `capacity`, so memory stays bounded for
+/// long-running processes.
+pub struct DedupCache<K> {
+    capacity: usize,
+    max_age: Duration,
+    entries: VecDeque<(K, Instant)>,
+    keys: HashSet<K>,
+}
+
+impl<K: Eq + Hash + Clone> DedupCache<K> {
+    /// Creates a new, empty `DedupCache`.
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - The maximum number of keys kept in memory (at least one).
+    /// * `max_age` - The maximum time a key is remembered.
+    pub fn new(capacity: usize, max_age: Duration) -> DedupCache<K> {
+        DedupCache {
+            capacity: capacity.max(1),
+            max_age,
+            entries: VecDeque::new(),
+            keys: HashSet::new(),
+        }
+    }
+
+    /// Records a key as seen now.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to record.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the key was not already present, `false` if it is a duplicate.
+    pub fn insert(&mut self, key: K) -> bool {
+        self.insert_at(key, Instant::now())
+    }
+
+    /// Records a key as seen at the given instant.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to record.
+    /// * `now` - The instant the key was seen.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the key was not already present, `false` if it is a duplicate.
+    pub fn insert_at(&mut self, key: K, now: Instant) -> bool {
+        self.evict_expired(now);
+        if self.keys.contains(&key) {
+            return false;
+        }
+        if self.entries.len() >= self.capacity {
+            if let Some((oldest, _)) = self.entries.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+        self.keys.insert(key.clone());
+        self.entries.push_back((key, now));
+        true
+    }
+
+    /// Returns `true` if the key is currently remembered.
+    pub fn contains(&self, key: &K) -> bool {
+        self.keys.contains(key)
+    }
+
+    /// Returns the number of remembered keys.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if no keys are remembered.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Evicts every entry older than `max_age`.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The instant used to compute the age of the entries.
+    fn evict_expired(&mut self, now: Instant) {
+        while let Some((key, seen)) = self.entries.front() {
+            if now.saturating_duration_since(*seen) <= self.max_age {
+                break;
+            }
+            self.keys.remove(key);
+            self.entries.pop_front();
+        }
+    }
+}
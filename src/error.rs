@@ -1,24 +1,388 @@
-#[derive(Debug)]
+use actix_web::{
+    error::BlockingError,
+    http::{header, StatusCode},
+    HttpResponse, ResponseError,
+};
+use serde::Serialize;
+use serde_json::json;
+use std::{any::Any, error::Error, fmt, io, thread::JoinHandle};
+
+/// The seconds clients are asked to wait before retrying a request that found the database busy.
+const BUSY_RETRY_AFTER_SECS: u64 = 1;
+
+#[derive(Debug, PartialEq)]
 pub enum RuntimeError {
-    AggregatorError,
-    WebServerError,
+    AggregatorError(AggregatorError),
+    WebServerError(SourceError),
+    ThreadPanicked(&'static str, SourceError),
+    LockError(SourceError),
 }
 
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug, PartialEq)]
 pub enum AggregatorError {
-    BlockFetchError,
-    EnvFetchError,
-    PubsubClientError,
-    SlotSubscribeError,
+    BlockFetchError(SourceError),
+    EnvFetchError(SourceError),
+    ConfigFileError(SourceError),
+    PubsubClientError(SourceError),
+    SlotSubscribeError(SourceError),
+    BlockSubscribeError(SourceError),
     MetaDataFetchError,
+    AccountFetchError,
+    BalanceFetchError,
+    SignatureFetchError,
+    TransactionDecodeError,
     TimeFetchError,
-    TransactionParseError,
-    DatabaseError,
+    SlotSkipped,
+    TransactionParseError(Box<AggregatorError>),
+    DatabaseError(DatabaseError),
+    DeadlineExceeded,
+    ReconnectError,
+    TransactionFetchError(SourceError),
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum DatabaseError {
     ConnectError,
+    InitTableError,
+    MigrationError,
     InsertionError,
+    ExportError,
+    QueryError,
+    PruneError,
+    BusyError,
+}
+
+/// The kind of an `ApiError`, which clients can match on instead of the message.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// A query parameter or a path segment is invalid. Returned as a 400.
+    InvalidParameter,
+    /// The request isn't authorized. Returned as a 403.
+    Forbidden,
+    /// The requested resource doesn't exist. Returned as a 404.
+    NotFound,
+    /// The client exceeded the rate limit. Returned as a 429 with a `Retry-After` header.
+    RateLimited,
+    /// The database can't be reached. Returned as a 503, since retrying may succeed.
+    DatabaseUnavailable,
+    /// The database stayed locked by another connection for longer than its busy timeout.
+    /// Returned as a 503 with a `Retry-After` header.
+    DatabaseBusy,
+    /// A database operation failed. Returned as a 500.
+    DatabaseError,
+    /// Any other failure. Returned as a 500.
+    InternalError,
+}
+
+/// An error returned by a REST endpoint, as a JSON body `{"code": ..., "message": ...}`
+/// with the status code of its `code`.
+#[derive(Debug, PartialEq)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ApiError {
+    /// Creates an `ApiError` for an invalid query parameter or path segment.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The description of the invalid value and of the expected ones.
+    pub fn invalid(message: impl fmt::Display) -> ApiError {
+        ApiError {
+            code: ErrorCode::InvalidParameter,
+            message: message.to_string(),
+        }
+    }
+
+    /// Creates an `ApiError` for a missing resource.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The description of the missing resource.
+    pub fn not_found(message: impl fmt::Display) -> ApiError {
+        ApiError {
+            code: ErrorCode::NotFound,
+            message: message.to_string(),
+        }
+    }
+
+    /// Creates an `ApiError` for an unauthorized request.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The description of the missing authorization.
+    pub fn forbidden(message: impl fmt::Display) -> ApiError {
+        ApiError {
+            code: ErrorCode::Forbidden,
+            message: message.to_string(),
+        }
+    }
+
+    /// Creates an `ApiError` for a client exceeding the rate limit.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The description of the exceeded limit.
+    pub fn rate_limited(message: impl fmt::Display) -> ApiError {
+        ApiError {
+            code: ErrorCode::RateLimited,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// The message of an external error that caused an `AggregatorError` or a `RuntimeError`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceError(String);
+
+impl SourceError {
+    /// Creates a new `SourceError` from the message of an external error.
+    ///
+    /// # Arguments
+    ///
+    /// * `err` - The external error.
+    pub fn new(err: impl fmt::Display) -> SourceError {
+        SourceError(err.to_string())
+    }
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for SourceError {}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::AggregatorError(_) => f.write_str("the aggregator failed"),
+            RuntimeError::WebServerError(_) => f.write_str("the web server failed"),
+            RuntimeError::ThreadPanicked(thread, _) => write!(f, "the {} thread panicked", thread),
+            RuntimeError::LockError(_) => {
+                f.write_str("failed to lock the database, is another instance running?")
+            }
+        }
+    }
+}
+
+impl fmt::Display for AggregatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            AggregatorError::BlockFetchError(_) => "failed to fetch the block",
+            AggregatorError::EnvFetchError(_) => "failed to read the environment variables",
+            AggregatorError::ConfigFileError(_) => "failed to read the configuration file",
+            AggregatorError::PubsubClientError(_) => "failed to connect to the websocket endpoint",
+            AggregatorError::SlotSubscribeError(_) => "failed to subscribe to slots",
+            AggregatorError::BlockSubscribeError(_) => "failed to subscribe to blocks",
+            AggregatorError::MetaDataFetchError => "the transaction has no metadata",
+            AggregatorError::AccountFetchError => "failed to read the transaction accounts",
+            AggregatorError::BalanceFetchError => "failed to read the transaction balances",
+            AggregatorError::SignatureFetchError => "the transaction has no signatures",
+            AggregatorError::TransactionDecodeError => "failed to decode the transaction",
+            AggregatorError::TimeFetchError => "the block has no block time",
+            AggregatorError::SlotSkipped => "the slot was skipped and has no block",
+            AggregatorError::TransactionParseError(_) => "failed to parse a transaction",
+            AggregatorError::DatabaseError(_) => "a database operation failed",
+            AggregatorError::DeadlineExceeded => "the slot was not processed before the deadline",
+            AggregatorError::ReconnectError => "failed to reconnect the slot subscription",
+            AggregatorError::TransactionFetchError(_) => "failed to fetch the transaction",
+        };
+        f.write_str(message)
+    }
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            DatabaseError::ConnectError => "failed to connect to the database",
+            DatabaseError::InitTableError => "failed to create the database tables",
+            DatabaseError::MigrationError => "failed to migrate the database schema",
+            DatabaseError::InsertionError => "failed to insert into the database",
+            DatabaseError::ExportError => "failed to export the database",
+            DatabaseError::QueryError => "failed to query the database",
+            DatabaseError::PruneError => "failed to prune the database",
+            DatabaseError::BusyError => "the database is busy",
+        };
+        f.write_str(message)
+    }
+}
+
+impl Error for RuntimeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RuntimeError::AggregatorError(err) => Some(err),
+            RuntimeError::WebServerError(err)
+            | RuntimeError::ThreadPanicked(_, err)
+            | RuntimeError::LockError(err) => Some(err),
+        }
+    }
+}
+
+impl Error for AggregatorError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AggregatorError::BlockFetchError(err)
+            | AggregatorError::EnvFetchError(err)
+            | AggregatorError::ConfigFileError(err)
+            | AggregatorError::PubsubClientError(err)
+            | AggregatorError::SlotSubscribeError(err)
+            | AggregatorError::BlockSubscribeError(err)
+            | AggregatorError::TransactionFetchError(err) => Some(err),
+            AggregatorError::TransactionParseError(err) => Some(err.as_ref()),
+            AggregatorError::DatabaseError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Error for DatabaseError {}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for ApiError {}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self.code {
+            ErrorCode::InvalidParameter => StatusCode::BAD_REQUEST,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::DatabaseUnavailable | ErrorCode::DatabaseBusy => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ErrorCode::DatabaseError | ErrorCode::InternalError => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if self.code == ErrorCode::DatabaseBusy {
+            response.insert_header((header::RETRY_AFTER, BUSY_RETRY_AFTER_SECS));
+        }
+        response.json(json!({ "code": self.code, "message": self.message }))
+    }
+}
+
+impl From<AggregatorError> for RuntimeError {
+    fn from(err: AggregatorError) -> RuntimeError {
+        RuntimeError::AggregatorError(err)
+    }
+}
+
+impl From<io::Error> for RuntimeError {
+    fn from(err: io::Error) -> RuntimeError {
+        RuntimeError::WebServerError(SourceError::new(err))
+    }
+}
+
+impl From<DatabaseError> for ApiError {
+    fn from(err: DatabaseError) -> ApiError {
+        let code = match err {
+            DatabaseError::ConnectError => ErrorCode::DatabaseUnavailable,
+            DatabaseError::BusyError => ErrorCode::DatabaseBusy,
+            _ => ErrorCode::DatabaseError,
+        };
+        ApiError {
+            code,
+            message: err.to_string(),
+        }
+    }
+}
+
+impl From<BlockingError> for ApiError {
+    fn from(err: BlockingError) -> ApiError {
+        ApiError {
+            code: ErrorCode::InternalError,
+            message: err.to_string(),
+        }
+    }
+}
+
+impl From<AggregatorError> for ApiError {
+    fn from(err: AggregatorError) -> ApiError {
+        match err {
+            AggregatorError::DatabaseError(err) => ApiError::from(err),
+            err => ApiError {
+                code: ErrorCode::InternalError,
+                message: report(&err),
+            },
+        }
+    }
+}
+
+impl From<DatabaseError> for AggregatorError {
+    fn from(err: DatabaseError) -> AggregatorError {
+        AggregatorError::DatabaseError(err)
+    }
+}
+
+/// Waits for a thread to finish and converts its outcome into a `RuntimeError`.
+///
+/// # Arguments
+///
+/// * `thread` - The name of the thread, used in the panic message.
+/// * `handle` - The handle of the thread.
+///
+/// # Errors
+///
+/// Returns the error of the thread converted into a `RuntimeError`, or
+/// `RuntimeError::ThreadPanicked` with the panic message if the thread panicked.
+pub fn join<E>(thread: &'static str, handle: JoinHandle<Result<(), E>>) -> Result<(), RuntimeError>
+where
+    RuntimeError: From<E>,
+{
+    match handle.join() {
+        Ok(result) => Ok(result?),
+        Err(payload) => Err(RuntimeError::ThreadPanicked(
+            thread,
+            SourceError::new(panic_message(payload.as_ref())),
+        )),
+    }
+}
+
+/// Returns the message of a panic payload.
+///
+/// # Arguments
+///
+/// * `payload` - The payload the thread panicked with.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Formats an error together with the chain of its sources.
+///
+/// # Arguments
+///
+/// * `err` - The error to format.
+///
+/// # Returns
+///
+/// The messages of the error and its sources, separated by `: `.
+pub fn report(err: &dyn Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        message.push_str(": ");
+        message.push_str(&err.to_string());
+        source = err.source();
+    }
+    message
 }
@@ -0,0 +1,37 @@
+use std::sync::OnceLock;
+use tokio::sync::broadcast;
+
+/// The number of transactions kept for each subscriber. A subscriber that falls further behind
+/// misses the oldest ones rather than slowing down the aggregator.
+const CHANNEL_CAPACITY: usize = 1_024;
+
+/// The newly inserted transactions, JSON-encoded.
+///
+/// The aggregator and the web server run on separate threads, so they share this channel
+/// rather than passing it around.
+static TRANSACTIONS: OnceLock<broadcast::Sender<String>> = OnceLock::new();
+
+/// Returns the sender of the newly inserted transactions.
+fn sender() -> &'static broadcast::Sender<String> {
+    TRANSACTIONS.get_or_init(|| broadcast::channel(CHANNEL_CAPACITY).0)
+}
+
+/// Publishes a newly inserted transaction to the subscribers.
+///
+/// Never blocks, and does nothing when there is no subscriber.
+///
+/// # Arguments
+///
+/// * `transaction` - The JSON-encoded transaction.
+pub fn publish(transaction: String) {
+    let _ = sender().send(transaction);
+}
+
+/// Subscribes to the transactions inserted from now on.
+///
+/// # Returns
+///
+/// The receiver of the JSON-encoded transactions.
+pub fn subscribe() -> broadcast::Receiver<String> {
+    sender().subscribe()
+}
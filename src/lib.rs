@@ -1,5 +1,20 @@
 pub mod aggregator;
+pub mod cli;
+pub mod config;
 pub mod database;
+pub mod dedup;
 pub mod error;
+pub mod events;
+pub mod lock;
+pub mod metrics;
+pub mod openapi;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod query_builder;
+pub mod rate_limit;
 pub mod restful_api;
+pub mod retention;
+pub mod shutdown;
+pub mod telemetry;
 pub mod tests;
+pub mod units;
@@ -0,0 +1,72 @@
+use crate::{
+    database,
+    error::{RuntimeError, SourceError},
+};
+use std::{
+    ffi::OsString,
+    fs::{File, OpenOptions, TryLockError},
+    path::{Path, PathBuf},
+};
+
+/// An exclusive lock on a database, held by the running instance of the aggregator.
+///
+/// The lock is taken on a `.lock` file next to the database and released when dropped, or by
+/// the operating system if the process dies.
+#[derive(Debug)]
+pub struct InstanceLock {
+    file: File,
+}
+
+impl InstanceLock {
+    /// Locks a database for this instance.
+    ///
+    /// The directory of the database is created if it doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `db_path` - The path of the database file.
+    ///
+    /// # Errors
+    ///
+    /// Returns `RuntimeError::LockError` if another instance holds the lock, or if the lock file
+    /// can't be created.
+    pub fn acquire(db_path: &Path) -> Result<InstanceLock, RuntimeError> {
+        let path = lock_path(db_path);
+        if let Err(err) = database::create_parent_dir(&path) {
+            return Err(RuntimeError::LockError(SourceError::new(err)));
+        }
+        let file = match OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+        {
+            Ok(res) => res,
+            Err(err) => return Err(RuntimeError::LockError(SourceError::new(err))),
+        };
+        match file.try_lock() {
+            Ok(_) => Ok(InstanceLock { file }),
+            Err(TryLockError::WouldBlock) => Err(RuntimeError::LockError(SourceError::new(
+                format!("another instance holds {}", path.display()),
+            ))),
+            Err(TryLockError::Error(err)) => Err(RuntimeError::LockError(SourceError::new(err))),
+        }
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+/// Returns the path of the lock file of a database.
+///
+/// # Arguments
+///
+/// * `db_path` - The path of the database file.
+pub fn lock_path(db_path: &Path) -> PathBuf {
+    let mut path = OsString::from(db_path.as_os_str());
+    path.push(".lock");
+    PathBuf::from(path)
+}
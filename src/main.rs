@@ -1,43 +1,115 @@
-use aggregator::aggregate_data;
-use error::{AggregatorError, RuntimeError};
-use std::thread;
-mod aggregator;
-mod database;
-mod error;
-mod restful_api;
-mod tests;
+use solana_aggregator::{
+    aggregator::{self, aggregate_data},
+    cli::{self, Mode},
+    config::{Config, DbBackend},
+    database,
+    error::{self, report, AggregatorError, RuntimeError},
+    lock::InstanceLock,
+    restful_api,
+    shutdown::{self, Shutdown},
+    telemetry,
+};
+use std::{env, thread};
+use tokio::sync::watch;
+use tracing::error;
 
 /// The main entry point for the application.
 ///
-/// This function starts two threads: one for running the web server and another
-/// for running the data aggregation process. It waits for both threads to complete
-/// and handles any errors that occur.
+/// By default, or with the `run` subcommand, this function starts two threads: one for running
+/// the web server and another for running the data aggregation process. It waits for both threads
+/// to complete and returns the first error or panic. With `backfill --from <slot> --to <slot>`,
+/// it processes that range of slots instead and exits. Invalid arguments print the usage and exit.
+/// The configuration is loaded once from `config.toml` and the environment variables, and shared
+/// by both threads. The SQLite database is locked while running, so a second instance using the
+/// same database exits with an error. The database is connected to before starting the threads,
+/// retrying with a backoff while it can't be reached. The web server reads the database the
+/// aggregator writes to, SQLite or Postgres. Logs are filtered with `RUST_LOG`, and traces are
+/// exported to `OTEL_ENDPOINT` when it is set.
+/// On Ctrl-C, both threads are asked to shut down gracefully. With `EXIT_ON_AGGREGATOR_ERROR`,
+/// either thread failing asks the other one to shut down too, so the process exits with the
+/// error instead of serving a database that is no longer updated.
 ///
 /// # Returns
 ///
 /// A `Result` indicating the success or failure of the operation. Returns `Ok(())` if
 /// both threads complete successfully, or a `RuntimeError` if an error occurs in either thread.
 fn main() -> Result<(), RuntimeError> {
-    let t1 = thread::spawn(restful_api::web_server);
-    let t2 = thread::spawn(run);
-    if t1.join().unwrap().is_err() {
-        return Err(RuntimeError::WebServerError);
-    } else if let Err(_err) = t2.join().unwrap() {
-        return Err(RuntimeError::AggregatorError);
+    let mode = cli::parse(env::args_os()).unwrap_or_else(|err| err.exit());
+    let config = Config::load()?;
+    let _telemetry = telemetry::init(config.otel_endpoint.as_deref());
+    if let Mode::Backfill { from, to } = mode {
+        backfill(config, from, to);
+        return Ok(());
+    }
+    let _lock = match config.db_backend {
+        DbBackend::Sqlite => Some(InstanceLock::acquire(&config.db_path)?),
+        DbBackend::Postgres => None,
     };
-    Ok(())
+    if let Err(err) = database::connect_with_retries(&config) {
+        return Err(AggregatorError::from(err).into());
+    }
+    let (sender, shutdown) = shutdown::channel();
+    let server_shutdown = shutdown.clone();
+    let server_sender = sender.clone();
+    let server_config = config.clone();
+    let t1 = thread::spawn(move || {
+        let exit_on_error = server_config.exit_on_aggregator_error;
+        let result = restful_api::web_server(server_config, server_shutdown);
+        if exit_on_error && shutdown::request_on_error(&result, &server_sender) {
+            error!("the web server failed, stopping the aggregator");
+        }
+        result
+    });
+    let t2 = thread::spawn(move || run(config, sender, shutdown));
+    error::join("web server", t1)?;
+    error::join("aggregator", t2)
 }
 
 /// Runs the data aggregation process asynchronously.
 ///
 /// This function initializes the data aggregation process by calling `aggregate_data()`.
-/// It is designed to be run within a Tokio runtime.
+/// It is designed to be run within a Tokio runtime. Errors are logged with their sources.
+///
+/// # Arguments
+///
+/// * `config` - The configuration.
+/// * `sender` - Requests the shutdown of the aggregator and the web server on Ctrl-C, or when the
+///   aggregator fails with `EXIT_ON_AGGREGATOR_ERROR`.
+/// * `shutdown` - Receives the request to stop aggregating.
 ///
 /// # Returns
 ///
 /// A `Result` indicating the success or failure of the data aggregation process. Returns `Ok(())`
 /// if the aggregation completes successfully, or an `AggregatorError` if an error occurs.
 #[tokio::main]
-async fn run() -> Result<(), AggregatorError> {
-    aggregate_data().await
+async fn run(
+    config: Config,
+    sender: watch::Sender<bool>,
+    shutdown: Shutdown,
+) -> Result<(), AggregatorError> {
+    tokio::spawn(shutdown::on_ctrl_c(sender.clone()));
+    let exit_on_error = config.exit_on_aggregator_error;
+    let result = aggregate_data(config, shutdown).await;
+    if let Err(err) = &result {
+        error!(error = %report(err), "the aggregator stopped");
+    }
+    if exit_on_error && shutdown::request_on_error(&result, &sender) {
+        error!("stopping the web server");
+    }
+    result
+}
+
+/// Processes a range of past slots and logs the slots that failed.
+///
+/// # Arguments
+///
+/// * `config` - The configuration.
+/// * `from` - The first slot to process.
+/// * `to` - The last slot to process.
+#[tokio::main]
+async fn backfill(config: Config, from: u64, to: u64) {
+    let failed = aggregator::backfill(config, from, to).await;
+    if !failed.is_empty() {
+        error!(?failed, "failed to backfill some slots");
+    }
 }
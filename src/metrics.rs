@@ -0,0 +1,166 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The metrics of the aggregator, exposed by the web server's `/metrics` endpoint.
+///
+/// The aggregator and the web server run on separate threads, so they share this registry
+/// rather than passing it around.
+pub static METRICS: Metrics = Metrics::new();
+
+/// Counts the blocks, transactions and failures handled by the aggregator.
+pub struct Metrics {
+    pub blocks_processed: AtomicU64,
+    pub transactions_inserted: AtomicU64,
+    pub insert_errors: AtomicU64,
+    pub parse_errors: AtomicU64,
+    pub rpc_retries: AtomicU64,
+    pub last_processed_slot: AtomicU64,
+    pub reconnects: AtomicU64,
+    pub blocks_fetched: AtomicU64,
+    pub blocks_failed: AtomicU64,
+}
+
+/// A snapshot of the metrics, served as JSON by the web server's `/debug/stats` endpoint.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct AggregatorStats {
+    pub reconnects: u64,
+    pub rpc_retries: u64,
+    pub blocks_fetched: u64,
+    pub blocks_failed: u64,
+    pub blocks_processed: u64,
+    pub transactions_inserted: u64,
+    pub insert_errors: u64,
+    pub parse_errors: u64,
+    pub last_processed_slot: u64,
+}
+
+impl Metrics {
+    /// Creates a registry with every metric at zero.
+    pub const fn new() -> Metrics {
+        Metrics {
+            blocks_processed: AtomicU64::new(0),
+            transactions_inserted: AtomicU64::new(0),
+            insert_errors: AtomicU64::new(0),
+            parse_errors: AtomicU64::new(0),
+            rpc_retries: AtomicU64::new(0),
+            last_processed_slot: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            blocks_fetched: AtomicU64::new(0),
+            blocks_failed: AtomicU64::new(0),
+        }
+    }
+
+    /// Adds to a counter.
+    ///
+    /// # Arguments
+    ///
+    /// * `counter` - The counter, one of the fields of the registry.
+    /// * `value` - The value to add.
+    pub fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Records a slot as processed. Blocks may finish out of order, so the gauge only moves forward.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - The slot that has been processed.
+    pub fn set_last_processed_slot(&self, slot: u64) {
+        self.last_processed_slot.fetch_max(slot, Ordering::Relaxed);
+    }
+
+    /// Reads the current value of every metric.
+    pub fn snapshot(&self) -> AggregatorStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        AggregatorStats {
+            reconnects: load(&self.reconnects),
+            rpc_retries: load(&self.rpc_retries),
+            blocks_fetched: load(&self.blocks_fetched),
+            blocks_failed: load(&self.blocks_failed),
+            blocks_processed: load(&self.blocks_processed),
+            transactions_inserted: load(&self.transactions_inserted),
+            insert_errors: load(&self.insert_errors),
+            parse_errors: load(&self.parse_errors),
+            last_processed_slot: load(&self.last_processed_slot),
+        }
+    }
+
+    /// Formats the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let metrics = [
+            (
+                "solana_aggregator_blocks_processed_total",
+                "counter",
+                "The number of blocks processed.",
+                &self.blocks_processed,
+            ),
+            (
+                "solana_aggregator_transactions_inserted_total",
+                "counter",
+                "The number of transactions inserted into the database.",
+                &self.transactions_inserted,
+            ),
+            (
+                "solana_aggregator_insert_errors_total",
+                "counter",
+                "The number of failed transaction insertions.",
+                &self.insert_errors,
+            ),
+            (
+                "solana_aggregator_parse_errors_total",
+                "counter",
+                "The number of transactions skipped because they couldn't be parsed.",
+                &self.parse_errors,
+            ),
+            (
+                "solana_aggregator_rpc_retries_total",
+                "counter",
+                "The number of retried block fetches.",
+                &self.rpc_retries,
+            ),
+            (
+                "solana_aggregator_last_processed_slot",
+                "gauge",
+                "The highest slot fully written to the database.",
+                &self.last_processed_slot,
+            ),
+            (
+                "solana_aggregator_reconnects_total",
+                "counter",
+                "The number of slot subscription reconnections.",
+                &self.reconnects,
+            ),
+            (
+                "solana_aggregator_blocks_fetched_total",
+                "counter",
+                "The number of blocks fetched with getBlock.",
+                &self.blocks_fetched,
+            ),
+            (
+                "solana_aggregator_blocks_failed_total",
+                "counter",
+                "The number of slots whose block couldn't be processed.",
+                &self.blocks_failed,
+            ),
+        ];
+        let mut result = String::new();
+        for (name, kind, help, value) in metrics {
+            result.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} {}\n{} {}\n",
+                name,
+                help,
+                name,
+                kind,
+                name,
+                value.load(Ordering::Relaxed)
+            ));
+        }
+        result
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,419 @@
+use serde_json::{json, Value};
+
+/// The query parameters of `Info`, accepted by every endpoint filtering transactions like
+/// `/transactions`, with their type and description.
+const FILTER_PARAMS: [(&str, &str, &str); 19] = [
+    (
+        "start_date",
+        "string",
+        "The earliest timestamp, as `%Y-%m-%d %H:%M:%S` or `%Y-%m-%d` in UTC, or RFC 3339.",
+    ),
+    (
+        "end_date",
+        "string",
+        "The latest timestamp, as `%Y-%m-%d %H:%M:%S` or `%Y-%m-%d` for the whole day in UTC, or RFC 3339.",
+    ),
+    (
+        "signature",
+        "string",
+        "The first signature of the transaction.",
+    ),
+    (
+        "sender",
+        "array",
+        "The senders, repeated or comma-separated, at most 100.",
+    ),
+    (
+        "receiver",
+        "array",
+        "The receivers, repeated or comma-separated, at most 100.",
+    ),
+    (
+        "log_contains",
+        "string",
+        "Text the log messages of the transaction must contain.",
+    ),
+    ("min_amount", "integer", "The minimum amount, in lamports."),
+    ("max_amount", "integer", "The maximum amount, in lamports."),
+    (
+        "order_by",
+        "string",
+        "The column to order by: `timestamp`, `amount`, `fee`, `slot` or `signature`.",
+    ),
+    (
+        "program_id",
+        "string",
+        "The program invoked by the transaction.",
+    ),
+    ("mint", "string", "The mint of the transferred token."),
+    (
+        "min_signatures",
+        "integer",
+        "The minimum number of signatures.",
+    ),
+    ("min_fee", "integer", "The minimum fee, in lamports."),
+    ("max_fee", "integer", "The maximum fee, in lamports."),
+    (
+        "status",
+        "string",
+        "`success` or `failed`, for the failed transactions that still paid their fee.",
+    ),
+    (
+        "min_block_height",
+        "integer",
+        "The minimum height of the block, excluding the transactions whose block height is unknown.",
+    ),
+    (
+        "max_block_height",
+        "integer",
+        "The maximum height of the block, excluding the transactions whose block height is unknown.",
+    ),
+    (
+        "since",
+        "string",
+        "The signature of the last transaction of the previous page, or its timestamp and signature separated by a comma, to return the transactions after it ordered by `order_by`, `timestamp` by default, and then by signature.",
+    ),
+    ("limit", "integer", "The maximum number of transactions."),
+];
+
+/// Builds the OpenAPI description of a query parameter.
+///
+/// # Arguments
+///
+/// * `name` - The name of the parameter.
+/// * `kind` - The JSON schema type of the parameter, `array` for a list of strings.
+/// * `description` - The description of the parameter.
+fn query_param(name: &str, kind: &str, description: &str) -> Value {
+    let schema = match kind {
+        "array" => json!({ "type": "array", "items": { "type": "string" } }),
+        _ => json!({ "type": kind }),
+    };
+    json!({
+        "name": name,
+        "in": "query",
+        "required": false,
+        "description": description,
+        "schema": schema,
+    })
+}
+
+/// Builds the OpenAPI description of the `signature` path parameter.
+fn signature_param() -> Value {
+    json!({
+        "name": "signature",
+        "in": "path",
+        "required": true,
+        "description": "The first signature of the transaction.",
+        "schema": { "type": "string" },
+    })
+}
+
+/// Builds the description of one of the filter parameters of `/transactions`.
+///
+/// # Arguments
+///
+/// * `name` - The name of the parameter, one of `FILTER_PARAMS`.
+fn filter_param(name: &str) -> Value {
+    match FILTER_PARAMS.iter().find(|param| param.0 == name) {
+        Some((name, kind, description)) => query_param(name, kind, description),
+        None => query_param(name, "string", ""),
+    }
+}
+
+/// Builds the filter parameters of `/transactions`, followed by the given extra ones.
+///
+/// # Arguments
+///
+/// * `extra` - The parameters specific to the endpoint.
+fn filter_params(extra: Vec<Value>) -> Value {
+    let mut params: Vec<Value> = FILTER_PARAMS
+        .iter()
+        .map(|(name, kind, description)| query_param(name, kind, description))
+        .collect();
+    params.extend(extra);
+    Value::Array(params)
+}
+
+/// Builds the OpenAPI description of a JSON response.
+///
+/// # Arguments
+///
+/// * `description` - The description of the response.
+/// * `schema` - The schema of the response body.
+fn json_response(description: &str, schema: Value) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": schema } },
+    })
+}
+
+/// Builds the OpenAPI description of an error response, whose body holds the `ErrorCode` of the
+/// error under `code` and its message under `message`.
+///
+/// # Arguments
+///
+/// * `description` - When the error is returned.
+fn error_response(description: &str) -> Value {
+    json_response(
+        description,
+        json!({
+            "type": "object",
+            "required": ["code", "message"],
+            "properties": {
+                "code": {
+                    "type": "string",
+                    "enum": [
+                        "invalid_parameter", "forbidden", "not_found", "rate_limited",
+                        "database_unavailable", "database_busy", "database_error", "internal_error"
+                    ],
+                },
+                "message": { "type": "string" },
+            },
+        }),
+    )
+}
+
+/// Builds the schema of a list of the given schema.
+fn array_of(schema: Value) -> Value {
+    json!({ "type": "array", "items": schema })
+}
+
+/// Builds a reference to a schema of the components.
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+/// Builds the schemas of the response bodies.
+fn schemas() -> Value {
+    json!({
+        "TransactionRecord": {
+            "type": "object",
+            "required": [
+                "sender", "receiver", "amount", "amount_sol", "net_change", "transfer_direction",
+                "timestamp", "signature", "signatures", "slot", "tx_index", "fee", "transfer_type", "status"
+            ],
+            "properties": {
+                "sender": { "type": "string" },
+                "receiver": { "type": "string" },
+                "amount": { "type": "integer", "description": "The transferred lamports, never negative." },
+                "amount_sol": { "type": "string", "description": "The amount in SOL, as an exact decimal." },
+                "net_change": { "type": "integer", "description": "The signed balance change of the account at `SENDER_INDEX`, excluding the fee." },
+                "transfer_direction": { "type": "string", "enum": ["outgoing", "incoming", "none"] },
+                "timestamp": { "type": ["string", "null"], "description": "The time of the block, or `null` if it was unknown." },
+                "signature": { "type": "string" },
+                "signatures": { "type": "array", "items": { "type": "string" } },
+                "slot": { "type": "integer" },
+                "block_height": { "type": ["integer", "null"], "description": "The height of the block, or `null` if the RPC node didn't return it." },
+                "tx_index": { "type": "integer" },
+                "fee": { "type": "integer" },
+                "program_id": { "type": ["string", "null"] },
+                "transfer_type": { "type": "string", "enum": ["sol", "token"] },
+                "token_mint": { "type": ["string", "null"] },
+                "token_amount": { "type": ["string", "null"] },
+                "token_decimals": { "type": ["integer", "null"] },
+                "status": { "type": "string", "enum": ["success", "failed"] },
+            },
+        },
+        "SenderStats": {
+            "type": "object",
+            "required": ["sender", "tx_count", "total_amount", "total_amount_sol"],
+            "properties": {
+                "sender": { "type": "string" },
+                "tx_count": { "type": "integer" },
+                "total_amount": { "type": "integer" },
+                "total_amount_sol": { "type": "string" },
+            },
+        },
+        "BatchQuery": {
+            "type": "object",
+            "required": ["op"],
+            "properties": {
+                "op": { "type": "string", "enum": ["transactions", "count", "by_sender", "timeseries"] },
+                "params": {
+                    "type": "object",
+                    "description": "The query parameters of the endpoint, with arrays for the repeated ones.",
+                },
+            },
+        },
+        "BatchResult": {
+            "type": "object",
+            "description": "The body the endpoint would respond with under `result`, or the error of the query under `error`.",
+            "properties": {
+                "result": {},
+                "error": {
+                    "type": "object",
+                    "properties": {
+                        "code": { "type": "string" },
+                        "message": { "type": "string" },
+                    },
+                },
+            },
+        },
+        "TimeBucket": {
+            "type": "object",
+            "required": ["bucket_start", "tx_count", "total_amount"],
+            "properties": {
+                "bucket_start": { "type": "string" },
+                "tx_count": { "type": "integer" },
+                "total_amount": { "type": "integer" },
+            },
+        },
+    })
+}
+
+/// Builds the OpenAPI description of the REST endpoints.
+///
+/// The description is written by hand, so it must be updated along with the query parameters
+/// and the responses of the endpoints.
+///
+/// # Returns
+///
+/// The OpenAPI 3.1 document, served at `/openapi.json`.
+pub fn spec() -> Value {
+    let invalid = error_response("The query parameters are invalid.");
+    let failed = error_response("The database can't be queried.");
+    let unavailable = error_response(
+        "The database can't be reached, or stayed locked by another connection, in which case the `Retry-After` header tells when to retry.",
+    );
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "solana-aggregator",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": {
+            "/health": {
+                "get": {
+                    "summary": "Checks that the service is alive and the database can be read.",
+                    "responses": {
+                        "200": json_response("The service is alive.", json!({
+                            "type": "object",
+                            "properties": { "status": { "type": "string", "enum": ["ok"] } },
+                        })),
+                        "503": json_response("The database can't be read.", json!({
+                            "type": "object",
+                            "properties": {
+                                "status": { "type": "string", "enum": ["error"] },
+                                "error": { "type": "string" },
+                            },
+                        })),
+                    },
+                },
+            },
+            "/transactions": {
+                "get": {
+                    "summary": "Lists the transactions matching the filters, as JSON or CSV if the request accepts `text/csv`.",
+                    "parameters": filter_params(vec![]),
+                    "responses": {
+                        "200": {
+                            "description": "The matching transactions.",
+                            "content": {
+                                "application/json": { "schema": array_of(schema_ref("TransactionRecord")) },
+                                "text/csv": { "schema": { "type": "string" } },
+                            },
+                        },
+                        "400": invalid.clone(),
+                        "500": failed.clone(),
+                        "503": unavailable.clone(),
+                    },
+                },
+            },
+            "/transactions/count": {
+                "get": {
+                    "summary": "Counts the transactions matching the filters.",
+                    "parameters": filter_params(vec![]),
+                    "responses": {
+                        "200": json_response("The number of matching transactions.", json!({
+                            "type": "object",
+                            "properties": { "count": { "type": "integer" } },
+                        })),
+                        "400": invalid.clone(),
+                        "500": failed.clone(),
+                        "503": unavailable.clone(),
+                    },
+                },
+            },
+            "/transactions/export": {
+                "get": {
+                    "summary": "Exports the transactions matching the filters as newline-delimited JSON.",
+                    "parameters": filter_params(vec![]),
+                    "responses": {
+                        "200": {
+                            "description": "One transaction per line.",
+                            "content": { "application/x-ndjson": { "schema": schema_ref("TransactionRecord") } },
+                        },
+                        "400": invalid.clone(),
+                        "500": failed.clone(),
+                        "503": unavailable.clone(),
+                    },
+                },
+            },
+            "/transactions/{signature}": {
+                "get": {
+                    "summary": "Retrieves a transaction by its signature.",
+                    "parameters": [signature_param()],
+                    "responses": {
+                        "200": json_response("The transaction.", schema_ref("TransactionRecord")),
+                        "404": error_response("No transaction has this signature."),
+                        "500": failed.clone(),
+                        "503": unavailable.clone(),
+                    },
+                },
+            },
+            "/stats/by-sender": {
+                "get": {
+                    "summary": "Lists the senders with the highest total amount.",
+                    "parameters": [
+                        filter_param("start_date"),
+                        filter_param("end_date"),
+                        filter_param("min_amount"),
+                        filter_param("max_amount"),
+                        query_param("limit", "integer", "The number of senders, 10 by default and at most 1000."),
+                    ],
+                    "responses": {
+                        "200": json_response("The senders, highest total amount first.", array_of(schema_ref("SenderStats"))),
+                        "400": invalid.clone(),
+                        "500": failed.clone(),
+                        "503": unavailable.clone(),
+                    },
+                },
+            },
+            "/stats/timeseries": {
+                "get": {
+                    "summary": "Groups the transactions matching the filters into hourly or daily buckets.",
+                    "parameters": filter_params(vec![
+                        json!({
+                            "name": "interval",
+                            "in": "query",
+                            "required": false,
+                            "description": "The length of the buckets.",
+                            "schema": { "type": "string", "enum": ["hour", "day"], "default": "day" },
+                        }),
+                        query_param("fill", "boolean", "Include the empty buckets between the first and the last non-empty one."),
+                    ]),
+                    "responses": {
+                        "200": json_response("The buckets, oldest first.", array_of(schema_ref("TimeBucket"))),
+                        "400": invalid.clone(),
+                        "500": failed,
+                        "503": unavailable.clone(),
+                    },
+                },
+            },
+            "/batch": {
+                "post": {
+                    "summary": "Runs up to 20 queries of `/transactions`, `/transactions/count`, `/stats/by-sender` and `/stats/timeseries` in one request.",
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/json": { "schema": array_of(schema_ref("BatchQuery")) } },
+                    },
+                    "responses": {
+                        "200": json_response("The result of each query, in the order of the queries.", array_of(schema_ref("BatchResult"))),
+                        "400": error_response("The body isn't a valid batch or has too many queries."),
+                        "503": unavailable,
+                    },
+                },
+            },
+        },
+        "components": { "schemas": schemas() },
+    })
+}
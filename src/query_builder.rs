@@ -0,0 +1,138 @@
+/// Builds a `SELECT` query from conditions whose values are bound as parameters.
+///
+/// The conditions are joined with `AND` into a `WHERE` clause, which is left out when there is
+/// none. Values are never written into the SQL, including the limit and the offset, so they
+/// don't need quoting and the statement of a query is the same for any of them. A condition
+/// containing `OR` must wrap it in parentheses.
+pub struct QueryBuilder {
+    select: String,
+    conditions: Vec<String>,
+    params: Vec<String>,
+    order_by: Option<String>,
+    limit: Option<u32>,
+    offset: Option<u64>,
+}
+
+impl QueryBuilder {
+    /// Creates a new `QueryBuilder`.
+    ///
+    /// # Arguments
+    ///
+    /// * `select` - The `SELECT ... FROM ...` part of the query, without any condition.
+    pub fn new(select: &str) -> QueryBuilder {
+        QueryBuilder {
+            select: select.to_string(),
+            conditions: vec![],
+            params: vec![],
+            order_by: None,
+            limit: None,
+            offset: None,
+        }
+    }
+
+    /// Adds a condition.
+    ///
+    /// # Arguments
+    ///
+    /// * `condition` - The SQL of the condition, with a `?` placeholder for each value.
+    /// * `values` - The values bound to the placeholders, in order.
+    pub fn condition<T: ToString>(
+        &mut self,
+        condition: &str,
+        values: impl IntoIterator<Item = T>,
+    ) -> &mut QueryBuilder {
+        self.conditions.push(condition.to_string());
+        self.params
+            .extend(values.into_iter().map(|value| value.to_string()));
+        self
+    }
+
+    /// Adds a condition on a single value, unless the value is missing.
+    ///
+    /// # Arguments
+    ///
+    /// * `condition` - The SQL of the condition, with a single `?` placeholder.
+    /// * `value` - The value bound to the placeholder.
+    pub fn optional<T: ToString>(
+        &mut self,
+        condition: &str,
+        value: Option<T>,
+    ) -> &mut QueryBuilder {
+        if let Some(value) = value {
+            self.condition(condition, [value]);
+        }
+        self
+    }
+
+    /// Adds a condition matching any of the values of a column, unless there is no value.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - The column.
+    /// * `values` - The values, any of which matches.
+    pub fn any_of(&mut self, column: &str, values: &[String]) -> &mut QueryBuilder {
+        if !values.is_empty() {
+            let placeholders = vec!["?"; values.len()].join(", ");
+            self.condition(&format!("{} IN ({})", column, placeholders), values);
+        }
+        self
+    }
+
+    /// Orders the rows by a column.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - The column, which must not come from the user.
+    pub fn order_by(&mut self, column: &str) -> &mut QueryBuilder {
+        self.order_by = Some(column.to_string());
+        self
+    }
+
+    /// Limits the number of rows.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The maximum number of rows.
+    pub fn limit(&mut self, limit: u32) -> &mut QueryBuilder {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Skips the first rows. The offset only applies along with a `limit`, which SQLite requires
+    /// before it.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The number of rows to skip.
+    pub fn offset(&mut self, offset: u64) -> &mut QueryBuilder {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Builds the query.
+    ///
+    /// # Returns
+    ///
+    /// The SQL query string and the values bound to its `?` parameters, in order.
+    pub fn build(&self) -> (String, Vec<String>) {
+        let mut query = self.select.clone();
+        if !self.conditions.is_empty() {
+            query.push_str(" WHERE ");
+            query.push_str(&self.conditions.join(" AND "));
+        }
+        if let Some(column) = &self.order_by {
+            query.push_str(" ORDER BY ");
+            query.push_str(column);
+        }
+        let mut params = self.params.clone();
+        if let Some(limit) = self.limit {
+            query.push_str(" LIMIT ?");
+            params.push(limit.to_string());
+            if let Some(offset) = self.offset {
+                query.push_str(" OFFSET ?");
+                params.push(offset.to_string());
+            }
+        }
+        (query, params)
+    }
+}
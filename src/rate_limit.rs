@@ -0,0 +1,214 @@
+use crate::{config::Config, error::ApiError};
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{self, HeaderValue},
+    middleware::Next,
+    web, Error, ResponseError,
+};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// The paths that are never rate limited, so that monitoring keeps working under load.
+const EXEMPT_PATHS: [&str; 1] = ["/health"];
+
+/// The token bucket of a single client.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// The token buckets of the clients.
+struct Buckets {
+    clients: HashMap<String, Bucket>,
+    swept: Instant,
+}
+
+/// A per-client token bucket rate limiter.
+///
+/// Each client may send `burst` requests at once, and its bucket refills at
+/// `requests_per_second` tokens per second. Buckets that have refilled to full capacity are
+/// dropped when a new client arrives, at most once per refill period, since a new bucket
+/// starts full anyway.
+pub struct RateLimiter {
+    requests_per_second: f64,
+    burst: f64,
+    trust_forwarded_for: bool,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    /// Creates a new `RateLimiter`.
+    ///
+    /// # Arguments
+    ///
+    /// * `requests_per_second` - The rate at which a client's bucket refills.
+    /// * `burst` - The capacity of a client's bucket (at least one).
+    /// * `trust_forwarded_for` - Identify clients by the `X-Forwarded-For` header, for use behind a trusted proxy.
+    pub fn new(requests_per_second: f64, burst: u32, trust_forwarded_for: bool) -> RateLimiter {
+        RateLimiter {
+            requests_per_second,
+            burst: burst.max(1) as f64,
+            trust_forwarded_for,
+            buckets: Mutex::new(Buckets {
+                clients: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        }
+    }
+
+    /// Creates a `RateLimiter` from the `rate_limit_rps`, `rate_limit_burst` and
+    /// `trust_forwarded_for` settings.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration.
+    ///
+    /// # Returns
+    ///
+    /// The rate limiter, or `None` if `rate_limit_rps` isn't positive.
+    pub fn from_config(config: &Config) -> Option<RateLimiter> {
+        if config.rate_limit_rps <= 0.0 {
+            return None;
+        }
+        let burst = config
+            .rate_limit_burst
+            .unwrap_or(config.rate_limit_rps.ceil() as u32);
+        Some(RateLimiter::new(
+            config.rate_limit_rps,
+            burst,
+            config.trust_forwarded_for,
+        ))
+    }
+
+    /// Takes a token from a client's bucket.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The client identifier.
+    ///
+    /// # Errors
+    ///
+    /// Returns the time to wait until a token is available if the bucket is empty.
+    pub fn check(&self, client: &str) -> Result<(), Duration> {
+        self.check_at(client, Instant::now())
+    }
+
+    /// Waits until a token of a client's bucket is available and takes it.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The client identifier.
+    pub async fn acquire(&self, client: &str) {
+        while let Err(wait) = self.check(client) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Takes a token from a client's bucket at the given instant.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The client identifier.
+    /// * `now` - The instant of the request.
+    ///
+    /// # Errors
+    ///
+    /// Returns the time to wait until a token is available if the bucket is empty.
+    pub fn check_at(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if !buckets.clients.contains_key(client) {
+            self.sweep(&mut buckets, now);
+        }
+        let bucket = buckets.clients.entry(client.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.requests_per_second).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.requests_per_second,
+            ))
+        }
+    }
+
+    /// Returns the number of clients with a bucket.
+    pub fn clients(&self) -> usize {
+        let buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        buckets.clients.len()
+    }
+
+    /// Drops the buckets that have refilled to full capacity, unless the last sweep was less
+    /// than a refill period ago.
+    ///
+    /// # Arguments
+    ///
+    /// * `buckets` - The buckets of the clients.
+    /// * `now` - The instant of the request.
+    fn sweep(&self, buckets: &mut Buckets, now: Instant) {
+        let refill = self.burst / self.requests_per_second;
+        if now.saturating_duration_since(buckets.swept).as_secs_f64() < refill {
+            return;
+        }
+        buckets.clients.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * self.requests_per_second < self.burst
+        });
+        buckets.swept = now;
+    }
+
+    /// Identifies the client of a request.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The request.
+    ///
+    /// # Returns
+    ///
+    /// The client's IP address, taken from `X-Forwarded-For` when it is trusted.
+    fn client(&self, req: &ServiceRequest) -> String {
+        if self.trust_forwarded_for {
+            if let Some(ip) = req.connection_info().realip_remote_addr() {
+                return ip.to_string();
+            }
+        }
+        req.peer_addr()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// Middleware rejecting requests from clients that exceed the rate limit.
+///
+/// The limiter is taken from the application data; requests pass through when none is
+/// registered, and so do health checks. Rejected requests get a 429 with the `rate_limited` code
+/// and a `Retry-After` header.
+pub async fn rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let limiter = req
+        .app_data::<web::Data<RateLimiter>>()
+        .filter(|_| !EXEMPT_PATHS.contains(&req.path()));
+    if let Some(limiter) = limiter {
+        if let Err(wait) = limiter.check(&limiter.client(&req)) {
+            let mut response = ApiError::rate_limited("rate limit exceeded").error_response();
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(wait.as_secs_f64().ceil() as u64),
+            );
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
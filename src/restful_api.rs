@@ -1,164 +1,1561 @@
-use crate::database::Database;
-use actix_web::{get, web, App, HttpResponse, HttpServer, Responder};
-use serde::Deserialize;
+use crate::{
+    config::Config,
+    database::{
+        self, BucketInterval, Store, StorePool, TransactionRecord, TransactionSummary,
+        STATUS_FAILED, STATUS_SUCCESS,
+    },
+    error::{ApiError, DatabaseError},
+    events,
+    metrics::METRICS,
+    openapi,
+    query_builder::QueryBuilder,
+    rate_limit::{rate_limit, RateLimiter},
+    shutdown::{self, Shutdown},
+    units::lamports_to_sol,
+};
+use actix_files::NamedFile;
+use actix_web::{
+    dev::{Server, ServerHandle},
+    get,
+    http::header::{self, ContentDisposition, DispositionParam, DispositionType, HeaderValue},
+    middleware::from_fn,
+    post, web, App, HttpRequest, HttpResponse, HttpServer, Responder,
+};
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use futures_util::StreamExt;
+use rss::{ChannelBuilder, GuidBuilder, ItemBuilder};
+use rustls_pemfile::Item;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, Map, Value};
+use solana_sdk::pubkey::Pubkey;
+use std::{
+    env, fs,
+    io::{BufReader, ErrorKind},
+    net::SocketAddr,
+    path::Path,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{broadcast, mpsc};
+use url::form_urlencoded;
 
-/// Starts the web server and binds it to the specified address and port.
+const DEFAULT_CONTEXT_WINDOW: u32 = 10;
+const MAX_CONTEXT_WINDOW: u32 = 100;
+const ORDER_BY_COLUMNS: [&str; 5] = ["timestamp", "amount", "fee", "slot", "signature"];
+const STATUSES: [&str; 2] = [STATUS_SUCCESS, STATUS_FAILED];
+const DEFAULT_FEED_LIMIT: u32 = 20;
+const MAX_FEED_LIMIT: u32 = 100;
+const DEFAULT_ACTIVITY_LIMIT: u32 = 50;
+const MAX_ACTIVITY_LIMIT: u32 = 1_000;
+const DEFAULT_COUNTERPARTY_LIMIT: u32 = 50;
+const MAX_COUNTERPARTY_LIMIT: u32 = 1_000;
+const DEFAULT_STATS_LIMIT: u32 = 10;
+const MAX_STATS_LIMIT: u32 = 1_000;
+const MAX_BATCH_QUERIES: usize = 20;
+const DATE_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+const DATE_FORMAT: &str = "%Y-%m-%d";
+const LIST_PARAMS: [&str; 2] = ["sender", "receiver"];
+const MAX_LIST_VALUES: usize = 100;
+const EXPORT_BUFFER_ROWS: usize = 256;
+const CSV_HEADER: &str = "sender,receiver,amount,timestamp,signature,signatures,slot,tx_index,fee";
+
+/// Starts the web server and runs it until a shutdown is requested.
+///
+/// The server is built by `build_server`, and stops gracefully, finishing the requests in
+/// progress, once a shutdown is requested.
 ///
-/// This function initializes the HTTP server and sets up the route for handling
-/// transaction queries. It binds the server to the address `127.0.0.1` and port `8080`.
+/// # Arguments
+///
+/// * `config` - The configuration.
+/// * `shutdown` - Receives the request to stop the server.
 ///
 /// # Returns
 ///
-/// A `std::io::Result<()>` indicating the success or failure of starting the server.
+/// A `std::io::Result<()>` indicating the success or failure of starting the server, which
+/// describes the error if the TLS certificate or key can't be loaded.
 #[actix_web::main]
-pub async fn web_server() -> std::io::Result<()> {
-    HttpServer::new(|| App::new().service(transactions))
-        .bind(("127.0.0.1", 8080))?
-        .run()
-        .await
+pub async fn web_server(config: Config, shutdown: Shutdown) -> std::io::Result<()> {
+    let WebServer { server, handle, .. } = build_server(config, shutdown.clone())?;
+    actix_web::rt::spawn(async move {
+        shutdown::requested(shutdown).await;
+        handle.stop(true).await;
+    });
+    server.await
+}
+
+/// A web server bound to its addresses.
+pub struct WebServer {
+    /// Runs the server until it is stopped, once awaited or spawned.
+    pub server: Server,
+    /// Stops the server, e.g. with `handle.stop(true).await` to finish the requests in progress.
+    pub handle: ServerHandle,
+    /// The addresses the server listens on, which tell the port picked when `port` is `0`.
+    pub addrs: Vec<SocketAddr>,
+}
+
+/// Builds the web server and binds it to the specified address and port, without running it.
+///
+/// This function sets up the routes for handling transaction queries. It binds the server to
+/// the configured `bind_addr` and `port` (default `127.0.0.1:8080`) and serves the database of
+/// the configured `db_backend`, see `database::connect_pool`.
+/// The server is served over HTTPS when `tls_cert` and `tls_key` are set, and over plain HTTP
+/// otherwise.
+/// Requests are rate limited per client IP when `rate_limit_rps` is set.
+/// Queries use a pool of `db_pool_size` read-only connections shared by the handlers with
+/// SQLite, so they aren't blocked by the aggregator's writes, or one shared connection with
+/// Postgres. The timestamps of the responses are formatted with
+/// `timestamp_format` and `timestamp_offset`, while the filters take UTC timestamps.
+/// The server runs `http_workers` worker threads when it is set, and one per CPU otherwise.
+/// Failed requests get a JSON body `{"code": ..., "message": ...}`, see `ApiError`, including
+/// the ones whose query parameters can't be parsed. A database that can't be reached returns a
+/// 503 rather than a 500, and so does a database that stays locked for longer than
+/// `db_busy_timeout_ms`, along with a `Retry-After` header.
+///
+/// # Arguments
+///
+/// * `config` - The configuration.
+/// * `shutdown` - Ends the `/stream` responses once a shutdown is requested. It doesn't stop the
+///   server, which is stopped with its handle.
+///
+/// # Errors
+///
+/// Returns a `std::io::Error` if the database can't be initialized, if the TLS certificate or key can't be loaded,
+/// or if the address can't be bound.
+///
+/// # Returns
+///
+/// The bound server, with its handle and addresses.
+pub fn build_server(config: Config, shutdown: Shutdown) -> std::io::Result<WebServer> {
+    let tls = tls_config(&config)?;
+    let limiter = RateLimiter::from_config(&config).map(web::Data::new);
+    let pool = match database::connect_pool(&config) {
+        Ok(res) => web::Data::from(res),
+        Err(err) => {
+            return Err(std::io::Error::other(format!(
+                "failed to initialize the database: {}",
+                err
+            )))
+        }
+    };
+    let address = (config.bind_addr.clone(), config.port);
+    let workers = config.http_workers;
+    let config = web::Data::new(config);
+    let streams_shutdown = web::Data::new(shutdown);
+    let mut server = HttpServer::new(move || {
+        let mut app = App::new()
+            .app_data(pool.clone())
+            .app_data(config.clone())
+            .app_data(streams_shutdown.clone());
+        if let Some(limiter) = &limiter {
+            app = app.app_data(limiter.clone());
+        }
+        app.wrap(from_fn(rate_limit)).configure(routes)
+    });
+    // Every worker runs its own copy of the app, but the copies share the same pool, so the
+    // number of open connections is bounded by `db_pool_size` rather than by the workers, and
+    // more workers than connections wait for one under load.
+    if let Some(workers) = workers {
+        server = server.workers(workers.get());
+    }
+    let server = match tls {
+        Some(tls) => server.bind_rustls_021(address, tls)?,
+        None => server.bind(address)?,
+    };
+    let addrs = server.addrs();
+    let server = server.run();
+    Ok(WebServer {
+        handle: server.handle(),
+        server,
+        addrs,
+    })
+}
+
+/// Loads the TLS configuration of the web server from the `tls_cert` and `tls_key` files.
+///
+/// # Arguments
+///
+/// * `config` - The configuration.
+///
+/// # Errors
+///
+/// Returns a `std::io::Error` describing the problem if only one of the files is set, if a file
+/// can't be read, if it holds no certificate or private key, or if the key doesn't match the
+/// certificate.
+///
+/// # Returns
+///
+/// The TLS configuration, or `None` if neither file is set and the server uses plain HTTP.
+pub fn tls_config(config: &Config) -> std::io::Result<Option<rustls::ServerConfig>> {
+    let (cert_path, key_path) = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        (None, None) => return Ok(None),
+        _ => {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "TLS_CERT and TLS_KEY must be set together",
+            ))
+        }
+    };
+    let read_pem = |path: &Path| match fs::File::open(path) {
+        Ok(file) => match rustls_pemfile::read_all(&mut BufReader::new(file)) {
+            Ok(items) => Ok(items),
+            Err(err) => Err(std::io::Error::new(
+                err.kind(),
+                format!("failed to parse {}: {}", path.display(), err),
+            )),
+        },
+        Err(err) => Err(std::io::Error::new(
+            err.kind(),
+            format!("failed to read {}: {}", path.display(), err),
+        )),
+    };
+    let certs: Vec<rustls::Certificate> = read_pem(cert_path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(cert) => Some(rustls::Certificate(cert)),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("no certificate found in {}", cert_path.display()),
+        ));
+    }
+    let key = match read_pem(key_path)?.into_iter().find_map(|item| match item {
+        Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+        _ => None,
+    }) {
+        Some(res) => res,
+        None => {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("no private key found in {}", key_path.display()),
+            ))
+        }
+    };
+    match rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+    {
+        Ok(res) => Ok(Some(res)),
+        Err(err) => Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid TLS certificate or key: {}", err),
+        )),
+    }
+}
+
+/// Registers the endpoints of the web server on an app.
+///
+/// Query parameters and JSON bodies that can't be parsed are rejected with an `ApiError`. The app must provide
+/// the `StorePool`, the `Config` and the `Shutdown` the handlers use.
+///
+/// # Arguments
+///
+/// * `cfg` - The configuration of the app.
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(web::QueryConfig::default().error_handler(|err, _| ApiError::invalid(err).into()))
+        .app_data(web::JsonConfig::default().error_handler(|err, _| ApiError::invalid(err).into()))
+        .service(health)
+        .service(transactions)
+        .service(transactions_feed)
+        .service(transactions_count)
+        .service(export_ndjson)
+        .service(transaction_by_signature)
+        .service(transaction_context)
+        .service(transaction_logs)
+        .service(export_sqlite)
+        .service(balance_history)
+        .service(account_transactions)
+        .service(account_counterparties)
+        .service(sender_stats)
+        .service(timeseries)
+        .service(batch)
+        .service(metrics)
+        .service(debug_stats)
+        .service(openapi_spec)
+        .service(stream);
+}
+
+/// Runs a query on a connection of the pool.
+///
+/// The stores block the current thread until their queries complete, so the query runs on a
+/// blocking thread rather than on the worker serving the requests.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+/// * `query` - Runs the query on the connection.
+///
+/// # Errors
+///
+/// Returns an `ApiError` if no connection is available, if the query fails, or if its thread
+/// panicked.
+async fn with_store<T, E>(
+    pool: &web::Data<dyn StorePool>,
+    query: impl FnOnce(&mut dyn Store) -> Result<T, E> + Send + 'static,
+) -> Result<T, ApiError>
+where
+    T: Send + 'static,
+    E: Into<ApiError> + Send + 'static,
+{
+    let mut database = pool.store()?;
+    match web::block(move || query(database.as_mut())).await? {
+        Ok(res) => Ok(res),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Handles HTTP GET requests to check that the service is alive.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+///
+/// # Returns
+///
+/// A 200 with `{"status":"ok"}` if the database can be read, or a 503 with the error otherwise.
+#[get("/health")]
+pub async fn health(pool: web::Data<dyn StorePool>) -> impl Responder {
+    match with_store(&pool, |database| database.ping()).await {
+        Ok(_) => HttpResponse::Ok().json(json!({ "status": "ok" })),
+        Err(err) => HttpResponse::ServiceUnavailable()
+            .json(json!({ "status": "error", "error": err.message })),
+    }
+}
+
+/// Handles HTTP GET requests to scrape the aggregator's metrics.
+///
+/// # Returns
+///
+/// The metrics in the Prometheus text exposition format.
+#[get("/metrics")]
+pub async fn metrics() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(METRICS.render())
+}
+
+/// Handles HTTP GET requests to read the counters of the aggregator.
+///
+/// This is a human-readable snapshot of the `/metrics` counters, for debugging flaky RPC
+/// providers without a Prometheus server.
+///
+/// # Returns
+///
+/// A JSON object with the reconnections, RPC retries, fetched, failed and processed blocks,
+/// inserted transactions, insertion errors and the last processed slot.
+#[get("/debug/stats")]
+pub async fn debug_stats() -> impl Responder {
+    HttpResponse::Ok().json(METRICS.snapshot())
+}
+
+/// Handles HTTP GET requests to describe the REST endpoints.
+///
+/// # Returns
+///
+/// The OpenAPI document of the endpoints, with their query parameters and response shapes.
+#[get("/openapi.json")]
+pub async fn openapi_spec() -> impl Responder {
+    HttpResponse::Ok().json(openapi::spec())
+}
+
+/// Handles HTTP GET requests to follow the newly aggregated transactions as Server-Sent Events.
+///
+/// Each transaction is sent as a JSON `data` event once it is inserted. Clients that fall behind
+/// miss the oldest transactions instead of slowing down the aggregator. The stream ends when a
+/// shutdown is requested.
+///
+/// # Arguments
+///
+/// * `shutdown` - Receives the request to stop the server.
+///
+/// # Returns
+///
+/// A `text/event-stream` response.
+#[get("/stream")]
+pub async fn stream(shutdown: web::Data<Shutdown>) -> impl Responder {
+    let updates = futures_util::stream::unfold(events::subscribe(), |mut receiver| async {
+        loop {
+            match receiver.recv().await {
+                Ok(transaction) => {
+                    let event = web::Bytes::from(format!("data: {}\n\n", transaction));
+                    return Some((Ok::<_, actix_web::Error>(event), receiver));
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(updates.take_until(shutdown::requested(shutdown.get_ref().clone())))
 }
 
 /// Represents query parameters for filtering transactions.
-#[derive(Deserialize)]
-struct Info {
+#[derive(Deserialize, Default)]
+pub struct Info {
     start_date: Option<String>,
     end_date: Option<String>,
     signature: Option<String>,
-    sender: Option<String>,
-    receiver: Option<String>,
+    #[serde(default, deserialize_with = "comma_separated")]
+    sender: Vec<String>,
+    #[serde(default, deserialize_with = "comma_separated")]
+    receiver: Vec<String>,
+    log_contains: Option<String>,
+    min_amount: Option<i64>,
+    max_amount: Option<i64>,
+    order_by: Option<String>,
+    program_id: Option<String>,
+    mint: Option<String>,
+    min_signatures: Option<u32>,
+    min_fee: Option<i64>,
+    max_fee: Option<i64>,
+    status: Option<String>,
+    min_block_height: Option<u64>,
+    max_block_height: Option<u64>,
+    since: Option<String>,
+    limit: Option<u32>,
+}
+
+impl Info {
+    /// Parses the query parameters of a request.
+    ///
+    /// `sender` and `receiver` may be repeated, and each value may list several comma-separated
+    /// addresses.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query string of the request.
+    ///
+    /// # Errors
+    ///
+    /// Returns a message describing the invalid parameter.
+    pub fn parse(query: &str) -> Result<Info, String> {
+        let mut pairs: Vec<(String, String)> = vec![];
+        for (name, value) in form_urlencoded::parse(query.as_bytes()) {
+            let repeated = pairs
+                .iter_mut()
+                .find(|(key, _)| *key == name && LIST_PARAMS.contains(&key.as_str()));
+            match repeated {
+                Some((_, values)) => {
+                    values.push(',');
+                    values.push_str(&value);
+                }
+                None => pairs.push((name.into_owned(), value.into_owned())),
+            }
+        }
+        let query = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(pairs)
+            .finish();
+        match web::Query::<Info>::from_query(&query) {
+            Ok(res) => Ok(res.into_inner()),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    /// Resolves the column to order the transactions by.
+    ///
+    /// # Errors
+    ///
+    /// Returns a message describing the expected values if `order_by` is not one of `ORDER_BY_COLUMNS`.
+    ///
+    /// # Returns
+    ///
+    /// The column, or `None` if no order is requested.
+    fn order_by_column(&self) -> Result<Option<&'static str>, String> {
+        match &self.order_by {
+            Some(order_by) => match ORDER_BY_COLUMNS.iter().find(|column| *column == order_by) {
+                Some(column) => Ok(Some(column)),
+                None => Err(format!(
+                    "invalid order_by, expected one of {}",
+                    ORDER_BY_COLUMNS.join(", ")
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Resolves the status to filter the transactions by.
+    ///
+    /// # Errors
+    ///
+    /// Returns a message describing the expected values if `status` is not one of `STATUSES`.
+    ///
+    /// # Returns
+    ///
+    /// The status, or `None` if no status is requested.
+    fn status_value(&self) -> Result<Option<&'static str>, String> {
+        match &self.status {
+            Some(status) => match STATUSES.iter().find(|value| *value == status) {
+                Some(value) => Ok(Some(value)),
+                None => Err(format!(
+                    "invalid status, expected one of {}",
+                    STATUSES.join(", ")
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+/// Deserializes a list of comma-separated values, ignoring empty ones.
+///
+/// # Arguments
+///
+/// * `deserializer` - The deserializer of the query parameter.
+fn comma_separated<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let values = String::deserialize(deserializer)?;
+    Ok(values
+        .split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .collect())
 }
 
 /// Handles HTTP GET requests to retrieve filtered transactions.
 ///
 /// This function queries the database for transactions that match the specified
 /// query parameters. The supported query parameters are `start_date`, `end_date`,
-/// `signature`, `sender`, and `receiver`.
+/// `signature`, `sender`, `receiver`, `log_contains`, `min_amount`, `max_amount`, `program_id`,
+/// `mint`, `min_signatures`, `min_fee`, `max_fee`, `status`, `min_block_height`,
+/// `max_block_height`, `order_by`, `since` and `limit`. `sender` and `receiver` select the transactions matching any of their values.
+/// `status` is `success` or `failed`. `since` pages through the transactions with a cursor: it
+/// takes the signature of the last transaction of the previous page and returns the ones after
+/// it, ordered by `order_by`, `timestamp` by default, and then by signature.
 ///
 /// # Arguments
 ///
-/// * `info` - The query parameters for filtering the transactions.
+/// * `req` - The HTTP request, whose query string holds the filters and whose `Accept` header
+///   selects JSON or CSV.
+/// * `pool` - The database connection pool.
 ///
 /// # Returns
 ///
-/// A JSON response containing the filtered transactions.
+/// A JSON array of the filtered transactions, each formatted like `/transactions/{signature}`, or
+/// a CSV file if the request accepts `text/csv`, a 400 if the query parameters are invalid, or a 500 with the error if the
+/// database can't be queried. A filter matching no transaction returns a 200 with an empty
+/// array, or a CSV file with the header row only.
 #[get("/transactions")]
-async fn transactions(info: web::Query<Info>) -> impl Responder {
-    let mut database = Database::new_connection().unwrap();
-    let mut query = "SELECT * FROM transactions".to_string();
-    let mut flag = false;
+pub async fn transactions(
+    req: HttpRequest,
+    pool: web::Data<dyn StorePool>,
+) -> Result<HttpResponse, ApiError> {
+    let (query, params) = Info::parse(req.query_string())
+        .and_then(|info| filter_query(&info))
+        .map_err(ApiError::invalid)?;
+    let records = with_store(&pool, move |database| database.records(&query, &params)).await?;
+    if !accepts_csv(&req) {
+        let data: Vec<_> = records.iter().map(record_json).collect();
+        return Ok(HttpResponse::Ok().json(data));
+    }
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename("transactions.csv".to_string())],
+        })
+        .body(to_csv(&records)))
+}
+
+/// Handles HTTP GET requests to export the transactions matching the filters as
+/// newline-delimited JSON.
+///
+/// Accepts the same query parameters as `/transactions`. The rows are read one at a time on a
+/// blocking thread and streamed as they are read, holding at most `EXPORT_BUFFER_ROWS` of them
+/// in memory, so that large exports don't buffer the whole result. The database connection is
+/// returned to the pool once the export is done or the client disconnects.
+///
+/// # Arguments
+///
+/// * `req` - The HTTP request, whose query string holds the filters.
+/// * `pool` - The database connection pool.
+///
+/// # Returns
+///
+/// An `application/x-ndjson` response with one JSON transaction per line, like
+/// `/transactions/{signature}`, a 400 if the query parameters are invalid, or a 500 with the
+/// error if no database connection is available. A query failing once the response has started
+/// ends the response early.
+#[get("/transactions/export")]
+pub async fn export_ndjson(
+    req: HttpRequest,
+    pool: web::Data<dyn StorePool>,
+) -> Result<HttpResponse, ApiError> {
+    let (query, params) = Info::parse(req.query_string())
+        .and_then(|info| filter_query(&info))
+        .map_err(ApiError::invalid)?;
+    let mut database = pool.store()?;
+    let (sender, receiver) = mpsc::channel::<Result<web::Bytes, DatabaseError>>(EXPORT_BUFFER_ROWS);
+    actix_web::rt::task::spawn_blocking(move || {
+        let result = database.each_record(&query, &params, &mut |record| {
+            let mut line = record_json(&record).to_string();
+            line.push('\n');
+            // Fails once the client has disconnected.
+            sender.blocking_send(Ok(web::Bytes::from(line))).is_ok()
+        });
+        if let Err(err) = result {
+            let _ = sender.blocking_send(Err(err));
+        }
+    });
+    let lines = futures_util::stream::unfold(receiver, |mut receiver| async {
+        match receiver.recv().await? {
+            Ok(line) => Some((Ok(line), receiver)),
+            Err(err) => Some((
+                Err(actix_web::error::ErrorInternalServerError(err)),
+                receiver,
+            )),
+        }
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(lines))
+}
+
+/// Handles HTTP GET requests to count the transactions matching the filters.
+///
+/// Accepts the same query parameters as `/transactions`, so clients can tell how many
+/// transactions a filter selects without fetching them.
+///
+/// # Arguments
+///
+/// * `req` - The HTTP request, whose query string holds the filters.
+/// * `pool` - The database connection pool.
+///
+/// # Returns
+///
+/// A JSON object with the number of matching transactions under `count`, a 400 if the query
+/// parameters are invalid, or a 500 with the error if the database can't be queried.
+#[get("/transactions/count")]
+pub async fn transactions_count(
+    req: HttpRequest,
+    pool: web::Data<dyn StorePool>,
+) -> Result<HttpResponse, ApiError> {
+    let (query, params) = Info::parse(req.query_string())
+        .and_then(|info| filter_query(&info))
+        .map_err(ApiError::invalid)?;
+    let count = with_store(&pool, move |database| database.count(&query, &params)).await?;
+    Ok(HttpResponse::Ok().json(json!({ "count": count })))
+}
+
+/// Checks whether a request asks for CSV rather than JSON.
+///
+/// # Arguments
+///
+/// * `req` - The HTTP request.
+///
+/// # Returns
+///
+/// `true` if the `Accept` header lists `text/csv` before `application/json`.
+fn accepts_csv(req: &HttpRequest) -> bool {
+    let accept = match req.headers().get(header::ACCEPT) {
+        Some(res) => res.to_str().unwrap_or_default(),
+        None => return false,
+    };
+    let csv = accept.find("text/csv");
+    let json = accept.find("application/json");
+    match (csv, json) {
+        (Some(csv), Some(json)) => csv < json,
+        (csv, _) => csv.is_some(),
+    }
+}
+
+/// Formats transactions as CSV, with a header row.
+///
+/// The signatures of a transaction are separated by spaces.
+///
+/// # Arguments
+///
+/// * `records` - The transactions to format.
+pub fn to_csv(records: &[TransactionRecord]) -> String {
+    let mut csv = CSV_HEADER.to_string();
+    csv.push_str("\r\n");
+    for record in records {
+        let fields = [
+            csv_field(&record.sender),
+            csv_field(&record.receiver),
+            record.amount.to_string(),
+            csv_field(record.timestamp.as_deref().unwrap_or("")),
+            csv_field(&record.signature),
+            csv_field(&record.signatures.join(" ")),
+            record.slot.to_string(),
+            record.tx_index.to_string(),
+            record.fee.to_string(),
+        ];
+        csv.push_str(&fields.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+/// Escapes a CSV field.
+///
+/// Fields containing a comma, a quote or a line break are quoted, with their quotes doubled.
+///
+/// # Arguments
+///
+/// * `field` - The field to escape.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Builds the query selecting the transactions that match the query parameters.
+///
+/// # Arguments
+///
+/// * `info` - The query parameters for filtering the transactions.
+///
+/// # Returns
+///
+/// The SQL query string and the values bound to its `?` parameters, or a message describing the
+/// expected values if a date, the order, the status or the cursor is invalid, or if too many
+/// values are listed.
+pub fn filter_query(info: &Info) -> Result<(String, Vec<String>), String> {
+    let order_by = info.order_by_column()?;
+    let status = info.status_value()?;
+    for (name, values) in [("sender", &info.sender), ("receiver", &info.receiver)] {
+        if values.len() > MAX_LIST_VALUES {
+            return Err(format!(
+                "too many {} values, expected at most {}",
+                name, MAX_LIST_VALUES
+            ));
+        }
+    }
+    let mut query = QueryBuilder::new("SELECT transactions.*, transaction_signatures.signatures FROM transactions LEFT JOIN transaction_signatures USING (signature)");
     if let Some(start_date) = &info.start_date {
-        start_date_query(&mut flag, &mut query, start_date)
+        match parse_date(start_date, false) {
+            Some(start_date) => query.condition("timestamp >= ?", [start_date]),
+            None => return Err(invalid_date("start_date")),
+        };
     }
     if let Some(end_date) = &info.end_date {
-        end_date_query(&mut flag, &mut query, end_date)
+        match parse_date(end_date, true) {
+            Some(end_date) => query.condition("timestamp <= ?", [end_date]),
+            None => return Err(invalid_date("end_date")),
+        };
     }
-    if let Some(signature) = &info.signature {
-        signature_query(&mut flag, &mut query, signature)
+    query
+        .optional("signature = ?", info.signature.as_ref())
+        .any_of("sender", &info.sender)
+        .any_of("receiver", &info.receiver)
+        .optional(
+            "signature IN (SELECT signature FROM transaction_logs, json_each(logs) WHERE instr(json_each.value, ?) > 0)",
+            info.log_contains.as_ref(),
+        )
+        .optional("amount >= ?", info.min_amount)
+        .optional("amount <= ?", info.max_amount)
+        .optional("program_id = ?", info.program_id.as_ref())
+        .optional("token_mint = ?", info.mint.as_ref())
+        .optional("signature_count >= ?", info.min_signatures)
+        .optional("fee >= ?", info.min_fee)
+        .optional("fee <= ?", info.max_fee)
+        .optional("block_height >= ?", info.min_block_height)
+        .optional("block_height <= ?", info.max_block_height);
+    // Transactions stored before their status was recorded count as successful.
+    if let Some(status) = status {
+        query.condition("COALESCE(status, ?) = ?", [STATUS_SUCCESS, status]);
     }
-    if let Some(sender) = &info.sender {
-        sender_query(&mut flag, &mut query, sender)
+    // The signature breaks the ties, so that pages of the same order never overlap.
+    let order_by = match (&info.since, order_by) {
+        (Some(_), None) => Some("timestamp"),
+        (_, order_by) => order_by,
+    };
+    if let Some(column) = order_by {
+        if let Some(since) = &info.since {
+            cursor_condition(&mut query, column, since)?;
+        }
+        query.order_by(&format!("{}, signature", column));
     }
-    if let Some(recevier) = &info.receiver {
-        receiver_query(&mut flag, &mut query, recevier)
+    if let Some(limit) = info.limit {
+        query.limit(limit);
     }
-    let data = database.query(&query);
-    HttpResponse::Ok().json(data)
+    Ok(query.build())
 }
 
-/// Adds a sender filter to the query string.
+/// Selects the transactions strictly after a cursor, in the order of a column and then of the
+/// signature.
+///
+/// Unlike an offset, the cursor doesn't shift when transactions are inserted between the pages,
+/// so polling clients neither skip nor repeat any.
 ///
 /// # Arguments
 ///
-/// * `flag` - A mutable reference to a boolean flag indicating whether this is the first filter.
-/// * `query` - A mutable reference to the query string.
-/// * `sender` - The sender to filter by.
-fn sender_query(flag: &mut bool, query: &mut String, sender: &str) {
-    if !(*flag) {
-        query.push_str(" WHERE");
-        *flag = true;
-    } else {
-        query.push_str(" AND");
-        *flag = true;
+/// * `query` - The query to add the condition to.
+/// * `column` - The column the transactions are ordered by, one of `ORDER_BY_COLUMNS`.
+/// * `since` - The signature of the last transaction of the previous page, or its timestamp and
+///   signature separated by a comma when ordering by `timestamp`.
+///
+/// # Errors
+///
+/// Returns a message describing the expected values if the timestamp of the cursor is invalid,
+/// or given while ordering by another column.
+fn cursor_condition(query: &mut QueryBuilder, column: &str, since: &str) -> Result<(), String> {
+    match since.split_once(',') {
+        Some((timestamp, signature)) => {
+            if column != "timestamp" {
+                return Err(
+                    "invalid since, a timestamp is only accepted when ordering by timestamp"
+                        .to_string(),
+                );
+            }
+            let timestamp = match parse_date(timestamp, false) {
+                Some(res) => res,
+                None => return Err(invalid_date("since")),
+            };
+            query.condition(
+                "(timestamp, signature) > (?, ?)",
+                [timestamp, signature.to_string()],
+            );
+        }
+        None => {
+            query.condition(
+                &format!(
+                    "({0}, signature) > (SELECT {0}, signature FROM transactions WHERE signature = ?)",
+                    column
+                ),
+                [since],
+            );
+        }
     }
-    query.push_str(" sender=\"");
-    query.push_str(sender);
-    query.push('"');
+    Ok(())
 }
 
-/// Adds a receiver filter to the query string.
+/// Parses a date query parameter into the format timestamps are stored in.
+///
+/// Accepts `DATE_TIME_FORMAT` or `DATE_FORMAT` in UTC, or an RFC 3339 timestamp with any
+/// offset, like the ones of the responses with `TIMESTAMP_FORMAT=rfc3339`, optionally
+/// surrounded by quotes.
 ///
 /// # Arguments
 ///
-/// * `flag` - A mutable reference to a boolean flag indicating whether this is the first filter.
-/// * `query` - A mutable reference to the query string.
-/// * `receiver` - The receiver to filter by.
-fn receiver_query(flag: &mut bool, query: &mut String, receiver: &str) {
-    if !(*flag) {
-        query.push_str(" WHERE");
-        *flag = true;
+/// * `date` - The query parameter.
+/// * `end_of_day` - Whether a date without a time means the end of the day rather than its start.
+///
+/// # Returns
+///
+/// The normalized date and time, or `None` if the parameter is not a valid date.
+fn parse_date(date: &str, end_of_day: bool) -> Option<String> {
+    let date = date.trim_matches('"');
+    let datetime = if let Ok(res) = NaiveDateTime::parse_from_str(date, DATE_TIME_FORMAT) {
+        res
+    } else if let Ok(res) = DateTime::parse_from_rfc3339(date) {
+        res.naive_utc()
     } else {
-        query.push_str(" AND");
-        *flag = true;
+        let date = NaiveDate::parse_from_str(date, DATE_FORMAT).ok()?;
+        if end_of_day {
+            date.and_hms_opt(23, 59, 59)?
+        } else {
+            date.and_hms_opt(0, 0, 0)?
+        }
+    };
+    Some(datetime.format(DATE_TIME_FORMAT).to_string())
+}
+
+/// Describes the expected formats of an invalid date query parameter.
+fn invalid_date(name: &str) -> String {
+    format!(
+        "invalid {}, expected {} or {}, or an RFC 3339 timestamp",
+        name, DATE_TIME_FORMAT, DATE_FORMAT
+    )
+}
+
+/// Represents query parameters for the transactions feed.
+#[derive(Deserialize)]
+struct FeedInfo {
+    limit: Option<u32>,
+}
+
+/// Represents query parameters for the per-sender statistics.
+#[derive(Deserialize)]
+struct StatsInfo {
+    start_date: Option<String>,
+    end_date: Option<String>,
+    min_amount: Option<i64>,
+    max_amount: Option<i64>,
+    limit: Option<u32>,
+}
+
+/// Handles HTTP GET requests to retrieve the senders with the highest total amount.
+///
+/// The transactions are grouped by sender in SQL, after applying the `start_date`, `end_date`,
+/// `min_amount` and `max_amount` filters of `/transactions`. The response contains up to `limit`
+/// senders, which defaults to 10 and is capped at 1000.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+/// * `info` - The query parameters.
+///
+/// # Returns
+///
+/// A JSON array of the senders with their `tx_count`, `total_amount` and `total_amount_sol`,
+/// highest total amount first and empty if no transaction matches, a 400 if the query parameters
+/// are invalid, or a 500 with the error if the database can't be queried.
+#[get("/stats/by-sender")]
+pub async fn sender_stats(
+    pool: web::Data<dyn StorePool>,
+    info: web::Query<StatsInfo>,
+) -> Result<HttpResponse, ApiError> {
+    let (query, params, limit) = stats_query(info.into_inner())?;
+    let stats = with_store(&pool, move |database| {
+        database.sender_stats(&query, &params, limit)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(stats))
+}
+
+/// Builds the query of the transactions the per-sender statistics are computed from.
+///
+/// # Arguments
+///
+/// * `info` - The query parameters of `/stats/by-sender`.
+///
+/// # Errors
+///
+/// Returns an `ApiError` if the query parameters are invalid.
+///
+/// # Returns
+///
+/// The SQL query string, the values bound to its parameters and the number of senders.
+fn stats_query(info: StatsInfo) -> Result<(String, Vec<String>, u32), ApiError> {
+    let filter = Info {
+        start_date: info.start_date,
+        end_date: info.end_date,
+        min_amount: info.min_amount,
+        max_amount: info.max_amount,
+        ..Default::default()
+    };
+    let (query, params) = filter_query(&filter).map_err(ApiError::invalid)?;
+    let limit = info
+        .limit
+        .unwrap_or(DEFAULT_STATS_LIMIT)
+        .min(MAX_STATS_LIMIT);
+    Ok((query, params, limit))
+}
+
+/// Represents query parameters for the transaction time series.
+#[derive(Deserialize)]
+struct TimeseriesInfo {
+    #[serde(default)]
+    interval: BucketInterval,
+    #[serde(default)]
+    fill: bool,
+}
+
+/// Handles HTTP GET requests to retrieve the number and total amount of transactions over time.
+///
+/// The transactions matching the filters of `/transactions` are grouped in SQL into hourly or
+/// daily buckets, selected by the `interval` query parameter (`day` by default). Empty buckets
+/// are omitted, unless `fill=true` is set, in which case the empty buckets between the first and
+/// the last non-empty one are included with a zero count.
+///
+/// # Arguments
+///
+/// * `req` - The HTTP request, whose query string holds the filters.
+/// * `pool` - The database connection pool.
+/// * `info` - The query parameters.
+///
+/// # Returns
+///
+/// A JSON array of `{bucket_start, tx_count, total_amount}` objects, oldest first and empty if no
+/// transaction matches, a 400 if the query parameters are invalid, or a 500 with the error if the
+/// database can't be queried.
+#[get("/stats/timeseries")]
+pub async fn timeseries(
+    req: HttpRequest,
+    pool: web::Data<dyn StorePool>,
+    info: web::Query<TimeseriesInfo>,
+) -> Result<HttpResponse, ApiError> {
+    let (query, params) = Info::parse(req.query_string())
+        .and_then(|info| filter_query(&info))
+        .map_err(ApiError::invalid)?;
+    let TimeseriesInfo { interval, fill } = info.into_inner();
+    let buckets = with_store(&pool, move |database| {
+        database.time_buckets(&query, &params, interval, fill)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(buckets))
+}
+
+/// The endpoints a `/batch` request can query.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchOperation {
+    /// `/transactions`, as JSON.
+    Transactions,
+    /// `/transactions/count`.
+    Count,
+    /// `/stats/by-sender`.
+    BySender,
+    /// `/stats/timeseries`.
+    Timeseries,
+}
+
+/// A query of a `/batch` request.
+#[derive(Deserialize)]
+pub struct BatchQuery {
+    op: BatchOperation,
+    /// The query parameters of the endpoint, whose values are strings, numbers or booleans, or
+    /// arrays of them for the parameters that may be repeated.
+    #[serde(default)]
+    params: Map<String, Value>,
+}
+
+impl BatchQuery {
+    /// Builds the query string of the endpoint from the parameters.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `ApiError` if a parameter is an object or an array of arrays or objects.
+    fn query_string(&self) -> Result<String, ApiError> {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        for (name, value) in &self.params {
+            let values = match value {
+                Value::Array(values) => values.as_slice(),
+                value => std::slice::from_ref(value),
+            };
+            for value in values {
+                match value {
+                    Value::Null => {}
+                    Value::String(value) => {
+                        query.append_pair(name, value);
+                    }
+                    Value::Number(_) | Value::Bool(_) => {
+                        query.append_pair(name, &value.to_string());
+                    }
+                    _ => return Err(ApiError::invalid(format!("invalid {}", name))),
+                }
+            }
+        }
+        Ok(query.finish())
+    }
+
+    /// Runs the query like its endpoint would.
+    ///
+    /// # Arguments
+    ///
+    /// * `database` - The database connection shared by the queries of the batch.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `ApiError` if the parameters are invalid or the database can't be queried.
+    ///
+    /// # Returns
+    ///
+    /// The JSON body the endpoint would respond with.
+    fn run(&self, database: &mut dyn Store) -> Result<Value, ApiError> {
+        let query_string = self.query_string()?;
+        let filter = || {
+            Info::parse(&query_string)
+                .and_then(|info| filter_query(&info))
+                .map_err(ApiError::invalid)
+        };
+        match self.op {
+            BatchOperation::Transactions => {
+                let (query, params) = filter()?;
+                let records = database.records(&query, &params)?;
+                Ok(json!(records.iter().map(record_json).collect::<Vec<_>>()))
+            }
+            BatchOperation::Count => {
+                let (query, params) = filter()?;
+                Ok(json!({ "count": database.count(&query, &params)? }))
+            }
+            BatchOperation::BySender => {
+                let info = web::Query::<StatsInfo>::from_query(&query_string)
+                    .map_err(ApiError::invalid)?;
+                let (query, params, limit) = stats_query(info.into_inner())?;
+                Ok(json!(database.sender_stats(&query, &params, limit)?))
+            }
+            BatchOperation::Timeseries => {
+                let info = web::Query::<TimeseriesInfo>::from_query(&query_string)
+                    .map_err(ApiError::invalid)?;
+                let (query, params) = filter()?;
+                Ok(json!(database.time_buckets(
+                    &query,
+                    &params,
+                    info.interval,
+                    info.fill
+                )?))
+            }
+        }
     }
-    query.push_str(" receiver=\"");
-    query.push_str(receiver);
-    query.push('"');
 }
 
-/// Adds a signature filter to the query string.
+/// Handles HTTP POST requests to run several queries in one round trip.
+///
+/// The body is a JSON array of up to `MAX_BATCH_QUERIES` queries, each an object naming the
+/// endpoint under `op`, one of `transactions`, `count`, `by_sender` and `timeseries`, and its
+/// query parameters under `params`, e.g.
+/// `[{"op": "count"}, {"op": "by_sender", "params": {"limit": 5}}]`. The queries run one after
+/// the other on the same database connection, and each of them fails on its own.
 ///
 /// # Arguments
 ///
-/// * `flag` - A mutable reference to a boolean flag indicating whether this is the first filter.
-/// * `query` - A mutable reference to the query string.
-/// * `signature` - The signature to filter by.
-fn signature_query(flag: &mut bool, query: &mut String, signature: &str) {
-    if !(*flag) {
-        query.push_str(" WHERE");
-        *flag = true;
-    } else {
-        query.push_str(" AND");
-        *flag = true;
+/// * `pool` - The database connection pool.
+/// * `queries` - The queries of the batch.
+///
+/// # Returns
+///
+/// A JSON array with a result for each query, in the order of the queries: `{"result": ...}`
+/// with the body the endpoint would respond with, or `{"error": {"code": ..., "message": ...}}`
+/// if the query failed. A 400 if the body isn't a valid batch or has too many queries, or a 503
+/// if no database connection is available.
+#[post("/batch")]
+pub async fn batch(
+    pool: web::Data<dyn StorePool>,
+    queries: web::Json<Vec<BatchQuery>>,
+) -> Result<HttpResponse, ApiError> {
+    if queries.len() > MAX_BATCH_QUERIES {
+        return Err(ApiError::invalid(format!(
+            "a batch holds at most {} queries",
+            MAX_BATCH_QUERIES
+        )));
     }
-    query.push_str(" signature=\"");
-    query.push_str(signature);
-    query.push('"');
+    let queries = queries.into_inner();
+    let results = with_store(&pool, move |database| {
+        let results: Vec<Value> = queries
+            .iter()
+            .map(|query| match query.run(database) {
+                Ok(result) => json!({ "result": result }),
+                Err(err) => json!({ "error": { "code": err.code, "message": err.message } }),
+            })
+            .collect();
+        Ok::<_, ApiError>(results)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(results))
 }
 
-/// Adds a start date filter to the query string.
+/// Handles HTTP GET requests to retrieve the latest transactions as an RSS feed.
+///
+/// The feed contains up to `limit` transactions, newest first. `limit` defaults to 20
+/// and is capped at 100.
 ///
 /// # Arguments
 ///
-/// * `flag` - A mutable reference to a boolean flag indicating whether this is the first filter.
-/// * `query` - A mutable reference to the query string.
-/// * `start_date` - The start date to filter by.
-fn start_date_query(flag: &mut bool, query: &mut String, start_date: &str) {
-    if !(*flag) {
-        query.push_str(" WHERE");
-        *flag = true;
-    } else {
-        query.push_str(" AND");
-        *flag = true;
+/// * `pool` - The database connection pool.
+/// * `info` - The query parameters.
+///
+/// # Returns
+///
+/// An RSS response containing the latest transactions.
+#[get("/transactions.rss")]
+async fn transactions_feed(
+    pool: web::Data<dyn StorePool>,
+    info: web::Query<FeedInfo>,
+) -> Result<HttpResponse, ApiError> {
+    let limit = info.limit.unwrap_or(DEFAULT_FEED_LIMIT).min(MAX_FEED_LIMIT);
+    let data = with_store(&pool, move |database| database.latest(limit)).await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/rss+xml")
+        .body(rss_feed(&data)))
+}
+
+/// Builds an RSS feed from transactions.
+///
+/// Each item summarizes the sender, receiver, amount and time of a transaction and
+/// links to the transaction's query.
+///
+/// # Arguments
+///
+/// * `summaries` - The transactions to include, in feed order.
+///
+/// # Returns
+///
+/// The RSS document as a string.
+pub fn rss_feed(summaries: &[TransactionSummary]) -> String {
+    let items: Vec<_> = summaries
+        .iter()
+        .map(|transaction| {
+            let link = format!("/transactions?signature={}", transaction.signature);
+            ItemBuilder::default()
+                .title(Some(format!(
+                    "{} → {}: {} lamports",
+                    transaction.sender, transaction.receiver, transaction.amount
+                )))
+                .description(Some(match &transaction.timestamp {
+                    Some(timestamp) => format!(
+                        "{} sent {} lamports to {} at {}",
+                        transaction.sender, transaction.amount, transaction.receiver, timestamp
+                    ),
+                    None => format!(
+                        "{} sent {} lamports to {}",
+                        transaction.sender, transaction.amount, transaction.receiver
+                    ),
+                }))
+                .link(Some(link))
+                .guid(Some(
+                    GuidBuilder::default()
+                        .value(transaction.signature.clone())
+                        .permalink(false)
+                        .build(),
+                ))
+                .build()
+        })
+        .collect();
+    ChannelBuilder::default()
+        .title("Solana Aggregator transactions")
+        .link("/transactions")
+        .description("The latest transactions aggregated from the Solana blockchain")
+        .items(items)
+        .build()
+        .to_string()
+}
+
+/// Handles HTTP GET requests to export filtered transactions as a SQLite database file.
+///
+/// The filtered rows are copied into a new database file through a pooled connection, and the
+/// file is streamed as a download. This endpoint is only available when `ADMIN_TOKEN` is set, and
+/// requires an `Authorization: Bearer <ADMIN_TOKEN>` header.
+///
+/// # Arguments
+///
+/// * `req` - The HTTP request.
+/// * `config` - The configuration.
+/// * `pool` - The database connection pool.
+///
+/// # Returns
+///
+/// The exported SQLite file, a 403 if the request is not authorized, a 400 if the query parameters
+/// are invalid, or the error of the export, e.g. a 500 if the file can't be written.
+#[get("/export.sqlite")]
+async fn export_sqlite(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    pool: web::Data<dyn StorePool>,
+) -> Result<HttpResponse, ApiError> {
+    if !is_admin(&req, &config) {
+        return Err(ApiError::forbidden("admin token required"));
     }
-    query.push_str(" timestamp>=");
-    query.push_str(start_date);
+    let (query, params) = Info::parse(req.query_string())
+        .and_then(|info| filter_query(&info))
+        .map_err(ApiError::invalid)?;
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let path = env::temp_dir().join(format!("export_{}_{}.sqlite", std::process::id(), nanos));
+    let file = with_store(&pool, move |database| {
+        let file = database
+            .export(&path, &query, &params)
+            .and_then(|_| NamedFile::open(&path).map_err(|_| DatabaseError::ExportError));
+        // The open file stays readable until the response is sent, and is deleted once closed.
+        let _ = fs::remove_file(&path);
+        file
+    })
+    .await?;
+    let mut response = file
+        .set_content_disposition(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename("export.sqlite".to_string())],
+        })
+        .into_response(&req);
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/vnd.sqlite3"),
+    );
+    Ok(response)
 }
 
-/// Adds an end date filter to the query string.
+/// Checks whether a request carries the admin token.
 ///
 /// # Arguments
 ///
-/// * `flag` - A mutable reference to a boolean flag indicating whether this is the first filter.
-/// * `query` - A mutable reference to the query string.
-/// * `end_date` - The end date to filter by.
-fn end_date_query(flag: &mut bool, query: &mut String, end_date: &str) {
-    if !(*flag) {
-        query.push_str(" WHERE");
-        *flag = true;
-    } else {
-        query.push_str(" AND");
-        *flag = true;
+/// * `req` - The HTTP request.
+/// * `config` - The configuration.
+///
+/// # Returns
+///
+/// `true` if `admin_token` is set and the request's `Authorization` header is `Bearer <ADMIN_TOKEN>`.
+fn is_admin(req: &HttpRequest, config: &Config) -> bool {
+    let token = match &config.admin_token {
+        Some(token) if !token.is_empty() => token,
+        _ => return false,
+    };
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|value| value == token)
+}
+
+/// Handles HTTP GET requests to retrieve a single transaction.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+/// * `signature` - The signature of the transaction.
+///
+/// # Returns
+///
+/// A JSON object containing the transaction with its amount in SOL, or a 404 if the signature is unknown.
+#[get("/transactions/{signature}")]
+pub async fn transaction_by_signature(
+    pool: web::Data<dyn StorePool>,
+    signature: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let signature = signature.into_inner();
+    match with_store(&pool, move |database| database.transaction(&signature)).await? {
+        Some(data) => Ok(HttpResponse::Ok().json(record_json(&data))),
+        None => Err(ApiError::not_found("transaction not found")),
     }
-    query.push_str(" timestamp<=");
-    query.push_str(end_date);
+}
+
+/// Converts a transaction record to JSON, adding its `transfer_direction` and its amount in SOL under
+/// `amount_sol`.
+///
+/// # Arguments
+///
+/// * `record` - The transaction record.
+fn record_json(record: &TransactionRecord) -> serde_json::Value {
+    let mut body = record.to_json();
+    body["amount_sol"] = json!(lamports_to_sol(record.amount));
+    body
+}
+
+/// Represents query parameters for the transaction context endpoint.
+#[derive(Deserialize)]
+struct ContextInfo {
+    window: Option<u32>,
+}
+
+/// Handles HTTP GET requests to retrieve the transactions surrounding a given one.
+///
+/// The response contains up to `window` transactions immediately before and after the
+/// given transaction in chronological (slot, then position in block) order, with the
+/// transaction itself in the middle. `window` defaults to 10 and is capped at 100.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+/// * `signature` - The signature of the transaction.
+/// * `info` - The query parameters.
+///
+/// # Returns
+///
+/// A JSON array of the surrounding transactions, each formatted like `/transactions/{signature}`,
+/// or a 404 if the signature is unknown.
+#[get("/transactions/{signature}/context")]
+async fn transaction_context(
+    pool: web::Data<dyn StorePool>,
+    signature: web::Path<String>,
+    info: web::Query<ContextInfo>,
+) -> Result<HttpResponse, ApiError> {
+    let signature = signature.into_inner();
+    let window = info
+        .window
+        .unwrap_or(DEFAULT_CONTEXT_WINDOW)
+        .min(MAX_CONTEXT_WINDOW);
+    match with_store(&pool, move |database| database.context(&signature, window)).await? {
+        Some(records) => {
+            let data: Vec<_> = records.iter().map(record_json).collect();
+            Ok(HttpResponse::Ok().json(data))
+        }
+        None => Err(ApiError::not_found("transaction not found")),
+    }
+}
+
+/// Handles HTTP GET requests to retrieve the log messages of a transaction.
+///
+/// Logs are only stored when the aggregator runs with `STORE_LOGS` set.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+/// * `signature` - The signature of the transaction.
+///
+/// # Returns
+///
+/// A JSON response containing the log messages, or a 404 if no logs are stored for the signature.
+#[get("/transactions/{signature}/logs")]
+async fn transaction_logs(
+    pool: web::Data<dyn StorePool>,
+    signature: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let signature = signature.into_inner();
+    let logs = with_store(&pool, move |database| {
+        Ok::<_, DatabaseError>(database.logs(&signature))
+    })
+    .await?;
+    match logs {
+        Some(logs) => Ok(HttpResponse::Ok().json(logs)),
+        None => Err(ApiError::not_found("logs not found")),
+    }
+}
+
+/// Represents a single point of an address's balance history.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct BalancePoint {
+    pub timestamp: String,
+    pub delta: i64,
+    pub net_change: i64,
+}
+
+/// Handles HTTP GET requests to retrieve the balance history of an address.
+///
+/// The starting balance of an address is unknown to the aggregator, so each point
+/// reports the cumulative net change since the first aggregated transaction rather
+/// than an absolute balance.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+/// * `pubkey` - The public key of the address.
+///
+/// # Returns
+///
+/// A JSON response containing the balance history as a time series.
+#[get("/address/{pubkey}/balance-history")]
+async fn balance_history(
+    pool: web::Data<dyn StorePool>,
+    pubkey: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let pubkey = pubkey.into_inner();
+    let changes = with_store(&pool, move |database| database.balance_changes(&pubkey)).await?;
+    Ok(HttpResponse::Ok().json(cumulative_balances(&changes)))
+}
+
+/// Represents query parameters for the transactions of an account.
+#[derive(Deserialize)]
+struct ActivityInfo {
+    limit: Option<u32>,
+    offset: Option<u64>,
+    order: Option<String>,
+}
+
+/// Handles HTTP GET requests to retrieve the transactions sent or received by an account.
+///
+/// The transactions are ordered by slot and then by their position within the block, newest
+/// first unless `order` is `asc`. The response contains up to `limit` transactions, which
+/// defaults to 50 and is capped at 1000, after skipping the first `offset` ones.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+/// * `pubkey` - The public key of the account.
+/// * `info` - The query parameters.
+///
+/// # Returns
+///
+/// A JSON array of the transactions with their amount in SOL and their `direction` relative to
+/// the account, `sent` if the account is the sender and `received` otherwise, next to the
+/// `transfer_direction` of their net change, a 400 if the public key or the order is invalid, or
+/// a 500 with the error if the database can't be queried.
+#[get("/accounts/{pubkey}/transactions")]
+pub async fn account_transactions(
+    pool: web::Data<dyn StorePool>,
+    pubkey: web::Path<String>,
+    info: web::Query<ActivityInfo>,
+) -> Result<HttpResponse, ApiError> {
+    let account = match Pubkey::from_str(&pubkey) {
+        Ok(res) => res.to_string(),
+        Err(_) => return Err(ApiError::invalid(format!("invalid public key {}", pubkey))),
+    };
+    let order = match info.order.as_deref() {
+        None | Some("desc") => "DESC",
+        Some("asc") => "ASC",
+        Some(_) => return Err(ApiError::invalid("invalid order, expected asc or desc")),
+    };
+    let limit = info
+        .limit
+        .unwrap_or(DEFAULT_ACTIVITY_LIMIT)
+        .min(MAX_ACTIVITY_LIMIT);
+    let (query, params) = QueryBuilder::new("SELECT transactions.*, transaction_signatures.signatures FROM transactions LEFT JOIN transaction_signatures USING (signature)")
+        .condition("(sender = ? OR receiver = ?)", [&account, &account])
+        .order_by(&format!("slot {0}, tx_index {0}", order))
+        .limit(limit)
+        .offset(info.offset.unwrap_or(0))
+        .build();
+    let records = with_store(&pool, move |database| database.records(&query, &params)).await?;
+    let body: Vec<serde_json::Value> = records
+        .into_iter()
+        .map(|record| {
+            let direction = if record.sender == account {
+                "sent"
+            } else {
+                "received"
+            };
+            let mut body = record_json(&record);
+            body["direction"] = json!(direction);
+            body
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(body))
+}
+
+/// Represents query parameters for the counterparties of an account.
+#[derive(Deserialize)]
+struct CounterpartiesInfo {
+    limit: Option<u32>,
+}
+
+/// Handles HTTP GET requests to retrieve the addresses an account has transacted with.
+///
+/// The response contains up to `limit` counterparties, which defaults to 50 and is capped at
+/// 1000, with the most transactions first.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+/// * `pubkey` - The public key of the account.
+/// * `info` - The query parameters.
+///
+/// # Returns
+///
+/// A JSON array of `{counterparty, tx_count, total_amount, total_amount_sol}` objects, empty if
+/// the account has no transaction, or a 400 if the public key is invalid.
+#[get("/accounts/{pubkey}/counterparties")]
+pub async fn account_counterparties(
+    pool: web::Data<dyn StorePool>,
+    pubkey: web::Path<String>,
+    info: web::Query<CounterpartiesInfo>,
+) -> Result<HttpResponse, ApiError> {
+    let account = match Pubkey::from_str(&pubkey) {
+        Ok(res) => res.to_string(),
+        Err(_) => return Err(ApiError::invalid(format!("invalid public key {}", pubkey))),
+    };
+    let limit = info
+        .limit
+        .unwrap_or(DEFAULT_COUNTERPARTY_LIMIT)
+        .min(MAX_COUNTERPARTY_LIMIT);
+    let counterparties = with_store(&pool, move |database| {
+        database.counterparties(&account, limit)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(counterparties))
+}
+
+/// Reconstructs a cumulative balance series from chronologically ordered deltas.
+///
+/// # Arguments
+///
+/// * `changes` - The `(timestamp, delta)` pairs in chronological order.
+///
+/// # Returns
+///
+/// A vector of `BalancePoint`s carrying the running sum of the deltas.
+pub fn cumulative_balances(changes: &[(String, i64)]) -> Vec<BalancePoint> {
+    let mut net_change = 0;
+    changes
+        .iter()
+        .map(|(timestamp, delta)| {
+            net_change += delta;
+            BalancePoint {
+                timestamp: timestamp.clone(),
+                delta: *delta,
+                net_change,
+            }
+        })
+        .collect()
 }
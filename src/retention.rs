@@ -0,0 +1,99 @@
+use crate::{
+    aggregator,
+    database::DatabaseWriter,
+    error::DatabaseError,
+    shutdown::{self, Shutdown},
+};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, info};
+
+/// The number of transactions deleted at a time, so that the writes of the aggregator can take
+/// turns with the pruning.
+pub const PRUNE_BATCH_SIZE: u32 = 1_000;
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// Computes the timestamp before which transactions are pruned.
+///
+/// The cutoff is formatted like the stored timestamps, in the default format in UTC, so that
+/// they can be compared.
+///
+/// # Arguments
+///
+/// * `now` - The current Unix timestamp.
+/// * `retention_days` - The number of days transactions are kept.
+pub fn cutoff(now: i64, retention_days: u32) -> String {
+    aggregator::get_timestamp((now - i64::from(retention_days) * SECONDS_PER_DAY).max(0))
+}
+
+/// Deletes the transactions stored before a cutoff and reclaims their space.
+///
+/// The transactions are deleted in batches of `PRUNE_BATCH_SIZE`, and the write connection is
+/// released between the batches, so the blocks being aggregated aren't held up until the whole
+/// pruning is done.
+///
+/// # Arguments
+///
+/// * `writer` - The write connection shared with the aggregator.
+/// * `cutoff` - The timestamp, in the stored format, before which transactions are deleted.
+///
+/// # Errors
+///
+/// Returns `DatabaseError::PruneError` if a batch can't be deleted or the space can't be
+/// reclaimed. The batches deleted before stay deleted.
+///
+/// # Returns
+///
+/// The number of deleted transactions.
+pub async fn prune(writer: &DatabaseWriter, cutoff: &str) -> Result<usize, DatabaseError> {
+    let mut deleted = 0;
+    loop {
+        let batch = writer.lock().await.prune(cutoff, PRUNE_BATCH_SIZE)?;
+        deleted += batch;
+        if batch < PRUNE_BATCH_SIZE as usize {
+            break;
+        }
+    }
+    if deleted > 0 {
+        writer.lock().await.reclaim_space()?;
+    }
+    Ok(deleted)
+}
+
+/// Prunes the transactions older than `retention_days` every `interval` until a shutdown is
+/// requested.
+///
+/// The first pruning runs right away. A failed pruning is logged and retried on the next
+/// interval.
+///
+/// # Arguments
+///
+/// * `writer` - The write connection shared with the aggregator.
+/// * `retention_days` - The number of days transactions are kept.
+/// * `interval` - The time between two prunings.
+/// * `shutdown` - Receives the request to stop pruning.
+pub async fn run(
+    writer: Arc<DatabaseWriter>,
+    retention_days: u32,
+    interval: Duration,
+    shutdown: Shutdown,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = shutdown::requested(shutdown.clone()) => return,
+            _ = ticks.tick() => {}
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs() as i64)
+            .unwrap_or_default();
+        let cutoff = cutoff(now, retention_days);
+        match prune(&writer, &cutoff).await {
+            Ok(deleted) => info!(deleted, cutoff, "pruned the old transactions"),
+            Err(err) => error!(error = %err, cutoff, "failed to prune the old transactions"),
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use tokio::sync::watch;
+
+/// Receives the request to shut down the aggregator and the web server.
+pub type Shutdown = watch::Receiver<bool>;
+
+/// Creates a shutdown channel.
+///
+/// # Returns
+///
+/// The sender requesting the shutdown, and the receiver cloned into every task that should stop.
+pub fn channel() -> (watch::Sender<bool>, Shutdown) {
+    watch::channel(false)
+}
+
+/// Checks whether a shutdown has been requested.
+///
+/// # Arguments
+///
+/// * `shutdown` - The shutdown receiver.
+pub fn is_requested(shutdown: &Shutdown) -> bool {
+    *shutdown.borrow()
+}
+
+/// Waits until a shutdown is requested.
+///
+/// Never completes if the sender is dropped without requesting one.
+///
+/// # Arguments
+///
+/// * `shutdown` - The shutdown receiver.
+pub async fn requested(mut shutdown: Shutdown) {
+    if shutdown.wait_for(|requested| *requested).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Requests a shutdown if a task failed, so that a fatal error of one task stops the others.
+///
+/// # Arguments
+///
+/// * `result` - The outcome of the task.
+/// * `sender` - The shutdown sender.
+///
+/// # Returns
+///
+/// `true` if a shutdown was requested.
+pub fn request_on_error<T, E>(result: &Result<T, E>, sender: &watch::Sender<bool>) -> bool {
+    if result.is_err() {
+        let _ = sender.send(true);
+    }
+    result.is_err()
+}
+
+/// Requests a shutdown once the process receives Ctrl-C.
+///
+/// # Arguments
+///
+/// * `sender` - The shutdown sender.
+pub async fn on_ctrl_c(sender: watch::Sender<bool>) {
+    if tokio::signal::ctrl_c().await.is_ok() {
+        let _ = sender.send(true);
+    }
+}
@@ -0,0 +1,81 @@
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::{SdkTracerProvider, Tracer};
+use tracing::{error, Subscriber};
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::{
+    layer::SubscriberExt, registry::LookupSpan, util::SubscriberInitExt, EnvFilter,
+};
+
+const TRACER_NAME: &str = "solana-aggregator";
+const DEFAULT_LOG_FILTER: &str = "info";
+
+/// Flushes and shuts down the OpenTelemetry exporter when dropped.
+pub struct TelemetryGuard {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            let _ = provider.shutdown();
+        }
+    }
+}
+
+/// Initializes logging and OpenTelemetry trace export.
+///
+/// Logs are written to standard output and filtered with the `RUST_LOG` environment variable
+/// (default `info`). When an endpoint is given, the spans of the fetch, parse and insert pipeline
+/// are also exported over OTLP/HTTP to it.
+///
+/// # Arguments
+///
+/// * `otel_endpoint` - The OTLP/HTTP endpoint to export traces to.
+///
+/// # Returns
+///
+/// A `TelemetryGuard` that must be kept alive for as long as traces should be exported.
+pub fn init(otel_endpoint: Option<&str>) -> TelemetryGuard {
+    let (provider, exporter_error) = match otel_endpoint {
+        Some(endpoint) if !endpoint.is_empty() => match SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+        {
+            Ok(exporter) => (
+                Some(
+                    SdkTracerProvider::builder()
+                        .with_batch_exporter(exporter)
+                        .build(),
+                ),
+                None,
+            ),
+            Err(err) => (None, Some(err)),
+        },
+        _ => (None, None),
+    };
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_FILTER));
+    let _ = tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .with(provider.as_ref().map(layer))
+        .try_init();
+    if let Some(err) = exporter_error {
+        error!(error = %err, "failed to build the OpenTelemetry exporter");
+    }
+    TelemetryGuard { provider }
+}
+
+/// Creates a tracing layer that records spans with the given tracer provider.
+///
+/// # Arguments
+///
+/// * `provider` - The OpenTelemetry tracer provider.
+pub fn layer<S>(provider: &SdkTracerProvider) -> OpenTelemetryLayer<S, Tracer>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    tracing_opentelemetry::layer().with_tracer(provider.tracer(TRACER_NAME))
+}
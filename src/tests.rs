@@ -1,22 +1,622 @@
 #[allow(unused_imports)]
-use crate::{aggregator, database::Database, error::AggregatorError};
+use crate::{
+    aggregator::{self, BlockOptions, SlotScheduler},
+    config::{Config, DbBackend, InsertFailurePolicy, SubscribeMode},
+    database::{self, Database, DatabasePool, DatabaseWriter, Store, StorePool, TransactionRecord},
+    dedup::DedupCache,
+    error::{AggregatorError, DatabaseError, SourceError},
+    rate_limit::{rate_limit, RateLimiter},
+    restful_api, shutdown,
+};
 #[allow(unused_imports)]
-use std::env;
+use actix_web::{http::StatusCode, middleware::from_fn, web, App, HttpResponse};
+#[allow(unused_imports)]
+use serde_json::{json, Value};
+#[allow(unused_imports)]
+use solana_sdk::{bs58, pubkey::Pubkey};
+#[allow(unused_imports)]
+use solana_transaction_status::EncodedConfirmedBlock;
+#[allow(unused_imports)]
+use std::{
+    env, fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 
-#[tokio::test]
-async fn test_env() {
+/// Reads every stored transaction, ordered by slot and position in the block.
+#[allow(dead_code)]
+fn stored_transactions(database: &mut Database) -> Vec<TransactionRecord> {
+    database
+        .records(
+            "SELECT * FROM transactions LEFT JOIN transaction_signatures USING (signature) ORDER BY slot, tx_index",
+            &[],
+        )
+        .unwrap()
+}
+
+/// Builds the record of a SOL transfer from the sender to the receiver, with a single signature.
+#[allow(dead_code, clippy::too_many_arguments)]
+fn transfer_record(
+    sender: Pubkey,
+    receiver: Pubkey,
+    amount: i64,
+    fee: i64,
+    timestamp: &str,
+    signature: &str,
+    slot: u64,
+    tx_index: usize,
+) -> TransactionRecord {
+    TransactionRecord {
+        sender: sender.to_string(),
+        receiver: receiver.to_string(),
+        amount,
+        net_change: -amount,
+        timestamp: Some(timestamp.to_string()),
+        signature: signature.to_string(),
+        signatures: vec![signature.to_string()],
+        slot,
+        block_height: None,
+        tx_index: tx_index as u64,
+        fee,
+        program_id: None,
+        transfer_type: database::TRANSFER_TYPE_SOL.to_string(),
+        token_mint: None,
+        token_amount: None,
+        token_decimals: None,
+        status: database::STATUS_SUCCESS.to_string(),
+        balance_changes: vec![],
+        logs: None,
+    }
+}
+
+/// Shares a pool with the handlers of an app.
+#[allow(dead_code)]
+fn store_pool(pool: DatabasePool) -> web::Data<dyn StorePool> {
+    let pool: std::sync::Arc<dyn StorePool> = std::sync::Arc::new(pool);
+    web::Data::from(pool)
+}
+
+/// A database path in the temp directory, whose file is removed along with its `-wal` and `-shm`
+/// files when it is dropped, even if the test panics.
+struct TempDbPath(PathBuf);
+
+impl TempDbPath {
+    /// Removes the database file and its WAL files.
+    fn remove(&self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = self.0.clone().into_os_string();
+            path.push(suffix);
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl Deref for TempDbPath {
+    type Target = PathBuf;
+
+    fn deref(&self) -> &PathBuf {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDbPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDbPath {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+/// Returns a fresh, process-unique database path in the temp directory, removed when dropped.
+#[allow(dead_code)]
+fn temp_db_path(name: &str) -> TempDbPath {
+    let path = TempDbPath(env::temp_dir().join(format!("{}_{}.db", name, std::process::id())));
+    path.remove();
+    path
+}
+
+#[test]
+fn test_env() {
     env::set_var("rpc_url", "Invalid Url");
     env::set_var("wc_url", "Invalid Url");
+    let err = Config::load().unwrap_err();
+    assert!(matches!(err, AggregatorError::EnvFetchError(_)));
+    assert!(!std::error::Error::source(&err)
+        .unwrap()
+        .to_string()
+        .is_empty());
+    env::remove_var("rpc_url");
+    env::remove_var("ws_url");
+}
+
+#[test]
+fn test_config_file() {
+    let file = r#"
+        ws_url = "wss://file.example.com"
+        rpc_url = "https://file.example.com"
+        db_path = "file.db"
+        port = 9000
+        commitment = "confirmed"
+        max_concurrent_blocks = 8
+        max_slots = 10
+        account_allowlist = ["first", "second"]
+        db_backend = "postgres"
+        database_url = "postgres://localhost/solana"
+        admin_token = "secret"
+        rate_limit_rps = 2.5
+    "#;
+    let vars = |vars: &[(&str, &str)]| -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    };
+
+    let config = Config::from_sources(Some(file), vars(&[])).unwrap();
+    assert_eq!("wss://file.example.com/", config.ws_url.as_str());
+    assert_eq!(PathBuf::from("file.db"), config.db_path);
+    assert_eq!("127.0.0.1", config.bind_addr);
+    assert_eq!(9000, config.port);
     assert_eq!(
-        Err(AggregatorError::EnvFetchError),
-        aggregator::get_block(102000).await
+        solana_sdk::commitment_config::CommitmentLevel::Confirmed,
+        config.commitment
     );
+    assert_eq!(8, config.max_concurrent_blocks);
+    assert_eq!(10, config.max_slots);
+    assert_eq!(vec!["first", "second"], config.account_allowlist);
+    assert_eq!(DbBackend::Postgres, config.db_backend);
     assert_eq!(
-        Err(AggregatorError::EnvFetchError),
-        aggregator::aggregate_data().await
+        Some("postgres://localhost/solana"),
+        config.database_url.as_deref()
     );
-    env::remove_var("rpc_url");
-    env::remove_var("ws_url");
+    assert_eq!(Some("secret"), config.admin_token.as_deref());
+    assert!(RateLimiter::from_config(&config).is_some());
+    // Values the file doesn't set keep their defaults.
+    assert_eq!(3, config.rpc_max_attempts);
+    assert_eq!(SubscribeMode::Slot, config.subscribe_mode);
+    assert_eq!(4, config.db_pool_size);
+    assert!(!config.trust_forwarded_for);
+    assert_eq!(None, config.otel_endpoint);
+
+    // Environment variables take precedence over the file.
+    let config = Config::from_sources(
+        Some(file),
+        vars(&[
+            ("rpc_url", "https://env.example.com"),
+            ("MAX_SLOTS", "20"),
+            ("BIND_ADDR", "0.0.0.0"),
+            ("SUBSCRIBE_MODE", "block"),
+            ("DB_POOL_SIZE", "8"),
+            ("RATE_LIMIT_RPS", "0"),
+        ]),
+    )
+    .unwrap();
+    assert_eq!(8, config.db_pool_size);
+    assert!(RateLimiter::from_config(&config).is_none());
+    assert_eq!("https://env.example.com/", config.rpc_url.as_str());
+    assert_eq!(SubscribeMode::Block, config.subscribe_mode);
+    assert_eq!("wss://file.example.com/", config.ws_url.as_str());
+    assert_eq!(20, config.max_slots);
+    assert_eq!("0.0.0.0", config.bind_addr);
+
+    // Without a file, the environment variables are enough.
+    let config = Config::from_sources(
+        None,
+        vars(&[
+            ("ws_url", "wss://env.example.com"),
+            ("rpc_url", "https://env.example.com"),
+        ]),
+    )
+    .unwrap();
+    assert_eq!(PathBuf::from("transactions.db"), config.db_path);
+    assert_eq!(8080, config.port);
+
+    assert!(matches!(
+        Config::from_sources(Some("port = "), vars(&[])),
+        Err(AggregatorError::ConfigFileError(_))
+    ));
+    assert!(matches!(
+        Config::from_sources(None, vars(&[])),
+        Err(AggregatorError::EnvFetchError(_))
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_get_block_shared_rpc_client() {
+    let path = temp_db_path("test_get_block_shared_rpc_client");
+    // The configured endpoint is unreachable, so blocks can only come from the shared client.
+    let config = Config::from_sources(
+        None,
+        [
+            ("ws_url", "ws://127.0.0.1:1"),
+            ("rpc_url", "http://127.0.0.1:1"),
+            ("db_path", path.to_str().unwrap()),
+            ("rpc_max_attempts", "1"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string())),
+    )
+    .unwrap();
+    let config = std::sync::Arc::new(config);
+    let rpc = std::sync::Arc::new(aggregator::LimitedRpcClient::from(
+        solana_client::nonblocking::rpc_client::RpcClient::new_mock("succeeds".to_string()),
+    ));
+    let writer = std::sync::Arc::new(DatabaseWriter::with_path(&path).unwrap());
+    for slot in 1..=3 {
+        // The mock block has no block time, so it is fetched but can't be processed.
+        let err = aggregator::get_block(config.clone(), rpc.clone(), writer.clone(), slot)
+            .await
+            .unwrap_err();
+        assert_eq!(AggregatorError::TimeFetchError, err);
+    }
+    assert_eq!(1, std::sync::Arc::strong_count(&rpc));
+}
+
+/// Serves JSON-RPC requests on a local port.
+///
+/// # Arguments
+///
+/// * `respond` - Builds the `result` or `error` member of the response to a request.
+///
+/// # Returns
+///
+/// The URL of the server.
+fn rpc_server(respond: impl Fn(&Value) -> Value + Send + 'static) -> String {
+    use std::io::{BufRead, Read, Write};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = std::io::BufReader::new(stream.unwrap());
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).unwrap();
+                let line = line.trim().to_ascii_lowercase();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(value) = line.strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).unwrap();
+            let request: Value = serde_json::from_slice(&body).unwrap();
+            let mut response = respond(&request);
+            response["jsonrpc"] = json!("2.0");
+            response["id"] = request["id"].clone();
+            let response = response.to_string();
+            let _ = write!(
+                stream.get_mut(),
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                response.len(),
+                response
+            );
+        }
+    });
+    url
+}
+
+/// Serves JSON-RPC requests with the given error on a local port.
+///
+/// # Returns
+///
+/// The URL of the server and the number of requests it received.
+#[allow(dead_code)]
+fn rpc_error_server(
+    code: i64,
+    message: &'static str,
+) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+    let requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = requests.clone();
+    let url = rpc_server(move |_| {
+        counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        json!({ "error": { "code": code, "message": message } })
+    });
+    (url, requests)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_slot_skipped() {
+    let path = temp_db_path("test_slot_skipped");
+    let (rpc_url, requests) = rpc_error_server(
+        solana_client::rpc_custom_error::JSON_RPC_SERVER_ERROR_SLOT_SKIPPED,
+        "Slot 5 was skipped, or missing due to ledger jump to recent snapshot",
+    );
+    let config = Config::from_sources(
+        None,
+        [
+            ("ws_url", "ws://127.0.0.1:1"),
+            ("rpc_url", rpc_url.as_str()),
+            ("db_path", path.to_str().unwrap()),
+            ("rpc_max_attempts", "3"),
+            ("rpc_initial_backoff_ms", "1"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string())),
+    )
+    .unwrap();
+    let rpc = std::sync::Arc::new(aggregator::rpc_client(&config));
+    let writer = std::sync::Arc::new(DatabaseWriter::with_path(&path).unwrap());
+    let config = std::sync::Arc::new(config);
+
+    let result = aggregator::get_block(config.clone(), rpc.clone(), writer.clone(), 5).await;
+    assert_eq!(Err(AggregatorError::SlotSkipped), result);
+    assert_eq!(1, requests.load(std::sync::atomic::Ordering::SeqCst));
+    let mut database = Database::with_path(&path).unwrap();
+    assert_eq!(None, database.slot_attempts(5, "get_block"));
+
+    let failed = aggregator::backfill_range(5, 7, 2, |slot| {
+        aggregator::get_block(config.clone(), rpc.clone(), writer.clone(), slot)
+    })
+    .await;
+    assert!(failed.is_empty());
+    assert_eq!(4, requests.load(std::sync::atomic::Ordering::SeqCst));
+}
+#[tokio::test]
+async fn test_rpc_requests_dont_block_the_runtime() {
+    // The server answers after 200ms, while the other task of this single threaded runtime
+    // only sleeps for 10ms.
+    let rpc_url = rpc_server(|_| {
+        std::thread::sleep(Duration::from_millis(200));
+        json!({ "result": 10 })
+    });
+    let rpc = aggregator::LimitedRpcClient::from(
+        solana_client::nonblocking::rpc_client::RpcClient::new(rpc_url),
+    );
+    let ticker = async {
+        let start = Instant::now();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        start.elapsed()
+    };
+    let (committed, ticked) = tokio::join!(
+        aggregator::wait_for_commitment(
+            &rpc,
+            5,
+            solana_sdk::commitment_config::CommitmentLevel::Confirmed,
+            Duration::from_millis(1),
+            Duration::from_secs(5),
+        ),
+        ticker
+    );
+    assert!(committed);
+    assert!(ticked < Duration::from_millis(150), "{:?}", ticked);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_fetch_waits_for_commitment() {
+    let path = temp_db_path("test_fetch_waits_for_commitment");
+    // The confirmed slot advances by one on each `getSlot`, from 2, and reaches slot 5 on the
+    // fourth request.
+    let requests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let received = requests.clone();
+    let rpc_url = rpc_server(move |request| {
+        let mut received = received.lock().unwrap();
+        received.push(request.clone());
+        match request["method"].as_str() {
+            Some("getSlot") => json!({ "result": 1 + received.len() }),
+            _ => json!({
+                "error": {
+                    "code": solana_client::rpc_custom_error::JSON_RPC_SERVER_ERROR_SLOT_SKIPPED,
+                    "message": "Slot 5 was skipped",
+                },
+            }),
+        }
+    });
+    let config = |wait_secs: &str| {
+        Config::from_sources(
+            None,
+            [
+                ("ws_url", "ws://127.0.0.1:1"),
+                ("rpc_url", rpc_url.as_str()),
+                ("db_path", path.to_str().unwrap()),
+                ("commitment", "confirmed"),
+                ("commitment_wait_secs", wait_secs),
+                ("commitment_poll_ms", "1"),
+            ]
+            .map(|(key, value)| (key.to_string(), value.to_string())),
+        )
+        .unwrap()
+    };
+    let rpc = std::sync::Arc::new(aggregator::rpc_client(&config("0")));
+    let writer = std::sync::Arc::new(DatabaseWriter::with_path(&path).unwrap());
+
+    let result = aggregator::get_block(
+        std::sync::Arc::new(config("10")),
+        rpc.clone(),
+        writer.clone(),
+        5,
+    )
+    .await;
+    assert_eq!(Err(AggregatorError::SlotSkipped), result);
+    let methods: Vec<Value> = requests
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|request| request["method"].clone())
+        .collect();
+    assert_eq!(
+        json!(["getSlot", "getSlot", "getSlot", "getSlot", "getBlock"]),
+        json!(methods)
+    );
+    let requests_sent = requests.lock().unwrap().clone();
+    assert_eq!("confirmed", requests_sent[0]["params"][0]["commitment"]);
+    assert_eq!(5, requests_sent[4]["params"][0]);
+
+    // The block is fetched right away once the slot is committed, and without waiting when the
+    // wait is disabled.
+    requests.lock().unwrap().clear();
+    let _ = aggregator::get_block(
+        std::sync::Arc::new(config("10")),
+        rpc.clone(),
+        writer.clone(),
+        1,
+    )
+    .await;
+    let _ = aggregator::get_block(std::sync::Arc::new(config("0")), rpc, writer, 5).await;
+    let methods: Vec<Value> = requests
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|request| request["method"].clone())
+        .collect();
+    assert_eq!(json!(["getSlot", "getBlock", "getBlock"]), json!(methods));
+}
+#[tokio::test(flavor = "multi_thread")]
+async fn test_rpc_failover() {
+    let path = temp_db_path("test_rpc_failover");
+    let (primary, failed) = rpc_error_server(-32005, "Node is unhealthy");
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+    let block = serde_json::to_value(block_json(
+        Some(1722201110),
+        vec![transaction_json(&["sig"], &keys, &[100, 0], &[40, 60])],
+    ))
+    .unwrap();
+    let requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = requests.clone();
+    let fallback = rpc_server(move |_| {
+        counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        json!({ "result": block })
+    });
+    let config = std::sync::Arc::new(
+        Config::from_sources(
+            None,
+            [
+                ("ws_url", "ws://127.0.0.1:1"),
+                ("rpc_url", primary.as_str()),
+                ("rpc_urls", &format!("{},{}", primary, fallback)),
+                ("db_path", path.to_str().unwrap()),
+                ("rpc_max_attempts", "2"),
+                ("rpc_initial_backoff_ms", "1"),
+            ]
+            .map(|(key, value)| (key.to_string(), value.to_string())),
+        )
+        .unwrap(),
+    );
+    let rpc = std::sync::Arc::new(aggregator::rpc_client(&config));
+    // The primary endpoint isn't listed twice.
+    assert_eq!(2, rpc.endpoints.len());
+    let writer = std::sync::Arc::new(DatabaseWriter::with_path(&path).unwrap());
+
+    let result = aggregator::get_block(config.clone(), rpc.clone(), writer.clone(), 5).await;
+    assert_eq!(Ok(()), result);
+    assert!(writer.lock().await.transaction("sig").unwrap().is_some());
+    assert_eq!(1, failed.load(std::sync::atomic::Ordering::SeqCst));
+    assert_eq!(1, requests.load(std::sync::atomic::Ordering::SeqCst));
+    assert_eq!(1, rpc.endpoints[0].failures());
+    assert_eq!(0, rpc.endpoints[1].failures());
+
+    // The next fetch goes straight to the endpoint that succeeded.
+    let result = aggregator::get_block(config, rpc.clone(), writer, 6).await;
+    assert_eq!(Ok(()), result);
+    assert_eq!(1, failed.load(std::sync::atomic::Ordering::SeqCst));
+    assert_eq!(2, requests.load(std::sync::atomic::Ordering::SeqCst));
+    assert_eq!(
+        fallback.trim_end_matches('/'),
+        rpc.current().url.trim_end_matches('/')
+    );
+}
+#[tokio::test(flavor = "multi_thread")]
+async fn test_rpc_timeout() {
+    let path = temp_db_path("test_rpc_timeout");
+    // The endpoint accepts connections but never responds.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let rpc_url = format!("http://{}", listener.local_addr().unwrap());
+    let config = Config::from_sources(
+        None,
+        [
+            ("ws_url", "ws://127.0.0.1:1"),
+            ("rpc_url", rpc_url.as_str()),
+            ("db_path", path.to_str().unwrap()),
+            ("rpc_max_attempts", "1"),
+            ("rpc_timeout_secs", "1"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string())),
+    )
+    .unwrap();
+    let rpc = std::sync::Arc::new(aggregator::rpc_client(&config));
+    let writer = std::sync::Arc::new(DatabaseWriter::with_path(&path).unwrap());
+
+    let start = Instant::now();
+    let result = aggregator::get_block(std::sync::Arc::new(config), rpc, writer, 1).await;
+    let elapsed = start.elapsed();
+    assert!(matches!(result, Err(AggregatorError::BlockFetchError(_))));
+    assert!(elapsed >= Duration::from_secs(1));
+    assert!(elapsed < Duration::from_secs(10));
+    drop(listener);
+}
+#[test]
+fn test_instance_lock() {
+    use crate::{error::RuntimeError, lock::InstanceLock};
+    let path = temp_db_path("test_instance_lock");
+    let first = InstanceLock::acquire(&path).unwrap();
+    let err = InstanceLock::acquire(&path).unwrap_err();
+    assert!(matches!(err, RuntimeError::LockError(_)));
+    assert!(std::error::Error::source(&err)
+        .unwrap()
+        .to_string()
+        .contains("another instance"));
+    drop(first);
+    let second = InstanceLock::acquire(&path).unwrap();
+    drop(second);
+    let _ = fs::remove_file(crate::lock::lock_path(&path));
+}
+
+#[test]
+fn test_cli_parse() {
+    use crate::cli::{self, Mode};
+    assert_eq!(Mode::Run, cli::parse(["solana-aggregator"]).unwrap());
+    assert_eq!(Mode::Run, cli::parse(["solana-aggregator", "run"]).unwrap());
+    assert_eq!(
+        Mode::Backfill { from: 10, to: 12 },
+        cli::parse([
+            "solana-aggregator",
+            "backfill",
+            "--from",
+            "10",
+            "--to",
+            "12"
+        ])
+        .unwrap()
+    );
+    assert!(cli::parse(["solana-aggregator", "backfill", "--from", "10"]).is_err());
+    assert!(cli::parse(["solana-aggregator", "backfill", "--from", "x", "--to", "12"]).is_err());
+    assert!(cli::parse([
+        "solana-aggregator",
+        "backfill",
+        "--from",
+        "12",
+        "--to",
+        "10"
+    ])
+    .is_err());
+}
+
+#[tokio::test]
+async fn test_backfill_range() {
+    let seen = std::sync::Mutex::new(vec![]);
+    let failed = aggregator::backfill_range(100, 105, 2, |slot| {
+        seen.lock().unwrap().push(slot);
+        async move {
+            if slot % 2 == 1 {
+                Err(AggregatorError::TimeFetchError)
+            } else {
+                Ok(())
+            }
+        }
+    })
+    .await;
+    // Failed slots don't stop the range.
+    assert_eq!(vec![101, 103, 105], failed);
+    let mut seen = seen.into_inner().unwrap();
+    seen.sort_unstable();
+    assert_eq!((100..=105).collect::<Vec<u64>>(), seen);
 }
 
 #[test]
@@ -24,3 +624,4981 @@ fn test_get_timestamp() {
     let timestamp = 1722201110;
     assert_eq!("2024-07-28 21:11:50", aggregator::get_timestamp(timestamp));
 }
+
+#[test]
+fn test_timestamp_format() {
+    let timestamp = 1722201110;
+    let vars = |vars: &[(&str, &str)]| -> Vec<(String, String)> {
+        [
+            ("ws_url", "wss://example.com"),
+            ("rpc_url", "https://example.com"),
+        ]
+        .iter()
+        .chain(vars)
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+    };
+    let options = |vars: Vec<(String, String)>| {
+        BlockOptions::from_config(&Config::from_sources(None, vars).unwrap())
+    };
+
+    assert_eq!(
+        "2024-07-28 21:11:50",
+        options(vars(&[])).timestamp_format.format(timestamp)
+    );
+    assert_eq!(
+        "2024-07-28T21:11:50Z",
+        options(vars(&[("TIMESTAMP_FORMAT", "rfc3339")]))
+            .timestamp_format
+            .format(timestamp)
+    );
+    assert_eq!(
+        "2024-07-28T23:11:50+02:00",
+        options(vars(&[
+            ("TIMESTAMP_FORMAT", "RFC3339"),
+            ("TIMESTAMP_OFFSET", "+02:00")
+        ]))
+        .timestamp_format
+        .format(timestamp)
+    );
+    // An ISO 8601 format string, in a timezone behind UTC.
+    assert_eq!(
+        "2024-07-28T15:41:50-05:30",
+        options(vars(&[
+            ("TIMESTAMP_FORMAT", "%Y-%m-%dT%H:%M:%S%:z"),
+            ("TIMESTAMP_OFFSET", "-05:30")
+        ]))
+        .timestamp_format
+        .format(timestamp)
+    );
+
+    for (key, value) in [
+        ("TIMESTAMP_FORMAT", "%Y-%m-%d %Q"),
+        ("TIMESTAMP_FORMAT", ""),
+        ("TIMESTAMP_OFFSET", "UTC+2"),
+    ] {
+        assert!(matches!(
+            Config::from_sources(None, vars(&[(key, value)])),
+            Err(AggregatorError::EnvFetchError(_))
+        ));
+    }
+}
+
+#[actix_web::test]
+async fn test_timestamp_format_responses() {
+    use crate::aggregator::TimestampFormat;
+    let path = temp_db_path("test_timestamp_format_responses");
+    let config = Config::from_sources(
+        None,
+        [
+            ("ws_url", "ws://127.0.0.1:1"),
+            ("rpc_url", "http://127.0.0.1:1"),
+            ("timestamp_format", "rfc3339"),
+            ("timestamp_offset", "+02:00"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string())),
+    )
+    .unwrap();
+    let mut database = Database::with_path(&path).unwrap();
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+    for (slot, block_time, signature) in [(1, 1722201110, "first"), (2, 1722290000, "second")] {
+        let block = block_json(
+            Some(block_time),
+            vec![transaction_json(&[signature], &keys, &[100, 0], &[40, 60])],
+        );
+        aggregator::handle_block(
+            block,
+            slot,
+            &BlockOptions::from_config(&config),
+            &mut database,
+        )
+        .unwrap();
+    }
+    // The timestamps are stored in UTC in the default format whatever the configured one.
+    let stored: String = rusqlite::Connection::open(&path)
+        .unwrap()
+        .query_row(
+            "SELECT timestamp FROM transactions WHERE signature = 'first'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!("2024-07-28 21:11:50", stored);
+    drop(database);
+
+    let pool = DatabasePool::read_only(&path, 1)
+        .with_timestamp_format(TimestampFormat::from_config(&config));
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(pool))
+            .app_data(web::Data::new(config))
+            .app_data(web::Data::new(shutdown::channel().1))
+            .configure(restful_api::routes),
+    )
+    .await;
+    let get = |uri: &str| actix_web::test::TestRequest::get().uri(uri).to_request();
+
+    // The responses are formatted, while the filters compare the stored UTC timestamps.
+    let body: Value =
+        actix_web::test::call_and_read_body_json(&app, get("/transactions/first")).await;
+    assert_eq!("2024-07-28T23:11:50+02:00", body["timestamp"]);
+    let body: Value = actix_web::test::call_and_read_body_json(
+        &app,
+        get("/transactions?start_date=2024-07-28%2021:11:50&end_date=2024-07-28"),
+    )
+    .await;
+    assert_eq!(1, body.as_array().unwrap().len());
+    assert_eq!("2024-07-28T23:11:50+02:00", body[0]["timestamp"]);
+    // The timestamp of a response is accepted back as a cursor.
+    let body: Value = actix_web::test::call_and_read_body_json(
+        &app,
+        get("/transactions?since=2024-07-28T23:11:50%2B02:00,first"),
+    )
+    .await;
+    assert_eq!(1, body.as_array().unwrap().len());
+    assert_eq!("second", body[0]["signature"]);
+    let body: Value =
+        actix_web::test::call_and_read_body_json(&app, get("/stats/timeseries?interval=day")).await;
+    assert_eq!("2024-07-28T02:00:00+02:00", body[0]["bucket_start"]);
+    assert_eq!("2024-07-29T02:00:00+02:00", body[1]["bucket_start"]);
+}
+#[test]
+fn test_lamports_to_sol() {
+    use crate::units::lamports_to_sol;
+    assert_eq!("0", lamports_to_sol(0));
+    assert_eq!("1", lamports_to_sol(1_000_000_000));
+    assert_eq!("1.5", lamports_to_sol(1_500_000_000));
+    assert_eq!("0.000000001", lamports_to_sol(1));
+    assert_eq!("-0.000005", lamports_to_sol(-5_000));
+    // Amounts beyond 2^53 lamports lose precision as f64.
+    assert_eq!("9007199.254740993", lamports_to_sol(9_007_199_254_740_993));
+    assert_ne!(
+        9_007_199_254_740_993,
+        (9_007_199_254_740_993_i64 as f64) as i64
+    );
+    assert_eq!("9223372036.854775807", lamports_to_sol(i64::MAX));
+    assert_eq!("-9223372036.854775808", lamports_to_sol(i64::MIN));
+}
+
+#[test]
+fn test_cumulative_balances() {
+    let changes = vec![
+        ("2024-07-28 21:11:50".to_string(), 500),
+        ("2024-07-28 21:12:10".to_string(), -200),
+        ("2024-07-28 21:13:30".to_string(), 50),
+    ];
+    let series = restful_api::cumulative_balances(&changes);
+    assert_eq!(changes.len(), series.len());
+    for (i, point) in series.iter().enumerate() {
+        let expected: i64 = changes[..=i].iter().map(|(_, delta)| delta).sum();
+        assert_eq!(changes[i].0, point.timestamp);
+        assert_eq!(expected, point.net_change);
+    }
+}
+
+/// Builds a JSON encoded transaction with the given signatures, account keys and balances.
+#[allow(dead_code)]
+fn transaction_json(
+    signatures: &[&str],
+    account_keys: &[String],
+    pre_balances: &[u64],
+    post_balances: &[u64],
+) -> Value {
+    json!({
+        "transaction": {
+            "signatures": signatures,
+            "message": {
+                "header": {
+                    "numRequiredSignatures": 1,
+                    "numReadonlySignedAccounts": 0,
+                    "numReadonlyUnsignedAccounts": 1
+                },
+                "accountKeys": account_keys,
+                "recentBlockhash": Pubkey::default().to_string(),
+                "instructions": []
+            }
+        },
+        "meta": {
+            "err": null,
+            "status": { "Ok": null },
+            "fee": 5000,
+            "preBalances": pre_balances,
+            "postBalances": post_balances
+        }
+    })
+}
+
+/// Builds a block with the given block time and JSON encoded transactions.
+#[allow(dead_code)]
+fn block_json(block_time: Option<i64>, transactions: Vec<Value>) -> EncodedConfirmedBlock {
+    serde_json::from_value(json!({
+        "previousBlockhash": Pubkey::default().to_string(),
+        "blockhash": Pubkey::default().to_string(),
+        "parentSlot": 0,
+        "transactions": transactions,
+        "rewards": [],
+        "blockTime": block_time,
+        "blockHeight": null
+    }))
+    .unwrap()
+}
+
+#[test]
+fn test_in_memory_database() {
+    let mut database = Database::in_memory().unwrap();
+    let sender = Pubkey::new_unique();
+    database
+        .insert_batch(&[transfer_record(
+            sender,
+            Pubkey::new_unique(),
+            42,
+            5000,
+            "2024-07-28 21:11:50",
+            "in_memory_signature",
+            1,
+            0,
+        )])
+        .unwrap();
+    let records = stored_transactions(&mut database);
+    assert_eq!(1, records.len());
+    assert_eq!("in_memory_signature", records[0].signature);
+    assert_eq!(sender.to_string(), records[0].sender);
+
+    // The in-memory schema is created by the same code as the file schema.
+    let path = temp_db_path("test_in_memory_database");
+    drop(Database::with_path(&path).unwrap());
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    let mut stmt = connection
+        .prepare("SELECT name, IFNULL(sql, '') FROM sqlite_master")
+        .unwrap();
+    let schema: Vec<(String, String)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(
+        schema.len() as u64,
+        database.count("SELECT * FROM sqlite_master", &[]).unwrap()
+    );
+    for (name, sql) in schema {
+        let query = "SELECT * FROM sqlite_master WHERE name = ? AND IFNULL(sql, '') = ?";
+        assert_eq!(
+            1,
+            database.count(query, &[name.clone(), sql]).unwrap(),
+            "{}",
+            name
+        );
+    }
+}
+#[cfg(feature = "postgres")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_postgres_store() {
+    use solana_sdk::signature::{Keypair, Signer};
+    // Runs against the database in `TEST_DATABASE_URL`, and is skipped when it is not set.
+    let url = match env::var("TEST_DATABASE_URL") {
+        Ok(res) => res,
+        Err(_) => return,
+    };
+    let mut database = crate::postgres::PostgresStore::connect(&url).unwrap();
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let record = TransactionRecord {
+        sender: Pubkey::new_unique().to_string(),
+        receiver: Pubkey::new_unique().to_string(),
+        amount: 42,
+        net_change: -42,
+        timestamp: Some("2024-07-28 21:11:50".to_string()),
+        signature: format!("postgres_signature_{}", nanos),
+        signatures: vec![
+            format!("postgres_signature_{}", nanos),
+            "second_signature".to_string(),
+        ],
+        slot: 1,
+        block_height: None,
+        tx_index: 0,
+        fee: 5000,
+        program_id: None,
+        transfer_type: "sol".to_string(),
+        token_mint: None,
+        token_amount: None,
+        token_decimals: None,
+        status: "success".to_string(),
+        balance_changes: vec![],
+        logs: None,
+    };
+    database.insert_batch(&[record]).unwrap();
+    // Inserting the same signature again is a no-op.
+    let duplicate = TransactionRecord {
+        amount: 7,
+        net_change: -7,
+        ..database
+            .transaction(&format!("postgres_signature_{}", nanos))
+            .unwrap()
+            .unwrap()
+    };
+    database.insert_batch(&[duplicate]).unwrap();
+    let stored = database
+        .transaction(&format!("postgres_signature_{}", nanos))
+        .unwrap()
+        .unwrap();
+    assert_eq!(42, stored.amount);
+    assert_eq!(2, stored.signatures.len());
+    assert_eq!(Some("2024-07-28 21:11:50"), stored.timestamp.as_deref());
+
+    let context = database
+        .context(&format!("postgres_signature_{}", nanos), 0)
+        .unwrap()
+        .unwrap();
+    assert_eq!(1, context.len());
+    assert_eq!(42, context[0].amount);
+
+    database.set_last_processed_slot(u32::MAX as u64).unwrap();
+    database.set_last_processed_slot(1).unwrap();
+    assert!(database.last_processed_slot().unwrap() >= u32::MAX as u64);
+
+    // The filter queries of the REST API run on Postgres too. The keys are random, since the
+    // database keeps the transactions of the previous runs.
+    let (sender, receiver) = (Keypair::new().pubkey(), Keypair::new().pubkey());
+    let records: Vec<_> = [
+        (100, "2024-07-01 10:00:00"),
+        (200, "2024-07-01 11:30:00"),
+        (300, "2024-07-03 09:00:00"),
+    ]
+    .into_iter()
+    .enumerate()
+    .map(|(index, (amount, timestamp))| TransactionRecord {
+        logs: Some(vec![format!("Program log: transfer {}", index)]),
+        ..transfer_record(
+            sender,
+            receiver,
+            amount,
+            5000,
+            timestamp,
+            &format!("postgres_filter_{}_{}", nanos, index),
+            2,
+            index,
+        )
+    })
+    .collect();
+    database.insert_batch(&records).unwrap();
+    let filter = |query: &str| {
+        let info = restful_api::Info::parse(&format!("sender={}&{}", sender, query)).unwrap();
+        restful_api::filter_query(&info).unwrap()
+    };
+    let (query, params) = filter("min_amount=150&order_by=amount");
+    let amounts: Vec<i64> = database
+        .records(&query, &params)
+        .unwrap()
+        .iter()
+        .map(|record| record.amount)
+        .collect();
+    assert_eq!(vec![200, 300], amounts);
+    assert_eq!(2, database.count(&query, &params).unwrap());
+    let mut signatures = vec![];
+    database
+        .each_record(&query, &params, &mut |record| {
+            signatures.push(record.signature);
+            true
+        })
+        .unwrap();
+    assert_eq!(2, signatures.len());
+    let (query, params) = filter("order_by=amount&limit=1");
+    assert_eq!(100, database.records(&query, &params).unwrap()[0].amount);
+    let (query, params) = filter("log_contains=transfer%201");
+    let records = database.records(&query, &params).unwrap();
+    assert_eq!(1, records.len());
+    assert_eq!(200, records[0].amount);
+    let (query, params) = filter("");
+    let stats = database.sender_stats(&query, &params, 10).unwrap();
+    assert_eq!((3, 600), (stats[0].tx_count, stats[0].total_amount));
+    let buckets = database
+        .time_buckets(&query, &params, database::BucketInterval::Day, true)
+        .unwrap();
+    assert_eq!(
+        vec![
+            ("2024-07-01 00:00:00", 2),
+            ("2024-07-02 00:00:00", 0),
+            ("2024-07-03 00:00:00", 1)
+        ],
+        buckets
+            .iter()
+            .map(|bucket| (bucket.bucket_start.as_str(), bucket.tx_count))
+            .collect::<Vec<_>>()
+    );
+    let counterparties = database.counterparties(&sender.to_string(), 10).unwrap();
+    assert_eq!(receiver.to_string(), counterparties[0].counterparty);
+    assert_eq!(3, counterparties[0].tx_count);
+    database.ping().unwrap();
+
+    // The export copies the selected transactions into a SQLite file.
+    let export_path = temp_db_path(&format!("test_postgres_export_{}", nanos));
+    let (query, params) = filter("min_amount=150");
+    database.export(&export_path, &query, &params).unwrap();
+    let mut exported = Database::with_path(&export_path).unwrap();
+    let record = exported
+        .transaction(&format!("postgres_filter_{}_2", nanos))
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        (300, Some("2024-07-03 09:00:00")),
+        (record.amount, record.timestamp.as_deref())
+    );
+    assert_eq!(
+        None,
+        exported
+            .transaction(&format!("postgres_filter_{}_0", nanos))
+            .unwrap()
+    );
+}
+#[cfg(feature = "postgres")]
+#[test]
+fn test_postgres_web_server() {
+    use solana_sdk::signature::{Keypair, Signer};
+    // Runs against the database in `TEST_DATABASE_URL`, and is skipped when it is not set.
+    let url = match env::var("TEST_DATABASE_URL") {
+        Ok(res) => res,
+        Err(_) => return,
+    };
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let sender = Keypair::new().pubkey();
+    let signature = format!("postgres_web_{}", nanos);
+    tokio::runtime::Runtime::new().unwrap().block_on(async {
+        crate::postgres::PostgresStore::connect(&url)
+            .unwrap()
+            .insert_batch(&[transfer_record(
+                sender,
+                Pubkey::new_unique(),
+                100,
+                5000,
+                "2024-07-01 10:00:00",
+                &signature,
+                3,
+                0,
+            )])
+            .unwrap();
+    });
+
+    // The web server serves the transactions from Postgres, from its single-threaded runtime.
+    actix_web::rt::System::new().block_on(async {
+        let config = Config::from_sources(
+            None,
+            [
+                ("ws_url", "ws://127.0.0.1:1"),
+                ("rpc_url", "http://127.0.0.1:1"),
+                ("db_backend", "postgres"),
+                ("database_url", url.as_str()),
+                ("port", "0"),
+                ("timestamp_format", "rfc3339"),
+            ]
+            .map(|(key, value)| (key.to_string(), value.to_string())),
+        )
+        .unwrap();
+        let (_sender, shutdown) = shutdown::channel();
+        let server = restful_api::build_server(config, shutdown).unwrap();
+        let address = server.addrs[0];
+        let running = actix_web::rt::spawn(server.server);
+
+        let response = http_get(address, &format!("/transactions?sender={}", sender)).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains(&signature));
+        assert!(response.contains("2024-07-01T10:00:00Z"));
+        let response = http_get(address, "/health").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+        server.handle.stop(true).await;
+        running.await.unwrap().unwrap();
+    });
+}
+
+#[test]
+fn test_filters_use_indexes() {
+    let path = temp_db_path("test_filters_use_indexes");
+    drop(Database::with_path(&path).unwrap());
+    // Creating the indexes again on startup is a no-op.
+    drop(Database::with_path(&path).unwrap());
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    let plan = |filter: &str| -> String {
+        let info = web::Query::<restful_api::Info>::from_query(filter).unwrap();
+        let (query, params) = restful_api::filter_query(&info).unwrap();
+        connection
+            .query_row(
+                &format!("EXPLAIN QUERY PLAN {}", query),
+                rusqlite::params_from_iter(params),
+                |row| row.get::<usize, String>(3),
+            )
+            .unwrap()
+    };
+
+    assert!(plan("sender=abc").contains("USING INDEX transactions_sender"));
+    assert!(plan("receiver=abc").contains("USING INDEX transactions_receiver"));
+    assert!(plan("start_date=2024-07-28").contains("USING INDEX transactions_timestamp"));
+    assert!(plan("signature=abc").contains("USING INDEX transactions_signature"));
+}
+#[test]
+fn test_insert_batch() {
+    let mut database = Database::in_memory().unwrap();
+    let record = |index: u64| TransactionRecord {
+        sender: Pubkey::new_unique().to_string(),
+        receiver: Pubkey::new_unique().to_string(),
+        amount: index as i64,
+        net_change: -(index as i64),
+        timestamp: Some("2024-07-28 21:11:50".to_string()),
+        signature: format!("batch_{}", index),
+        signatures: vec![format!("batch_{}", index), format!("cosigner_{}", index)],
+        slot: 1,
+        block_height: None,
+        tx_index: index,
+        fee: 5000,
+        program_id: None,
+        transfer_type: "sol".to_string(),
+        token_mint: None,
+        token_amount: None,
+        token_decimals: None,
+        status: "success".to_string(),
+        balance_changes: vec![],
+        logs: None,
+    };
+    let records: Vec<TransactionRecord> = (0..1000).map(record).collect();
+
+    let start = Instant::now();
+    database.insert_batch(&records).unwrap();
+    println!("inserted 1000 records in {:?}", start.elapsed());
+    assert_eq!(records, stored_transactions(&mut database));
+    assert_eq!(
+        records[999],
+        database.transaction("batch_999").unwrap().unwrap()
+    );
+
+    // A failing record rolls back the whole batch, with the balance changes and logs.
+    let account = Pubkey::new_unique();
+    let mut failing = vec![record(1000), record(1001)];
+    failing[0].balance_changes = vec![(account, -5000)];
+    failing[0].logs = Some(vec!["Program log: batch".to_string()]);
+    failing[1].slot = u64::MAX;
+    assert_eq!(
+        Err(DatabaseError::InsertionError),
+        database.insert_batch(&failing)
+    );
+    assert!(database.transaction("batch_1000").unwrap().is_none());
+    assert!(database
+        .balance_changes(&account.to_string())
+        .unwrap()
+        .is_empty());
+    assert_eq!(None, database.logs("batch_1000"));
+    failing.truncate(1);
+    database.insert_batch(&failing).unwrap();
+    assert_eq!(
+        vec![("2024-07-28 21:11:50".to_string(), -5000)],
+        database.balance_changes(&account.to_string()).unwrap()
+    );
+    assert_eq!(
+        Some(vec!["Program log: batch".to_string()]),
+        database.logs("batch_1000")
+    );
+    assert_eq!(
+        1001,
+        database.count("SELECT * FROM transactions", &[]).unwrap()
+    );
+}
+
+#[test]
+fn test_dedup_cache_eviction() {
+    let mut cache = DedupCache::new(3, Duration::from_secs(60));
+    for root in 0..5u64 {
+        assert!(cache.insert(root));
+    }
+    assert_eq!(3, cache.len());
+    assert!(!cache.contains(&0));
+    assert!(!cache.contains(&1));
+    assert!(!cache.insert(4));
+    assert!(cache.insert(0));
+
+    let start = Instant::now();
+    let mut cache = DedupCache::new(10, Duration::from_secs(60));
+    assert!(cache.insert_at(1u64, start));
+    assert!(cache.insert_at(2u64, start + Duration::from_secs(30)));
+    assert!(!cache.insert_at(2u64, start + Duration::from_secs(61)));
+    assert!(!cache.contains(&1));
+    assert_eq!(1, cache.len());
+}
+
+#[test]
+fn test_transaction_context() {
+    let mut database = Database::in_memory().unwrap();
+    for (slot, tx_index) in [(11u64, 1usize), (10, 0), (12, 0), (10, 1), (11, 0), (13, 0)] {
+        database
+            .insert_batch(&[transfer_record(
+                Pubkey::new_unique(),
+                Pubkey::new_unique(),
+                1,
+                0,
+                "2024-07-28 21:11:50",
+                &format!("sig_{}_{}", slot, tx_index),
+                slot,
+                tx_index,
+            )])
+            .unwrap();
+    }
+
+    let context = database.context("sig_11_1", 2).unwrap().unwrap();
+    let expected = ["sig_10_1", "sig_11_0", "sig_11_1", "sig_12_0", "sig_13_0"];
+    let signatures: Vec<&str> = context
+        .iter()
+        .map(|record| record.signature.as_str())
+        .collect();
+    assert_eq!(expected.to_vec(), signatures);
+    assert_eq!(vec!["sig_11_1".to_string()], context[2].signatures);
+    assert!(database.context("unknown_signature", 2).unwrap().is_none());
+}
+
+#[test]
+fn test_insert_duplicate_signature() {
+    let mut database = Database::in_memory().unwrap();
+    for _ in 0..2 {
+        assert!(database
+            .insert_batch(&[transfer_record(
+                Pubkey::new_unique(),
+                Pubkey::new_unique(),
+                1,
+                0,
+                "2024-07-28 21:11:50",
+                "duplicate_signature",
+                1,
+                0,
+            )])
+            .is_ok());
+    }
+    assert_eq!(
+        1,
+        database.count("SELECT * FROM transactions", &[]).unwrap()
+    );
+}
+
+#[test]
+fn test_empty_balances() {
+    let mut database = Database::in_memory().unwrap();
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+    let block = || {
+        block_json(
+            Some(1722201110),
+            vec![
+                transaction_json(&["empty_balances"], &keys, &[], &[]),
+                transaction_json(&["valid_balances"], &keys, &[100, 0], &[40, 60]),
+            ],
+        )
+    };
+
+    let strict = BlockOptions::default();
+    assert_eq!(
+        Err(AggregatorError::TransactionParseError(Box::new(
+            AggregatorError::BalanceFetchError
+        ))),
+        aggregator::handle_block(block(), 1, &strict, &mut database)
+    );
+
+    let lenient = BlockOptions {
+        lenient_balances: true,
+        ..BlockOptions::default()
+    };
+    assert_eq!(
+        Ok(()),
+        aggregator::handle_block(block(), 1, &lenient, &mut database)
+    );
+    let records = stored_transactions(&mut database);
+    assert_eq!(1, records.len());
+    assert_eq!("valid_balances", records[0].signature);
+}
+
+#[test]
+fn test_lenient_transactions() {
+    let mut database = Database::in_memory().unwrap();
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+    let mut unparseable = transaction_json(&["unparseable"], &keys, &[100, 0], &[40, 60]);
+    unparseable["meta"] = json!(null);
+    let block = || {
+        block_json(
+            Some(1722201110),
+            vec![
+                transaction_json(&["first"], &keys, &[100, 0], &[40, 60]),
+                unparseable.clone(),
+                transaction_json(&["second"], &keys, &[100, 0], &[40, 60]),
+            ],
+        )
+    };
+
+    assert_eq!(
+        Err(AggregatorError::TransactionParseError(Box::new(
+            AggregatorError::MetaDataFetchError
+        ))),
+        aggregator::handle_block(block(), 1, &BlockOptions::default(), &mut database)
+    );
+    assert!(stored_transactions(&mut database).is_empty());
+
+    let parse_errors = crate::metrics::METRICS
+        .parse_errors
+        .load(std::sync::atomic::Ordering::Relaxed);
+    let lenient = BlockOptions {
+        lenient_transactions: true,
+        ..BlockOptions::default()
+    };
+    assert_eq!(
+        Ok(()),
+        aggregator::handle_block(block(), 1, &lenient, &mut database)
+    );
+    assert!(database.transaction("first").unwrap().is_some());
+    assert!(database.transaction("second").unwrap().is_some());
+    assert!(database.transaction("unparseable").unwrap().is_none());
+    assert!(
+        crate::metrics::METRICS
+            .parse_errors
+            .load(std::sync::atomic::Ordering::Relaxed)
+            > parse_errors
+    );
+}
+
+#[test]
+fn test_all_signatures_stored() {
+    let mut database = Database::in_memory().unwrap();
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+
+    let block = block_json(
+        Some(1722201110),
+        vec![transaction_json(&[], &keys, &[100, 0], &[40, 60])],
+    );
+    assert_eq!(
+        Err(AggregatorError::TransactionParseError(Box::new(
+            AggregatorError::SignatureFetchError
+        ))),
+        aggregator::handle_block(block, 1, &BlockOptions::default(), &mut database)
+    );
+    assert!(stored_transactions(&mut database).is_empty());
+
+    let block = block_json(
+        Some(1722201110),
+        vec![transaction_json(
+            &["first_signature", "second_signature"],
+            &keys,
+            &[100, 0],
+            &[40, 60],
+        )],
+    );
+    aggregator::handle_block(block, 1, &BlockOptions::default(), &mut database).unwrap();
+    let record = database.transaction("first_signature").unwrap().unwrap();
+    assert_eq!("first_signature", record.signature);
+    assert_eq!(
+        vec!["first_signature", "second_signature"],
+        record.signatures
+    );
+    let info = web::Query::<restful_api::Info>::from_query("").unwrap();
+    let (query, params) = restful_api::filter_query(&info).unwrap();
+    let records = database.records(&query, &params).unwrap();
+    assert_eq!(1, records.len());
+    assert_eq!(
+        vec!["first_signature", "second_signature"],
+        records[0].signatures
+    );
+}
+
+/// Builds a configuration connecting to a SQLite file with retries.
+#[allow(dead_code)]
+fn retries_config(path: &std::path::Path, max_attempts: u32, backoff_ms: u64) -> Config {
+    Config::from_sources(
+        None,
+        [
+            ("ws_url", "ws://127.0.0.1:1"),
+            ("rpc_url", "http://127.0.0.1:1"),
+            ("db_path", path.to_str().unwrap()),
+            ("db_connect_max_attempts", &max_attempts.to_string()),
+            ("db_connect_initial_backoff_ms", &backoff_ms.to_string()),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string())),
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_database_creates_directory() {
+    let dir = env::temp_dir().join(format!("test_database_dir_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let path = dir.join("nested").join("transactions.db");
+    let mut store = database::connect_with_retries(&retries_config(&path, 1, 0)).unwrap();
+    assert_eq!(None, store.last_processed_slot());
+    assert!(path.exists());
+
+    // A directory that can't be created fails every attempt.
+    let blocked = dir.join("file");
+    fs::write(&blocked, "").unwrap();
+    let start = Instant::now();
+    assert!(matches!(
+        database::connect_with_retries(&retries_config(&blocked.join("transactions.db"), 3, 10)),
+        Err(DatabaseError::ConnectError)
+    ));
+    assert!(start.elapsed() >= Duration::from_millis(30));
+
+    // The web server fails to start without a `database_url` to read Postgres from.
+    let mut config = retries_config(&path, 1, 0);
+    config.db_backend = DbBackend::Postgres;
+    assert!(restful_api::build_server(config, shutdown::channel().1).is_err());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_init_table_error() {
+    // A table of an older, incompatible schema makes the migration creating its index fail.
+    let path = temp_db_path("test_init_table_error");
+    rusqlite::Connection::open(&path)
+        .unwrap()
+        .execute("CREATE TABLE balance_changes (delta bigint)", [])
+        .unwrap();
+    assert!(matches!(
+        Database::with_path(&path),
+        Err(DatabaseError::MigrationError)
+    ));
+    let _ = fs::remove_file(&path);
+
+    // An index taking the name of a table makes creating the table fail.
+    rusqlite::Connection::open(&path)
+        .unwrap()
+        .execute_batch("CREATE TABLE other (a text); CREATE INDEX transactions ON other (a);")
+        .unwrap();
+    assert!(matches!(
+        Database::with_path(&path),
+        Err(DatabaseError::InitTableError)
+    ));
+    // The error isn't retried, since reconnecting can't fix the schema.
+    assert!(matches!(
+        database::connect_with_retries(&retries_config(&path, 3, 10_000)),
+        Err(DatabaseError::InitTableError)
+    ));
+}
+#[test]
+fn test_program_id() {
+    let mut database = Database::in_memory().unwrap();
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+        solana_sdk::compute_budget::id().to_string(),
+        solana_sdk::system_program::id().to_string(),
+    ];
+    let mut transaction = transaction_json(&["transfer"], &keys, &[100, 0, 1, 1], &[40, 60, 1, 1]);
+    transaction["transaction"]["message"]["instructions"] = json!([
+        { "programIdIndex": 2, "accounts": [], "data": "" },
+        { "programIdIndex": 3, "accounts": [0, 1], "data": "" },
+    ]);
+    let block = block_json(
+        Some(1722201110),
+        vec![
+            transaction,
+            transaction_json(&["no_instruction"], &keys[..2], &[100, 0], &[40, 60]),
+        ],
+    );
+    aggregator::handle_block(block, 1, &BlockOptions::default(), &mut database).unwrap();
+
+    let record = database.transaction("transfer").unwrap().unwrap();
+    assert_eq!(
+        Some(solana_sdk::system_program::id().to_string()),
+        record.program_id
+    );
+    assert_eq!(
+        None,
+        database
+            .transaction("no_instruction")
+            .unwrap()
+            .unwrap()
+            .program_id
+    );
+
+    let info = web::Query::<restful_api::Info>::from_query(&format!(
+        "program_id={}",
+        solana_sdk::system_program::id()
+    ))
+    .unwrap();
+    let (query, params) = restful_api::filter_query(&info).unwrap();
+    let records = database.records(&query, &params).unwrap();
+    assert_eq!(1, records.len());
+    assert_eq!("transfer", records[0].signature);
+    assert_eq!(
+        Some(solana_sdk::system_program::id().to_string()),
+        records[0].program_id
+    );
+
+    // Databases created before the column existed get it on startup.
+    let path = temp_db_path("test_program_id");
+    rusqlite::Connection::open(&path)
+        .unwrap()
+        .execute(
+            "CREATE TABLE transactions (sender text, receiver text, amount bigint, timestamp char(20), signature text, slot bigint, tx_index bigint, fee bigint)",
+            [],
+        )
+        .unwrap();
+    let mut database = Database::with_path(&path).unwrap();
+    let block = block_json(
+        Some(1722201110),
+        vec![transaction_json(
+            &["migrated"],
+            &keys[..2],
+            &[100, 0],
+            &[40, 60],
+        )],
+    );
+    aggregator::handle_block(block, 1, &BlockOptions::default(), &mut database).unwrap();
+    assert!(database.transaction("migrated").unwrap().is_some());
+}
+#[test]
+fn test_insert_failure_policy() {
+    let path = temp_db_path("test_insert_failure_policy");
+    let mut database = Database::with_path(&path).unwrap();
+    rusqlite::Connection::open(&path)
+        .unwrap()
+        .execute(
+            "CREATE TRIGGER reject_transaction BEFORE INSERT ON transactions WHEN NEW.signature = 'rejected' BEGIN SELECT RAISE(ABORT, 'rejected'); END",
+            [],
+        )
+        .unwrap();
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+    let block = || {
+        block_json(
+            Some(1722201110),
+            vec![
+                transaction_json(&["rejected"], &keys, &[100, 0], &[40, 60]),
+                transaction_json(&["accepted"], &keys, &[100, 0], &[40, 60]),
+            ],
+        )
+    };
+
+    // By default the whole block fails.
+    assert_eq!(
+        Err(AggregatorError::TransactionParseError(Box::new(
+            AggregatorError::DatabaseError(DatabaseError::InsertionError)
+        ))),
+        aggregator::handle_block(block(), 1, &BlockOptions::default(), &mut database)
+    );
+    assert!(stored_transactions(&mut database).is_empty());
+
+    let skip = BlockOptions {
+        insert_failure_policy: InsertFailurePolicy::Skip,
+        ..BlockOptions::default()
+    };
+    assert_eq!(
+        Ok(()),
+        aggregator::handle_block(block(), 1, &skip, &mut database)
+    );
+    let records = stored_transactions(&mut database);
+    assert_eq!(1, records.len());
+    assert_eq!("accepted", records[0].signature);
+    assert!(database.transaction("rejected").unwrap().is_none());
+    assert_eq!(1, database.balance_changes(&keys[1]).unwrap().len());
+
+    // The balance changes are inserted along with the transactions, so failing to insert them
+    // fails their transaction too.
+    rusqlite::Connection::open(&path)
+        .unwrap()
+        .execute(
+            "CREATE TRIGGER reject_balance_change BEFORE INSERT ON balance_changes WHEN NEW.signature = 'details' BEGIN SELECT RAISE(ABORT, 'rejected'); END",
+            [],
+        )
+        .unwrap();
+    let block = || {
+        block_json(
+            Some(1722201110),
+            vec![transaction_json(&["details"], &keys, &[100, 0], &[40, 60])],
+        )
+    };
+    assert_eq!(
+        Err(AggregatorError::TransactionParseError(Box::new(
+            AggregatorError::DatabaseError(DatabaseError::InsertionError)
+        ))),
+        aggregator::handle_block(block(), 2, &BlockOptions::default(), &mut database)
+    );
+    assert!(database.transaction("details").unwrap().is_none());
+    assert_eq!(
+        Ok(()),
+        aggregator::handle_block(block(), 2, &skip, &mut database)
+    );
+    assert!(database.transaction("details").unwrap().is_none());
+    assert_eq!(1, database.balance_changes(&keys[1]).unwrap().len());
+}
+#[test]
+fn test_dry_run() {
+    let mut database = Database::in_memory().unwrap();
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+    let block = block_json(
+        Some(1722201110),
+        vec![
+            transaction_json(&["first"], &keys, &[100, 0], &[40, 60]),
+            transaction_json(&["second"], &keys, &[100, 0], &[90, 10]),
+        ],
+    );
+    let config = Config::from_sources(
+        None,
+        [
+            ("ws_url", "ws://127.0.0.1:1"),
+            ("rpc_url", "http://127.0.0.1:1"),
+            ("DRY_RUN", "true"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string())),
+    )
+    .unwrap();
+    let options = BlockOptions::from_config(&config);
+    assert!(options.dry_run);
+
+    let logs = LogBuffer::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+    let result = tracing::subscriber::with_default(subscriber, || {
+        aggregator::handle_block(block, 1, &options, &mut database)
+    });
+    assert_eq!(Ok(()), result);
+    assert!(stored_transactions(&mut database).is_empty());
+    assert!(database.balance_changes(&keys[1]).unwrap().is_empty());
+
+    // The transactions were still parsed, and are summarized instead.
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    let line = logs
+        .lines()
+        .find(|line| line.contains("dry run, not inserting the transactions"))
+        .unwrap();
+    assert!(line.contains("transactions=2"));
+    assert!(line.contains(r#""signature":"first""#));
+    assert!(line.contains(r#""amount":4990"#));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_retention() {
+    use crate::retention;
+    let path = temp_db_path("test_retention");
+    let mut database = Database::with_path(&path).unwrap();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let old = aggregator::get_timestamp(now - 40 * 86_400);
+    let new = aggregator::get_timestamp(now - 86_400);
+    let account = Pubkey::new_unique();
+    for (timestamp, signature) in [(&old, "old"), (&new, "new")] {
+        database
+            .insert_batch(&[transfer_record(
+                account,
+                Pubkey::new_unique(),
+                1,
+                0,
+                timestamp,
+                signature,
+                1,
+                0,
+            )])
+            .unwrap();
+        database
+            .insert_balance_change(account, -1, timestamp, signature)
+            .unwrap();
+        database
+            .insert_logs(signature, &["Program log: hello".to_string()])
+            .unwrap();
+    }
+    // More old transactions than a batch holds.
+    rusqlite::Connection::open(&path)
+        .unwrap()
+        .execute(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < ?1) INSERT INTO transactions (sender, receiver, amount, timestamp, signature) SELECT 'a', 'b', 1, ?2, 'old_' || i FROM n",
+            rusqlite::params![retention::PRUNE_BATCH_SIZE + 500, old],
+        )
+        .unwrap();
+    drop(database);
+
+    assert_eq!(
+        aggregator::get_timestamp(now - 30 * 86_400),
+        retention::cutoff(now, 30)
+    );
+    let writer = std::sync::Arc::new(DatabaseWriter::with_path(&path).unwrap());
+    let (sender, shutdown) = shutdown::channel();
+    let task = tokio::spawn(retention::run(
+        writer.clone(),
+        30,
+        Duration::from_secs(3_600),
+        shutdown,
+    ));
+    let started = Instant::now();
+    loop {
+        let count = Database::read_only(&path)
+            .unwrap()
+            .count("SELECT * FROM transactions", &[])
+            .unwrap();
+        if count == 1 {
+            break;
+        }
+        assert!(started.elapsed() < Duration::from_secs(10));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    sender.send(true).unwrap();
+    task.await.unwrap();
+
+    let mut database = Database::with_path(&path).unwrap();
+    assert!(database.transaction("old").unwrap().is_none());
+    assert!(database.logs("old").is_none());
+    assert!(database.transaction("new").unwrap().is_some());
+    assert!(database.logs("new").is_some());
+    let changes = database.balance_changes(&account.to_string()).unwrap();
+    assert_eq!(vec![(new.clone(), -1)], changes);
+    // The space of the deleted rows is now released incrementally.
+    let auto_vacuum: i64 = rusqlite::Connection::open(&path)
+        .unwrap()
+        .query_row("PRAGMA auto_vacuum", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(2, auto_vacuum);
+    let writer = DatabaseWriter::with_path(&path).unwrap();
+    assert_eq!(0, retention::prune(&writer, &new).await.unwrap());
+}
+#[test]
+fn test_token_transfer() {
+    let mut database = Database::in_memory().unwrap();
+    let mint = Pubkey::new_unique().to_string();
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+        "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA".to_string(),
+    ];
+    let token_balance = |account_index: u8, amount: &str| {
+        json!({
+            "accountIndex": account_index,
+            "mint": mint,
+            "uiTokenAmount": {
+                "uiAmount": null,
+                "decimals": 6,
+                "amount": amount,
+                "uiAmountString": amount
+            }
+        })
+    };
+    let mut transaction = transaction_json(&["token"], &keys, &[100, 1, 1, 1], &[95, 1, 1, 1]);
+    // A `transferChecked` instruction, whose data starts with 12.
+    transaction["transaction"]["message"]["instructions"] = json!([
+        { "programIdIndex": 3, "accounts": [1, 2, 0], "data": bs58::encode([12]).into_string() },
+    ]);
+    transaction["meta"]["preTokenBalances"] =
+        json!([token_balance(1, "2500000"), token_balance(2, "0")]);
+    transaction["meta"]["postTokenBalances"] =
+        json!([token_balance(1, "1000000"), token_balance(2, "1500000")]);
+
+    // Token balances changing without a transfer instruction, e.g. a burn, are not transfers.
+    let mut burn = transaction_json(&["burn"], &keys, &[100, 1, 1, 1], &[95, 1, 1, 1]);
+    burn["transaction"]["message"]["instructions"] = json!([
+        { "programIdIndex": 3, "accounts": [1, 0], "data": bs58::encode([8]).into_string() },
+    ]);
+    burn["meta"]["preTokenBalances"] = json!([token_balance(1, "2500000")]);
+    burn["meta"]["postTokenBalances"] = json!([token_balance(1, "1000000")]);
+
+    let block = block_json(
+        Some(1722201110),
+        vec![
+            transaction,
+            burn,
+            transaction_json(&["sol"], &keys[..2], &[100, 0], &[40, 60]),
+        ],
+    );
+    aggregator::handle_block(block, 1, &BlockOptions::default(), &mut database).unwrap();
+
+    let record = database.transaction("token").unwrap().unwrap();
+    assert_eq!("token", record.transfer_type);
+    assert_eq!(Some(mint.clone()), record.token_mint);
+    assert_eq!(Some("1500000".to_string()), record.token_amount);
+    assert_eq!(Some(6), record.token_decimals);
+    for signature in ["burn", "sol"] {
+        let record = database.transaction(signature).unwrap().unwrap();
+        assert_eq!("sol", record.transfer_type);
+        assert_eq!(None, record.token_mint);
+        assert_eq!(None, record.token_amount);
+        assert_eq!(None, record.token_decimals);
+    }
+
+    let info = web::Query::<restful_api::Info>::from_query(&format!("mint={}", mint)).unwrap();
+    let (query, params) = restful_api::filter_query(&info).unwrap();
+    let records = database.records(&query, &params).unwrap();
+    assert_eq!(1, records.len());
+    assert_eq!("token", records[0].signature);
+    assert_eq!("token", records[0].transfer_type);
+    assert_eq!(Some(mint.to_string()), records[0].token_mint);
+    assert_eq!(Some("1500000".to_string()), records[0].token_amount);
+    assert_eq!(Some(6), records[0].token_decimals);
+}
+
+#[test]
+fn test_vote_transactions_skipped() {
+    let mut database = Database::in_memory().unwrap();
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+    let mut vote = transaction_json(
+        &["vote"],
+        &[
+            keys[0].clone(),
+            keys[1].clone(),
+            "Vote111111111111111111111111111111111111111".to_string(),
+        ],
+        &[100, 0, 1],
+        &[95, 0, 1],
+    );
+    vote["transaction"]["message"]["instructions"] = json!([
+        { "programIdIndex": 2, "accounts": [1, 0], "data": "" }
+    ]);
+    let block = || {
+        block_json(
+            Some(1722201110),
+            vec![
+                vote.clone(),
+                transaction_json(&["transfer"], &keys, &[100, 0], &[40, 60]),
+            ],
+        )
+    };
+
+    aggregator::handle_block(block(), 1, &BlockOptions::default(), &mut database).unwrap();
+    let records = stored_transactions(&mut database);
+    assert_eq!(1, records.len());
+    assert_eq!("transfer", records[0].signature);
+
+    let options = BlockOptions {
+        include_votes: true,
+        ..BlockOptions::default()
+    };
+    aggregator::handle_block(block(), 1, &options, &mut database).unwrap();
+    assert!(database.transaction("vote").unwrap().is_some());
+}
+
+#[test]
+fn test_program_ids_filter() {
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+    let (tracked, other) = (
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    );
+    // Each transaction invokes a program, at the index following the two accounts.
+    let invoking = |signature: &str, program: &str| {
+        let mut transaction = transaction_json(
+            &[signature],
+            &[keys[0].clone(), keys[1].clone(), program.to_string()],
+            &[100, 0, 1],
+            &[40, 60, 1],
+        );
+        transaction["transaction"]["message"]["instructions"] = json!([
+            { "programIdIndex": 2, "accounts": [0, 1], "data": "" }
+        ]);
+        transaction
+    };
+    let block = || {
+        block_json(
+            Some(1722201110),
+            vec![
+                invoking("tracked_1", &tracked),
+                invoking("other", &other),
+                invoking("tracked_2", &tracked),
+                invoking("vote", "Vote111111111111111111111111111111111111111"),
+                transaction_json(&["no_instruction"], &keys, &[100, 0], &[40, 60]),
+            ],
+        )
+    };
+    let config = Config::from_sources(
+        None,
+        [
+            ("ws_url", "ws://127.0.0.1:1"),
+            ("rpc_url", "http://127.0.0.1:1"),
+            ("program_ids", &format!(" {} ,", tracked)),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string())),
+    )
+    .unwrap();
+    let options = BlockOptions::from_config(&config);
+    assert_eq!(vec![tracked.clone()], options.program_ids);
+
+    let mut database = Database::in_memory().unwrap();
+    aggregator::handle_block(block(), 1, &options, &mut database).unwrap();
+    let records = stored_transactions(&mut database);
+    assert_eq!(2, records.len());
+    assert_eq!("tracked_1", records[0].signature);
+    assert_eq!("tracked_2", records[1].signature);
+
+    // Vote transactions stay skipped even when the vote program is tracked.
+    let options = BlockOptions {
+        program_ids: vec![
+            other.clone(),
+            "Vote111111111111111111111111111111111111111".to_string(),
+        ],
+        ..BlockOptions::default()
+    };
+    let mut database = Database::in_memory().unwrap();
+    aggregator::handle_block(block(), 1, &options, &mut database).unwrap();
+    let records = stored_transactions(&mut database);
+    assert_eq!(1, records.len());
+    assert_eq!("other", records[0].signature);
+
+    let mut database = Database::in_memory().unwrap();
+    aggregator::handle_block(block(), 1, &BlockOptions::default(), &mut database).unwrap();
+    assert_eq!(
+        4,
+        database.count("SELECT * FROM transactions", &[]).unwrap()
+    );
+}
+
+#[test]
+fn test_min_signatures() {
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+    let block = || {
+        block_json(
+            Some(1722201110),
+            vec![
+                transaction_json(&["single"], &keys, &[100, 0], &[40, 60]),
+                transaction_json(&["multisig", "cosigner"], &keys, &[100, 0], &[40, 60]),
+            ],
+        )
+    };
+
+    let mut database = Database::in_memory().unwrap();
+    let options = BlockOptions {
+        min_signatures: 2,
+        ..BlockOptions::default()
+    };
+    aggregator::handle_block(block(), 1, &options, &mut database).unwrap();
+    assert!(database.transaction("single").unwrap().is_none());
+    assert!(database.transaction("multisig").unwrap().is_some());
+
+    let mut database = Database::in_memory().unwrap();
+    aggregator::handle_block(block(), 1, &BlockOptions::default(), &mut database).unwrap();
+    let info = web::Query::<restful_api::Info>::from_query("min_signatures=2").unwrap();
+    let (query, params) = restful_api::filter_query(&info).unwrap();
+    let records = database.records(&query, &params).unwrap();
+    assert_eq!(1, records.len());
+    assert_eq!("multisig", records[0].signature);
+    assert_eq!(vec!["multisig", "cosigner"], records[0].signatures);
+    let info = web::Query::<restful_api::Info>::from_query("min_signatures=1").unwrap();
+    let (query, params) = restful_api::filter_query(&info).unwrap();
+    assert_eq!(2, database.records(&query, &params).unwrap().len());
+}
+
+#[test]
+fn test_query_builder() {
+    use crate::query_builder::QueryBuilder;
+    let select = "SELECT * FROM transactions";
+    let (query, params) = QueryBuilder::new(select).build();
+    assert_eq!(select, query);
+    assert!(params.is_empty());
+
+    let (query, params) = QueryBuilder::new(select)
+        .optional("fee >= ?", Some(5000))
+        .optional("fee <= ?", None::<i64>)
+        .any_of("sender", &[])
+        .build();
+    assert_eq!("SELECT * FROM transactions WHERE fee >= ?", query);
+    assert_eq!(vec!["5000"], params);
+
+    let (query, params) = QueryBuilder::new(select)
+        .condition("timestamp >= ?", ["2024-07-28 00:00:00"])
+        .any_of("sender", &["a".to_string(), "b".to_string()])
+        .condition("COALESCE(status, ?) = ?", ["success", "failed"])
+        .order_by("slot")
+        .limit(10)
+        .build();
+    assert_eq!(
+        "SELECT * FROM transactions WHERE timestamp >= ? AND sender IN (?, ?) AND COALESCE(status, ?) = ? ORDER BY slot LIMIT ?",
+        query
+    );
+    assert_eq!(
+        vec!["2024-07-28 00:00:00", "a", "b", "success", "failed", "10"],
+        params
+    );
+
+    // The offset is bound after the limit, and needs one.
+    let (query, params) = QueryBuilder::new(select).limit(10).offset(20).build();
+    assert_eq!("SELECT * FROM transactions LIMIT ? OFFSET ?", query);
+    assert_eq!(vec!["10", "20"], params);
+    assert_eq!(select, QueryBuilder::new(select).offset(20).build().0);
+}
+
+#[test]
+fn test_since_cursor() {
+    let mut database = Database::in_memory().unwrap();
+    let insert = |database: &mut Database, timestamp: &str, signature: &str| {
+        database
+            .insert_batch(&[transfer_record(
+                Pubkey::new_unique(),
+                Pubkey::new_unique(),
+                1,
+                0,
+                timestamp,
+                signature,
+                1,
+                0,
+            )])
+            .unwrap();
+    };
+    // Pairs of transactions share a timestamp, so the signature must break the ties.
+    for index in 0..6 {
+        let timestamp = format!("2024-07-28 21:11:{:02}", 10 + index / 2);
+        insert(&mut database, &timestamp, &format!("tx_{}", index));
+    }
+    let page = |database: &mut Database, query: &str| -> Vec<String> {
+        let info = restful_api::Info::parse(query).unwrap();
+        let (query, params) = restful_api::filter_query(&info).unwrap();
+        database
+            .records(&query, &params)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.signature)
+            .collect()
+    };
+
+    // Offset pagination repeats a transaction when an older one is inserted between pages.
+    let offset_page = |database: &mut Database, offset: usize| -> Vec<String> {
+        let query = format!(
+            "SELECT transactions.*, transaction_signatures.signatures FROM transactions LEFT JOIN transaction_signatures USING (signature) ORDER BY timestamp, signature LIMIT 4 OFFSET {}",
+            offset
+        );
+        database
+            .records(&query, &[])
+            .unwrap()
+            .into_iter()
+            .map(|record| record.signature)
+            .collect()
+    };
+    let first = offset_page(&mut database, 0);
+    insert(&mut database, "2024-07-28 21:11:00", "old_0");
+    let second = offset_page(&mut database, 4);
+    assert!(second.contains(&first[3]));
+
+    // The cursor neither repeats nor skips any, and picks up the newer transactions.
+    let mut seen = page(&mut database, "order_by=timestamp&limit=4");
+    assert_eq!(vec!["old_0", "tx_0", "tx_1", "tx_2"], seen);
+    insert(&mut database, "2024-07-28 21:11:01", "old_1");
+    insert(&mut database, "2024-07-28 21:11:12", "tx_3b");
+    insert(&mut database, "2024-07-28 21:11:59", "new");
+    loop {
+        let since = seen.last().unwrap().clone();
+        let next = page(&mut database, &format!("since={}&limit=4", since));
+        if next.is_empty() {
+            break;
+        }
+        seen.extend(next);
+    }
+    assert_eq!(
+        vec!["old_0", "tx_0", "tx_1", "tx_2", "tx_3", "tx_3b", "tx_4", "tx_5", "new"],
+        seen
+    );
+
+    // The cursor may be a timestamp and a signature, which doesn't need to be stored.
+    assert_eq!(
+        vec!["tx_3b", "tx_4"],
+        page(&mut database, "since=2024-07-28 21:11:11,tx_3&limit=2")
+    );
+    assert_eq!(
+        vec!["tx_2", "tx_3"],
+        page(&mut database, "since=2024-07-28 21:11:11,a&limit=2")
+    );
+    // Other orders tie-break by signature too.
+    assert_eq!(
+        vec!["tx_1", "tx_2"],
+        page(&mut database, "order_by=signature&since=tx_0&limit=2")
+    );
+    for query in [
+        "since=yesterday,tx_0",
+        "order_by=amount&since=2024-07-28 21:11:11,tx_3",
+    ] {
+        let info = restful_api::Info::parse(query).unwrap();
+        assert!(restful_api::filter_query(&info).is_err());
+    }
+}
+
+#[test]
+fn test_filter_values_are_bound() {
+    let sender = Pubkey::new_unique();
+    let mut database = Database::in_memory().unwrap();
+    database
+        .insert_batch(&[transfer_record(
+            sender,
+            Pubkey::new_unique(),
+            1,
+            0,
+            "2024-07-28 21:11:50",
+            "bound_signature",
+            1,
+            0,
+        )])
+        .unwrap();
+    let mut signatures = |query: &str| {
+        let info = restful_api::Info::parse(query).unwrap();
+        let (query, params) = restful_api::filter_query(&info).unwrap();
+        database.records(&query, &params).unwrap().len()
+    };
+    // A double-quoted value naming a column used to compare the column with itself.
+    assert_eq!(0, signatures("signature=signature"));
+    assert_eq!(1, signatures("signature=bound_signature"));
+    assert_eq!(1, signatures("start_date=2024-07-28&end_date=2024-07-28"));
+    assert_eq!(
+        1,
+        signatures(&format!("sender={}&min_amount=1&max_amount=1", sender))
+    );
+}
+
+#[test]
+fn test_fee_and_status_filters() {
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+    // Failed transactions only pay their fee, so their balances change by the fee alone.
+    let transaction = |signature: &str, fee: u64, failed: bool| {
+        let post = if failed {
+            [100_000 - fee, 0]
+        } else {
+            [100_000 - fee - 60, 60]
+        };
+        let mut transaction = transaction_json(&[signature], &keys, &[100_000, 0], &post);
+        transaction["meta"]["fee"] = json!(fee);
+        if failed {
+            let err = json!({ "InstructionError": [0, { "Custom": 1 }] });
+            transaction["meta"]["err"] = err.clone();
+            transaction["meta"]["status"] = json!({ "Err": err });
+        }
+        transaction
+    };
+    let block = block_json(
+        Some(1722201110),
+        vec![
+            transaction("failed_high", 20_000, true),
+            transaction("failed_low", 5_000, true),
+            transaction("success_high", 20_000, false),
+            transaction("success_low", 5_000, false),
+        ],
+    );
+    let mut database = Database::in_memory().unwrap();
+    aggregator::handle_block(block, 1, &BlockOptions::default(), &mut database).unwrap();
+    assert_eq!(
+        "failed",
+        database.transaction("failed_high").unwrap().unwrap().status
+    );
+    assert_eq!(
+        "success",
+        database
+            .transaction("success_high")
+            .unwrap()
+            .unwrap()
+            .status
+    );
+
+    let mut signatures = |query: &str| {
+        let info = restful_api::Info::parse(query).unwrap();
+        let (query, params) = restful_api::filter_query(&info).unwrap();
+        let mut signatures: Vec<String> = database
+            .records(&query, &params)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.signature)
+            .collect();
+        signatures.sort();
+        signatures
+    };
+    assert_eq!(
+        vec!["failed_high"],
+        signatures("status=failed&min_fee=10000")
+    );
+    assert_eq!(
+        vec!["failed_low"],
+        signatures("status=failed&max_fee=10000")
+    );
+    assert_eq!(
+        vec!["failed_high", "success_high"],
+        signatures("min_fee=10000&max_fee=20000")
+    );
+    assert_eq!(
+        vec!["success_high", "success_low"],
+        signatures("status=success")
+    );
+    assert!(signatures("status=failed&min_fee=10000&sender=unknown").is_empty());
+
+    let info = restful_api::Info::parse("status=pending").unwrap();
+    assert!(restful_api::filter_query(&info).is_err());
+}
+
+#[test]
+fn test_block_height() {
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+    let block = |signatures: &[&str], height: Option<u64>| {
+        let mut block = block_json(
+            Some(1722201110),
+            signatures
+                .iter()
+                .map(|signature| {
+                    transaction_json(&[signature], &keys, &[100_000, 0], &[94_940, 60])
+                })
+                .collect(),
+        );
+        block.block_height = height;
+        block
+    };
+    let mut database = Database::in_memory().unwrap();
+    let options = BlockOptions::default();
+    aggregator::handle_block(block(&["h1"], Some(1_000)), 1_100, &options, &mut database).unwrap();
+    aggregator::handle_block(block(&["h2"], Some(1_001)), 1_101, &options, &mut database).unwrap();
+    aggregator::handle_block(
+        block(&["h3", "h4"], Some(1_003)),
+        1_104,
+        &options,
+        &mut database,
+    )
+    .unwrap();
+    aggregator::handle_block(block(&["unknown"], None), 1_105, &options, &mut database).unwrap();
+    assert_eq!(
+        Some(1_000),
+        database.transaction("h1").unwrap().unwrap().block_height
+    );
+    assert_eq!(
+        Some(1_003),
+        database.transaction("h4").unwrap().unwrap().block_height
+    );
+    assert_eq!(
+        None,
+        database
+            .transaction("unknown")
+            .unwrap()
+            .unwrap()
+            .block_height
+    );
+    assert_eq!(
+        json!(1_001),
+        database.transaction("h2").unwrap().unwrap().to_json()["block_height"]
+    );
+    assert_eq!(
+        Value::Null,
+        database.transaction("unknown").unwrap().unwrap().to_json()["block_height"]
+    );
+
+    let mut signatures = |query: &str| {
+        let info = restful_api::Info::parse(query).unwrap();
+        let (query, params) = restful_api::filter_query(&info).unwrap();
+        let mut signatures: Vec<String> = database
+            .records(&query, &params)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.signature)
+            .collect();
+        signatures.sort();
+        signatures
+    };
+    assert_eq!(vec!["h2", "h3", "h4"], signatures("min_block_height=1001"));
+    assert_eq!(vec!["h1", "h2"], signatures("max_block_height=1002"));
+    assert_eq!(
+        vec!["h2"],
+        signatures("min_block_height=1001&max_block_height=1002")
+    );
+    assert_eq!(vec!["h1", "h2", "h3", "h4", "unknown"], signatures(""));
+    assert!(restful_api::Info::parse("min_block_height=-1").is_err());
+}
+
+#[test]
+fn test_versioned_transaction_loaded_addresses() {
+    let payer = Pubkey::new_unique().to_string();
+    let system_program = solana_sdk::system_program::id().to_string();
+    let receiver = Pubkey::new_unique().to_string();
+    let readonly = Pubkey::new_unique().to_string();
+    // The receiver is only listed in the lookup table, after the static account keys.
+    let mut transaction = transaction_json(
+        &["versioned"],
+        &[payer.clone(), system_program],
+        &[100_000, 1, 0, 7],
+        &[94_940, 1, 60, 7],
+    );
+    transaction["version"] = json!(0);
+    transaction["transaction"]["message"]["addressTableLookups"] = json!([{
+        "accountKey": Pubkey::new_unique().to_string(),
+        "writableIndexes": [0],
+        "readonlyIndexes": [1]
+    }]);
+    transaction["meta"]["loadedAddresses"] = json!({
+        "writable": [receiver.clone()],
+        "readonly": [readonly]
+    });
+    let block = block_json(Some(1722201110), vec![transaction]);
+
+    let mut database = Database::in_memory().unwrap();
+    aggregator::handle_block(block, 1, &BlockOptions::default(), &mut database).unwrap();
+    let record = database.transaction("versioned").unwrap().unwrap();
+    assert_eq!(payer, record.sender);
+    assert_eq!(receiver, record.receiver);
+    assert_eq!(60, record.amount);
+    assert_eq!(-60, record.net_change);
+
+    // The allowlist matches the loaded addresses too.
+    let options = BlockOptions {
+        allowlist: vec![receiver.clone()],
+        ..BlockOptions::default()
+    };
+    let mut transaction = transaction_json(
+        &["versioned_tracked"],
+        &[payer, solana_sdk::system_program::id().to_string()],
+        &[100_000, 1, 0],
+        &[94_940, 1, 60],
+    );
+    transaction["version"] = json!(0);
+    transaction["meta"]["loadedAddresses"] = json!({ "writable": [receiver], "readonly": [] });
+    let block = block_json(Some(1722201110), vec![transaction]);
+    let mut database = Database::in_memory().unwrap();
+    aggregator::handle_block(block, 1, &options, &mut database).unwrap();
+    assert!(database.transaction("versioned_tracked").unwrap().is_some());
+}
+
+#[test]
+fn test_base64_encoded_transaction() {
+    use solana_sdk::{signature::Signature, system_instruction, transaction::Transaction};
+    use solana_transaction_status::{Encodable, UiTransactionEncoding};
+    let mut database = Database::in_memory().unwrap();
+    let sender = Pubkey::new_unique();
+    let receiver = Pubkey::new_unique();
+    let transaction = Transaction::new_with_payer(
+        &[system_instruction::transfer(&sender, &receiver, 60)],
+        Some(&sender),
+    );
+    let mut encoded = transaction_json(&[], &[], &[10_000, 0, 1], &[4_940, 60, 1]);
+    encoded["transaction"] =
+        serde_json::to_value(transaction.encode(UiTransactionEncoding::Base64)).unwrap();
+    let mut corrupted = encoded.clone();
+    corrupted["transaction"][0] = json!("not base64");
+
+    let block = block_json(Some(1722201110), vec![corrupted]);
+    assert_eq!(
+        Err(AggregatorError::TransactionParseError(Box::new(
+            AggregatorError::TransactionDecodeError
+        ))),
+        aggregator::handle_block(block, 1, &BlockOptions::default(), &mut database)
+    );
+
+    let block = block_json(Some(1722201110), vec![encoded]);
+    aggregator::handle_block(block, 1, &BlockOptions::default(), &mut database).unwrap();
+    let record = database
+        .transaction(&Signature::default().to_string())
+        .unwrap()
+        .unwrap();
+    assert_eq!(sender.to_string(), record.sender);
+    assert_eq!(receiver.to_string(), record.receiver);
+    assert_eq!(60, record.amount);
+}
+
+#[test]
+fn test_get_block_params() {
+    use solana_sdk::commitment_config::CommitmentLevel;
+    assert_eq!(
+        json!([42, {
+            "encoding": "json",
+            "transactionDetails": "full",
+            "commitment": "finalized",
+            "maxSupportedTransactionVersion": 0
+        }]),
+        aggregator::get_block_params(42, CommitmentLevel::Finalized)
+    );
+    assert_eq!(
+        "confirmed",
+        aggregator::get_block_params(42, CommitmentLevel::Confirmed)[1]["commitment"]
+    );
+}
+
+#[test]
+fn test_last_processed_slot() {
+    let path = temp_db_path("test_last_processed_slot");
+    let mut database = Database::with_path(&path).unwrap();
+    assert_eq!(None, database.last_processed_slot());
+    database.set_last_processed_slot(105).unwrap();
+    assert_eq!(Some(105), database.last_processed_slot());
+    database.set_last_processed_slot(103).unwrap();
+    assert_eq!(Some(105), database.last_processed_slot());
+
+    let mut database = Database::with_path(&path).unwrap();
+    assert_eq!(Some(105), database.last_processed_slot());
+}
+#[test]
+fn test_handle_block_update() {
+    let mut database = Database::in_memory().unwrap();
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+    let block = serde_json::to_value(block_json(
+        Some(1722201110),
+        vec![transaction_json(
+            &["subscribed"],
+            &keys,
+            &[100, 0],
+            &[40, 60],
+        )],
+    ))
+    .unwrap();
+    let update = |slot: u64, block: Value, err: Value| {
+        serde_json::from_value::<solana_client::rpc_response::RpcBlockUpdate>(json!({
+            "slot": slot,
+            "block": block,
+            "err": err,
+        }))
+        .unwrap()
+    };
+
+    aggregator::handle_block_update(
+        update(7, block.clone(), Value::Null),
+        &BlockOptions::default(),
+        &mut database,
+    )
+    .unwrap();
+    let record = database.transaction("subscribed").unwrap().unwrap();
+    assert_eq!(keys[0], record.sender);
+    assert_eq!(7, record.slot);
+    assert_eq!(Some(7), database.last_processed_slot());
+
+    // Notifications without a block are ignored.
+    aggregator::handle_block_update(
+        update(8, Value::Null, Value::Null),
+        &BlockOptions::default(),
+        &mut database,
+    )
+    .unwrap();
+    assert_eq!(Some(7), database.last_processed_slot());
+
+    assert!(matches!(
+        aggregator::handle_block_update(
+            update(9, block, json!("BlockStoreError")),
+            &BlockOptions::default(),
+            &mut database,
+        ),
+        Err(AggregatorError::BlockFetchError(_))
+    ));
+    assert_eq!(Some(7), database.last_processed_slot());
+}
+
+#[test]
+fn test_lenient_block_time() {
+    let mut database = Database::in_memory().unwrap();
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+    let block = || {
+        block_json(
+            None,
+            vec![transaction_json(&["timeless"], &keys, &[100, 0], &[40, 60])],
+        )
+    };
+
+    assert_eq!(
+        Err(AggregatorError::TimeFetchError),
+        aggregator::handle_block(block(), 1, &BlockOptions::default(), &mut database)
+    );
+    assert!(database.transaction("timeless").unwrap().is_none());
+
+    let options = BlockOptions {
+        lenient_block_time: true,
+        ..Default::default()
+    };
+    aggregator::handle_block(block(), 1, &options, &mut database).unwrap();
+    let record = database.transaction("timeless").unwrap().unwrap();
+    assert_eq!(keys[0], record.sender);
+    assert_eq!(None, record.timestamp);
+    assert!(database.balance_changes(&keys[0]).unwrap().is_empty());
+
+    // Transactions without a timestamp are kept by retention.
+    assert_eq!(0, database.prune("2100-01-01 00:00:00", 10).unwrap());
+
+    // The transactions fetched by account follow the same rule.
+    let confirmed = || {
+        let mut transaction = transaction_json(&["timeless_account"], &keys, &[100, 0], &[40, 60]);
+        transaction["slot"] = json!(2);
+        serde_json::from_value(transaction).unwrap()
+    };
+    let block_heights = std::collections::HashMap::new();
+    assert_eq!(
+        Err(AggregatorError::TimeFetchError),
+        aggregator::handle_account_transactions(
+            vec![confirmed()],
+            &block_heights,
+            &BlockOptions::default(),
+            &mut database,
+        )
+    );
+    assert!(database.transaction("timeless_account").unwrap().is_none());
+    aggregator::handle_account_transactions(
+        vec![confirmed()],
+        &block_heights,
+        &options,
+        &mut database,
+    )
+    .unwrap();
+    let record = database.transaction("timeless_account").unwrap().unwrap();
+    assert_eq!(None, record.timestamp);
+}
+
+#[test]
+fn test_backfill_slots() {
+    assert_eq!(
+        (101..105).collect::<Vec<u64>>(),
+        aggregator::backfill_slots(100, 105, 100).collect::<Vec<u64>>()
+    );
+    assert_eq!(
+        (103..105).collect::<Vec<u64>>(),
+        aggregator::backfill_slots(100, 105, 2).collect::<Vec<u64>>()
+    );
+    assert_eq!(0, aggregator::backfill_slots(105, 105, 100).count());
+    assert_eq!(0, aggregator::backfill_slots(110, 105, 100).count());
+}
+
+#[test]
+fn test_retry_exhausted_attempts() {
+    let mut database = Database::in_memory().unwrap();
+
+    let mut calls = 0;
+    let (result, attempts) = aggregator::retry(3, || {
+        calls += 1;
+        Err::<(), _>(AggregatorError::BlockFetchError(SourceError::new(
+            "timeout",
+        )))
+    });
+    assert!(result.is_err());
+    assert_eq!(3, attempts);
+    assert_eq!(3, calls);
+    aggregator::record_attempts(&mut database, 7, "get_block", attempts, result.is_err());
+    assert_eq!(Some((3, true)), database.slot_attempts(7, "get_block"));
+
+    let mut calls = 0;
+    let (result, attempts) = aggregator::retry(3, || {
+        calls += 1;
+        if calls < 2 {
+            Err(AggregatorError::BlockFetchError(SourceError::new(
+                "timeout",
+            )))
+        } else {
+            Ok(())
+        }
+    });
+    assert!(result.is_ok());
+    aggregator::record_attempts(&mut database, 8, "get_block", attempts, result.is_err());
+    assert_eq!(Some((2, false)), database.slot_attempts(8, "get_block"));
+
+    let (result, attempts) = aggregator::retry(3, || Ok::<(), AggregatorError>(()));
+    aggregator::record_attempts(&mut database, 9, "get_block", attempts, result.is_err());
+    assert_eq!(None, database.slot_attempts(9, "get_block"));
+}
+
+#[test]
+fn test_fee_excluded_from_amount() {
+    let mut database = Database::in_memory().unwrap();
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+    let block = block_json(
+        Some(1722201110),
+        vec![transaction_json(
+            &["fee_signature"],
+            &keys,
+            &[100_000, 0],
+            &[40_000, 55_000],
+        )],
+    );
+    aggregator::handle_block(block, 1, &BlockOptions::default(), &mut database).unwrap();
+    let records = stored_transactions(&mut database);
+    assert_eq!(1, records.len());
+    assert_eq!(55000, records[0].amount);
+    assert_eq!(5000, records[0].fee);
+}
+
+#[test]
+fn test_amount_sign() {
+    let mut database = Database::in_memory().unwrap();
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+    // (signature, sender index, pre balances, post balances, amount, net change, direction),
+    // with a fee of 5000 paid by the first account.
+    let cases = [
+        (
+            "outgoing",
+            0,
+            [100_000, 0],
+            [40_000, 55_000],
+            55_000,
+            -55_000,
+            "outgoing",
+        ),
+        (
+            "incoming",
+            1,
+            [100_000, 0],
+            [40_000, 55_000],
+            55_000,
+            55_000,
+            "incoming",
+        ),
+        (
+            "refund",
+            0,
+            [100_000, 50_000],
+            [145_000, 0],
+            50_000,
+            50_000,
+            "incoming",
+        ),
+        ("fee_only", 0, [100_000, 0], [95_000, 0], 0, 0, "none"),
+    ];
+    for (signature, sender_index, pre, post, amount, net_change, direction) in cases {
+        let block = block_json(
+            Some(1722201110),
+            vec![transaction_json(&[signature], &keys, &pre, &post)],
+        );
+        let options = BlockOptions {
+            sender_index,
+            ..BlockOptions::default()
+        };
+        aggregator::handle_block(block, 1, &options, &mut database).unwrap();
+        let record = database.transaction(signature).unwrap().unwrap();
+        assert_eq!(amount, record.amount, "{}", signature);
+        assert_eq!(net_change, record.net_change, "{}", signature);
+        assert_eq!(
+            direction,
+            record.to_json()["transfer_direction"],
+            "{}",
+            signature
+        );
+        assert!(record.to_json().get("direction").is_none());
+    }
+}
+
+#[test]
+fn test_schema_version() {
+    let path = temp_db_path("test_schema_version");
+    // An unversioned database stored the balance decrease as the amount.
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    connection
+        .execute_batch(
+            "
+            CREATE TABLE transactions (sender text, receiver text, amount bigint, timestamp char(20), signature text, slot bigint, tx_index bigint, fee bigint);
+            INSERT INTO transactions VALUES ('a', 'b', 500, '2024-07-28 21:11:50', 'sent', 1, 0, 5000);
+            INSERT INTO transactions VALUES ('a', 'b', -300, '2024-07-28 21:11:50', 'received', 1, 1, 5000);
+            ",
+        )
+        .unwrap();
+    drop(connection);
+
+    for _ in 0..2 {
+        let mut database = Database::with_path(&path).unwrap();
+        let sent = database.transaction("sent").unwrap().unwrap();
+        assert_eq!((500, -500), (sent.amount, sent.net_change));
+        let received = database.transaction("received").unwrap().unwrap();
+        assert_eq!((300, 300), (received.amount, received.net_change));
+    }
+    let version: i64 = rusqlite::Connection::open(&path)
+        .unwrap()
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(crate::database::SCHEMA_VERSION, version);
+}
+#[test]
+fn test_migrations() {
+    let path = temp_db_path("test_migrations");
+    // The schema of the first release, before any migration.
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    connection
+        .execute_batch(
+            "
+            CREATE TABLE IF NOT EXISTS transactions (
+                sender              text,
+                receiver            text,
+                amount              bigint,
+                timestamp           char(20),
+                signature           text
+                );
+            INSERT INTO transactions VALUES ('a', 'b', 500, '2024-07-28 21:11:50', 'old');
+            INSERT INTO transactions VALUES ('a', 'b', 700, '2024-07-28 21:11:51', 'old');
+            INSERT INTO transactions VALUES ('a', 'b', 900, '1970-01-01 00:00:00', 'timeless');
+            ",
+        )
+        .unwrap();
+    drop(connection);
+    let version = || -> i64 {
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap()
+    };
+    assert_eq!(0, version());
+
+    let mut database = Database::with_path(&path).unwrap();
+    assert_eq!(crate::database::SCHEMA_VERSION, version());
+    let old = database.transaction("old").unwrap().unwrap();
+    assert_eq!(
+        ("a", "b", 500, 0, 0, 0),
+        (
+            old.sender.as_str(),
+            old.receiver.as_str(),
+            old.amount,
+            old.slot,
+            old.tx_index,
+            old.fee
+        )
+    );
+    assert_eq!(Some("2024-07-28 21:11:50"), old.timestamp.as_deref());
+    assert_eq!(crate::database::TRANSFER_TYPE_SOL, old.transfer_type);
+    // The epoch timestamps stored for unknown block times are cleared.
+    let timeless = database.transaction("timeless").unwrap().unwrap();
+    assert_eq!(None, timeless.timestamp);
+    // The indexes are created by the migrations too.
+    assert_eq!(
+        7,
+        database
+            .count(
+                "SELECT * FROM sqlite_master WHERE type = 'index' AND sql IS NOT NULL",
+                &[]
+            )
+            .unwrap()
+    );
+    // Only the first copy of a duplicated signature is kept, so that it can be indexed.
+    assert_eq!(
+        1,
+        database
+            .count("SELECT * FROM transactions WHERE signature = 'old'", &[])
+            .unwrap()
+    );
+    // The migrated columns accept new records.
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+    let block = block_json(
+        Some(1722201110),
+        vec![transaction_json(
+            &["new"],
+            &keys,
+            &[100_000, 0],
+            &[40_000, 55_000],
+        )],
+    );
+    aggregator::handle_block(block, 2, &BlockOptions::default(), &mut database).unwrap();
+    assert_eq!(55_000, database.transaction("new").unwrap().unwrap().amount);
+    drop(database);
+
+    // Opening an up-to-date database applies nothing and keeps the data.
+    let mut database = Database::with_path(&path).unwrap();
+    assert_eq!(crate::database::SCHEMA_VERSION, version());
+    assert_eq!(
+        3,
+        database.count("SELECT * FROM transactions", &[]).unwrap()
+    );
+    assert_eq!(
+        -500,
+        database.transaction("old").unwrap().unwrap().net_change
+    );
+}
+#[actix_web::test]
+async fn test_export() {
+    let path = temp_db_path("test_export");
+    let export_path = temp_db_path("test_export_file");
+    let mut database = Database::with_path(&path).unwrap();
+    let sender = Pubkey::new_unique();
+    for i in 0..5 {
+        let from = if i % 2 == 0 {
+            sender
+        } else {
+            Pubkey::new_unique()
+        };
+        database
+            .insert_batch(&[transfer_record(
+                from,
+                Pubkey::new_unique(),
+                i,
+                0,
+                "2024-07-28 21:11:50",
+                &format!("export_{}", i),
+                1,
+                i as usize,
+            )])
+            .unwrap();
+    }
+    drop(database);
+    let config = Config::from_sources(
+        None,
+        [
+            ("ws_url", "ws://127.0.0.1:1"),
+            ("rpc_url", "http://127.0.0.1:1"),
+            ("db_path", path.to_str().unwrap()),
+            ("admin_token", "secret"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string())),
+    )
+    .unwrap();
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(DatabasePool::read_only(&path, 1)))
+            .app_data(web::Data::new(config))
+            .app_data(web::Data::new(shutdown::channel().1))
+            .configure(restful_api::routes),
+    )
+    .await;
+    let uri = format!(
+        "/export.sqlite?sender={}&min_amount=1&order_by=amount",
+        sender
+    );
+
+    // The export requires the admin token.
+    let response = actix_web::test::call_service(
+        &app,
+        actix_web::test::TestRequest::get().uri(&uri).to_request(),
+    )
+    .await;
+    assert_eq!(StatusCode::FORBIDDEN, response.status());
+
+    let response = actix_web::test::call_service(
+        &app,
+        actix_web::test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(("Authorization", "Bearer secret"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!(
+        "application/vnd.sqlite3",
+        response.headers().get("content-type").unwrap()
+    );
+    // The downloaded file is complete on its own, without a WAL file.
+    fs::write(&export_path, actix_web::test::read_body(response).await).unwrap();
+    let mut exported = Database::with_path(&export_path).unwrap();
+    assert_eq!(2, stored_transactions(&mut exported).len());
+    let signatures: Vec<String> = ["export_2", "export_4"]
+        .iter()
+        .map(|signature| exported.transaction(signature).unwrap().unwrap().signature)
+        .collect();
+    assert_eq!(vec!["export_2", "export_4"], signatures);
+    assert_eq!(None, exported.transaction("export_0").unwrap());
+}
+#[actix_web::test]
+async fn test_export_sqlite_signatures() {
+    use crate::aggregator::TimestampFormat;
+    let path = temp_db_path("test_export_sqlite_signatures");
+    let export_path = temp_db_path("test_export_sqlite_signatures_file");
+    let mut database = Database::with_path(&path).unwrap();
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+    let block = block_json(
+        Some(1722201110),
+        vec![transaction_json(
+            &["first_signature", "second_signature"],
+            &keys,
+            &[100, 0],
+            &[40, 60],
+        )],
+    );
+    aggregator::handle_block(block, 1, &BlockOptions::default(), &mut database).unwrap();
+    drop(database);
+    let config = Config::from_sources(
+        None,
+        [
+            ("ws_url", "ws://127.0.0.1:1"),
+            ("rpc_url", "http://127.0.0.1:1"),
+            ("db_path", path.to_str().unwrap()),
+            ("admin_token", "secret"),
+            ("timestamp_format", "rfc3339"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string())),
+    )
+    .unwrap();
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(
+                DatabasePool::read_only(&path, 1)
+                    .with_timestamp_format(TimestampFormat::from_config(&config)),
+            ))
+            .app_data(web::Data::new(config))
+            .app_data(web::Data::new(shutdown::channel().1))
+            .configure(restful_api::routes),
+    )
+    .await;
+
+    // The filter query joins the signatures, which the export copies to their own table.
+    let response = actix_web::test::call_service(
+        &app,
+        actix_web::test::TestRequest::get()
+            .uri(&format!("/export.sqlite?sender={}", keys[0]))
+            .insert_header(("Authorization", "Bearer secret"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(StatusCode::OK, response.status());
+    fs::write(&export_path, actix_web::test::read_body(response).await).unwrap();
+    let mut exported = Database::with_path(&export_path).unwrap();
+    let record = exported.transaction("first_signature").unwrap().unwrap();
+    assert_eq!(
+        vec!["first_signature", "second_signature"],
+        record.signatures
+    );
+    // The timestamps are exported as stored, whatever the format of the responses.
+    assert_eq!(Some("2024-07-28 21:11:50"), record.timestamp.as_deref());
+}
+#[actix_web::test]
+async fn test_rate_limit_per_ip() {
+    let limiter = web::Data::new(RateLimiter::new(0.001, 2, false));
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(limiter)
+            .wrap(from_fn(rate_limit))
+            .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+    let request = |ip: &str| {
+        actix_web::test::TestRequest::get()
+            .uri("/")
+            .peer_addr(format!("{}:4000", ip).parse().unwrap())
+            .to_request()
+    };
+
+    for _ in 0..2 {
+        let response = actix_web::test::call_service(&app, request("10.0.0.1")).await;
+        assert_eq!(StatusCode::OK, response.status());
+    }
+    let response = actix_web::test::call_service(&app, request("10.0.0.1")).await;
+    assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+    assert!(response.headers().contains_key("retry-after"));
+    let body: Value = actix_web::test::read_body_json(response).await;
+    assert_eq!(
+        json!({"code": "rate_limited", "message": "rate limit exceeded"}),
+        body
+    );
+
+    let response = actix_web::test::call_service(&app, request("10.0.0.2")).await;
+    assert_eq!(StatusCode::OK, response.status());
+}
+
+#[actix_web::test]
+async fn test_rate_limit_exemptions() {
+    let limiter = web::Data::new(RateLimiter::new(20.0, 1, false));
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(limiter)
+            .wrap(from_fn(rate_limit))
+            .route("/transactions", web::get().to(HttpResponse::Ok))
+            .route("/health", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+    let request = |uri: &str| {
+        actix_web::test::TestRequest::get()
+            .uri(uri)
+            .peer_addr("10.0.0.1:4000".parse().unwrap())
+            .to_request()
+    };
+
+    // Rapid requests beyond the burst are rejected, but not health checks.
+    let response = actix_web::test::call_service(&app, request("/transactions")).await;
+    assert_eq!(StatusCode::OK, response.status());
+    let response = actix_web::test::call_service(&app, request("/transactions")).await;
+    assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+    for _ in 0..5 {
+        let response = actix_web::test::call_service(&app, request("/health")).await;
+        assert_eq!(StatusCode::OK, response.status());
+    }
+
+    // Requests slower than the rate succeed.
+    for _ in 0..3 {
+        actix_web::rt::time::sleep(Duration::from_millis(60)).await;
+        let response = actix_web::test::call_service(&app, request("/transactions")).await;
+        assert_eq!(StatusCode::OK, response.status());
+    }
+}
+
+#[test]
+fn test_rate_limit_refill() {
+    let limiter = RateLimiter::new(1.0, 1, false);
+    let start = Instant::now();
+    assert!(limiter.check_at("client", start).is_ok());
+    assert!(limiter.check_at("client", start).is_err());
+    assert!(limiter
+        .check_at("client", start + Duration::from_secs(1))
+        .is_ok());
+}
+
+#[test]
+fn test_rate_limit_evicts_full_buckets() {
+    let limiter = RateLimiter::new(1.0, 2, false);
+    let start = Instant::now();
+    assert!(limiter.check_at("idle", start).is_ok());
+    assert!(limiter.check_at("busy", start).is_ok());
+    assert!(limiter
+        .check_at("busy", start + Duration::from_secs(2))
+        .is_ok());
+    assert_eq!(2, limiter.clients());
+
+    // A new client sweeps the buckets once a refill period has passed. The idle bucket is full
+    // again and is dropped, while the busy one still misses half a token.
+    assert!(limiter
+        .check_at("new", start + Duration::from_millis(2500))
+        .is_ok());
+    assert_eq!(2, limiter.clients());
+    assert!(limiter
+        .check_at("idle", start + Duration::from_millis(2500))
+        .is_ok());
+    assert_eq!(3, limiter.clients());
+
+    // No sweep happens again before another refill period.
+    assert!(limiter
+        .check_at("other", start + Duration::from_secs(4))
+        .is_ok());
+    assert_eq!(4, limiter.clients());
+    assert!(limiter
+        .check_at("last", start + Duration::from_millis(4500))
+        .is_ok());
+    assert_eq!(2, limiter.clients());
+}
+
+#[test]
+fn test_counterparties_from_balance_deltas() {
+    let mut database = Database::in_memory().unwrap();
+    let single = vec![Pubkey::new_unique().to_string()];
+    let multi: Vec<String> = (0..4).map(|_| Pubkey::new_unique().to_string()).collect();
+    let block = block_json(
+        Some(1722201110),
+        vec![
+            transaction_json(&["single_account"], &single, &[10_000], &[5_000]),
+            transaction_json(
+                &["multi_account"],
+                &multi,
+                &[100_000, 50, 0, 7],
+                &[40_000, 50, 55_000, 7],
+            ),
+        ],
+    );
+    aggregator::handle_block(block, 1, &BlockOptions::default(), &mut database).unwrap();
+
+    let record = database.transaction("single_account").unwrap().unwrap();
+    assert_eq!(single[0], record.sender);
+    assert_eq!(single[0], record.receiver);
+
+    let record = database.transaction("multi_account").unwrap().unwrap();
+    assert_eq!(multi[0], record.sender);
+    assert_eq!(multi[2], record.receiver);
+}
+
+#[test]
+fn test_spans_exported_for_processed_block() {
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let exporter = InMemorySpanExporter::default();
+    let provider = SdkTracerProvider::builder()
+        .with_simple_exporter(exporter.clone())
+        .build();
+    let subscriber = tracing_subscriber::registry().with(crate::telemetry::layer(&provider));
+
+    let mut database = Database::in_memory().unwrap();
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+    let block = block_json(
+        Some(1722201110),
+        vec![transaction_json(
+            &["span_signature"],
+            &keys,
+            &[100_000, 0],
+            &[40_000, 55_000],
+        )],
+    );
+    tracing::subscriber::with_default(subscriber, || {
+        aggregator::handle_block(block, 1, &BlockOptions::default(), &mut database).unwrap();
+    });
+    provider.force_flush().unwrap();
+
+    let spans = exporter.get_finished_spans().unwrap();
+    assert!(spans.iter().any(|span| span.name == "handle_block"));
+    assert!(spans.iter().any(|span| span.name == "insert_transactions"));
+}
+
+#[test]
+fn test_rpc_limiter_caps_aggregate_rate() {
+    let config = Config::from_sources(
+        None,
+        [
+            ("ws_url", "ws://127.0.0.1:1"),
+            ("rpc_url", "http://127.0.0.1:1"),
+            ("rpc_requests_per_second", "20"),
+            ("rpc_burst", "2"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string())),
+    )
+    .unwrap();
+    let rpc = std::sync::Arc::new(aggregator::rpc_client(&config));
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    let start = Instant::now();
+    runtime.block_on(async {
+        // Four concurrent tasks send 20 requests, 2 at once and then 18 at 20 per second.
+        let tasks: Vec<_> = (0..4)
+            .map(|_| {
+                let rpc = rpc.clone();
+                tokio::spawn(async move {
+                    for _ in 0..5 {
+                        rpc.acquire().await;
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+    });
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(850));
+    assert!(elapsed < Duration::from_secs(3));
+
+    let unlimited = aggregator::LimitedRpcClient::from(
+        solana_client::nonblocking::rpc_client::RpcClient::new_mock("succeeds".to_string()),
+    );
+    let start = Instant::now();
+    runtime.block_on(async {
+        for _ in 0..100 {
+            unlimited.acquire().await;
+        }
+    });
+    assert!(start.elapsed() < Duration::from_millis(500));
+}
+
+#[test]
+fn test_slot_scheduler_resumes_after_checkpoint() {
+    let roots = DedupCache::new(100, Duration::from_secs(60));
+    let mut scheduler = SlotScheduler::new(Some(100), 100, 100, roots);
+    assert!(scheduler.schedule(99).is_empty());
+    assert!(scheduler.schedule(100).is_empty());
+    assert_eq!(vec![101, 102, 103], scheduler.schedule(103));
+    assert_eq!(vec![104], scheduler.schedule(104));
+    assert!(scheduler.schedule(103).is_empty());
+
+    let roots = DedupCache::new(100, Duration::from_secs(60));
+    let mut scheduler = SlotScheduler::new(None, 100, 100, roots);
+    assert_eq!(vec![99], scheduler.schedule(99));
+}
+
+#[test]
+fn test_slot_scheduler_fills_gaps() {
+    let roots = DedupCache::new(100, Duration::from_secs(60));
+    let mut scheduler = SlotScheduler::new(None, 100, 5, roots);
+    assert_eq!(vec![100], scheduler.schedule(100));
+    assert_eq!(vec![101, 102, 103], scheduler.schedule(103));
+    assert_eq!(vec![104], scheduler.schedule(104));
+    assert!(scheduler.schedule(102).is_empty());
+    assert_eq!(vec![105, 106], scheduler.schedule(106));
+    assert_eq!(vec![115, 116, 117, 118, 119, 120], scheduler.schedule(120));
+}
+
+/// Collects the output of a log subscriber.
+#[allow(dead_code)]
+#[derive(Clone, Default)]
+struct LogBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_block_fetch_error_logged() {
+    let logs = LogBuffer::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    let result = tracing::subscriber::with_default(subscriber, || {
+        runtime.block_on(aggregator::process_slot(42, async {
+            Err(AggregatorError::BlockFetchError(SourceError::new(
+                "connection refused",
+            )))
+        }))
+    });
+    assert!(result.is_err());
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    let line = logs
+        .lines()
+        .find(|line| line.contains("failed to process the slot"))
+        .unwrap();
+    assert!(line.contains("ERROR"));
+    assert!(line.contains("slot=42"));
+    assert!(line.contains("failed to fetch the block: connection refused"));
+}
+
+#[tokio::test]
+async fn test_retry_with_backoff() {
+    let calls = std::cell::Cell::new(0);
+    let start = Instant::now();
+    let (result, attempts) = aggregator::retry_with_backoff(
+        3,
+        Duration::from_millis(10),
+        |_: &AggregatorError| true,
+        || {
+            calls.set(calls.get() + 1);
+            let call = calls.get();
+            async move {
+                if call <= 2 {
+                    Err(AggregatorError::BlockFetchError(SourceError::new(
+                        "timeout",
+                    )))
+                } else {
+                    Ok(call)
+                }
+            }
+        },
+    )
+    .await;
+    assert_eq!(Ok(3), result);
+    assert_eq!(3, attempts);
+    assert!(start.elapsed() >= Duration::from_millis(30));
+
+    let calls = std::cell::Cell::new(0);
+    let (result, attempts) = aggregator::retry_with_backoff(
+        3,
+        Duration::from_millis(10),
+        |_: &AggregatorError| false,
+        || {
+            calls.set(calls.get() + 1);
+            async {
+                Err::<(), _>(AggregatorError::BlockFetchError(SourceError::new(
+                    "timeout",
+                )))
+            }
+        },
+    )
+    .await;
+    assert!(result.is_err());
+    assert_eq!(1, attempts);
+    assert_eq!(1, calls.get());
+}
+
+#[tokio::test]
+async fn test_pipeline_deadline_enqueues_retry() {
+    let (retry_queue, mut retries) = tokio::sync::mpsc::unbounded_channel();
+    let deadline = Duration::from_millis(50);
+    let start = Instant::now();
+    let result = aggregator::run_with_deadline(
+        42,
+        1,
+        deadline,
+        2,
+        async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok(())
+        },
+        &retry_queue,
+    )
+    .await;
+    assert_eq!(Err(AggregatorError::DeadlineExceeded), result);
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(Ok((42, 2)), retries.try_recv());
+
+    let result =
+        aggregator::run_with_deadline(42, 2, deadline, 2, std::future::pending(), &retry_queue)
+            .await;
+    assert_eq!(Err(AggregatorError::DeadlineExceeded), result);
+    assert!(retries.try_recv().is_err());
+
+    let result =
+        aggregator::run_with_deadline(43, 1, deadline, 2, async { Ok(()) }, &retry_queue).await;
+    assert_eq!(Ok(()), result);
+    assert!(retries.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_handle_notifications_cap() {
+    let mut handled = vec![];
+    let count = aggregator::handle_notifications(
+        futures_util::stream::iter(0..10),
+        Some(3),
+        |notification| handled.push(notification),
+    )
+    .await;
+    assert_eq!(3, count);
+    assert_eq!(vec![0, 1, 2], handled);
+
+    let count =
+        aggregator::handle_notifications(futures_util::stream::iter(0..10), None, |_| {}).await;
+    assert_eq!(10, count);
+}
+
+#[test]
+fn test_rss_feed() {
+    let mut database = Database::in_memory().unwrap();
+    for tx_index in 0..5 {
+        database
+            .insert_batch(&[transfer_record(
+                Pubkey::new_unique(),
+                Pubkey::new_unique(),
+                1,
+                0,
+                "2024-07-28 21:11:50",
+                &format!("sig_{}", tx_index),
+                10,
+                tx_index,
+            )])
+            .unwrap();
+    }
+
+    let feed = restful_api::rss_feed(&database.latest(3).unwrap());
+    let channel = rss::Channel::read_from(feed.as_bytes()).unwrap();
+    let guids: Vec<_> = channel
+        .items()
+        .iter()
+        .map(|item| item.guid().unwrap().value().to_string())
+        .collect();
+    assert_eq!(vec!["sig_4", "sig_3", "sig_2"], guids);
+}
+
+#[tokio::test]
+async fn test_reconnect_after_subscription_ends() {
+    let mut sessions = vec![];
+    let (_sender, shutdown) = shutdown::channel();
+    let result = aggregator::run_subscriptions(
+        Some(5),
+        3,
+        Duration::from_millis(1),
+        shutdown.clone(),
+        |remaining| {
+            sessions.push(remaining);
+            // The first subscription ends after two notifications.
+            async move { Ok(remaining.unwrap().min(2)) }
+        },
+    )
+    .await;
+    assert_eq!(Ok(()), result);
+    assert_eq!(vec![Some(5), Some(3), Some(1)], sessions);
+
+    let mut attempts = 0;
+    let result = aggregator::run_subscriptions(None, 3, Duration::from_millis(1), shutdown, |_| {
+        attempts += 1;
+        async {
+            Err(AggregatorError::PubsubClientError(SourceError::new(
+                "refused",
+            )))
+        }
+    })
+    .await;
+    assert_eq!(Err(AggregatorError::ReconnectError), result);
+    assert_eq!(3, attempts);
+}
+
+#[tokio::test]
+async fn test_shutdown_ends_subscription() {
+    use futures_util::StreamExt;
+    let (sender, shutdown) = shutdown::channel();
+    let notifications =
+        futures_util::stream::iter([1, 2, 3]).chain(futures_util::stream::pending());
+    let mut handled = vec![];
+    let mut unsubscribed = false;
+    let count = aggregator::run_session(
+        notifications,
+        || {
+            unsubscribed = true;
+            async {}
+        },
+        None,
+        shutdown.clone(),
+        |notification| {
+            handled.push(notification);
+            if notification == 2 {
+                sender.send(true).unwrap();
+            }
+        },
+    )
+    .await;
+    assert_eq!(2, count);
+    assert_eq!(vec![1, 2], handled);
+    assert!(unsubscribed);
+
+    // A requested shutdown stops the reconnection loop instead of starting a new session.
+    let mut sessions = 0;
+    let result = aggregator::run_subscriptions(None, 3, Duration::from_secs(60), shutdown, |_| {
+        sessions += 1;
+        async { Ok(0) }
+    })
+    .await;
+    assert_eq!(Ok(()), result);
+    assert_eq!(0, sessions);
+
+    let (sender, shutdown) = shutdown::channel();
+    let result = aggregator::run_subscriptions(None, 3, Duration::from_secs(60), shutdown, |_| {
+        sessions += 1;
+        let _ = sender.send(true);
+        async { Ok(0) }
+    })
+    .await;
+    assert_eq!(Ok(()), result);
+    assert_eq!(1, sessions);
+}
+
+#[tokio::test]
+async fn test_account_pushdown_fetches_tracked_account_only() {
+    let tracked = Pubkey::new_unique();
+    let confirmed = |slot: u64, signature: &str| {
+        let mut transaction = transaction_json(
+            &[signature],
+            &[tracked.to_string(), Pubkey::new_unique().to_string()],
+            &[10_000, 0],
+            &[4_000, 1_000],
+        );
+        transaction["slot"] = json!(slot);
+        transaction["blockTime"] = json!(1722201110);
+        serde_json::from_value(transaction).unwrap()
+    };
+    let mut cursors = std::collections::HashMap::new();
+    let mut addresses = vec![];
+    let mut fetched = vec![];
+    let transactions = aggregator::fetch_account_transactions(
+        &[tracked],
+        &mut cursors,
+        |account, until| {
+            addresses.push((account, until));
+            async { Ok(vec!["sig_2".to_string(), "sig_1".to_string()]) }
+        },
+        |signature| {
+            fetched.push(signature.clone());
+            let transaction = confirmed(10, &signature);
+            async { Ok(transaction) }
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(vec![(tracked, None)], addresses);
+    assert_eq!(vec!["sig_1", "sig_2"], fetched);
+    assert_eq!(Some(&"sig_2".to_string()), cursors.get(&tracked));
+
+    let mut database = Database::in_memory().unwrap();
+    let block_heights = std::collections::HashMap::from([(10, 9)]);
+    aggregator::handle_account_transactions(
+        transactions,
+        &block_heights,
+        &BlockOptions::default(),
+        &mut database,
+    )
+    .unwrap();
+    let records = stored_transactions(&mut database);
+    assert_eq!(2, records.len());
+    assert_eq!("sig_1", records[0].signature);
+    for signature in ["sig_1", "sig_2"] {
+        let record = database.transaction(signature).unwrap().unwrap();
+        assert_eq!(Some(9), record.block_height, "{}", signature);
+    }
+
+    // The height of a slot missing from the map is left unset.
+    aggregator::handle_account_transactions(
+        vec![confirmed(11, "sig_3")],
+        &block_heights,
+        &BlockOptions::default(),
+        &mut database,
+    )
+    .unwrap();
+    let record = database.transaction("sig_3").unwrap().unwrap();
+    assert_eq!(None, record.block_height);
+
+    // Transactions rejected by the deny list are skipped, like in blocks.
+    let denied = Pubkey::new_unique();
+    let mut transaction = transaction_json(
+        &["sig_denied"],
+        &[tracked.to_string(), denied.to_string()],
+        &[10_000, 0],
+        &[4_000, 1_000],
+    );
+    transaction["slot"] = json!(12);
+    transaction["blockTime"] = json!(1722201110);
+    let options = BlockOptions {
+        denylist: vec![denied.to_string()],
+        ..BlockOptions::default()
+    };
+    aggregator::handle_account_transactions(
+        vec![
+            serde_json::from_value(transaction).unwrap(),
+            confirmed(12, "sig_allowed"),
+        ],
+        &block_heights,
+        &options,
+        &mut database,
+    )
+    .unwrap();
+    assert!(database.transaction("sig_denied").unwrap().is_none());
+    assert!(database.transaction("sig_allowed").unwrap().is_some());
+}
+
+#[tokio::test]
+async fn test_concurrent_block_fetches_bounded() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    let permits = Arc::new(tokio::sync::Semaphore::new(3));
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let max_in_flight = Arc::new(AtomicUsize::new(0));
+    let tasks: Vec<_> = (0..10)
+        .map(|_| {
+            let permits = permits.clone();
+            let in_flight = in_flight.clone();
+            let max_in_flight = max_in_flight.clone();
+            tokio::spawn(async move {
+                aggregator::run_with_permit(&permits, async {
+                    let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_in_flight.fetch_max(current, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                })
+                .await
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    assert_eq!(3, max_in_flight.load(Ordering::SeqCst));
+    assert_eq!(3, permits.available_permits());
+}
+
+#[test]
+fn test_reads_not_blocked_by_writes() {
+    let path = temp_db_path("test_reads_not_blocked_by_writes");
+    let mut database = Database::with_path(&path).unwrap();
+    database
+        .insert_batch(&[transfer_record(
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            1,
+            0,
+            "2024-07-28 21:11:50",
+            "committed",
+            10,
+            0,
+        )])
+        .unwrap();
+
+    let writer = rusqlite::Connection::open(&path).unwrap();
+    writer
+        .execute_batch(
+            "BEGIN EXCLUSIVE; INSERT INTO transactions (signature, slot, tx_index) VALUES ('pending', 11, 0);",
+        )
+        .unwrap();
+
+    let mut reader = Database::read_only(&path).unwrap();
+    let started = Instant::now();
+    let records = stored_transactions(&mut reader);
+    assert!(started.elapsed() < Duration::from_secs(1));
+    assert_eq!(1, records.len());
+    assert_eq!("committed", records[0].signature);
+
+    writer.execute_batch("COMMIT").unwrap();
+    assert_eq!(2, reader.count("SELECT * FROM transactions", &[]).unwrap());
+    drop((database, reader, writer));
+}
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_reads_during_writes() {
+    let path = temp_db_path("test_concurrent_reads_during_writes");
+    let writer = std::sync::Arc::new(DatabaseWriter::with_path(&path).unwrap());
+    let pool = std::sync::Arc::new(DatabasePool::read_only(&path, 4));
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+    let writing = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let pool = pool.clone();
+            let writing = writing.clone();
+            std::thread::spawn(move || {
+                let info = restful_api::Info::parse("").unwrap();
+                let (query, params) = restful_api::filter_query(&info).unwrap();
+                let mut reads = 0;
+                while writing.load(std::sync::atomic::Ordering::SeqCst) || reads == 0 {
+                    let mut database = pool.get().unwrap();
+                    database.count(&query, &params).unwrap();
+                    reads += 1;
+                }
+                reads
+            })
+        })
+        .collect();
+    let writers: Vec<_> = (0..4)
+        .map(|task| {
+            let writer = writer.clone();
+            let keys = keys.clone();
+            tokio::spawn(async move {
+                for block in 0..10 {
+                    let signatures: Vec<String> = (0..10)
+                        .map(|index| format!("{}_{}_{}", task, block, index))
+                        .collect();
+                    let transactions = signatures
+                        .iter()
+                        .map(|signature| {
+                            transaction_json(&[signature], &keys, &[100_000, 0], &[94_940, 60])
+                        })
+                        .collect();
+                    let block = block_json(Some(1722201110), transactions);
+                    let mut database = writer.lock().await;
+                    aggregator::handle_block(block, 1, &BlockOptions::default(), database.as_mut())
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for task in writers {
+        task.await.unwrap();
+    }
+    writing.store(false, std::sync::atomic::Ordering::SeqCst);
+    for reader in readers {
+        assert!(reader.join().unwrap() > 0);
+    }
+
+    let mut database = pool.get().unwrap();
+    let info = restful_api::Info::parse("").unwrap();
+    let (query, params) = restful_api::filter_query(&info).unwrap();
+    assert_eq!(400, database.count(&query, &params).unwrap());
+    drop((database, pool, writer));
+}
+#[test]
+fn test_thread_errors_surface() {
+    use crate::error::{self, report, RuntimeError};
+    let aggregator = std::thread::spawn(|| Err(AggregatorError::ReconnectError));
+    let err = error::join("aggregator", aggregator).unwrap_err();
+    assert_eq!(
+        RuntimeError::AggregatorError(AggregatorError::ReconnectError),
+        err
+    );
+    assert_eq!(
+        "the aggregator failed: failed to reconnect the slot subscription",
+        report(&err)
+    );
+
+    let web_server = std::thread::spawn(|| Err(std::io::Error::other("address in use")));
+    assert_eq!(
+        "the web server failed: address in use",
+        report(&error::join("web server", web_server).unwrap_err())
+    );
+
+    let panicking = std::thread::spawn(|| -> Result<(), AggregatorError> { panic!("boom") });
+    assert_eq!(
+        "the aggregator thread panicked: boom",
+        report(&error::join("aggregator", panicking).unwrap_err())
+    );
+    assert_eq!(
+        Ok(()),
+        error::join(
+            "aggregator",
+            std::thread::spawn(|| Ok::<(), AggregatorError>(()))
+        )
+    );
+}
+
+#[test]
+fn test_error_display() {
+    use crate::error::{DatabaseError, RuntimeError};
+    assert_eq!(
+        "failed to fetch the block",
+        AggregatorError::BlockFetchError(SourceError::new("timeout")).to_string()
+    );
+    assert_eq!(
+        "failed to connect to the database",
+        DatabaseError::ConnectError.to_string()
+    );
+    assert_eq!(
+        "the aggregator failed",
+        RuntimeError::AggregatorError(AggregatorError::ReconnectError).to_string()
+    );
+    assert_eq!(
+        AggregatorError::DatabaseError(DatabaseError::InsertionError),
+        AggregatorError::from(DatabaseError::InsertionError)
+    );
+    let err: Box<dyn std::error::Error> = Box::new(AggregatorError::ReconnectError);
+    assert!(!err.to_string().is_empty());
+    assert_eq!(
+        "the slot was skipped and has no block",
+        AggregatorError::SlotSkipped.to_string()
+    );
+}
+
+#[test]
+fn test_transaction_logs() {
+    let mut database = Database::in_memory().unwrap();
+    let accounts = vec![Pubkey::new_unique().to_string()];
+    let logs = vec![
+        "Program 11111111111111111111111111111111 invoke [1]".to_string(),
+        "Program log: memo \"hello\"".to_string(),
+    ];
+    let mut logged = transaction_json(&["logged"], &accounts, &[10_000], &[5_000]);
+    logged["meta"]["logMessages"] = json!(logs);
+    let block = block_json(
+        Some(1722201110),
+        vec![
+            logged,
+            transaction_json(&["unlogged"], &accounts, &[10_000], &[5_000]),
+        ],
+    );
+    let options = BlockOptions {
+        store_logs: true,
+        ..Default::default()
+    };
+    aggregator::handle_block(block, 1, &options, &mut database).unwrap();
+
+    assert_eq!(Some(logs), database.logs("logged"));
+    assert!(database.logs("unlogged").is_none());
+    let info =
+        web::Query::<restful_api::Info>::from_query("log_contains=memo%20%22hello%22").unwrap();
+    let (query, params) = restful_api::filter_query(&info).unwrap();
+    let records = database.records(&query, &params).unwrap();
+    assert_eq!(1, records.len());
+    assert_eq!("logged", records[0].signature);
+}
+
+#[test]
+fn test_error_source_propagates() {
+    use crate::error::report;
+    use std::error::Error;
+    let err = AggregatorError::BlockFetchError(SourceError::new("HTTP status client error (429)"));
+    assert_eq!(
+        "HTTP status client error (429)",
+        err.source().unwrap().to_string()
+    );
+
+    let err = AggregatorError::TransactionParseError(Box::new(err));
+    assert_eq!(
+        "failed to parse a transaction: failed to fetch the block: HTTP status client error (429)",
+        report(&err)
+    );
+}
+
+#[actix_web::test]
+async fn test_database_busy() {
+    let path = temp_db_path("test_database_busy");
+    let mut database = Database::with_path(&path).unwrap();
+    database
+        .insert_batch(&[transfer_record(
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            100,
+            5_000,
+            "2024-07-28 21:11:50",
+            "busy",
+            1,
+            0,
+        )])
+        .unwrap();
+    drop(database);
+    // Readers only wait for writers in the rollback journal mode, so another process holding an
+    // exclusive lock on such a file locks them out.
+    let locker = rusqlite::Connection::open(&path).unwrap();
+    locker
+        .query_row("PRAGMA journal_mode = DELETE", [], |row| {
+            row.get::<usize, String>(0)
+        })
+        .unwrap();
+    locker
+        .execute_batch("BEGIN EXCLUSIVE; DELETE FROM transactions;")
+        .unwrap();
+
+    let pool = DatabasePool::with_busy_timeout(&path, 2, Duration::from_millis(50));
+    assert_eq!(
+        Err(DatabaseError::BusyError),
+        pool.get().unwrap().count("SELECT * FROM transactions", &[])
+    );
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(pool))
+            .configure(restful_api::routes),
+    )
+    .await;
+    for uri in ["/transactions", "/transactions/count", "/transactions/busy"] {
+        let request = actix_web::test::TestRequest::get().uri(uri).to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(
+            StatusCode::SERVICE_UNAVAILABLE,
+            response.status(),
+            "{}",
+            uri
+        );
+        assert_eq!(
+            "1",
+            response.headers().get("Retry-After").unwrap(),
+            "{}",
+            uri
+        );
+        let body: Value = actix_web::test::read_body_json(response).await;
+        assert_eq!("database_busy", body["code"], "{}", uri);
+        assert_eq!("the database is busy", body["message"], "{}", uri);
+    }
+
+    locker.execute_batch("ROLLBACK").unwrap();
+    let request = actix_web::test::TestRequest::get()
+        .uri("/transactions/count")
+        .to_request();
+    let body: Value = actix_web::test::call_and_read_body_json(&app, request).await;
+    assert_eq!(json!({ "count": 1 }), body);
+    drop(locker);
+}
+#[actix_web::test]
+async fn test_health() {
+    let path = temp_db_path("test_health");
+    let missing = temp_db_path("test_health_missing");
+    drop(Database::with_path(&path).unwrap());
+    let request = || {
+        actix_web::test::TestRequest::get()
+            .uri("/health")
+            .to_request()
+    };
+
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(DatabasePool::read_only(&path, 1)))
+            .service(restful_api::health),
+    )
+    .await;
+    let response = actix_web::test::call_service(&app, request()).await;
+    assert_eq!(StatusCode::OK, response.status());
+    let body: Value = actix_web::test::read_body_json(response).await;
+    assert_eq!(json!({ "status": "ok" }), body);
+
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(DatabasePool::read_only(&missing, 1)))
+            .service(restful_api::health),
+    )
+    .await;
+    let response = actix_web::test::call_service(&app, request()).await;
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+}
+#[actix_web::test]
+async fn test_transactions_csv() {
+    let path = temp_db_path("test_transactions_csv");
+    let mut database = Database::with_path(&path).unwrap();
+    let record = |sender: &str, tx_index: u64| TransactionRecord {
+        sender: sender.to_string(),
+        receiver: Pubkey::new_unique().to_string(),
+        amount: 42,
+        net_change: -42,
+        timestamp: Some("2024-07-28 21:11:50".to_string()),
+        signature: format!("csv_{}", tx_index),
+        signatures: vec![format!("csv_{}", tx_index), "second".to_string()],
+        slot: 1,
+        block_height: None,
+        tx_index,
+        fee: 5000,
+        program_id: None,
+        transfer_type: "sol".to_string(),
+        token_mint: None,
+        token_amount: None,
+        token_decimals: None,
+        status: "success".to_string(),
+        balance_changes: vec![],
+        logs: None,
+    };
+    database
+        .insert_batch(&[record("plain", 0), record("with, \"comma\"", 1)])
+        .unwrap();
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(DatabasePool::read_only(&path, 1)))
+            .service(restful_api::transactions),
+    )
+    .await;
+    let request = |accept: &str| {
+        actix_web::test::TestRequest::get()
+            .uri("/transactions?order_by=signature")
+            .insert_header(("Accept", accept))
+            .to_request()
+    };
+
+    let response = actix_web::test::call_service(&app, request("application/json")).await;
+    assert_eq!(StatusCode::OK, response.status());
+    let rows: Vec<Value> = actix_web::test::read_body_json(response).await;
+    assert_eq!("plain", rows[0]["sender"]);
+    assert_eq!(json!(["csv_0", "second"]), rows[0]["signatures"]);
+    assert_eq!("with, \"comma\"", rows[1]["sender"]);
+
+    let response = actix_web::test::call_service(&app, request("text/csv")).await;
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!(
+        "text/csv; charset=utf-8",
+        response.headers().get("content-type").unwrap()
+    );
+    assert!(response
+        .headers()
+        .get("content-disposition")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .contains("transactions.csv"));
+    let body = actix_web::test::read_body(response).await;
+    let csv = String::from_utf8(body.to_vec()).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(rows.len() + 1, lines.len());
+    assert_eq!(
+        "sender,receiver,amount,timestamp,signature,signatures,slot,tx_index,fee",
+        lines[0]
+    );
+    assert!(lines[1].starts_with("plain,"));
+    assert!(lines[1].ends_with(",42,2024-07-28 21:11:50,csv_0,csv_0 second,1,0,5000"));
+    assert!(lines[2].starts_with("\"with, \"\"comma\"\"\","));
+}
+#[actix_web::test]
+async fn test_metrics() {
+    let mut database = Database::in_memory().unwrap();
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+    let block = block_json(
+        Some(1722201110),
+        vec![transaction_json(&["metrics"], &keys, &[100, 0], &[40, 60])],
+    );
+    let processed = crate::metrics::METRICS
+        .blocks_processed
+        .load(std::sync::atomic::Ordering::Relaxed);
+    aggregator::handle_block(block, 1, &BlockOptions::default(), &mut database).unwrap();
+    assert!(
+        crate::metrics::METRICS
+            .blocks_processed
+            .load(std::sync::atomic::Ordering::Relaxed)
+            > processed
+    );
+
+    let app = actix_web::test::init_service(App::new().service(restful_api::metrics)).await;
+    let request = actix_web::test::TestRequest::get()
+        .uri("/metrics")
+        .to_request();
+    let response = actix_web::test::call_service(&app, request).await;
+    assert_eq!(StatusCode::OK, response.status());
+    let body = actix_web::test::read_body(response).await;
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    for name in [
+        "solana_aggregator_blocks_processed_total",
+        "solana_aggregator_transactions_inserted_total",
+        "solana_aggregator_insert_errors_total",
+        "solana_aggregator_parse_errors_total",
+        "solana_aggregator_rpc_retries_total",
+        "solana_aggregator_last_processed_slot",
+        "solana_aggregator_reconnects_total",
+    ] {
+        assert!(body.contains(&format!("# TYPE {} ", name)));
+        assert!(body
+            .lines()
+            .any(|line| line.starts_with(&format!("{} ", name))));
+    }
+}
+
+#[actix_web::test]
+async fn test_debug_stats() {
+    use crate::metrics::{Metrics, METRICS};
+    let before = METRICS.snapshot();
+    Metrics::add(&METRICS.reconnects, 1);
+    Metrics::add(&METRICS.rpc_retries, 2);
+    Metrics::add(&METRICS.blocks_fetched, 3);
+    Metrics::add(&METRICS.blocks_failed, 4);
+
+    let app = actix_web::test::init_service(App::new().service(restful_api::debug_stats)).await;
+    let request = actix_web::test::TestRequest::get()
+        .uri("/debug/stats")
+        .to_request();
+    let response = actix_web::test::call_service(&app, request).await;
+    assert_eq!(StatusCode::OK, response.status());
+    let body: serde_json::Value = actix_web::test::read_body_json(response).await;
+    // Other tests share the counters, so they may have grown further.
+    for (name, previous, added) in [
+        ("reconnects", before.reconnects, 1),
+        ("rpc_retries", before.rpc_retries, 2),
+        ("blocks_fetched", before.blocks_fetched, 3),
+        ("blocks_failed", before.blocks_failed, 4),
+    ] {
+        assert!(body[name].as_u64().unwrap() >= previous + added);
+    }
+    for name in [
+        "blocks_processed",
+        "transactions_inserted",
+        "insert_errors",
+        "parse_errors",
+        "last_processed_slot",
+    ] {
+        assert!(body[name].is_u64());
+    }
+}
+
+#[actix_web::test]
+async fn test_openapi_spec() {
+    let app = actix_web::test::init_service(App::new().service(restful_api::openapi_spec)).await;
+    let request = actix_web::test::TestRequest::get()
+        .uri("/openapi.json")
+        .to_request();
+    let response = actix_web::test::call_service(&app, request).await;
+    assert_eq!(StatusCode::OK, response.status());
+    let spec: serde_json::Value = actix_web::test::read_body_json(response).await;
+    assert_eq!("3.1.0", spec["openapi"]);
+    let transactions = &spec["paths"]["/transactions"]["get"];
+    let params: Vec<&str> = transactions["parameters"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|param| param["name"].as_str().unwrap())
+        .collect();
+    for name in [
+        "start_date",
+        "end_date",
+        "signature",
+        "sender",
+        "receiver",
+        "log_contains",
+        "min_amount",
+        "max_amount",
+        "order_by",
+        "program_id",
+        "mint",
+        "min_signatures",
+    ] {
+        assert!(params.contains(&name), "{} is missing", name);
+    }
+    assert_eq!(
+        "#/components/schemas/TransactionRecord",
+        transactions["responses"]["200"]["content"]["application/json"]["schema"]["items"]["$ref"]
+    );
+    for name in ["/health", "/stats/by-sender", "/stats/timeseries"] {
+        assert!(spec["paths"][name]["get"]["responses"]["200"].is_object());
+    }
+
+    // The record schema lists the fields of a transaction returned by the API.
+    let properties = &spec["components"]["schemas"]["TransactionRecord"]["properties"];
+    let record = TransactionRecord {
+        sender: "sender".to_string(),
+        receiver: "receiver".to_string(),
+        amount: 1,
+        net_change: -1,
+        timestamp: Some("2024-07-28 21:11:50".to_string()),
+        signature: "signature".to_string(),
+        signatures: vec!["signature".to_string()],
+        slot: 1,
+        block_height: None,
+        tx_index: 0,
+        fee: 5000,
+        program_id: None,
+        transfer_type: "sol".to_string(),
+        token_mint: None,
+        token_amount: None,
+        token_decimals: None,
+        status: "success".to_string(),
+        balance_changes: vec![],
+        logs: None,
+    };
+    for field in record.to_json().as_object().unwrap().keys() {
+        assert!(properties[field].is_object(), "{} is missing", field);
+    }
+    assert!(properties["amount_sol"].is_object());
+}
+
+/// Sends a GET request to a running server over a plain TCP connection and returns the raw response.
+#[allow(dead_code)]
+async fn http_get(address: std::net::SocketAddr, path: &str) -> String {
+    use std::io::{Read, Write};
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        path
+    );
+    web::block(move || {
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    })
+    .await
+    .unwrap()
+}
+
+#[actix_web::test]
+async fn test_server_handle() {
+    let path = temp_db_path("test_server_handle");
+    let config = Config::from_sources(
+        None,
+        [
+            ("ws_url", "ws://127.0.0.1:1"),
+            ("rpc_url", "http://127.0.0.1:1"),
+            ("db_path", path.to_str().unwrap()),
+            ("port", "0"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string())),
+    )
+    .unwrap();
+    let (_sender, shutdown) = shutdown::channel();
+    let server = restful_api::build_server(config, shutdown).unwrap();
+    let address = server.addrs[0];
+    let running = actix_web::rt::spawn(server.server);
+
+    let response = http_get(address, "/health").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+    server.handle.stop(true).await;
+    running.await.unwrap().unwrap();
+    assert!(std::net::TcpStream::connect(address).is_err());
+}
+#[test]
+fn test_aggregator_error_stops_server() {
+    let path = temp_db_path("test_aggregator_error_stops_server");
+    // The database of the aggregator can't be created under a file, so it fails right away.
+    let blocked = temp_db_path("test_aggregator_error_stops_server_blocked");
+    fs::write(&blocked, "").unwrap();
+    let config = |db_path: PathBuf| {
+        Config::from_sources(
+            None,
+            [
+                ("ws_url", "ws://127.0.0.1:1"),
+                ("rpc_url", "http://127.0.0.1:1"),
+                ("db_path", db_path.to_str().unwrap()),
+                ("port", "0"),
+                ("EXIT_ON_AGGREGATOR_ERROR", "true"),
+            ]
+            .map(|(key, value)| (key.to_string(), value.to_string())),
+        )
+        .unwrap()
+    };
+    let server_config = config(path.clone());
+    assert!(server_config.exit_on_aggregator_error);
+
+    let (sender, shutdown) = shutdown::channel();
+    let server_shutdown = shutdown.clone();
+    let server =
+        std::thread::spawn(move || restful_api::web_server(server_config, server_shutdown));
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let result = runtime.block_on(aggregator::aggregate_data(
+        config(blocked.join("db")),
+        shutdown.clone(),
+    ));
+    assert!(result.is_err());
+    assert!(shutdown::request_on_error(&result, &sender));
+    assert!(shutdown::is_requested(&shutdown));
+
+    // The server stops gracefully once signaled.
+    let started = Instant::now();
+    while !server.is_finished() {
+        assert!(started.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    server.join().unwrap().unwrap();
+
+    // A successful task requests nothing.
+    let (sender, shutdown) = shutdown::channel();
+    assert!(!shutdown::request_on_error(
+        &Ok::<(), AggregatorError>(()),
+        &sender
+    ));
+    assert!(!shutdown::is_requested(&shutdown));
+}
+#[actix_web::test]
+async fn test_http_workers() {
+    let path = temp_db_path("test_http_workers");
+    let config = |workers: Option<&str>| {
+        let mut vars = vec![
+            ("ws_url", "ws://127.0.0.1:1"),
+            ("rpc_url", "http://127.0.0.1:1"),
+            ("db_path", path.to_str().unwrap()),
+            ("port", "0"),
+        ];
+        vars.extend(workers.map(|workers| ("HTTP_WORKERS", workers)));
+        Config::from_sources(
+            None,
+            vars.into_iter()
+                .map(|(key, value)| (key.to_string(), value.to_string())),
+        )
+    };
+    assert_eq!(None, config(None).unwrap().http_workers);
+    assert!(config(Some("0")).is_err());
+    let config = config(Some("2")).unwrap();
+    assert_eq!(Some(2), config.http_workers.map(|workers| workers.get()));
+
+    let (_sender, shutdown) = shutdown::channel();
+    let server = restful_api::build_server(config, shutdown).unwrap();
+    let address = server.addrs[0];
+    let running = actix_web::rt::spawn(server.server);
+    for _ in 0..2 {
+        let response = http_get(address, "/health").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+    }
+    server.handle.stop(true).await;
+    running.await.unwrap().unwrap();
+}
+#[actix_web::test]
+async fn test_stream() {
+    use actix_web::body::MessageBody;
+    let (sender, shutdown) = shutdown::channel();
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::new(shutdown))
+            .service(restful_api::stream),
+    )
+    .await;
+    let request = actix_web::test::TestRequest::get()
+        .uri("/stream")
+        .to_request();
+    let response = actix_web::test::call_service(&app, request).await;
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!(
+        "text/event-stream",
+        response.headers().get("content-type").unwrap()
+    );
+    let mut body = Box::pin(response.into_body());
+
+    let mut database = Database::in_memory().unwrap();
+    let keys = vec![
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    ];
+    let block = block_json(
+        Some(1722201110),
+        vec![transaction_json(&["streamed"], &keys, &[100, 0], &[40, 60])],
+    );
+    aggregator::handle_block(block, 1, &BlockOptions::default(), &mut database).unwrap();
+
+    // Other tests may publish transactions at the same time.
+    let event = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let chunk = futures_util::future::poll_fn(|cx| body.as_mut().poll_next(cx))
+                .await
+                .unwrap()
+                .unwrap();
+            let chunk = String::from_utf8(chunk.to_vec()).unwrap();
+            if chunk.contains("\"streamed\"") {
+                return chunk;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert!(event.starts_with("data: {"));
+    assert!(event.ends_with("}\n\n"));
+    let event: Value = serde_json::from_str(&event["data: ".len()..]).unwrap();
+    assert_eq!(keys[0], event["sender"]);
+    assert_eq!(keys[1], event["receiver"]);
+
+    // The stream ends on shutdown.
+    sender.send(true).unwrap();
+    assert!(
+        futures_util::future::poll_fn(|cx| body.as_mut().poll_next(cx))
+            .await
+            .is_none()
+    );
+}
+
+#[test]
+fn test_amount_range_filter() {
+    let mut database = Database::in_memory().unwrap();
+    let sender = Pubkey::new_unique();
+    for (tx_index, amount) in [100, 500, 1_000, 5_000].into_iter().enumerate() {
+        database
+            .insert_batch(&[transfer_record(
+                if tx_index % 2 == 0 {
+                    sender
+                } else {
+                    Pubkey::new_unique()
+                },
+                Pubkey::new_unique(),
+                amount,
+                0,
+                "2024-07-28 21:11:50",
+                &format!("amount_{}", amount),
+                1,
+                tx_index,
+            )])
+            .unwrap();
+    }
+    let mut signatures = |query: &str| -> Vec<String> {
+        let info = web::Query::<restful_api::Info>::from_query(query).unwrap();
+        let (query, params) = restful_api::filter_query(&info).unwrap();
+        database
+            .records(&format!("{} ORDER BY amount", query), &params)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.signature)
+            .collect()
+    };
+
+    assert_eq!(
+        vec!["amount_1000", "amount_5000"],
+        signatures("min_amount=1000")
+    );
+    assert_eq!(
+        vec!["amount_100", "amount_500"],
+        signatures("max_amount=500")
+    );
+    assert_eq!(
+        vec!["amount_500", "amount_1000"],
+        signatures("min_amount=200&max_amount=1000")
+    );
+    assert_eq!(
+        vec!["amount_1000"],
+        signatures(&format!("sender={}&min_amount=200", sender))
+    );
+    assert!(web::Query::<restful_api::Info>::from_query("min_amount=lots").is_err());
+}
+
+#[test]
+fn test_statement_cache() {
+    let mut database = Database::in_memory().unwrap();
+    database
+        .insert_batch(&[transfer_record(
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            1,
+            0,
+            "2024-07-28 21:11:50",
+            "cached",
+            1,
+            0,
+        )])
+        .unwrap();
+    let info = web::Query::<restful_api::Info>::from_query("signature=cached").unwrap();
+    let (query, params) = restful_api::filter_query(&info).unwrap();
+    assert_eq!(Some(0), database.statement_runs(&query));
+    for _ in 0..3 {
+        assert_eq!(1, database.records(&query, &params).unwrap().len());
+    }
+    assert_eq!(1, database.records(&query, &params).unwrap().len());
+    assert_eq!(Some(4), database.statement_runs(&query));
+    assert_eq!(None, database.statement_runs("SELECT * FROM missing"));
+}
+
+#[actix_web::test]
+async fn test_multiple_senders() {
+    let path = temp_db_path("test_multiple_senders");
+    let mut database = Database::with_path(&path).unwrap();
+    let senders: Vec<Pubkey> = (0..3).map(|_| Pubkey::new_unique()).collect();
+    for (tx_index, sender) in senders.iter().enumerate() {
+        database
+            .insert_batch(&[transfer_record(
+                *sender,
+                Pubkey::new_unique(),
+                1,
+                0,
+                "2024-07-28 21:11:50",
+                &format!("sender_{}", tx_index),
+                1,
+                tx_index,
+            )])
+            .unwrap();
+    }
+    drop(database);
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(DatabasePool::read_only(&path, 1)))
+            .service(restful_api::transactions),
+    )
+    .await;
+    let request = |uri: &str| actix_web::test::TestRequest::get().uri(uri).to_request();
+
+    for uri in [
+        format!(
+            "/transactions?sender={}&sender={}&order_by=signature",
+            senders[0], senders[2]
+        ),
+        format!(
+            "/transactions?sender={},{}&order_by=signature",
+            senders[0], senders[2]
+        ),
+    ] {
+        let rows: Vec<Value> = actix_web::test::call_and_read_body_json(&app, request(&uri)).await;
+        assert_eq!(2, rows.len());
+        assert_eq!("sender_0", rows[0]["signature"]);
+        assert_eq!(senders[0].to_string(), rows[0]["sender"]);
+        assert_eq!("sender_2", rows[1]["signature"]);
+        assert_eq!(senders[2].to_string(), rows[1]["sender"]);
+    }
+    let rows: Vec<Value> = actix_web::test::call_and_read_body_json(
+        &app,
+        request(&format!(
+            "/transactions?sender={}&sender={}&receiver={}",
+            senders[0],
+            senders[1],
+            Pubkey::new_unique()
+        )),
+    )
+    .await;
+    assert!(rows.is_empty());
+    let rows: Vec<Value> = actix_web::test::call_and_read_body_json(
+        &app,
+        request(&format!("/transactions?sender={}", senders[1])),
+    )
+    .await;
+    assert_eq!(1, rows.len());
+
+    let too_many = vec!["abc"; 101].join(",");
+    let response =
+        actix_web::test::call_service(&app, request(&format!("/transactions?sender={}", too_many)))
+            .await;
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+}
+#[actix_web::test]
+async fn test_no_matching_transactions() {
+    let path = temp_db_path("test_no_matching_transactions");
+    let mut database = Database::with_path(&path).unwrap();
+    database
+        .insert_batch(&[transfer_record(
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            1,
+            0,
+            "2024-07-28 21:11:50",
+            "unmatched",
+            1,
+            0,
+        )])
+        .unwrap();
+    drop(database);
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(DatabasePool::read_only(&path, 1)))
+            .service(restful_api::transactions),
+    )
+    .await;
+    let uri = format!("/transactions?sender={}", Pubkey::new_unique());
+
+    let request = actix_web::test::TestRequest::get().uri(&uri).to_request();
+    let response = actix_web::test::call_service(&app, request).await;
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!(
+        "application/json",
+        response.headers().get("content-type").unwrap()
+    );
+    let body = actix_web::test::read_body(response).await;
+    assert_eq!("[]", body);
+
+    let request = actix_web::test::TestRequest::get()
+        .uri(&uri)
+        .insert_header(("Accept", "text/csv"))
+        .to_request();
+    let response = actix_web::test::call_service(&app, request).await;
+    assert_eq!(StatusCode::OK, response.status());
+    let body = actix_web::test::read_body(response).await;
+    assert_eq!(
+        "sender,receiver,amount,timestamp,signature,signatures,slot,tx_index,fee\r\n",
+        body
+    );
+}
+#[actix_web::test]
+async fn test_transactions_count() {
+    let path = temp_db_path("test_transactions_count");
+    let mut database = Database::with_path(&path).unwrap();
+    let sender = Pubkey::new_unique();
+    for tx_index in 0..5 {
+        database
+            .insert_batch(&[transfer_record(
+                if tx_index < 3 {
+                    sender
+                } else {
+                    Pubkey::new_unique()
+                },
+                Pubkey::new_unique(),
+                tx_index as i64 * 100,
+                0,
+                "2024-07-28 21:11:50",
+                &format!("count_{}", tx_index),
+                1,
+                tx_index,
+            )])
+            .unwrap();
+    }
+    drop(database);
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(DatabasePool::read_only(&path, 1)))
+            .service(restful_api::transactions)
+            .service(restful_api::transactions_count)
+            .service(restful_api::transaction_by_signature),
+    )
+    .await;
+    let request = |uri: &str| actix_web::test::TestRequest::get().uri(uri).to_request();
+
+    for (filter, expected) in [
+        (String::new(), 5),
+        (format!("sender={}", sender), 3),
+        (format!("sender={}&min_amount=100", sender), 2),
+        ("max_amount=300&order_by=amount".to_string(), 4),
+        ("signature=unknown".to_string(), 0),
+    ] {
+        let rows: Vec<Value> = actix_web::test::call_and_read_body_json(
+            &app,
+            request(&format!("/transactions?{}", filter)),
+        )
+        .await;
+        assert_eq!(expected, rows.len());
+        let count: Value = actix_web::test::call_and_read_body_json(
+            &app,
+            request(&format!("/transactions/count?{}", filter)),
+        )
+        .await;
+        assert_eq!(json!({ "count": rows.len() }), count);
+    }
+    let response =
+        actix_web::test::call_service(&app, request("/transactions/count?order_by=sender")).await;
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+}
+#[actix_web::test]
+async fn test_account_transactions() {
+    let path = temp_db_path("test_account_transactions");
+    let mut database = Database::with_path(&path).unwrap();
+    let account = Pubkey::new_unique();
+    let other = Pubkey::new_unique();
+    for (tx_index, (sender, receiver)) in [
+        (account, other),
+        (other, account),
+        (other, Pubkey::new_unique()),
+        (account, account),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        database
+            .insert_batch(&[transfer_record(
+                sender,
+                receiver,
+                100,
+                0,
+                "2024-07-28 10:00:00",
+                &format!("activity_{}", tx_index),
+                1,
+                tx_index,
+            )])
+            .unwrap();
+    }
+    drop(database);
+
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(DatabasePool::read_only(&path, 1)))
+            .service(restful_api::account_transactions)
+            .service(restful_api::transaction_by_signature),
+    )
+    .await;
+    let request = |uri: String| actix_web::test::TestRequest::get().uri(&uri).to_request();
+    let summary = |body: Value| -> Vec<(String, String)> {
+        body.as_array()
+            .unwrap()
+            .iter()
+            .map(|transaction| {
+                (
+                    transaction["signature"].as_str().unwrap().to_string(),
+                    transaction["direction"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect()
+    };
+    let expected = |transactions: &[(&str, &str)]| -> Vec<(String, String)> {
+        transactions
+            .iter()
+            .map(|(signature, direction)| (signature.to_string(), direction.to_string()))
+            .collect()
+    };
+
+    let body: Value = actix_web::test::call_and_read_body_json(
+        &app,
+        request(format!("/accounts/{}/transactions", account)),
+    )
+    .await;
+    assert_eq!(
+        expected(&[
+            ("activity_3", "sent"),
+            ("activity_1", "received"),
+            ("activity_0", "sent"),
+        ]),
+        summary(body.clone())
+    );
+    assert_eq!("0.0000001", body[0]["amount_sol"]);
+    // The direction relative to the account doesn't replace the one of the net change.
+    assert_eq!("received", body[1]["direction"]);
+    assert_eq!("outgoing", body[1]["transfer_direction"]);
+
+    let body: Value = actix_web::test::call_and_read_body_json(
+        &app,
+        request("/transactions/activity_1".to_string()),
+    )
+    .await;
+    assert_eq!("outgoing", body["transfer_direction"]);
+    assert!(body.get("direction").is_none());
+
+    let body: Value = actix_web::test::call_and_read_body_json(
+        &app,
+        request(format!(
+            "/accounts/{}/transactions?order=asc&limit=1&offset=1",
+            account
+        )),
+    )
+    .await;
+    assert_eq!(expected(&[("activity_1", "received")]), summary(body));
+
+    for uri in [
+        "/accounts/not_a_pubkey/transactions".to_string(),
+        format!("/accounts/{}/transactions?order=sideways", account),
+    ] {
+        let response = actix_web::test::call_service(&app, request(uri)).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    }
+}
+#[actix_web::test]
+async fn test_account_counterparties() {
+    let path = temp_db_path("test_account_counterparties");
+    let mut database = Database::with_path(&path).unwrap();
+    let account = Pubkey::new_unique();
+    let (first, second, third) = (
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+    );
+    for (tx_index, (sender, receiver, amount)) in [
+        (account, first, 100),
+        (first, account, 50),
+        (account, first, 25),
+        (second, account, 1_000),
+        (account, second, 10),
+        (account, third, 5),
+        (account, account, 1),
+        // Not involving the account.
+        (first, second, 7),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        database
+            .insert_batch(&[transfer_record(
+                sender,
+                receiver,
+                amount,
+                0,
+                "2024-07-28 10:00:00",
+                &format!("counterparty_{}", tx_index),
+                1,
+                tx_index,
+            )])
+            .unwrap();
+    }
+    drop(database);
+
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(DatabasePool::read_only(&path, 1)))
+            .service(restful_api::account_counterparties),
+    )
+    .await;
+    let request = |uri: String| actix_web::test::TestRequest::get().uri(&uri).to_request();
+    let summary = |body: Value| -> Vec<(String, u64, i64)> {
+        body.as_array()
+            .unwrap()
+            .iter()
+            .map(|counterparty| {
+                (
+                    counterparty["counterparty"].as_str().unwrap().to_string(),
+                    counterparty["tx_count"].as_u64().unwrap(),
+                    counterparty["total_amount"].as_i64().unwrap(),
+                )
+            })
+            .collect()
+    };
+
+    let body: Value = actix_web::test::call_and_read_body_json(
+        &app,
+        request(format!("/accounts/{}/counterparties", account)),
+    )
+    .await;
+    // Ties on the count are ordered by total amount, and a transfer to itself counts once.
+    assert_eq!(
+        vec![
+            (first.to_string(), 3, 175),
+            (second.to_string(), 2, 1_010),
+            (third.to_string(), 1, 5),
+            (account.to_string(), 1, 1),
+        ],
+        summary(body.clone())
+    );
+    assert_eq!("0.00000101", body[1]["total_amount_sol"]);
+
+    let body: Value = actix_web::test::call_and_read_body_json(
+        &app,
+        request(format!("/accounts/{}/counterparties?limit=1", account)),
+    )
+    .await;
+    assert_eq!(vec![(first.to_string(), 3, 175)], summary(body));
+
+    let body: Value = actix_web::test::call_and_read_body_json(
+        &app,
+        request(format!("/accounts/{}/counterparties", Pubkey::new_unique())),
+    )
+    .await;
+    assert_eq!(json!([]), body);
+
+    let response = actix_web::test::call_service(
+        &app,
+        request("/accounts/not_a_pubkey/counterparties".to_string()),
+    )
+    .await;
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+}
+#[test]
+fn test_tls_config() {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert_path = temp_db_path("test_tls_config_cert");
+    let key_path = temp_db_path("test_tls_config_key");
+    fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+    fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+    let config = |vars: &[(&str, &PathBuf)]| {
+        Config::from_sources(
+            None,
+            [
+                ("ws_url", "wss://example.com"),
+                ("rpc_url", "https://example.com"),
+            ]
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .chain(
+                vars.iter()
+                    .map(|(key, path)| (key.to_string(), path.display().to_string())),
+            ),
+        )
+        .unwrap()
+    };
+
+    // Plain HTTP without a certificate and key, HTTPS with both.
+    assert!(restful_api::tls_config(&config(&[])).unwrap().is_none());
+    assert!(
+        restful_api::tls_config(&config(&[("TLS_CERT", &cert_path), ("TLS_KEY", &key_path)]))
+            .unwrap()
+            .is_some()
+    );
+
+    let err = restful_api::tls_config(&config(&[("TLS_CERT", &cert_path)])).unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+    let missing = temp_db_path("test_tls_config_missing");
+    let err = restful_api::tls_config(&config(&[("TLS_CERT", &missing), ("TLS_KEY", &key_path)]))
+        .unwrap_err();
+    assert!(err.to_string().contains(&missing.display().to_string()));
+    // The key file holds no certificate.
+    let err = restful_api::tls_config(&config(&[("TLS_CERT", &key_path), ("TLS_KEY", &key_path)]))
+        .unwrap_err();
+    assert!(err.to_string().starts_with("no certificate found"));
+}
+#[actix_web::test]
+async fn test_export_ndjson() {
+    let path = temp_db_path("test_export_ndjson");
+    let mut database = Database::with_path(&path).unwrap();
+    let senders = [Pubkey::new_unique(), Pubkey::new_unique()];
+    let records: Vec<TransactionRecord> = (0..3_000)
+        .map(|tx_index| TransactionRecord {
+            sender: senders[tx_index % 2].to_string(),
+            receiver: Pubkey::new_unique().to_string(),
+            amount: 1_000,
+            net_change: -1_000,
+            timestamp: Some("2024-07-28 10:00:00".to_string()),
+            signature: format!("export_{}", tx_index),
+            signatures: vec![format!("export_{}", tx_index)],
+            slot: 1,
+            block_height: None,
+            tx_index: tx_index as u64,
+            fee: 5_000,
+            program_id: None,
+            transfer_type: "sol".to_string(),
+            token_mint: None,
+            token_amount: None,
+            token_decimals: None,
+            status: "success".to_string(),
+            balance_changes: vec![],
+            logs: None,
+        })
+        .collect();
+    database.insert_batch(&records).unwrap();
+    drop(database);
+
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(DatabasePool::read_only(&path, 1)))
+            .service(restful_api::export_ndjson),
+    )
+    .await;
+    let request = |uri: String| actix_web::test::TestRequest::get().uri(&uri).to_request();
+
+    // The export is larger than the buffer of rows, so the reader has to wait for the response.
+    let response =
+        actix_web::test::call_service(&app, request("/transactions/export".to_string())).await;
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!(
+        "application/x-ndjson",
+        response.headers().get("content-type").unwrap()
+    );
+    let body = actix_web::test::read_body(response).await;
+    let lines: Vec<Value> = std::str::from_utf8(&body)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(3_000, lines.len());
+    assert_eq!("export_0", lines[0]["signature"]);
+    assert_eq!("0.000001", lines[0]["amount_sol"]);
+
+    // The filters of `/transactions` apply, and the connection was returned to the pool.
+    let body = actix_web::test::call_and_read_body(
+        &app,
+        request(format!("/transactions/export?sender={}", senders[1])),
+    )
+    .await;
+    assert_eq!(1_500, std::str::from_utf8(&body).unwrap().lines().count());
+
+    let response = actix_web::test::call_service(
+        &app,
+        request("/transactions/export?order_by=nonsense".to_string()),
+    )
+    .await;
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+}
+#[actix_web::test]
+async fn test_sender_stats() {
+    let path = temp_db_path("test_sender_stats");
+    drop(Database::with_path(&path).unwrap());
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(DatabasePool::read_only(&path, 1)))
+            .service(restful_api::sender_stats),
+    )
+    .await;
+    let request = |uri: &str| actix_web::test::TestRequest::get().uri(uri).to_request();
+    let stats: Value =
+        actix_web::test::call_and_read_body_json(&app, request("/stats/by-sender")).await;
+    assert_eq!(json!([]), stats);
+
+    let mut database = Database::with_path(&path).unwrap();
+    let senders: Vec<Pubkey> = (0..3).map(|_| Pubkey::new_unique()).collect();
+    for (tx_index, (sender, amount, timestamp)) in [
+        (0, 1_000_000_000, "2024-07-28 10:00:00"),
+        (1, 3_000_000_000, "2024-07-28 11:00:00"),
+        (0, 1_500_000_000, "2024-07-28 12:00:00"),
+        (2, 100, "2024-07-29 10:00:00"),
+        (1, 200, "2024-07-29 11:00:00"),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        database
+            .insert_batch(&[transfer_record(
+                senders[sender],
+                Pubkey::new_unique(),
+                amount,
+                0,
+                timestamp,
+                &format!("stats_{}", tx_index),
+                1,
+                tx_index,
+            )])
+            .unwrap();
+    }
+    drop(database);
+
+    let stats: Value =
+        actix_web::test::call_and_read_body_json(&app, request("/stats/by-sender")).await;
+    assert_eq!(
+        json!([
+            {
+                "sender": senders[1].to_string(),
+                "tx_count": 2,
+                "total_amount": 3_000_000_200i64,
+                "total_amount_sol": "3.0000002",
+            },
+            {
+                "sender": senders[0].to_string(),
+                "tx_count": 2,
+                "total_amount": 2_500_000_000i64,
+                "total_amount_sol": "2.5",
+            },
+            {
+                "sender": senders[2].to_string(),
+                "tx_count": 1,
+                "total_amount": 100,
+                "total_amount_sol": "0.0000001",
+            },
+        ]),
+        stats
+    );
+
+    let stats: Value = actix_web::test::call_and_read_body_json(
+        &app,
+        request("/stats/by-sender?end_date=2024-07-28&limit=1"),
+    )
+    .await;
+    assert_eq!(1, stats.as_array().unwrap().len());
+    assert_eq!(senders[1].to_string(), stats[0]["sender"]);
+    assert_eq!(3_000_000_000i64, stats[0]["total_amount"]);
+
+    let stats: Value =
+        actix_web::test::call_and_read_body_json(&app, request("/stats/by-sender?max_amount=1000"))
+            .await;
+    assert_eq!(2, stats.as_array().unwrap().len());
+    assert_eq!(senders[1].to_string(), stats[0]["sender"]);
+    assert_eq!(senders[2].to_string(), stats[1]["sender"]);
+
+    let response =
+        actix_web::test::call_service(&app, request("/stats/by-sender?start_date=soon")).await;
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+}
+#[actix_web::test]
+async fn test_timeseries() {
+    let path = temp_db_path("test_timeseries");
+    let mut database = Database::with_path(&path).unwrap();
+    let sender = Pubkey::new_unique();
+    for (tx_index, (amount, timestamp)) in [
+        (100, "2024-07-27 23:59:59"),
+        (200, "2024-07-28 00:00:00"),
+        (300, "2024-07-28 10:30:00"),
+        (400, "2024-07-28 10:45:00"),
+        (500, "2024-07-30 08:00:00"),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        database
+            .insert_batch(&[transfer_record(
+                if tx_index == 0 {
+                    sender
+                } else {
+                    Pubkey::new_unique()
+                },
+                Pubkey::new_unique(),
+                amount,
+                0,
+                timestamp,
+                &format!("timeseries_{}", tx_index),
+                1,
+                tx_index,
+            )])
+            .unwrap();
+    }
+    drop(database);
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(DatabasePool::read_only(&path, 1)))
+            .service(restful_api::timeseries),
+    )
+    .await;
+    let buckets = |uri: &str| {
+        let request = actix_web::test::TestRequest::get().uri(uri).to_request();
+        actix_web::test::call_and_read_body_json::<_, _, Value>(&app, request)
+    };
+
+    assert_eq!(
+        json!([
+            { "bucket_start": "2024-07-27 00:00:00", "tx_count": 1, "total_amount": 100 },
+            { "bucket_start": "2024-07-28 00:00:00", "tx_count": 3, "total_amount": 900 },
+            { "bucket_start": "2024-07-30 00:00:00", "tx_count": 1, "total_amount": 500 },
+        ]),
+        buckets("/stats/timeseries?interval=day").await
+    );
+    assert_eq!(
+        json!([
+            { "bucket_start": "2024-07-27 00:00:00", "tx_count": 1, "total_amount": 100 },
+            { "bucket_start": "2024-07-28 00:00:00", "tx_count": 3, "total_amount": 900 },
+            { "bucket_start": "2024-07-29 00:00:00", "tx_count": 0, "total_amount": 0 },
+            { "bucket_start": "2024-07-30 00:00:00", "tx_count": 1, "total_amount": 500 },
+        ]),
+        buckets("/stats/timeseries?interval=day&fill=true").await
+    );
+    assert_eq!(
+        json!([
+            { "bucket_start": "2024-07-28 00:00:00", "tx_count": 1, "total_amount": 200 },
+            { "bucket_start": "2024-07-28 10:00:00", "tx_count": 2, "total_amount": 700 },
+        ]),
+        buckets("/stats/timeseries?interval=hour&start_date=2024-07-28&end_date=2024-07-28").await
+    );
+    assert_eq!(
+        json!([{ "bucket_start": "2024-07-27 00:00:00", "tx_count": 1, "total_amount": 100 }]),
+        buckets(&format!("/stats/timeseries?sender={}&fill=true", sender)).await
+    );
+    assert_eq!(
+        json!([]),
+        buckets("/stats/timeseries?start_date=2025-01-01&fill=true").await
+    );
+
+    for uri in [
+        "/stats/timeseries?interval=week",
+        "/stats/timeseries?start_date=soon",
+    ] {
+        let request = actix_web::test::TestRequest::get().uri(uri).to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    }
+}
+#[actix_web::test]
+async fn test_date_filter() {
+    let path = temp_db_path("test_date_filter");
+    let mut database = Database::with_path(&path).unwrap();
+    for (tx_index, timestamp) in [
+        "2024-07-27 23:59:59",
+        "2024-07-28 00:00:00",
+        "2024-07-28 23:59:59",
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        database
+            .insert_batch(&[transfer_record(
+                Pubkey::new_unique(),
+                Pubkey::new_unique(),
+                1,
+                0,
+                timestamp,
+                &format!("tx_{}", tx_index),
+                1,
+                tx_index,
+            )])
+            .unwrap();
+    }
+    drop(database);
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(DatabasePool::read_only(&path, 1)))
+            .service(restful_api::transactions),
+    )
+    .await;
+    let request = |uri: &str| actix_web::test::TestRequest::get().uri(uri).to_request();
+
+    let rows: Vec<Value> = actix_web::test::call_and_read_body_json(
+        &app,
+        request(
+            "/transactions?start_date=2024-07-28%2000:00:00&end_date=%222024-07-28%2012:00:00%22",
+        ),
+    )
+    .await;
+    assert_eq!(1, rows.len());
+    assert_eq!("tx_1", rows[0]["signature"]);
+
+    let rows: Vec<Value> = actix_web::test::call_and_read_body_json(
+        &app,
+        request("/transactions?start_date=2024-07-28&end_date=2024-07-28"),
+    )
+    .await;
+    assert_eq!(2, rows.len());
+
+    for uri in [
+        "/transactions?start_date=yesterday",
+        "/transactions?end_date=2024-07-28%2025:00:00",
+        "/transactions?start_date=2024-07-28%22%20OR%201=1",
+    ] {
+        let response = actix_web::test::call_service(&app, request(uri)).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        let body: Value = actix_web::test::read_body_json(response).await;
+        assert_eq!("invalid_parameter", body["code"]);
+        assert!(body["message"]
+            .as_str()
+            .unwrap()
+            .contains("expected %Y-%m-%d %H:%M:%S or %Y-%m-%d"));
+    }
+}
+#[actix_web::test]
+async fn test_rest_api_in_memory() {
+    let name = format!("test_rest_api_in_memory_{}", std::process::id());
+    let mut database = Database::shared_in_memory(&name).unwrap();
+    let (alice, bob, carol, dave) = (
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+        Pubkey::new_unique().to_string(),
+    );
+    let record = |sender: &str,
+                  receiver: &str,
+                  amount: i64,
+                  timestamp: &str,
+                  signature: &str,
+                  fee: i64,
+                  status: &str| TransactionRecord {
+        sender: sender.to_string(),
+        receiver: receiver.to_string(),
+        amount,
+        net_change: -amount,
+        timestamp: Some(timestamp.to_string()),
+        signature: signature.to_string(),
+        signatures: vec![signature.to_string()],
+        slot: 1,
+        block_height: None,
+        tx_index: 0,
+        fee,
+        program_id: None,
+        transfer_type: "sol".to_string(),
+        token_mint: None,
+        token_amount: None,
+        token_decimals: None,
+        status: status.to_string(),
+        balance_changes: vec![],
+        logs: None,
+    };
+    database
+        .insert_batch(&[
+            record(
+                &alice,
+                &bob,
+                100,
+                "2024-07-01 10:00:00",
+                "t1",
+                5_000,
+                "success",
+            ),
+            record(
+                &alice,
+                &carol,
+                2_000,
+                "2024-07-02 10:00:00",
+                "t2",
+                5_000,
+                "success",
+            ),
+            record(
+                &bob,
+                &alice,
+                50,
+                "2024-07-03 10:00:00",
+                "t3",
+                10_000,
+                "failed",
+            ),
+            record(
+                &carol,
+                &dave,
+                7_000,
+                "2024-07-04 10:00:00",
+                "t4",
+                5_000,
+                "success",
+            ),
+            record(
+                &alice,
+                &dave,
+                300,
+                "2024-07-05 23:59:59",
+                "t5",
+                20_000,
+                "success",
+            ),
+        ])
+        .unwrap();
+    let (_sender, shutdown) = shutdown::channel();
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(DatabasePool::read_only(
+                &Database::shared_memory_path(&name),
+                2,
+            )))
+            .app_data(web::Data::new(shutdown))
+            .configure(restful_api::routes),
+    )
+    .await;
+    let request = |uri: &str| actix_web::test::TestRequest::get().uri(uri).to_request();
+    let signatures = |rows: Vec<Value>| -> Vec<String> {
+        rows.iter()
+            .map(|row| row["signature"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    let all = vec!["t1", "t2", "t3", "t4", "t5"];
+    for (query, expected) in [
+        (String::new(), all.clone()),
+        (format!("sender={}", alice), vec!["t1", "t2", "t5"]),
+        (
+            format!("sender={},{}", alice, bob),
+            vec!["t1", "t2", "t3", "t5"],
+        ),
+        (format!("receiver={}", dave), vec!["t4", "t5"]),
+        (
+            "start_date=2024-07-02&end_date=2024-07-04".to_string(),
+            vec!["t2", "t3", "t4"],
+        ),
+        ("end_date=2024-07-05".to_string(), all.clone()),
+        (
+            format!("sender={}&start_date=2024-07-02", alice),
+            vec!["t2", "t5"],
+        ),
+        (
+            "min_amount=100&max_amount=2000".to_string(),
+            vec!["t1", "t2", "t5"],
+        ),
+        ("min_fee=10000".to_string(), vec!["t3", "t5"]),
+        ("status=failed".to_string(), vec!["t3"]),
+        (format!("status=success&sender={}", bob), vec![]),
+        ("signature=t4".to_string(), vec!["t4"]),
+        (
+            format!("sender={}&receiver={}&min_amount=300", alice, dave),
+            vec!["t5"],
+        ),
+        // Values are bound, so injected SQL matches nothing instead of everything.
+        ("sender=%27%20OR%20%271%27%3D%271".to_string(), vec![]),
+        ("signature=t1%27%20OR%201%3D1%20--".to_string(), vec![]),
+    ] {
+        let response = actix_web::test::call_service(
+            &app,
+            request(&format!("/transactions?{}&order_by=timestamp", query)),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, response.status(), "{}", query);
+        let rows: Vec<Value> = actix_web::test::read_body_json(response).await;
+        assert_eq!(expected, signatures(rows), "{}", query);
+        let count: Value = actix_web::test::call_and_read_body_json(
+            &app,
+            request(&format!("/transactions/count?{}", query)),
+        )
+        .await;
+        assert_eq!(json!({ "count": expected.len() }), count, "{}", query);
+    }
+
+    // Paging with a cursor walks through every transaction once.
+    let mut pages = vec![];
+    let mut uri = "/transactions?order_by=timestamp&limit=2".to_string();
+    loop {
+        let rows: Vec<Value> = actix_web::test::call_and_read_body_json(&app, request(&uri)).await;
+        let page = signatures(rows);
+        match page.last() {
+            Some(last) => uri = format!("/transactions?since={}&limit=2", last),
+            None => break,
+        }
+        pages.push(page);
+    }
+    assert_eq!(vec![vec!["t1", "t2"], vec!["t3", "t4"], vec!["t5"]], pages);
+
+    for uri in [
+        "/transactions?start_date=yesterday",
+        "/transactions?order_by=amount;DROP",
+        "/transactions?status=pending",
+        "/transactions?min_amount=lots",
+        "/transactions/count?min_fee=lots",
+        "/stats/by-sender?limit=lots",
+    ] {
+        let response = actix_web::test::call_service(&app, request(uri)).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status(), "{}", uri);
+        let body: Value = actix_web::test::read_body_json(response).await;
+        assert_eq!("invalid_parameter", body["code"], "{}", uri);
+    }
+
+    let body: Value =
+        actix_web::test::call_and_read_body_json(&app, request("/transactions/t3")).await;
+    assert_eq!(bob, body["sender"]);
+    assert_eq!(alice, body["receiver"]);
+    assert_eq!(50, body["amount"]);
+    assert_eq!("0.00000005", body["amount_sol"]);
+    assert_eq!(10_000, body["fee"]);
+    assert_eq!("failed", body["status"]);
+    assert_eq!(json!(["t3"]), body["signatures"]);
+    assert_eq!("2024-07-03 10:00:00", body["timestamp"]);
+    let response = actix_web::test::call_service(&app, request("/transactions/t6")).await;
+    assert_eq!(StatusCode::NOT_FOUND, response.status());
+    drop(database);
+}
+
+#[actix_web::test]
+async fn test_batch() {
+    let name = format!("test_batch_{}", std::process::id());
+    let mut database = Database::shared_in_memory(&name).unwrap();
+    let (alice, bob) = (Pubkey::new_unique(), Pubkey::new_unique());
+    database
+        .insert_batch(&[transfer_record(
+            alice,
+            bob,
+            100,
+            5_000,
+            "2024-07-01 10:00:00",
+            "b1",
+            1,
+            0,
+        )])
+        .unwrap();
+    database
+        .insert_batch(&[transfer_record(
+            alice,
+            bob,
+            200,
+            5_000,
+            "2024-07-02 10:00:00",
+            "b2",
+            2,
+            0,
+        )])
+        .unwrap();
+    database
+        .insert_batch(&[transfer_record(
+            bob,
+            alice,
+            50,
+            5_000,
+            "2024-07-02 11:00:00",
+            "b3",
+            3,
+            0,
+        )])
+        .unwrap();
+    let (_sender, shutdown) = shutdown::channel();
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(DatabasePool::read_only(
+                &Database::shared_memory_path(&name),
+                2,
+            )))
+            .app_data(web::Data::new(shutdown))
+            .configure(restful_api::routes),
+    )
+    .await;
+    let batch = |body: Value| {
+        actix_web::test::TestRequest::post()
+            .uri("/batch")
+            .set_json(body)
+            .to_request()
+    };
+
+    let results: Value = actix_web::test::call_and_read_body_json(
+        &app,
+        batch(json!([
+            { "op": "by_sender", "params": { "limit": 1 } },
+            { "op": "count", "params": { "sender": [alice.to_string(), bob.to_string()], "start_date": "2024-07-02" } },
+            { "op": "transactions", "params": { "order_by": "timestamp", "limit": 1 } },
+            { "op": "count", "params": { "status": "pending" } },
+        ])),
+    )
+    .await;
+    let results = results.as_array().unwrap();
+    assert_eq!(4, results.len());
+    assert_eq!(alice.to_string(), results[0]["result"][0]["sender"]);
+    assert_eq!(300, results[0]["result"][0]["total_amount"]);
+    assert_eq!(1, results[0]["result"].as_array().unwrap().len());
+    assert_eq!(json!({ "count": 2 }), results[1]["result"]);
+    assert_eq!("b1", results[2]["result"][0]["signature"]);
+    assert_eq!(json!(["b1"]), results[2]["result"][0]["signatures"]);
+    assert_eq!("0.0000001", results[2]["result"][0]["amount_sol"]);
+    assert_eq!(1, results[2]["result"].as_array().unwrap().len());
+    // A failed query doesn't fail the others.
+    assert_eq!("invalid_parameter", results[3]["error"]["code"]);
+    assert!(results[3].get("result").is_none());
+
+    for body in [
+        json!([{ "op": "drop_table" }]),
+        json!({ "op": "count" }),
+        json!(vec![json!({ "op": "count" }); 21]),
+    ] {
+        let response = actix_web::test::call_service(&app, batch(body)).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        let body: Value = actix_web::test::read_body_json(response).await;
+        assert_eq!("invalid_parameter", body["code"]);
+    }
+    drop(database);
+}
+
+#[actix_web::test]
+async fn test_transaction_lookup() {
+    let path = temp_db_path("test_transaction_lookup");
+    let mut database = Database::with_path(&path).unwrap();
+    let sender = Pubkey::new_unique();
+    database
+        .insert_batch(&[transfer_record(
+            sender,
+            Pubkey::new_unique(),
+            42,
+            5_000,
+            "2024-07-28 21:11:50",
+            "lookup_signature",
+            7,
+            3,
+        )])
+        .unwrap();
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(DatabasePool::read_only(&path, 1)))
+            .service(restful_api::transaction_by_signature),
+    )
+    .await;
+    let request = |signature: &str| {
+        actix_web::test::TestRequest::get()
+            .uri(&format!("/transactions/{}", signature))
+            .to_request()
+    };
+
+    let response = actix_web::test::call_service(&app, request("lookup_signature")).await;
+    assert_eq!(StatusCode::OK, response.status());
+    let body: Value = actix_web::test::read_body_json(response).await;
+    assert_eq!("lookup_signature", body["signature"]);
+    assert_eq!(sender.to_string(), body["sender"]);
+    assert_eq!(42, body["amount"]);
+    assert_eq!("0.000000042", body["amount_sol"]);
+    assert_eq!(7, body["slot"]);
+
+    let response = actix_web::test::call_service(&app, request("unknown_signature")).await;
+    assert_eq!(StatusCode::NOT_FOUND, response.status());
+    let body: Value = actix_web::test::read_body_json(response).await;
+    assert_eq!("not_found", body["code"]);
+    assert_eq!("transaction not found", body["message"]);
+}
+#[actix_web::test]
+async fn test_concurrent_requests_share_pool() {
+    let path = temp_db_path("test_concurrent_requests_share_pool");
+    drop(Database::with_path(&path).unwrap());
+    let pool = std::sync::Arc::new(DatabasePool::read_only(&path, 4));
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::from(
+                pool.clone() as std::sync::Arc<dyn StorePool>
+            ))
+            .service(restful_api::health)
+            .service(restful_api::transaction_by_signature),
+    )
+    .await;
+
+    let responses = futures_util::future::join_all((0..500).map(|i| {
+        let uri = if i % 2 == 0 {
+            "/health".to_string()
+        } else {
+            format!("/transactions/unknown_{}", i)
+        };
+        actix_web::test::call_service(
+            &app,
+            actix_web::test::TestRequest::get().uri(&uri).to_request(),
+        )
+    }))
+    .await;
+    for (i, response) in responses.iter().enumerate() {
+        let expected = if i % 2 == 0 {
+            StatusCode::OK
+        } else {
+            StatusCode::NOT_FOUND
+        };
+        assert_eq!(expected, response.status());
+    }
+    assert!(pool.connections() <= 4);
+}
+#[actix_web::test]
+async fn test_transactions_error_responses() {
+    let path = temp_db_path("test_transactions_error_responses");
+    let empty = temp_db_path("test_transactions_error_responses_empty");
+    drop(Database::with_path(&path).unwrap());
+    // A database file without the `transactions` table.
+    drop(rusqlite::Connection::open(&empty).unwrap());
+    let request = |uri: &str| actix_web::test::TestRequest::get().uri(uri).to_request();
+
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(DatabasePool::read_only(&path, 1)))
+            .service(restful_api::transactions),
+    )
+    .await;
+    let response =
+        actix_web::test::call_service(&app, request("/transactions?order_by=amount")).await;
+    assert_eq!(StatusCode::OK, response.status());
+    let response =
+        actix_web::test::call_service(&app, request("/transactions?order_by=amount;DROP")).await;
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    let body: Value = actix_web::test::read_body_json(response).await;
+    assert_eq!("invalid_parameter", body["code"]);
+    assert!(body["message"].as_str().unwrap().contains("order_by"));
+
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(DatabasePool::read_only(&empty, 1)))
+            .service(restful_api::transactions),
+    )
+    .await;
+    let response = actix_web::test::call_service(&app, request("/transactions")).await;
+    assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+    let body: Value = actix_web::test::read_body_json(response).await;
+    assert_eq!("database_error", body["code"]);
+    assert_eq!("failed to query the database", body["message"]);
+
+    // A database that can't be opened is unavailable rather than failing.
+    let missing = env::temp_dir().join("missing_directory").join("missing.db");
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(store_pool(DatabasePool::read_only(&missing, 1)))
+            .service(restful_api::transactions)
+            .service(restful_api::transaction_by_signature),
+    )
+    .await;
+    for uri in ["/transactions", "/transactions/unknown"] {
+        let response = actix_web::test::call_service(&app, request(uri)).await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        let body: Value = actix_web::test::read_body_json(response).await;
+        assert_eq!("database_unavailable", body["code"]);
+        assert_eq!("failed to connect to the database", body["message"]);
+    }
+}
+#[test]
+fn test_api_error() {
+    use crate::error::{ApiError, ErrorCode};
+    use actix_web::ResponseError;
+    for (err, status) in [
+        (ApiError::invalid("invalid limit"), StatusCode::BAD_REQUEST),
+        (
+            ApiError::forbidden("admin token required"),
+            StatusCode::FORBIDDEN,
+        ),
+        (ApiError::not_found("logs not found"), StatusCode::NOT_FOUND),
+        (
+            ApiError::from(DatabaseError::ConnectError),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+        (
+            ApiError::from(DatabaseError::QueryError),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ),
+        (
+            ApiError::from(AggregatorError::TimeFetchError),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ),
+    ] {
+        assert_eq!(status, err.status_code());
+        assert_eq!(status, err.error_response().status());
+    }
+    assert_eq!(
+        ErrorCode::DatabaseUnavailable,
+        ApiError::from(AggregatorError::DatabaseError(DatabaseError::ConnectError)).code
+    );
+    assert_eq!(
+        json!("internal_error"),
+        serde_json::to_value(ErrorCode::InternalError).unwrap()
+    );
+}
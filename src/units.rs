@@ -0,0 +1,28 @@
+use solana_sdk::native_token::LAMPORTS_PER_SOL;
+
+/// The number of decimal places of a SOL amount.
+const SOL_DECIMALS: usize = 9;
+
+/// Converts an amount of lamports to SOL.
+///
+/// The conversion is done on integers rather than floats, so every lamport is kept. Trailing
+/// zeros of the fractional part are dropped.
+///
+/// # Arguments
+///
+/// * `lamports` - The amount in lamports, negative for a decrease.
+///
+/// # Returns
+///
+/// The amount in SOL as a decimal string, e.g. `1.5` for `1500000000` lamports.
+pub fn lamports_to_sol(lamports: i64) -> String {
+    let sign = if lamports < 0 { "-" } else { "" };
+    let lamports = lamports.unsigned_abs();
+    let whole = lamports / LAMPORTS_PER_SOL;
+    let fraction = lamports % LAMPORTS_PER_SOL;
+    if fraction == 0 {
+        return format!("{}{}", sign, whole);
+    }
+    let fraction = format!("{:0width$}", fraction, width = SOL_DECIMALS);
+    format!("{}{}.{}", sign, whole, fraction.trim_end_matches('0'))
+}